@@ -0,0 +1,669 @@
+//! Bitmap commands, which treat a string value as an array of bits. Bits are numbered from the
+//! most significant bit of the first byte, and reading past the end of the string reads zeros;
+//! writing past it grows the string with zero bytes first.
+
+use bytes::{Bytes, BytesMut};
+
+use crate::{
+    error::CommandError,
+    protocol_parser::{parse_integer, CommandArgs, RESPValue, Response},
+    rdb::{DBEntry, Rdb, Value},
+    string::{string, string_or_insert, substring_range},
+};
+
+// Strings can be at most 512MB, so this is the number of bits they can hold.
+const MAX_BITS: u64 = 512 * 1024 * 1024 * 8;
+
+/// Whether the range given to BITCOUNT or BITPOS counts bytes or bits.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Unit {
+    Byte,
+    Bit,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BitRange {
+    start: i64,
+    /// BITPOS may leave the end out, in which case the range runs to the end of the string.
+    end: Option<i64>,
+    unit: Unit,
+}
+
+impl BitRange {
+    // Parses the optional `start [end [BYTE|BIT]]` that BITCOUNT and BITPOS end in.
+    fn parse(args: &mut CommandArgs) -> Result<Option<Self>, CommandError> {
+        let Some(start) = args.next() else {
+            return Ok(None);
+        };
+        let start = parse_integer(&start)?;
+        let end = match args.next() {
+            Some(end) => Some(parse_integer(&end)?),
+            None => None,
+        };
+        let unit = match args.next() {
+            None => Unit::Byte,
+            Some(unit) => match String::from_utf8_lossy(&unit).to_ascii_uppercase().as_str() {
+                "BYTE" => Unit::Byte,
+                "BIT" => Unit::Bit,
+                _ => return Err(CommandError::Syntax),
+            },
+        };
+        args.finish().map_err(|_| CommandError::Syntax)?;
+
+        Ok(Some(BitRange { start, end, unit }))
+    }
+
+    /// Resolves the range against a string of `len` bytes into the inclusive positions of its
+    /// first and last bits, or `None` if it covers nothing.
+    fn resolve(range: Option<&Self>, len: usize) -> Option<(u64, u64)> {
+        let Some(range) = range else {
+            return (len > 0).then(|| (0, len as u64 * 8 - 1));
+        };
+        let end = range.end.unwrap_or(-1);
+        match range.unit {
+            Unit::Byte => substring_range(range.start, end, len)
+                .map(|(start, end)| (start as u64 * 8, end as u64 * 8 + 7)),
+            Unit::Bit => substring_range(range.start, end, len * 8)
+                .map(|(start, end)| (start as u64, end as u64)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BitOp {
+    And,
+    Or,
+    Xor,
+    Not,
+}
+
+/// An integer field of a bitmap, as BITFIELD addresses it: its type and the position of its first
+/// bit.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Field {
+    signed: bool,
+    width: u32,
+    offset: u64,
+}
+
+impl Field {
+    fn parse(encoding: &[u8], offset: &[u8]) -> Result<Self, CommandError> {
+        let invalid_type = || {
+            CommandError::Other(
+                "Invalid bitfield type. Use something like i16 u8. Note that u64 is not \
+                 supported but i64 is."
+                    .to_string(),
+            )
+        };
+        let (signed, width) = match encoding.split_first() {
+            Some((b'i' | b'I', width)) => (true, width),
+            Some((b'u' | b'U', width)) => (false, width),
+            _ => return Err(invalid_type()),
+        };
+        let width: u32 = parse_integer(width).map_err(|_| invalid_type())?;
+        let max_width = if signed { 64 } else { 63 };
+        if !(1..=max_width).contains(&width) {
+            return Err(invalid_type());
+        }
+
+        // A leading `#` counts the offset in fields of this type rather than in bits.
+        let offset = match offset.strip_prefix(b"#") {
+            Some(index) => parse_integer::<u64>(index)
+                .ok()
+                .and_then(|index| index.checked_mul(width as u64)),
+            None => parse_integer::<u64>(offset).ok(),
+        };
+        let offset = offset
+            .filter(|&offset| {
+                offset
+                    .checked_add(width as u64)
+                    .is_some_and(|end| end <= MAX_BITS)
+            })
+            .ok_or_else(invalid_offset)?;
+
+        Ok(Field {
+            signed,
+            width,
+            offset,
+        })
+    }
+
+    fn min(&self) -> i128 {
+        if self.signed {
+            -(1 << (self.width - 1))
+        } else {
+            0
+        }
+    }
+
+    fn max(&self) -> i128 {
+        if self.signed {
+            (1 << (self.width - 1)) - 1
+        } else {
+            (1 << self.width) - 1
+        }
+    }
+
+    fn get(&self, bytes: &[u8]) -> i64 {
+        let mut value: u64 = 0;
+        for bit in self.offset..self.offset + self.width as u64 {
+            value = (value << 1) | get_bit(bytes, bit) as u64;
+        }
+        // Sign-extend negative values of signed fields narrower than 64 bits.
+        if self.signed && self.width < 64 && value >> (self.width - 1) & 1 == 1 {
+            (value as i64) - (1i64 << self.width)
+        } else {
+            value as i64
+        }
+    }
+
+    fn set(&self, bytes: &mut BytesMut, value: i64) {
+        let value = value as u64;
+        for i in 0..self.width as u64 {
+            let bit = value >> (self.width as u64 - 1 - i) & 1 == 1;
+            set_bit(bytes, self.offset + i, bit);
+        }
+    }
+
+    /// Fits `value` into the field according to `overflow`, or returns `None` if it doesn't fit
+    /// and the overflow mode is FAIL.
+    fn fit(&self, value: i128, overflow: Overflow) -> Option<i64> {
+        let (min, max) = (self.min(), self.max());
+        if (min..=max).contains(&value) {
+            return Some(value as i64);
+        }
+        match overflow {
+            Overflow::Wrap => {
+                let modulus = 1i128 << self.width;
+                let wrapped = value.rem_euclid(modulus);
+                Some(if wrapped > max {
+                    wrapped - modulus
+                } else {
+                    wrapped
+                } as i64)
+            }
+            Overflow::Sat => Some(value.clamp(min, max) as i64),
+            Overflow::Fail => None,
+        }
+    }
+}
+
+/// What BITFIELD does when a SET or INCRBY doesn't fit in its field.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Overflow {
+    Wrap,
+    /// Saturate at the field's minimum or maximum value.
+    Sat,
+    /// Leave the field as it is and reply with a null.
+    Fail,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FieldOp {
+    Get(Field),
+    Set {
+        field: Field,
+        value: i64,
+        overflow: Overflow,
+    },
+    IncrBy {
+        field: Field,
+        increment: i64,
+        overflow: Overflow,
+    },
+}
+
+impl FieldOp {
+    fn field(&self) -> &Field {
+        match self {
+            FieldOp::Get(field) | FieldOp::Set { field, .. } | FieldOp::IncrBy { field, .. } => {
+                field
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum BitmapCommand {
+    SetBit {
+        key: Bytes,
+        offset: u64,
+        value: bool,
+    },
+    GetBit {
+        key: Bytes,
+        offset: u64,
+    },
+    Count {
+        key: Bytes,
+        range: Option<BitRange>,
+    },
+    Pos {
+        key: Bytes,
+        bit: bool,
+        range: Option<BitRange>,
+    },
+    Op {
+        op: BitOp,
+        destination: Bytes,
+        keys: Vec<Bytes>,
+    },
+    Field {
+        key: Bytes,
+        ops: Vec<FieldOp>,
+    },
+}
+
+impl BitmapCommand {
+    /// Parses the arguments of the bitmap command `name`, which must be upper-case.
+    pub fn parse(name: &str, args: &mut CommandArgs) -> Result<Self, CommandError> {
+        let command = match name {
+            "SETBIT" => {
+                let key = args.required()?;
+                let offset = parse_offset(&args.required()?)?;
+                let value = match args.required()?.as_ref() {
+                    b"0" => false,
+                    b"1" => true,
+                    _ => {
+                        return Err(CommandError::Other(
+                            "bit is not an integer or out of range".to_string(),
+                        ))
+                    }
+                };
+                BitmapCommand::SetBit { key, offset, value }
+            }
+            "GETBIT" => BitmapCommand::GetBit {
+                key: args.required()?,
+                offset: parse_offset(&args.required()?)?,
+            },
+            "BITCOUNT" => {
+                let key = args.required()?;
+                let range = BitRange::parse(args)?;
+                // Unlike BITPOS, a start has to come with an end.
+                if range.is_some_and(|range| range.end.is_none()) {
+                    return Err(CommandError::Syntax);
+                }
+                BitmapCommand::Count { key, range }
+            }
+            "BITPOS" => {
+                let key = args.required()?;
+                let bit = match args.required()?.as_ref() {
+                    b"0" => false,
+                    b"1" => true,
+                    _ => {
+                        return Err(CommandError::Other(
+                            "The bit argument must be 1 or 0.".to_string(),
+                        ))
+                    }
+                };
+                let range = BitRange::parse(args)?;
+                BitmapCommand::Pos { key, bit, range }
+            }
+            "BITOP" => {
+                let op = match String::from_utf8_lossy(&args.required()?)
+                    .to_ascii_uppercase()
+                    .as_str()
+                {
+                    "AND" => BitOp::And,
+                    "OR" => BitOp::Or,
+                    "XOR" => BitOp::Xor,
+                    "NOT" => BitOp::Not,
+                    _ => return Err(CommandError::Syntax),
+                };
+                let destination = args.required()?;
+                let keys = args.at_least_one()?;
+                if op == BitOp::Not && keys.len() != 1 {
+                    return Err(CommandError::Other(
+                        "BITOP NOT must be called with a single source key.".to_string(),
+                    ));
+                }
+                BitmapCommand::Op {
+                    op,
+                    destination,
+                    keys,
+                }
+            }
+            "BITFIELD" | "BITFIELD_RO" => {
+                let key = args.required()?;
+                let mut ops = Vec::new();
+                let mut overflow = Overflow::Wrap;
+                while let Some(subcommand) = args.next() {
+                    let subcommand = String::from_utf8_lossy(&subcommand).to_ascii_uppercase();
+                    if name == "BITFIELD_RO" && subcommand != "GET" {
+                        return Err(CommandError::Other(
+                            "BITFIELD_RO only supports the GET subcommand".to_string(),
+                        ));
+                    }
+
+                    match subcommand.as_str() {
+                        "GET" => {
+                            let field = Field::parse(&args.option_value()?, &args.option_value()?)?;
+                            ops.push(FieldOp::Get(field));
+                        }
+                        "SET" => {
+                            let field = Field::parse(&args.option_value()?, &args.option_value()?)?;
+                            let value = parse_integer(&args.option_value()?)?;
+                            ops.push(FieldOp::Set {
+                                field,
+                                value,
+                                overflow,
+                            });
+                        }
+                        "INCRBY" => {
+                            let field = Field::parse(&args.option_value()?, &args.option_value()?)?;
+                            let increment = parse_integer(&args.option_value()?)?;
+                            ops.push(FieldOp::IncrBy {
+                                field,
+                                increment,
+                                overflow,
+                            });
+                        }
+                        // Applies to the SET and INCRBY operations that follow it.
+                        "OVERFLOW" => {
+                            overflow = match String::from_utf8_lossy(&args.option_value()?)
+                                .to_ascii_uppercase()
+                                .as_str()
+                            {
+                                "WRAP" => Overflow::Wrap,
+                                "SAT" => Overflow::Sat,
+                                "FAIL" => Overflow::Fail,
+                                _ => {
+                                    return Err(CommandError::Other(
+                                        "Invalid OVERFLOW type specified".to_string(),
+                                    ))
+                                }
+                            };
+                        }
+                        _ => return Err(CommandError::Syntax),
+                    }
+                }
+                BitmapCommand::Field { key, ops }
+            }
+            _ => unreachable!("not a bitmap command: {}", name),
+        };
+
+        Ok(command)
+    }
+
+    pub fn execute(self, db: &mut Rdb) -> Result<Response, CommandError> {
+        let response = match self {
+            BitmapCommand::SetBit { key, offset, value } => {
+                let string = string_or_insert(db, &key)?;
+                let mut bytes = BytesMut::from(string.as_ref());
+                let previous = get_bit(&bytes, offset);
+                set_bit(&mut bytes, offset, value);
+                *string = bytes.freeze();
+                db.mark_dirty(1);
+                Response::Echo(RESPValue::Integer(previous as i64))
+            }
+            BitmapCommand::GetBit { key, offset } => {
+                let bit = string(db, &key)?.is_some_and(|bytes| get_bit(bytes, offset));
+                Response::Echo(RESPValue::Integer(bit as i64))
+            }
+            BitmapCommand::Count { key, range } => {
+                let bytes = string(db, &key)?.cloned().unwrap_or_default();
+                let count = BitRange::resolve(range.as_ref(), bytes.len())
+                    .map_or(0, |(first, last)| count_ones(&bytes, first, last));
+                Response::Echo(RESPValue::Integer(count as i64))
+            }
+            BitmapCommand::Pos { key, bit, range } => {
+                // A missing key is an endless run of zeros.
+                let Some(bytes) = string(db, &key)?.cloned() else {
+                    return Ok(Response::Echo(RESPValue::Integer(if bit { -1 } else { 0 })));
+                };
+                let position = match BitRange::resolve(range.as_ref(), bytes.len()) {
+                    None => -1,
+                    Some((first, last)) => match find_bit(&bytes, bit, first, last) {
+                        Some(position) => position as i64,
+                        // Without an explicit end, the string counts as padded with zeros, so
+                        // the first clear bit is the one just past the range.
+                        None if !bit && range.and_then(|range| range.end).is_none() => {
+                            last as i64 + 1
+                        }
+                        None => -1,
+                    },
+                };
+                Response::Echo(RESPValue::Integer(position))
+            }
+            BitmapCommand::Op {
+                op,
+                destination,
+                keys,
+            } => {
+                let sources = keys
+                    .iter()
+                    .map(|key| Ok(string(db, key)?.cloned().unwrap_or_default()))
+                    .collect::<Result<Vec<_>, CommandError>>()?;
+                let result = bit_op(op, &sources);
+
+                let len = result.len();
+                if result.is_empty() {
+                    db.remove(&destination);
+                } else {
+                    db.insert(destination, DBEntry::new(Value::String(result), None));
+                }
+                Response::Echo(RESPValue::Integer(len as i64))
+            }
+            BitmapCommand::Field { key, ops } => {
+                let mut bytes =
+                    BytesMut::from(string(db, &key)?.cloned().unwrap_or_default().as_ref());
+
+                // Any write makes sure the string reaches the furthest field written, whether or
+                // not the write ends up happening.
+                let writes_to = ops
+                    .iter()
+                    .filter(|op| !matches!(op, FieldOp::Get(_)))
+                    .map(|op| op.field().offset + op.field().width as u64)
+                    .max();
+                if let Some(bits) = writes_to {
+                    let len = bits.div_ceil(8) as usize;
+                    if bytes.len() < len {
+                        bytes.resize(len, 0);
+                    }
+                }
+
+                let mut changes = 0;
+                let replies = ops
+                    .iter()
+                    .map(|op| match *op {
+                        FieldOp::Get(field) => RESPValue::Integer(field.get(&bytes)),
+                        FieldOp::Set {
+                            field,
+                            value,
+                            overflow,
+                        } => {
+                            let previous = field.get(&bytes);
+                            // An unsigned field takes the value's bits as they are, so a negative
+                            // value overflows it.
+                            let value = if field.signed {
+                                value as i128
+                            } else {
+                                value as u64 as i128
+                            };
+                            match field.fit(value, overflow) {
+                                Some(value) => {
+                                    field.set(&mut bytes, value);
+                                    changes += 1;
+                                    RESPValue::Integer(previous)
+                                }
+                                None => RESPValue::Null,
+                            }
+                        }
+                        FieldOp::IncrBy {
+                            field,
+                            increment,
+                            overflow,
+                        } => {
+                            let value = field.get(&bytes) as i128 + increment as i128;
+                            match field.fit(value, overflow) {
+                                Some(value) => {
+                                    field.set(&mut bytes, value);
+                                    changes += 1;
+                                    RESPValue::Integer(value)
+                                }
+                                None => RESPValue::Null,
+                            }
+                        }
+                    })
+                    .collect();
+
+                if writes_to.is_some() {
+                    *string_or_insert(db, &key)? = bytes.freeze();
+                    db.mark_dirty(changes);
+                }
+                Response::Echo(RESPValue::Array(replies))
+            }
+        };
+
+        Ok(response)
+    }
+}
+
+fn parse_offset(arg: &[u8]) -> Result<u64, CommandError> {
+    parse_integer::<u64>(arg)
+        .ok()
+        .filter(|&offset| offset < MAX_BITS)
+        .ok_or_else(invalid_offset)
+}
+
+fn invalid_offset() -> CommandError {
+    CommandError::Other("bit offset is not an integer or out of range".to_string())
+}
+
+fn get_bit(bytes: &[u8], offset: u64) -> bool {
+    bytes
+        .get((offset / 8) as usize)
+        .is_some_and(|byte| byte >> (7 - offset % 8) & 1 == 1)
+}
+
+fn set_bit(bytes: &mut BytesMut, offset: u64, value: bool) {
+    let index = (offset / 8) as usize;
+    if bytes.len() <= index {
+        bytes.resize(index + 1, 0);
+    }
+    let mask = 1 << (7 - offset % 8);
+    if value {
+        bytes[index] |= mask;
+    } else {
+        bytes[index] &= !mask;
+    }
+}
+
+// The bytes holding the bits `first` to `last` inclusive, with the index of the first one. Each is
+// inverted first if `invert` is set, then masked down to just the bits in that range.
+fn masked_bytes(
+    bytes: &[u8],
+    first: u64,
+    last: u64,
+    invert: bool,
+) -> impl Iterator<Item = (usize, u8)> + '_ {
+    let (first_byte, last_byte) = ((first / 8) as usize, (last / 8) as usize);
+    (first_byte..=last_byte).map(move |index| {
+        let mut byte = bytes.get(index).copied().unwrap_or(0);
+        if invert {
+            byte = !byte;
+        }
+        if index == first_byte {
+            byte &= 0xFF >> (first % 8);
+        }
+        if index == last_byte {
+            byte &= 0xFF << (7 - last % 8);
+        }
+        (index, byte)
+    })
+}
+
+fn count_ones(bytes: &[u8], first: u64, last: u64) -> u64 {
+    masked_bytes(bytes, first, last, false)
+        .map(|(_, byte)| byte.count_ones() as u64)
+        .sum()
+}
+
+// Finds the first bit set to `bit` between `first` and `last` inclusive. Looking for a clear bit
+// is looking for a set one in the inverted bytes.
+fn find_bit(bytes: &[u8], bit: bool, first: u64, last: u64) -> Option<u64> {
+    masked_bytes(bytes, first, last, !bit)
+        .find(|&(_, byte)| byte != 0)
+        .map(|(index, byte)| index as u64 * 8 + byte.leading_zeros() as u64)
+}
+
+// Combines the sources byte by byte, with shorter ones padded with zero bytes to the longest.
+fn bit_op(op: BitOp, sources: &[Bytes]) -> Bytes {
+    let len = sources.iter().map(Bytes::len).max().unwrap_or(0);
+    let byte_at = |source: &Bytes, index: usize| source.get(index).copied().unwrap_or(0);
+
+    (0..len)
+        .map(|index| {
+            let mut bytes = sources.iter().map(|source| byte_at(source, index));
+            let first = bytes.next().unwrap_or(0);
+            match op {
+                BitOp::And => bytes.fold(first, |acc, byte| acc & byte),
+                BitOp::Or => bytes.fold(first, |acc, byte| acc | byte),
+                BitOp::Xor => bytes.fold(first, |acc, byte| acc ^ byte),
+                BitOp::Not => !first,
+            }
+        })
+        .collect::<Vec<u8>>()
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bit_search() {
+        let bytes = [0xFF, 0xF0, 0x00];
+        assert_eq!(count_ones(&bytes, 0, 23), 12);
+        assert_eq!(count_ones(&bytes, 5, 13), 7);
+        assert_eq!(find_bit(&bytes, false, 0, 23), Some(12));
+        assert_eq!(find_bit(&bytes, true, 12, 23), None);
+        assert_eq!(find_bit(&bytes, true, 3, 23), Some(3));
+        assert_eq!(find_bit(&[0x00, 0x01], true, 0, 15), Some(15));
+    }
+
+    #[test]
+    fn test_fields() {
+        let field = |encoding: &str, offset: &str| {
+            Field::parse(encoding.as_bytes(), offset.as_bytes()).unwrap()
+        };
+
+        let mut bytes = BytesMut::new();
+        let u8_at_1 = field("u8", "#1");
+        assert_eq!(u8_at_1.offset, 8);
+        u8_at_1.set(&mut bytes, 200);
+        assert_eq!(bytes.as_ref(), [0, 200]);
+        assert_eq!(field("i8", "8").get(&bytes), -56);
+        assert_eq!(field("u4", "8").get(&bytes), 12);
+        assert_eq!(field("i64", "0").get(&bytes), 200 << 48);
+
+        let i8 = field("i8", "0");
+        assert_eq!(i8.fit(127 + 1, Overflow::Wrap), Some(-128));
+        assert_eq!(i8.fit(-129, Overflow::Wrap), Some(127));
+        assert_eq!(i8.fit(1000, Overflow::Sat), Some(127));
+        assert_eq!(i8.fit(128, Overflow::Fail), None);
+        let u2 = field("u2", "0");
+        assert_eq!(u2.fit(5, Overflow::Wrap), Some(1));
+        assert_eq!(u2.fit(-1, Overflow::Sat), Some(0));
+        let i64 = field("i64", "0");
+        assert_eq!(
+            i64.fit(i64::MAX as i128 + 1, Overflow::Wrap),
+            Some(i64::MIN)
+        );
+
+        assert!(Field::parse(b"u64", b"0").is_err());
+        assert!(Field::parse(b"i0", b"0").is_err());
+        assert!(Field::parse(b"i8", b"-1").is_err());
+    }
+
+    #[test]
+    fn test_field_offset_range() {
+        let out_of_range = |encoding: &str, offset: &str| {
+            Field::parse(encoding.as_bytes(), offset.as_bytes()).err() == Some(invalid_offset())
+        };
+        assert!(out_of_range("u8", "18446744073709551615"));
+        assert!(out_of_range("u8", "#2305843009213693951"));
+        assert!(out_of_range("u8", &(MAX_BITS - 7).to_string()));
+        assert!(!out_of_range("u8", &(MAX_BITS - 8).to_string()));
+    }
+}
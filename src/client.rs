@@ -0,0 +1,51 @@
+use crate::protocol_parser::Protocol;
+use bytes::Bytes;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// State belonging to a single connection, which lives as long as the connection does rather than
+/// for a single command.
+#[derive(Debug)]
+pub struct Client {
+    id: u64,
+    protocol: Protocol,
+    name: Option<Bytes>,
+}
+
+impl Client {
+    pub fn new() -> Self {
+        Client {
+            id: NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed),
+            protocol: Protocol::default(),
+            name: None,
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    pub fn set_protocol(&mut self, protocol: Protocol) {
+        self.protocol = protocol;
+    }
+
+    #[allow(dead_code)]
+    pub fn name(&self) -> Option<&Bytes> {
+        self.name.as_ref()
+    }
+
+    pub fn set_name(&mut self, name: Option<Bytes>) {
+        self.name = name;
+    }
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Self::new()
+    }
+}
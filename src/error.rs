@@ -0,0 +1,69 @@
+use thiserror::Error;
+
+/// An error that is reported back to the client as an error reply, leaving the connection open.
+///
+/// The `Display` output is exactly what goes on the wire after the `-` prefix, so the first word
+/// is the Redis error code clients switch on (`ERR`, `WRONGTYPE`, ...).
+#[derive(Clone, Debug, Error, PartialEq)]
+pub enum CommandError {
+    #[error("ERR unknown command '{name}', with args beginning with: {args}")]
+    UnknownCommand { name: String, args: String },
+    #[error("ERR unknown subcommand '{subcommand}'. Try {command} HELP.")]
+    UnknownSubcommand { command: String, subcommand: String },
+    #[error("ERR wrong number of arguments for '{0}' command")]
+    WrongArity(String),
+    #[error("ERR syntax error")]
+    Syntax,
+    #[error("WRONGTYPE Operation against a key holding the wrong kind of value")]
+    WrongType,
+    #[error("ERR value is not an integer or out of range")]
+    NotInteger,
+    #[error("ERR value is not a valid float")]
+    NotFloat,
+    #[error("ERR value is out of range, must be positive")]
+    NotPositive,
+    #[error("ERR no such key")]
+    NoSuchKey,
+    #[error("WRONGTYPE Key is not a valid HyperLogLog string value.")]
+    InvalidHll,
+    #[error("INVALIDOBJ Corrupted HLL object detected")]
+    CorruptHll,
+    #[error("BUSYGROUP Consumer Group name already exists")]
+    BusyGroup,
+    #[error("NOGROUP {0}")]
+    NoGroup(String),
+    #[error("ERR invalid expire time in '{0}' command")]
+    InvalidExpireTime(String),
+    #[error("ERR Protocol error: {0}")]
+    Protocol(String),
+    #[error("NOPROTO unsupported protocol version")]
+    NoProto,
+    #[error("WRONGPASS invalid username-password pair or user is disabled.")]
+    WrongPass,
+    /// Any other `ERR` reply that only one or two commands can produce.
+    #[error("ERR {0}")]
+    Other(String),
+}
+
+impl CommandError {
+    /// Builds the error for a command name we don't recognise, quoting the start of its arguments
+    /// the same way Redis does so clients can tell what was attempted.
+    pub fn unknown_command(name: &[u8], args: &[bytes::Bytes]) -> Self {
+        const MAX_ARGS_LEN: usize = 128;
+
+        let mut quoted = String::new();
+        for arg in args {
+            if quoted.len() >= MAX_ARGS_LEN {
+                break;
+            }
+            let remaining = MAX_ARGS_LEN - quoted.len();
+            let arg = String::from_utf8_lossy(&arg[..arg.len().min(remaining)]);
+            quoted.push_str(&format!("'{}' ", arg));
+        }
+
+        CommandError::UnknownCommand {
+            name: String::from_utf8_lossy(name).into_owned(),
+            args: quoted,
+        }
+    }
+}
@@ -0,0 +1,106 @@
+//! Active expiry: periodically deleting expired keys that nobody reads any more, which lazy
+//! expiry on access would otherwise keep in memory forever.
+//!
+//! This follows Redis' approach. Every cycle samples random keys from those with an expiry set and
+//! deletes the ones that have expired. If a large share of a sample had expired there are
+//! probably many more, so it samples again, until the share drops or the cycle runs out of time.
+//! Hashes with fields that have an expiry of their own are sampled too, deleting those fields.
+
+use std::time::{Duration, Instant};
+
+/// How often a cycle runs, i.e. Redis' default `hz` of 10.
+pub const HZ: u64 = 10;
+/// How many keys are sampled in each round of a cycle.
+const KEYS_PER_LOOP: usize = 20;
+/// A cycle stops once no more than this percentage of a sample had expired.
+const ACCEPTABLE_STALE_PERCENT: usize = 10;
+/// The share of each period a cycle may spend before giving up, so it can't starve clients.
+const TIME_LIMIT_PERCENT: u64 = 25;
+
+/// Counters reported in the stats section of INFO.
+#[derive(Debug, Default)]
+pub struct ExpiryStats {
+    /// Keys deleted because they expired, whether found on access or by the active cycle.
+    pub expired_keys: u64,
+    /// Hash fields deleted because they expired.
+    pub expired_subkeys: u64,
+    /// A running estimate of the percentage of keys with an expiry that have expired.
+    pub expired_stale_perc: f64,
+    /// How many cycles stopped early because they ran out of time.
+    pub expired_time_cap_reached_count: u64,
+    pub expire_cycle_cpu_milliseconds: u64,
+    /// A running estimate of the average time to live of keys with an expiry, in milliseconds.
+    pub avg_ttl: i64,
+}
+
+pub async fn run_active_expiry() {
+    let mut interval = tokio::time::interval(Duration::from_millis(1000 / HZ));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        interval.tick().await;
+        active_expire_cycle();
+    }
+}
+
+fn active_expire_cycle() {
+    let start = Instant::now();
+    let time_limit = Duration::from_millis(1000 / HZ * TIME_LIMIT_PERCENT / 100);
+
+    let mut total_sampled = 0;
+    let mut total_expired = 0;
+    let mut time_cap_reached = false;
+
+    loop {
+        // Take the lock for one round at a time, so clients get a look in between rounds.
+        let mut db = crate::db();
+        let sample = db.expire_sample(KEYS_PER_LOOP);
+        if sample.sampled == 0 {
+            break;
+        }
+
+        total_sampled += sample.sampled;
+        total_expired += sample.expired;
+
+        let live = sample.sampled - sample.expired;
+        if live > 0 {
+            // Blend each round into the running average rather than replacing it, as Redis does.
+            let stats = db.expiry_stats_mut();
+            let avg_ttl = sample.ttl_sum / live as i64;
+            stats.avg_ttl = if stats.avg_ttl == 0 {
+                avg_ttl
+            } else {
+                (stats.avg_ttl / 50) * 49 + avg_ttl / 50
+            };
+        }
+
+        if start.elapsed() > time_limit {
+            time_cap_reached = true;
+            break;
+        }
+
+        if sample.expired * 100 <= sample.sampled * ACCEPTABLE_STALE_PERCENT {
+            break;
+        }
+    }
+
+    // Hash fields get one round of their own, which is enough to keep up with them as each
+    // sampled hash has all its expired fields deleted at once.
+    let mut db = crate::db();
+    if !time_cap_reached {
+        db.expire_fields_sample(KEYS_PER_LOOP);
+    }
+
+    let stats = db.expiry_stats_mut();
+    stats.expire_cycle_cpu_milliseconds += start.elapsed().as_millis() as u64;
+    if time_cap_reached {
+        stats.expired_time_cap_reached_count += 1;
+    }
+
+    let current_perc = if total_sampled > 0 {
+        total_expired as f64 / total_sampled as f64
+    } else {
+        0.0
+    };
+    stats.expired_stale_perc = current_perc * 0.05 + stats.expired_stale_perc * 0.95;
+}
@@ -0,0 +1,902 @@
+//! Geospatial indexes. These are plain sorted sets whose scores are 52 bit geohashes, the same
+//! encoding Redis uses, so the sorted set commands work on them too.
+//!
+//! A geohash scales the longitude and the latitude each to a 26 bit integer and interleaves their
+//! bits, latitude first. Nearby points then tend to have nearby scores: every cell of the grid at
+//! a coarser precision is one contiguous range of scores.
+
+use bytes::Bytes;
+
+use crate::{
+    error::CommandError,
+    protocol_parser::{parse_float, parse_integer, CommandArgs, RESPValue, Response},
+    rdb::Rdb,
+    zset::{self, sorted_set, sorted_set_or_insert, ScoreBound, ScoreRange},
+};
+
+// Latitudes are limited to what the Web Mercator projection covers.
+const LONGITUDE_MIN: f64 = -180.0;
+const LONGITUDE_MAX: f64 = 180.0;
+const LATITUDE_MIN: f64 = -85.05112878;
+const LATITUDE_MAX: f64 = 85.05112878;
+const STEP_MAX: u32 = 26;
+const MERCATOR_MAX: f64 = 20037726.37;
+const EARTH_RADIUS_IN_METERS: f64 = 6372797.560856;
+const GEOHASH_ALPHABET: &[u8; 32] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+/// A point on the globe, in degrees.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Point {
+    pub longitude: f64,
+    pub latitude: f64,
+}
+
+impl Point {
+    /// Parses a longitude and latitude, which must be within the area geohashes cover.
+    fn parse(longitude: &[u8], latitude: &[u8]) -> Result<Self, CommandError> {
+        let point = Point {
+            longitude: parse_float(longitude)?,
+            latitude: parse_float(latitude)?,
+        };
+        if !(LONGITUDE_MIN..=LONGITUDE_MAX).contains(&point.longitude)
+            || !(LATITUDE_MIN..=LATITUDE_MAX).contains(&point.latitude)
+        {
+            return Err(CommandError::Other(format!(
+                "invalid longitude,latitude pair {:.6},{:.6}",
+                point.longitude, point.latitude
+            )));
+        }
+        Ok(point)
+    }
+
+    /// The point's geohash at full precision, as stored in the sorted set.
+    pub fn score(self) -> f64 {
+        Cell::containing(self, STEP_MAX).bits as f64
+    }
+
+    /// The centre of the cell a score stands for, which is as close to the original point as a
+    /// geohash can get.
+    pub fn from_score(score: f64) -> Self {
+        Cell {
+            bits: score as u64,
+            step: STEP_MAX,
+        }
+        .bounds()
+        .centre()
+    }
+
+    /// The great-circle distance to `other` in meters, using the haversine formula.
+    pub fn distance(self, other: Point) -> f64 {
+        let v = ((other.longitude.to_radians() - self.longitude.to_radians()) / 2.0).sin();
+        // Along the same meridian, the distance is just the difference in latitude.
+        if v == 0.0 {
+            return latitude_distance(self.latitude, other.latitude);
+        }
+        let (lat1, lat2) = (self.latitude.to_radians(), other.latitude.to_radians());
+        let u = ((lat2 - lat1) / 2.0).sin();
+        let a = u * u + lat1.cos() * lat2.cos() * v * v;
+        2.0 * EARTH_RADIUS_IN_METERS * a.sqrt().asin()
+    }
+
+    /// The standard 11 character geohash. Unlike the scores, this uses the full range of
+    /// latitudes, and its last character is always `0` since there are only 52 bits to show.
+    fn geohash(self) -> String {
+        let bits = interleave(
+            scale(self.latitude, -90.0, 90.0, STEP_MAX),
+            scale(self.longitude, LONGITUDE_MIN, LONGITUDE_MAX, STEP_MAX),
+        );
+        (0..11)
+            .map(|i| {
+                let index = if i == 10 {
+                    0
+                } else {
+                    (bits >> (52 - (i + 1) * 5)) & 0x1f
+                };
+                GEOHASH_ALPHABET[index as usize] as char
+            })
+            .collect()
+    }
+}
+
+fn latitude_distance(lat1: f64, lat2: f64) -> f64 {
+    EARTH_RADIUS_IN_METERS * (lat2.to_radians() - lat1.to_radians()).abs()
+}
+
+// Scales a coordinate within `min..max` to a `step` bit integer.
+fn scale(value: f64, min: f64, max: f64, step: u32) -> u32 {
+    let offset = (value - min) / (max - min) * (1u64 << step) as f64;
+    // The top of the range would need one more bit, so it shares the last cell.
+    (offset as u64).min((1 << step) - 1) as u32
+}
+
+// Interleaves the bits of `even` and `odd`, `even` taking the lowest.
+fn interleave(even: u32, odd: u32) -> u64 {
+    spread(even) | spread(odd) << 1
+}
+
+// Spaces out the bits of `value` over the even bits of the result.
+fn spread(value: u32) -> u64 {
+    let mut v = value as u64;
+    v = (v | v << 16) & 0x0000ffff0000ffff;
+    v = (v | v << 8) & 0x00ff00ff00ff00ff;
+    v = (v | v << 4) & 0x0f0f0f0f0f0f0f0f;
+    v = (v | v << 2) & 0x3333333333333333;
+    v = (v | v << 1) & 0x5555555555555555;
+    v
+}
+
+// Gathers the even bits of `value` back together, undoing `spread`.
+fn squash(value: u64) -> u32 {
+    let mut v = value & 0x5555555555555555;
+    v = (v | v >> 1) & 0x3333333333333333;
+    v = (v | v >> 2) & 0x0f0f0f0f0f0f0f0f;
+    v = (v | v >> 4) & 0x00ff00ff00ff00ff;
+    v = (v | v >> 8) & 0x0000ffff0000ffff;
+    v = (v | v >> 16) & 0x00000000ffffffff;
+    v as u32
+}
+
+/// A cell of the geohash grid at a precision of `step` bits for each coordinate.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Cell {
+    bits: u64,
+    step: u32,
+}
+
+/// The edges of a cell, or of the area a search covers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Bounds {
+    min_longitude: f64,
+    max_longitude: f64,
+    min_latitude: f64,
+    max_latitude: f64,
+}
+
+impl Bounds {
+    fn centre(self) -> Point {
+        Point {
+            longitude: ((self.min_longitude + self.max_longitude) / 2.0)
+                .clamp(LONGITUDE_MIN, LONGITUDE_MAX),
+            latitude: ((self.min_latitude + self.max_latitude) / 2.0)
+                .clamp(LATITUDE_MIN, LATITUDE_MAX),
+        }
+    }
+}
+
+impl Cell {
+    fn containing(point: Point, step: u32) -> Self {
+        Cell {
+            bits: interleave(
+                scale(point.latitude, LATITUDE_MIN, LATITUDE_MAX, step),
+                scale(point.longitude, LONGITUDE_MIN, LONGITUDE_MAX, step),
+            ),
+            step,
+        }
+    }
+
+    fn bounds(self) -> Bounds {
+        let cells = (1u64 << self.step) as f64;
+        let latitude = squash(self.bits) as f64;
+        let longitude = squash(self.bits >> 1) as f64;
+        let latitude_scale = LATITUDE_MAX - LATITUDE_MIN;
+        let longitude_scale = LONGITUDE_MAX - LONGITUDE_MIN;
+        Bounds {
+            min_longitude: LONGITUDE_MIN + longitude / cells * longitude_scale,
+            max_longitude: LONGITUDE_MIN + (longitude + 1.0) / cells * longitude_scale,
+            min_latitude: LATITUDE_MIN + latitude / cells * latitude_scale,
+            max_latitude: LATITUDE_MIN + (latitude + 1.0) / cells * latitude_scale,
+        }
+    }
+
+    /// The cell `east` cells across and `north` cells up, wrapping around the edges of the grid.
+    fn neighbour(self, east: i64, north: i64) -> Self {
+        let cells = 1i64 << self.step;
+        let latitude = (squash(self.bits) as i64 + north).rem_euclid(cells);
+        let longitude = (squash(self.bits >> 1) as i64 + east).rem_euclid(cells);
+        Cell {
+            bits: interleave(latitude as u32, longitude as u32),
+            step: self.step,
+        }
+    }
+
+    /// The scores of every point inside this cell.
+    fn scores(self) -> ScoreRange {
+        let shift = 2 * (STEP_MAX - self.step);
+        ScoreRange {
+            min: ScoreBound::new((self.bits << shift) as f64, false),
+            max: ScoreBound::new(((self.bits + 1) << shift) as f64, true),
+        }
+    }
+}
+
+/// The units distances can be given and reported in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Unit {
+    Meters,
+    Kilometers,
+    Feet,
+    Miles,
+}
+
+impl Unit {
+    fn parse(arg: &[u8]) -> Result<Self, CommandError> {
+        match arg.to_ascii_lowercase().as_slice() {
+            b"m" => Ok(Unit::Meters),
+            b"km" => Ok(Unit::Kilometers),
+            b"ft" => Ok(Unit::Feet),
+            b"mi" => Ok(Unit::Miles),
+            _ => Err(CommandError::Other(
+                "unsupported unit provided. please use M, KM, FT, MI".to_string(),
+            )),
+        }
+    }
+
+    fn meters(self) -> f64 {
+        match self {
+            Unit::Meters => 1.0,
+            Unit::Kilometers => 1000.0,
+            Unit::Feet => 0.3048,
+            Unit::Miles => 1609.34,
+        }
+    }
+}
+
+// Distances are always replied with four decimal places.
+fn distance_reply(meters: f64, unit: Unit) -> RESPValue {
+    RESPValue::BulkString(Bytes::from(format!("{:.4}", meters / unit.meters())))
+}
+
+fn point_reply(point: Point) -> RESPValue {
+    RESPValue::Array(vec![
+        RESPValue::Double(point.longitude),
+        RESPValue::Double(point.latitude),
+    ])
+}
+
+/// Where a search is centred: on a member of the set, or at a given point.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Origin {
+    Member(Bytes),
+    Point(Point),
+}
+
+/// The area a search covers, with its dimensions in meters.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Shape {
+    Radius(f64),
+    Box { width: f64, height: f64 },
+}
+
+impl Shape {
+    /// The distance from `centre` to `point` if `point` is within this shape around `centre`.
+    fn distance_within(self, centre: Point, point: Point) -> Option<f64> {
+        match self {
+            Shape::Radius(radius) => {
+                let distance = centre.distance(point);
+                (distance <= radius).then_some(distance)
+            }
+            Shape::Box { width, height } => {
+                if latitude_distance(point.latitude, centre.latitude) > height / 2.0 {
+                    return None;
+                }
+                let across = Point {
+                    latitude: point.latitude,
+                    ..centre
+                };
+                if point.distance(across) > width / 2.0 {
+                    return None;
+                }
+                Some(centre.distance(point))
+            }
+        }
+    }
+
+    // The smallest box of longitudes and latitudes that holds the shape.
+    fn bounds(self, centre: Point) -> Bounds {
+        let (half_width, half_height) = match self {
+            Shape::Radius(radius) => (radius, radius),
+            Shape::Box { width, height } => (width / 2.0, height / 2.0),
+        };
+        let latitude_delta = (half_height / EARTH_RADIUS_IN_METERS).to_degrees();
+        // Lines of longitude are furthest apart on the side of the shape nearest the equator.
+        let nearest_equator = if centre.latitude < 0.0 {
+            centre.latitude - latitude_delta
+        } else {
+            centre.latitude + latitude_delta
+        };
+        let longitude_delta =
+            (half_width / EARTH_RADIUS_IN_METERS / nearest_equator.to_radians().cos()).to_degrees();
+        Bounds {
+            min_longitude: centre.longitude - longitude_delta,
+            max_longitude: centre.longitude + longitude_delta,
+            min_latitude: centre.latitude - latitude_delta,
+            max_latitude: centre.latitude + latitude_delta,
+        }
+    }
+
+    // The distance from the centre to the furthest point of the shape.
+    fn radius(self) -> f64 {
+        match self {
+            Shape::Radius(radius) => radius,
+            Shape::Box { width, height } => (width / 2.0).hypot(height / 2.0),
+        }
+    }
+
+    /// The cells that between them cover the shape around `centre`: the cell holding the centre,
+    /// chosen to be about as large as the shape, and whichever of its eight neighbours the shape
+    /// reaches into.
+    fn cells(self, centre: Point) -> Vec<Cell> {
+        let bounds = self.bounds(centre);
+        let mut step = estimate_step(self.radius(), centre.latitude);
+        let mut cell = Cell::containing(centre, step);
+
+        // Near the edge of a cell, the shape can reach past the neighbours, so use larger cells.
+        let reaches_past = cell.neighbour(0, 1).bounds().max_latitude < bounds.max_latitude
+            || cell.neighbour(0, -1).bounds().min_latitude > bounds.min_latitude
+            || cell.neighbour(1, 0).bounds().max_longitude < bounds.max_longitude
+            || cell.neighbour(-1, 0).bounds().min_longitude > bounds.min_longitude;
+        if step > 1 && reaches_past {
+            step -= 1;
+            cell = Cell::containing(centre, step);
+        }
+
+        let area = cell.bounds();
+        let mut cells: Vec<Cell> = Vec::with_capacity(9);
+        // Centre, north, south, east, west, then the corners, as Redis visits them.
+        for (east, north) in [
+            (0, 0),
+            (0, 1),
+            (0, -1),
+            (1, 0),
+            (-1, 0),
+            (1, 1),
+            (-1, 1),
+            (1, -1),
+            (-1, -1),
+        ] {
+            // Skip the neighbours the shape doesn't reach into.
+            let unreached = (north < 0 && area.min_latitude < bounds.min_latitude)
+                || (north > 0 && area.max_latitude > bounds.max_latitude)
+                || (east < 0 && area.min_longitude < bounds.min_longitude)
+                || (east > 0 && area.max_longitude > bounds.max_longitude);
+            if step >= 2 && unreached {
+                continue;
+            }
+            // With large enough cells, neighbours wrap around to the same cell.
+            let neighbour = cell.neighbour(east, north);
+            if !cells.contains(&neighbour) {
+                cells.push(neighbour);
+            }
+        }
+        cells
+    }
+}
+
+// Picks the precision at which a cell is about as large as a search with this radius.
+fn estimate_step(radius: f64, latitude: f64) -> u32 {
+    if radius == 0.0 {
+        return STEP_MAX;
+    }
+    let mut step: i32 = 1;
+    let mut range = radius;
+    while range < MERCATOR_MAX {
+        range *= 2.0;
+        step += 1;
+    }
+    // Leave room for the shape not being centred in its cell.
+    step -= 2;
+    // Cells narrow towards the poles.
+    if latitude.abs() > 66.0 {
+        step -= 1;
+        if latitude.abs() > 80.0 {
+            step -= 1;
+        }
+    }
+    step.clamp(1, STEP_MAX as i32) as u32
+}
+
+/// The order to return search results in, by distance from the centre.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Order {
+    Asc,
+    Desc,
+}
+
+// A member a search found, with its score and its distance from the centre in meters.
+type Match = (Bytes, f64, f64);
+
+/// GEOSEARCH and GEOSEARCHSTORE's options.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Search {
+    origin: Origin,
+    shape: Shape,
+    unit: Unit,
+    order: Option<Order>,
+    count: Option<usize>,
+    any: bool,
+    with_coord: bool,
+    with_dist: bool,
+    with_hash: bool,
+    store_dist: bool,
+}
+
+impl Search {
+    // Parses the options following the key. `store` is set for GEOSEARCHSTORE, which takes
+    // STOREDIST instead of the WITH options.
+    fn parse(name: &str, args: &mut CommandArgs, store: bool) -> Result<Self, CommandError> {
+        let mut origin = None;
+        let mut shape = None;
+        let mut order = None;
+        let mut count = None;
+        let mut any = false;
+        let (mut with_coord, mut with_dist, mut with_hash, mut store_dist) =
+            (false, false, false, false);
+
+        let one_origin = || {
+            CommandError::Other(format!(
+                "exactly one of FROMMEMBER or FROMLONLAT can be specified for {}",
+                name
+            ))
+        };
+        let one_shape = || {
+            CommandError::Other(format!(
+                "exactly one of BYRADIUS and BYBOX can be specified for {}",
+                name
+            ))
+        };
+
+        while let Some(option) = args.next() {
+            match option.to_ascii_uppercase().as_slice() {
+                b"WITHCOORD" => with_coord = true,
+                b"WITHDIST" => with_dist = true,
+                b"WITHHASH" => with_hash = true,
+                b"STOREDIST" if store => store_dist = true,
+                b"ANY" => any = true,
+                b"ASC" => order = Some(Order::Asc),
+                b"DESC" => order = Some(Order::Desc),
+                b"COUNT" => {
+                    let not_positive = || CommandError::Other("COUNT must be > 0".to_string());
+                    let value: i64 =
+                        parse_integer(&args.option_value()?).map_err(|_| not_positive())?;
+                    if value <= 0 {
+                        return Err(not_positive());
+                    }
+                    count = Some(value as usize);
+                }
+                b"FROMMEMBER" => {
+                    let member = args.option_value()?;
+                    if origin.is_some() {
+                        return Err(one_origin());
+                    }
+                    origin = Some(Origin::Member(member));
+                }
+                b"FROMLONLAT" => {
+                    let longitude = args.option_value()?;
+                    let latitude = args.option_value()?;
+                    if origin.is_some() {
+                        return Err(one_origin());
+                    }
+                    origin = Some(Origin::Point(Point::parse(&longitude, &latitude)?));
+                }
+                b"BYRADIUS" => {
+                    let radius = args.option_value()?;
+                    let unit = args.option_value()?;
+                    if shape.is_some() {
+                        return Err(one_shape());
+                    }
+                    let radius = parse_dimension(&radius, "radius")?;
+                    if radius < 0.0 {
+                        return Err(CommandError::Other("radius cannot be negative".to_string()));
+                    }
+                    let unit = Unit::parse(&unit)?;
+                    shape = Some((Shape::Radius(radius * unit.meters()), unit));
+                }
+                b"BYBOX" => {
+                    let width = args.option_value()?;
+                    let height = args.option_value()?;
+                    let unit = args.option_value()?;
+                    if shape.is_some() {
+                        return Err(one_shape());
+                    }
+                    let width = parse_dimension(&width, "width")?;
+                    let height = parse_dimension(&height, "height")?;
+                    if width < 0.0 || height < 0.0 {
+                        return Err(CommandError::Other(
+                            "height or width cannot be negative".to_string(),
+                        ));
+                    }
+                    let unit = Unit::parse(&unit)?;
+                    shape = Some((
+                        Shape::Box {
+                            width: width * unit.meters(),
+                            height: height * unit.meters(),
+                        },
+                        unit,
+                    ));
+                }
+                _ => return Err(CommandError::Syntax),
+            }
+        }
+
+        if store && (with_coord || with_dist || with_hash) {
+            return Err(CommandError::Other(format!(
+                "{} is not compatible with WITHDIST, WITHHASH and WITHCOORD options",
+                name
+            )));
+        }
+        let origin = origin.ok_or_else(one_origin)?;
+        let (shape, unit) = shape.ok_or_else(one_shape)?;
+        if any && count.is_none() {
+            return Err(CommandError::Other(
+                "the ANY argument requires COUNT argument".to_string(),
+            ));
+        }
+        // Without ANY, COUNT returns the closest matches, so they have to be sorted.
+        if count.is_some() && order.is_none() && !any {
+            order = Some(Order::Asc);
+        }
+
+        Ok(Search {
+            origin,
+            shape,
+            unit,
+            order,
+            count,
+            any,
+            with_coord,
+            with_dist,
+            with_hash,
+            store_dist,
+        })
+    }
+
+    // Finds the members within the search area, or `None` if there's no key to search.
+    fn run(&self, db: &mut Rdb, key: &[u8]) -> Result<Option<Vec<Match>>, CommandError> {
+        let Some(set) = sorted_set(db, key)? else {
+            return Ok(None);
+        };
+        let centre = match &self.origin {
+            Origin::Point(point) => *point,
+            Origin::Member(member) => {
+                set.score(member).map(Point::from_score).ok_or_else(|| {
+                    CommandError::Other("could not decode requested zset member".to_string())
+                })?
+            }
+        };
+
+        // With ANY, stop as soon as there are enough matches, whichever they are.
+        let limit = self.count.filter(|_| self.any).unwrap_or(usize::MAX);
+        let mut matches = Vec::new();
+        'cells: for cell in self.shape.cells(centre) {
+            let ranks = set.score_ranks(&cell.scores());
+            for (member, score) in set.entries(ranks, false) {
+                if matches.len() >= limit {
+                    break 'cells;
+                }
+                if let Some(distance) = self.shape.distance_within(centre, Point::from_score(score))
+                {
+                    matches.push((member, score, distance));
+                }
+            }
+        }
+
+        match self.order {
+            Some(Order::Asc) => matches.sort_by(|a, b| a.2.total_cmp(&b.2)),
+            Some(Order::Desc) => matches.sort_by(|a, b| b.2.total_cmp(&a.2)),
+            None => {}
+        }
+        if let Some(count) = self.count {
+            matches.truncate(count);
+        }
+        Ok(Some(matches))
+    }
+
+    fn reply(&self, matches: Vec<Match>) -> RESPValue {
+        let plain = !(self.with_coord || self.with_dist || self.with_hash);
+        RESPValue::Array(
+            matches
+                .into_iter()
+                .map(|(member, score, distance)| {
+                    if plain {
+                        return RESPValue::BulkString(member);
+                    }
+                    let mut item = vec![RESPValue::BulkString(member)];
+                    if self.with_dist {
+                        item.push(distance_reply(distance, self.unit));
+                    }
+                    if self.with_hash {
+                        item.push(RESPValue::Integer(score as i64));
+                    }
+                    if self.with_coord {
+                        item.push(point_reply(Point::from_score(score)));
+                    }
+                    RESPValue::Array(item)
+                })
+                .collect(),
+        )
+    }
+}
+
+fn parse_dimension(arg: &[u8], name: &str) -> Result<f64, CommandError> {
+    parse_float(arg).map_err(|_| CommandError::Other(format!("need numeric {}", name)))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum GeoCommand {
+    Add {
+        key: Bytes,
+        nx: bool,
+        xx: bool,
+        ch: bool,
+        members: Vec<(Point, Bytes)>,
+    },
+    Pos {
+        key: Bytes,
+        members: Vec<Bytes>,
+    },
+    Dist {
+        key: Bytes,
+        from: Bytes,
+        to: Bytes,
+        unit: Unit,
+    },
+    Hash {
+        key: Bytes,
+        members: Vec<Bytes>,
+    },
+    Search {
+        key: Bytes,
+        search: Search,
+    },
+    SearchStore {
+        destination: Bytes,
+        source: Bytes,
+        search: Search,
+    },
+}
+
+impl GeoCommand {
+    /// Parses the arguments of the geospatial command `name`, which must be upper-case.
+    pub fn parse(name: &str, args: &mut CommandArgs) -> Result<Self, CommandError> {
+        let command = match name {
+            "GEOADD" => {
+                let key = args.required()?;
+                let mut rest = args.rest().into_iter().peekable();
+                let (mut nx, mut xx, mut ch) = (false, false, false);
+                while let Some(option) = rest.peek() {
+                    match option.to_ascii_uppercase().as_slice() {
+                        b"NX" => nx = true,
+                        b"XX" => xx = true,
+                        b"CH" => ch = true,
+                        _ => break,
+                    }
+                    rest.next();
+                }
+                let rest: Vec<Bytes> = rest.collect();
+                if rest.is_empty() && !(nx || xx || ch) {
+                    return Err(args.arity_error());
+                }
+                let triples = rest.chunks_exact(3);
+                if rest.is_empty() || !triples.remainder().is_empty() || (nx && xx) {
+                    return Err(CommandError::Syntax);
+                }
+                let members = triples
+                    .map(|triple| Ok((Point::parse(&triple[0], &triple[1])?, triple[2].clone())))
+                    .collect::<Result<_, CommandError>>()?;
+                GeoCommand::Add {
+                    key,
+                    nx,
+                    xx,
+                    ch,
+                    members,
+                }
+            }
+            "GEOPOS" => GeoCommand::Pos {
+                key: args.required()?,
+                members: args.rest(),
+            },
+            "GEODIST" => {
+                let key = args.required()?;
+                let from = args.required()?;
+                let to = args.required()?;
+                let unit = match args.next() {
+                    Some(unit) => Unit::parse(&unit)?,
+                    None => Unit::Meters,
+                };
+                if !args.is_empty() {
+                    return Err(CommandError::Syntax);
+                }
+                GeoCommand::Dist {
+                    key,
+                    from,
+                    to,
+                    unit,
+                }
+            }
+            "GEOHASH" => GeoCommand::Hash {
+                key: args.required()?,
+                members: args.rest(),
+            },
+            "GEOSEARCH" => {
+                let key = args.required()?;
+                if args.is_empty() {
+                    return Err(args.arity_error());
+                }
+                GeoCommand::Search {
+                    key,
+                    search: Search::parse(name, args, false)?,
+                }
+            }
+            "GEOSEARCHSTORE" => {
+                let destination = args.required()?;
+                let source = args.required()?;
+                if args.is_empty() {
+                    return Err(args.arity_error());
+                }
+                GeoCommand::SearchStore {
+                    destination,
+                    source,
+                    search: Search::parse(name, args, true)?,
+                }
+            }
+            _ => unreachable!("not a geospatial command: {}", name),
+        };
+
+        Ok(command)
+    }
+
+    pub fn execute(self, db: &mut Rdb) -> Result<Response, CommandError> {
+        let response = match self {
+            GeoCommand::Add {
+                key,
+                nx,
+                xx,
+                ch,
+                members,
+            } => {
+                let set = sorted_set_or_insert(db, &key)?;
+                let (mut added, mut changed) = (0, 0);
+                for (point, member) in members {
+                    let score = point.score();
+                    match set.score(&member) {
+                        Some(_) if nx => continue,
+                        None if xx => continue,
+                        Some(current) if current != score => changed += 1,
+                        Some(_) => {}
+                        None => added += 1,
+                    }
+                    set.insert(member, score);
+                }
+                db.mark_dirty((added + changed) as usize);
+                // XX can leave a newly created key empty.
+                db.remove_if_empty(&key);
+                Response::Echo(RESPValue::Integer(if ch { added + changed } else { added }))
+            }
+            GeoCommand::Pos { key, members } => {
+                let set = sorted_set(db, &key)?;
+                let positions = members
+                    .iter()
+                    .map(|member| match set.and_then(|set| set.score(member)) {
+                        Some(score) => point_reply(Point::from_score(score)),
+                        None => RESPValue::Null,
+                    })
+                    .collect();
+                Response::Echo(RESPValue::Array(positions))
+            }
+            GeoCommand::Dist {
+                key,
+                from,
+                to,
+                unit,
+            } => {
+                let Some(set) = sorted_set(db, &key)? else {
+                    return Ok(Response::Null);
+                };
+                match (set.score(&from), set.score(&to)) {
+                    (Some(from), Some(to)) => {
+                        let distance = Point::from_score(from).distance(Point::from_score(to));
+                        Response::Echo(distance_reply(distance, unit))
+                    }
+                    _ => Response::Null,
+                }
+            }
+            GeoCommand::Hash { key, members } => {
+                let set = sorted_set(db, &key)?;
+                let hashes = members
+                    .iter()
+                    .map(|member| match set.and_then(|set| set.score(member)) {
+                        Some(score) => {
+                            RESPValue::BulkString(Bytes::from(Point::from_score(score).geohash()))
+                        }
+                        None => RESPValue::Null,
+                    })
+                    .collect();
+                Response::Echo(RESPValue::Array(hashes))
+            }
+            GeoCommand::Search { key, search } => {
+                let matches = search.run(db, &key)?.unwrap_or_default();
+                Response::Echo(search.reply(matches))
+            }
+            GeoCommand::SearchStore {
+                destination,
+                source,
+                search,
+            } => {
+                // A missing source leaves the destination alone.
+                let Some(matches) = search.run(db, &source)? else {
+                    return Ok(Response::Echo(RESPValue::Integer(0)));
+                };
+                let stored = matches.len();
+                let entries = matches
+                    .into_iter()
+                    .map(|(member, score, distance)| {
+                        let score = if search.store_dist {
+                            distance / search.unit.meters()
+                        } else {
+                            score
+                        };
+                        (member, score)
+                    })
+                    .collect();
+                zset::store(db, destination, entries);
+                Response::Echo(RESPValue::Integer(stored as i64))
+            }
+        };
+
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_score_round_trip() {
+        let palermo = Point {
+            longitude: 13.361389,
+            latitude: 38.115556,
+        };
+        // The score Redis gives Palermo in its GEOADD documentation.
+        assert_eq!(palermo.score(), 3479099956230698.0);
+        let decoded = Point::from_score(palermo.score());
+        assert!((decoded.longitude - palermo.longitude).abs() < 1e-5);
+        assert!((decoded.latitude - palermo.latitude).abs() < 1e-5);
+        assert_eq!(decoded.geohash(), "sqc8b49rny0");
+    }
+
+    #[test]
+    fn test_distance() {
+        let palermo = Point::from_score(3479099956230698.0);
+        let catania = Point::from_score(3479447370796909.0);
+        assert_eq!(format!("{:.4}", palermo.distance(catania)), "166274.1516");
+    }
+
+    #[test]
+    fn test_search_missing_key() {
+        let mut db = Rdb::default();
+        let mut run = |name: &str, args: &[&str]| {
+            let args: Vec<_> = args
+                .iter()
+                .map(|arg| Bytes::from(arg.to_string()))
+                .collect();
+            let mut args = CommandArgs::new(name.as_bytes(), args.into_iter());
+            GeoCommand::parse(name, &mut args)
+                .unwrap()
+                .execute(&mut db)
+                .unwrap()
+        };
+        let from_member = ["FROMMEMBER", "m", "BYRADIUS", "1", "km"];
+
+        let search = [&["missing"][..], &from_member].concat();
+        assert_eq!(
+            run("GEOSEARCH", &search),
+            Response::Echo(RESPValue::Array(vec![]))
+        );
+        let store = [&["destination", "missing"][..], &from_member].concat();
+        assert_eq!(
+            run("GEOSEARCHSTORE", &store),
+            Response::Echo(RESPValue::Integer(0))
+        );
+    }
+}
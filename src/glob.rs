@@ -0,0 +1,151 @@
+//! Glob-style pattern matching, with the same syntax and quirks as Redis' `stringmatchlen`:
+//!
+//! - `*` matches any run of bytes, including none
+//! - `?` matches exactly one byte
+//! - `[abc]` matches one of the listed bytes, `[^abc]` one byte that isn't listed, and `[a-z]`
+//!   one byte in a range (which may be given backwards, as `[z-a]`)
+//! - `\x` matches `x` literally, both inside and outside brackets
+
+/// Checks whether the whole of `string` matches `pattern`.
+pub fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    // Where to resume if what follows the last `*` fails to match: the pattern position just after
+    // the `*`, and the string position the `*` should be extended to cover.
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while s < string.len() {
+        if p < pattern.len() {
+            match pattern[p] {
+                b'*' => {
+                    while pattern.get(p) == Some(&b'*') {
+                        p += 1;
+                    }
+                    if p == pattern.len() {
+                        return true;
+                    }
+                    backtrack = Some((p, s));
+                    continue;
+                }
+                b'?' => {
+                    p += 1;
+                    s += 1;
+                    continue;
+                }
+                b'[' => {
+                    let (matched, next) = match_class(pattern, p, string[s]);
+                    if matched {
+                        p = next;
+                        s += 1;
+                        continue;
+                    }
+                }
+                b'\\' if p + 1 < pattern.len() => {
+                    if pattern[p + 1] == string[s] {
+                        p += 2;
+                        s += 1;
+                        continue;
+                    }
+                }
+                c => {
+                    if c == string[s] {
+                        p += 1;
+                        s += 1;
+                        continue;
+                    }
+                }
+            }
+        }
+
+        // Mismatch, so let the last `*` swallow one more byte and try again from there. Only the
+        // most recent `*` ever needs revisiting, which keeps this linear in the common case.
+        match backtrack {
+            Some((star_p, star_s)) => {
+                p = star_p;
+                s = star_s + 1;
+                backtrack = Some((star_p, s));
+            }
+            None => return false,
+        }
+    }
+
+    while pattern.get(p) == Some(&b'*') {
+        p += 1;
+    }
+    p == pattern.len()
+}
+
+// Matches `c` against the bracket expression opening at `pattern[start]`, returning whether it
+// matched and the position just past the closing `]`. An unterminated expression runs to the end
+// of the pattern.
+fn match_class(pattern: &[u8], start: usize, c: u8) -> (bool, usize) {
+    let mut i = start + 1;
+    let negate = pattern.get(i) == Some(&b'^');
+    if negate {
+        i += 1;
+    }
+
+    let mut matched = false;
+    while i < pattern.len() {
+        if pattern[i] == b'\\' && i + 1 < pattern.len() {
+            matched |= pattern[i + 1] == c;
+            i += 2;
+        } else if pattern[i] == b']' {
+            i += 1;
+            break;
+        } else if i + 2 < pattern.len() && pattern[i + 1] == b'-' {
+            let (low, high) = if pattern[i] <= pattern[i + 2] {
+                (pattern[i], pattern[i + 2])
+            } else {
+                (pattern[i + 2], pattern[i])
+            };
+            matched |= (low..=high).contains(&c);
+            i += 3;
+        } else {
+            matched |= pattern[i] == c;
+            i += 1;
+        }
+    }
+
+    (matched != negate, i)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wildcards() {
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"*", b"anything"));
+        assert!(glob_match(b"user:*", b"user:1000"));
+        assert!(!glob_match(b"user:*", b"users:1000"));
+        assert!(glob_match(b"h?llo", b"hello"));
+        assert!(!glob_match(b"h?llo", b"hllo"));
+        assert!(glob_match(b"*a*b*c", b"xxaxxbxxbxxc"));
+        assert!(!glob_match(b"*a*b*c", b"xxaxxbxxbxxcx"));
+        assert!(glob_match(b"a**b", b"ab"));
+    }
+
+    #[test]
+    fn test_classes() {
+        assert!(glob_match(b"h[ae]llo", b"hello"));
+        assert!(glob_match(b"h[ae]llo", b"hallo"));
+        assert!(!glob_match(b"h[ae]llo", b"hillo"));
+        assert!(glob_match(b"h[^e]llo", b"hallo"));
+        assert!(!glob_match(b"h[^e]llo", b"hello"));
+        assert!(glob_match(b"h[a-b]llo", b"hbllo"));
+        assert!(glob_match(b"h[b-a]llo", b"hallo"));
+        assert!(!glob_match(b"h[a-b]llo", b"hcllo"));
+        assert!(glob_match(b"[ab]*", b"apple"));
+        assert!(glob_match(b"[\\]]", b"]"));
+        assert!(glob_match(b"[abc", b"c"));
+    }
+
+    #[test]
+    fn test_escapes() {
+        assert!(glob_match(b"\\*", b"*"));
+        assert!(!glob_match(b"\\*", b"a"));
+        assert!(glob_match(b"what\\?", b"what?"));
+        assert!(glob_match(b"trailing\\", b"trailing\\"));
+    }
+}
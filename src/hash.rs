@@ -0,0 +1,804 @@
+//! The hash type: a map from field names to values, where each field can have an expiry of its own
+//! on top of any the key has.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    time::{Duration, SystemTime},
+};
+
+use bytes::Bytes;
+
+use crate::{
+    error::CommandError,
+    protocol_parser::{
+        format_double, parse_cursor, parse_expire_at, parse_float, parse_integer,
+        parse_random_count, CommandArgs, ExpireCondition, ExpiryFormat, Protocol, RESPValue,
+        Response,
+    },
+    rdb::{unix_millis, Rdb, Value},
+    scan::{scan_reply, ScanIndex, ScanOptions},
+};
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Hash {
+    fields: HashMap<Bytes, Field>,
+    // Every field with an expiry, soonest first, so expired ones can be found without a full walk.
+    expiries: BTreeSet<(SystemTime, Bytes)>,
+    scan_index: ScanIndex,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Field {
+    value: Bytes,
+    expires_at: Option<SystemTime>,
+}
+
+impl Hash {
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    pub fn get(&self, field: &[u8]) -> Option<&Bytes> {
+        self.fields.get(field).map(|field| &field.value)
+    }
+
+    /// When a field expires: `None` if there is no such field, `Some(None)` if it never does.
+    pub fn expires_at(&self, field: &[u8]) -> Option<Option<SystemTime>> {
+        self.fields.get(field).map(|field| field.expires_at)
+    }
+
+    /// Whether any field has an expiry of its own.
+    pub fn has_expiring_fields(&self) -> bool {
+        !self.expiries.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Bytes, &Bytes)> {
+        self.fields.iter().map(|(name, field)| (name, &field.value))
+    }
+
+    /// Sets a field's value, returning whether the field is new. Overwriting a field clears its
+    /// expiry unless `keep_ttl` is set, as it is when a field is incremented.
+    pub fn insert(&mut self, name: Bytes, value: Bytes, keep_ttl: bool) -> bool {
+        match self.fields.get_mut(&name) {
+            Some(field) => {
+                field.value = value;
+                if !keep_ttl {
+                    if let Some(expiry) = field.expires_at.take() {
+                        self.expiries.remove(&(expiry, name));
+                    }
+                }
+                false
+            }
+            None => {
+                self.scan_index.insert(name.clone());
+                self.fields.insert(
+                    name,
+                    Field {
+                        value,
+                        expires_at: None,
+                    },
+                );
+                true
+            }
+        }
+    }
+
+    /// Removes a field, returning whether it was there.
+    pub fn remove(&mut self, name: &[u8]) -> bool {
+        let Some((name, field)) = self.fields.remove_entry(name) else {
+            return false;
+        };
+        if let Some(expiry) = field.expires_at {
+            self.expiries.remove(&(expiry, name.clone()));
+        }
+        self.scan_index.remove(name);
+        true
+    }
+
+    /// Changes when a field expires, returning whether there was such a field.
+    pub fn set_expiry(&mut self, name: &[u8], expires_at: Option<SystemTime>) -> bool {
+        let Some((name, field)) = self.fields.get_key_value(name) else {
+            return false;
+        };
+        let name = name.clone();
+        if let Some(expiry) = field.expires_at {
+            self.expiries.remove(&(expiry, name.clone()));
+        }
+        if let Some(expiry) = expires_at {
+            self.expiries.insert((expiry, name.clone()));
+        }
+        self.fields.get_mut(&name).unwrap().expires_at = expires_at;
+        true
+    }
+
+    /// Deletes every field whose expiry is before `now`, returning how many there were.
+    pub fn remove_expired(&mut self, now: SystemTime) -> usize {
+        let mut removed = 0;
+        while let Some((expiry, name)) = self.expiries.first().cloned() {
+            if expiry >= now {
+                break;
+            }
+            self.remove(&name);
+            removed += 1;
+        }
+        removed
+    }
+
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<Bytes>) {
+        self.scan_index.scan(cursor, count)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum HashCommand {
+    Set {
+        key: Bytes,
+        pairs: Vec<(Bytes, Bytes)>,
+        /// HMSET replies OK rather than with the number of fields added.
+        reply_ok: bool,
+    },
+    SetNx {
+        key: Bytes,
+        field: Bytes,
+        value: Bytes,
+    },
+    Get {
+        key: Bytes,
+        field: Bytes,
+    },
+    MGet {
+        key: Bytes,
+        fields: Vec<Bytes>,
+    },
+    Del {
+        key: Bytes,
+        fields: Vec<Bytes>,
+    },
+    Exists {
+        key: Bytes,
+        field: Bytes,
+    },
+    Len(Bytes),
+    Keys(Bytes),
+    Vals(Bytes),
+    GetAll(Bytes),
+    IncrBy {
+        key: Bytes,
+        field: Bytes,
+        increment: i64,
+    },
+    IncrByFloat {
+        key: Bytes,
+        field: Bytes,
+        increment: f64,
+    },
+    StrLen {
+        key: Bytes,
+        field: Bytes,
+    },
+    RandField {
+        key: Bytes,
+        /// Without a count, a single field is returned rather than an array. A negative count
+        /// allows the same field to be returned more than once.
+        count: Option<i64>,
+        with_values: bool,
+    },
+    Scan {
+        key: Bytes,
+        cursor: u64,
+        options: ScanOptions,
+        no_values: bool,
+    },
+    Expire {
+        key: Bytes,
+        /// Milliseconds since the Unix epoch, which may be in the past.
+        at: i64,
+        condition: ExpireCondition,
+        fields: Vec<Bytes>,
+    },
+    Ttl {
+        key: Bytes,
+        fields: Vec<Bytes>,
+        milliseconds: bool,
+    },
+    ExpireTime {
+        key: Bytes,
+        fields: Vec<Bytes>,
+        milliseconds: bool,
+    },
+    Persist {
+        key: Bytes,
+        fields: Vec<Bytes>,
+    },
+}
+
+impl HashCommand {
+    /// Parses the arguments of the hash command `name`, which must be upper-case.
+    pub fn parse(name: &str, args: &mut CommandArgs) -> Result<Self, CommandError> {
+        let command = match name {
+            "HSET" | "HMSET" => {
+                let key = args.required()?;
+                let rest = args.at_least_one()?;
+                if rest.len() % 2 != 0 {
+                    return Err(args.arity_error());
+                }
+                let mut rest = rest.into_iter();
+                let pairs = std::iter::from_fn(|| Some((rest.next()?, rest.next()?))).collect();
+                HashCommand::Set {
+                    key,
+                    pairs,
+                    reply_ok: name == "HMSET",
+                }
+            }
+            "HSETNX" => HashCommand::SetNx {
+                key: args.required()?,
+                field: args.required()?,
+                value: args.required()?,
+            },
+            "HGET" => HashCommand::Get {
+                key: args.required()?,
+                field: args.required()?,
+            },
+            "HMGET" => HashCommand::MGet {
+                key: args.required()?,
+                fields: args.at_least_one()?,
+            },
+            "HDEL" => HashCommand::Del {
+                key: args.required()?,
+                fields: args.at_least_one()?,
+            },
+            "HEXISTS" => HashCommand::Exists {
+                key: args.required()?,
+                field: args.required()?,
+            },
+            "HLEN" => HashCommand::Len(args.required()?),
+            "HKEYS" => HashCommand::Keys(args.required()?),
+            "HVALS" => HashCommand::Vals(args.required()?),
+            "HGETALL" => HashCommand::GetAll(args.required()?),
+            "HINCRBY" => HashCommand::IncrBy {
+                key: args.required()?,
+                field: args.required()?,
+                increment: parse_integer(&args.required()?)?,
+            },
+            "HINCRBYFLOAT" => HashCommand::IncrByFloat {
+                key: args.required()?,
+                field: args.required()?,
+                increment: parse_float(&args.required()?)?,
+            },
+            "HSTRLEN" => HashCommand::StrLen {
+                key: args.required()?,
+                field: args.required()?,
+            },
+            "HRANDFIELD" => {
+                let key = args.required()?;
+                let count = match args.next() {
+                    Some(count) => Some(parse_random_count(&count)?),
+                    None => None,
+                };
+                let with_values = match args.next() {
+                    Some(option) if option.eq_ignore_ascii_case(b"WITHVALUES") => true,
+                    Some(_) => return Err(CommandError::Syntax),
+                    None => false,
+                };
+                HashCommand::RandField {
+                    key,
+                    count,
+                    with_values,
+                }
+            }
+            "HSCAN" => {
+                let key = args.required()?;
+                let cursor = parse_cursor(&args.required()?)?;
+                let mut options = ScanOptions::default();
+                let mut no_values = false;
+
+                while let Some(option) = args.next() {
+                    match String::from_utf8_lossy(&option)
+                        .to_ascii_uppercase()
+                        .as_str()
+                    {
+                        "NOVALUES" => no_values = true,
+                        option if options.parse_option(option, args)? => {}
+                        _ => return Err(CommandError::Syntax),
+                    }
+                }
+
+                HashCommand::Scan {
+                    key,
+                    cursor,
+                    options,
+                    no_values,
+                }
+            }
+            "HEXPIRE" | "HPEXPIRE" | "HEXPIREAT" | "HPEXPIREAT" => {
+                let format = match name {
+                    "HEXPIRE" => ExpiryFormat::Seconds,
+                    "HPEXPIRE" => ExpiryFormat::Milliseconds,
+                    "HEXPIREAT" => ExpiryFormat::UnixSeconds,
+                    _ => ExpiryFormat::UnixMilliseconds,
+                };
+                let key = args.required()?;
+                let time = args.required()?;
+                if parse_integer::<i64>(&time)? < 0 {
+                    return Err(CommandError::Other(
+                        "invalid expire time, must be >= 0".to_string(),
+                    ));
+                }
+                let at = parse_expire_at(&time, format, name)?;
+
+                // Unlike EXPIRE, only one of the conditions can be given.
+                let mut next = args.required()?;
+                let mut condition = ExpireCondition::default();
+                let flag = match String::from_utf8_lossy(&next).to_ascii_uppercase().as_str() {
+                    "NX" => Some(&mut condition.nx),
+                    "XX" => Some(&mut condition.xx),
+                    "GT" => Some(&mut condition.gt),
+                    "LT" => Some(&mut condition.lt),
+                    _ => None,
+                };
+                if let Some(flag) = flag {
+                    *flag = true;
+                    next = args.required()?;
+                }
+                if !next.eq_ignore_ascii_case(b"FIELDS") {
+                    return Err(missing_fields());
+                }
+
+                HashCommand::Expire {
+                    key,
+                    at,
+                    condition,
+                    fields: parse_fields(args)?,
+                }
+            }
+            "HTTL" | "HPTTL" => HashCommand::Ttl {
+                key: args.required()?,
+                fields: parse_fields_keyword(args)?,
+                milliseconds: name == "HPTTL",
+            },
+            "HEXPIRETIME" | "HPEXPIRETIME" => HashCommand::ExpireTime {
+                key: args.required()?,
+                fields: parse_fields_keyword(args)?,
+                milliseconds: name == "HPEXPIRETIME",
+            },
+            "HPERSIST" => HashCommand::Persist {
+                key: args.required()?,
+                fields: parse_fields_keyword(args)?,
+            },
+            _ => unreachable!("not a hash command: {}", name),
+        };
+
+        Ok(command)
+    }
+
+    pub fn execute(self, db: &mut Rdb, protocol: Protocol) -> Result<Response, CommandError> {
+        let response = match self {
+            HashCommand::Set {
+                key,
+                pairs,
+                reply_ok,
+            } => {
+                let hash = hash_or_insert(db, &key)?;
+                let written = pairs.len();
+                let added = pairs
+                    .into_iter()
+                    .filter(|(field, value)| hash.insert(field.clone(), value.clone(), false))
+                    .count();
+                db.mark_dirty(written);
+                if reply_ok {
+                    Response::Ok
+                } else {
+                    Response::Echo(RESPValue::Integer(added as i64))
+                }
+            }
+            HashCommand::SetNx { key, field, value } => {
+                let hash = hash_or_insert(db, &key)?;
+                let added = hash.get(&field).is_none() && hash.insert(field, value, false);
+                db.mark_dirty(added as usize);
+                Response::Echo(RESPValue::Integer(added as i64))
+            }
+            HashCommand::Get { key, field } => {
+                match hash(db, &key)?.and_then(|hash| hash.get(&field)) {
+                    Some(value) => Response::Echo(RESPValue::BulkString(value.clone())),
+                    None => Response::Null,
+                }
+            }
+            HashCommand::MGet { key, fields } => {
+                let hash = hash(db, &key)?;
+                let values = fields
+                    .iter()
+                    .map(|field| match hash.and_then(|hash| hash.get(field)) {
+                        Some(value) => RESPValue::BulkString(value.clone()),
+                        None => RESPValue::Null,
+                    })
+                    .collect();
+                Response::Echo(RESPValue::Array(values))
+            }
+            HashCommand::Del { key, fields } => {
+                let Some(hash) = hash_mut(db, &key)? else {
+                    return Ok(Response::Echo(RESPValue::Integer(0)));
+                };
+                let removed = fields.iter().filter(|field| hash.remove(field)).count();
+                db.mark_dirty(removed);
+                db.remove_if_empty(&key);
+                Response::Echo(RESPValue::Integer(removed as i64))
+            }
+            HashCommand::Exists { key, field } => {
+                let exists = hash(db, &key)?.is_some_and(|hash| hash.get(&field).is_some());
+                Response::Echo(RESPValue::Integer(exists as i64))
+            }
+            HashCommand::Len(key) => {
+                let len = hash(db, &key)?.map_or(0, Hash::len);
+                Response::Echo(RESPValue::Integer(len as i64))
+            }
+            HashCommand::Keys(key) => {
+                let fields = hash(db, &key)?
+                    .map(|hash| {
+                        hash.iter()
+                            .map(|(field, _)| RESPValue::BulkString(field.clone()))
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                Response::Echo(RESPValue::Array(fields))
+            }
+            HashCommand::Vals(key) => {
+                let values = hash(db, &key)?
+                    .map(|hash| {
+                        hash.iter()
+                            .map(|(_, value)| RESPValue::BulkString(value.clone()))
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                Response::Echo(RESPValue::Array(values))
+            }
+            HashCommand::GetAll(key) => {
+                let pairs = hash(db, &key)?
+                    .map(|hash| {
+                        hash.iter()
+                            .map(|(field, value)| {
+                                (
+                                    RESPValue::BulkString(field.clone()),
+                                    RESPValue::BulkString(value.clone()),
+                                )
+                            })
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                Response::Echo(RESPValue::Map(pairs))
+            }
+            HashCommand::IncrBy {
+                key,
+                field,
+                increment,
+            } => {
+                let hash = hash_or_insert(db, &key)?;
+                let current = match hash.get(&field) {
+                    Some(value) => parse_integer::<i64>(value).map_err(|_| {
+                        CommandError::Other("hash value is not an integer".to_string())
+                    })?,
+                    None => 0,
+                };
+                let Some(new) = current.checked_add(increment) else {
+                    return Err(CommandError::Other(
+                        "increment or decrement would overflow".to_string(),
+                    ));
+                };
+                hash.insert(field, Bytes::from(new.to_string()), true);
+                db.mark_dirty(1);
+                Response::Echo(RESPValue::Integer(new))
+            }
+            HashCommand::IncrByFloat {
+                key,
+                field,
+                increment,
+            } => {
+                // Checked up front too, so that a new key is never left holding an empty hash.
+                if !increment.is_finite() {
+                    return Err(nan_or_infinity());
+                }
+                let hash = hash_or_insert(db, &key)?;
+                let current = match hash.get(&field) {
+                    Some(value) => parse_float(value).map_err(|_| {
+                        CommandError::Other("hash value is not a float".to_string())
+                    })?,
+                    None => 0.0,
+                };
+                let new = current + increment;
+                if !new.is_finite() {
+                    return Err(nan_or_infinity());
+                }
+                let new = Bytes::from(format_double(new));
+                hash.insert(field, new.clone(), true);
+                db.mark_dirty(1);
+                Response::Echo(RESPValue::BulkString(new))
+            }
+            HashCommand::StrLen { key, field } => {
+                let len = hash(db, &key)?
+                    .and_then(|hash| hash.get(&field))
+                    .map_or(0, Bytes::len);
+                Response::Echo(RESPValue::Integer(len as i64))
+            }
+            HashCommand::RandField {
+                key,
+                count,
+                with_values,
+            } => {
+                let Some(hash) = hash(db, &key)? else {
+                    return Ok(match count {
+                        Some(_) => Response::Echo(RESPValue::Array(vec![])),
+                        None => Response::Null,
+                    });
+                };
+                let mut fields: Vec<_> = hash.iter().collect();
+
+                let Some(count) = count else {
+                    let (field, _) = fields[crate::random::below(fields.len())];
+                    return Ok(Response::Echo(RESPValue::BulkString(field.clone())));
+                };
+                let chosen: Vec<_> = if count >= 0 {
+                    let count = (count as usize).min(fields.len());
+                    crate::random::shuffle_prefix(&mut fields, count);
+                    fields.truncate(count);
+                    fields
+                } else {
+                    (0..count.unsigned_abs())
+                        .map(|_| fields[crate::random::below(fields.len())])
+                        .collect()
+                };
+
+                let items = chosen
+                    .into_iter()
+                    .flat_map(|(field, value)| {
+                        let field = RESPValue::BulkString(field.clone());
+                        let value = RESPValue::BulkString(value.clone());
+                        match (with_values, protocol) {
+                            (false, _) => vec![field],
+                            // RESP3 clients get each field paired with its value.
+                            (true, Protocol::Resp3) => vec![RESPValue::Array(vec![field, value])],
+                            (true, Protocol::Resp2) => vec![field, value],
+                        }
+                    })
+                    .collect();
+                Response::Echo(RESPValue::Array(items))
+            }
+            HashCommand::Scan {
+                key,
+                cursor,
+                options,
+                no_values,
+            } => {
+                let Some(hash) = hash(db, &key)? else {
+                    return Ok(scan_reply(0, vec![]));
+                };
+                let (cursor, visited) = hash.scan(cursor, options.count());
+
+                let mut items = Vec::new();
+                for field in visited {
+                    let Some(value) = hash.get(&field) else {
+                        continue;
+                    };
+                    if !options.matches(&field) {
+                        continue;
+                    }
+                    items.push(RESPValue::BulkString(field));
+                    if !no_values {
+                        items.push(RESPValue::BulkString(value.clone()));
+                    }
+                }
+
+                scan_reply(cursor, items)
+            }
+            HashCommand::Expire {
+                key,
+                at,
+                condition,
+                fields,
+            } => {
+                let Some(hash) = hash_mut(db, &key)? else {
+                    return Ok(field_replies(fields.iter().map(|_| -2)));
+                };
+                let now = unix_millis(SystemTime::now());
+
+                let mut replies = Vec::with_capacity(fields.len());
+                for field in &fields {
+                    let Some(current) = hash.expires_at(field) else {
+                        replies.push(-2);
+                        continue;
+                    };
+                    let current = current.map(unix_millis);
+                    if !condition.allows(current, at) {
+                        replies.push(0);
+                    } else if at <= now {
+                        // An expiry that has already passed deletes the field straight away.
+                        hash.remove(field);
+                        replies.push(2);
+                    } else {
+                        let expires_at = SystemTime::UNIX_EPOCH + Duration::from_millis(at as u64);
+                        hash.set_expiry(field, Some(expires_at));
+                        replies.push(1);
+                    }
+                }
+
+                db.mark_dirty(replies.iter().filter(|&&reply| reply > 0).count());
+                db.track_field_expiries(&key);
+                db.remove_if_empty(&key);
+                field_replies(replies.into_iter())
+            }
+            HashCommand::Ttl {
+                key,
+                fields,
+                milliseconds,
+            } => {
+                let hash = hash(db, &key)?;
+                let now = unix_millis(SystemTime::now());
+                field_replies(fields.iter().map(|field| {
+                    match hash.and_then(|hash| hash.expires_at(field)) {
+                        None => -2,
+                        Some(None) => -1,
+                        Some(Some(expiry)) => {
+                            let ttl = (unix_millis(expiry) - now).max(0);
+                            if milliseconds {
+                                ttl
+                            } else {
+                                (ttl + 500) / 1000
+                            }
+                        }
+                    }
+                }))
+            }
+            HashCommand::ExpireTime {
+                key,
+                fields,
+                milliseconds,
+            } => {
+                let hash = hash(db, &key)?;
+                field_replies(fields.iter().map(|field| {
+                    match hash.and_then(|hash| hash.expires_at(field)) {
+                        None => -2,
+                        Some(None) => -1,
+                        Some(Some(expiry)) if milliseconds => unix_millis(expiry),
+                        Some(Some(expiry)) => unix_millis(expiry) / 1000,
+                    }
+                }))
+            }
+            HashCommand::Persist { key, fields } => {
+                let Some(hash) = hash_mut(db, &key)? else {
+                    return Ok(field_replies(fields.iter().map(|_| -2)));
+                };
+                let replies: Vec<_> = fields
+                    .iter()
+                    .map(|field| match hash.expires_at(field) {
+                        None => -2,
+                        Some(None) => -1,
+                        Some(Some(_)) => {
+                            hash.set_expiry(field, None);
+                            1
+                        }
+                    })
+                    .collect();
+                db.mark_dirty(replies.iter().filter(|&&reply| reply == 1).count());
+                field_replies(replies.into_iter())
+            }
+        };
+
+        Ok(response)
+    }
+}
+
+fn hash<'a>(db: &'a mut Rdb, key: &[u8]) -> Result<Option<&'a Hash>, CommandError> {
+    db.get(key).map(|entry| entry.value().as_hash()).transpose()
+}
+
+fn hash_mut<'a>(db: &'a mut Rdb, key: &[u8]) -> Result<Option<&'a mut Hash>, CommandError> {
+    db.get_mut(key)
+        .map(|entry| entry.value_mut().as_hash_mut())
+        .transpose()
+}
+
+fn hash_or_insert<'a>(db: &'a mut Rdb, key: &Bytes) -> Result<&'a mut Hash, CommandError> {
+    db.get_or_insert_with(key, || Value::Hash(Hash::default()))
+        .value_mut()
+        .as_hash_mut()
+}
+
+// Parses `FIELDS numfields field [field ...]`, for the field expiry commands.
+fn parse_fields_keyword(args: &mut CommandArgs) -> Result<Vec<Bytes>, CommandError> {
+    if !args.required()?.eq_ignore_ascii_case(b"FIELDS") {
+        return Err(missing_fields());
+    }
+    parse_fields(args)
+}
+
+// Parses `numfields field [field ...]`, the part after the FIELDS keyword.
+fn parse_fields(args: &mut CommandArgs) -> Result<Vec<Bytes>, CommandError> {
+    let count: i64 = parse_integer(&args.required()?)?;
+    if count <= 0 {
+        return Err(CommandError::Other(
+            "Parameter `numFields` should be greater than 0".to_string(),
+        ));
+    }
+    let fields = args.at_least_one()?;
+    if fields.len() as i64 != count {
+        return Err(CommandError::Other(
+            "The `numfields` parameter must match the number of arguments".to_string(),
+        ));
+    }
+    Ok(fields)
+}
+
+fn nan_or_infinity() -> CommandError {
+    CommandError::Other("increment would produce NaN or Infinity".to_string())
+}
+
+fn missing_fields() -> CommandError {
+    CommandError::Other("Mandatory argument FIELDS is missing or not at the right position".into())
+}
+
+// The field expiry commands reply with one status code per field, in the order they were given.
+fn field_replies(codes: impl Iterator<Item = i64>) -> Response {
+    Response::Echo(RESPValue::Array(codes.map(RESPValue::Integer).collect()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol_parser::MAX_RANDOM_REPEATS;
+
+    #[test]
+    fn test_insert_clears_expiry() {
+        let mut hash = Hash::default();
+        let later = SystemTime::now() + Duration::from_secs(60);
+
+        assert!(hash.insert(Bytes::from("a"), Bytes::from("1"), false));
+        assert!(hash.set_expiry(b"a", Some(later)));
+        assert!(!hash.insert(Bytes::from("a"), Bytes::from("2"), true));
+        assert_eq!(hash.expires_at(b"a"), Some(Some(later)));
+        assert!(!hash.insert(Bytes::from("a"), Bytes::from("3"), false));
+        assert_eq!(hash.expires_at(b"a"), Some(None));
+        assert!(hash.expiries.is_empty());
+    }
+
+    #[test]
+    fn test_remove_expired() {
+        let mut hash = Hash::default();
+        let now = SystemTime::now();
+        let expiries = [
+            ("a", now - Duration::from_secs(2)),
+            ("b", now - Duration::from_secs(1)),
+            ("c", now + Duration::from_secs(60)),
+        ];
+        for (name, expiry) in expiries {
+            hash.insert(Bytes::from(name), Bytes::from("x"), false);
+            hash.set_expiry(name.as_bytes(), Some(expiry));
+        }
+        hash.insert(Bytes::from("d"), Bytes::from("x"), false);
+
+        assert_eq!(hash.remove_expired(now), 2);
+        assert_eq!(hash.len(), 2);
+        assert!(hash.get(b"a").is_none());
+        assert!(hash.get(b"c").is_some());
+        assert_eq!(hash.scan(0, 10).1.len(), 2);
+    }
+
+    #[test]
+    fn test_rand_field_count_range() {
+        let parse = |args: &[&str]| {
+            let args: Vec<_> = args
+                .iter()
+                .map(|arg| Bytes::from(arg.to_string()))
+                .collect();
+            HashCommand::parse(
+                "HRANDFIELD",
+                &mut CommandArgs::new(b"HRANDFIELD", args.into_iter()),
+            )
+        };
+        assert!(parse(&["h", "-9223372036854775807"]).is_err());
+        assert!(parse(&["h", &(-MAX_RANDOM_REPEATS - 1).to_string()]).is_err());
+        assert!(parse(&["h", &(-MAX_RANDOM_REPEATS).to_string(), "WITHVALUES"]).is_ok());
+        assert!(parse(&["h", "9223372036854775807"]).is_ok());
+    }
+}
@@ -0,0 +1,605 @@
+//! HyperLogLog, stored exactly as Redis stores it: a string value holding a 16 byte header and
+//! then 16384 six bit registers, either packed densely or run-length encoded in the sparse form.
+//! Values written here can be read by Redis, and the other way round.
+//!
+//! Commands decode the registers, work on them, and encode them again, rather than editing the
+//! encoded form in place. The sparse form this produces is always the shortest one, which can
+//! differ byte for byte from what Redis would have produced, but means the same.
+
+use bytes::Bytes;
+
+use crate::{
+    error::CommandError,
+    protocol_parser::{CommandArgs, RESPValue, Response},
+    rdb::Rdb,
+    string::string_or_insert,
+};
+
+const MAGIC: &[u8; 4] = b"HYLL";
+const HEADER_LEN: usize = 16;
+// Registers are addressed by the low 14 bits of an element's hash, and hold the length of the run
+// of zeros in the other 50 bits, plus one.
+const P: u32 = 14;
+const Q: u32 = 64 - P;
+const REGISTERS: usize = 1 << P;
+const REGISTER_BITS: usize = 6;
+const REGISTER_MAX: u8 = (1 << REGISTER_BITS) - 1;
+const DENSE_LEN: usize = HEADER_LEN + (REGISTERS * REGISTER_BITS).div_ceil(8);
+// Past this size, the sparse form is converted to the dense one, Redis's hll-sparse-max-bytes.
+const SPARSE_MAX_BYTES: usize = 3000;
+
+// The three sparse opcodes: ZERO covers up to 64 zero registers in one byte, XZERO up to 16384 in
+// two, and VAL up to 4 registers with the same value of at most 32 in one.
+const SPARSE_XZERO_BIT: u8 = 0x40;
+const SPARSE_VAL_BIT: u8 = 0x80;
+const SPARSE_ZERO_MAX_LEN: usize = 64;
+const SPARSE_XZERO_MAX_LEN: usize = 16384;
+const SPARSE_VAL_MAX_VALUE: u8 = 32;
+const SPARSE_VAL_MAX_LEN: usize = 4;
+
+const HASH_SEED: u32 = 0xadc83b19;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Encoding {
+    Dense,
+    Sparse,
+}
+
+impl Encoding {
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Dense => "dense",
+            Encoding::Sparse => "sparse",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HyperLogLog {
+    encoding: Encoding,
+    registers: Vec<u8>,
+    /// The cardinality as last counted, kept in the header until a register changes.
+    cached: Option<u64>,
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        HyperLogLog {
+            encoding: Encoding::Sparse,
+            registers: vec![0; REGISTERS],
+            cached: Some(0),
+        }
+    }
+}
+
+impl HyperLogLog {
+    /// Decodes a string value, failing if it isn't a HyperLogLog or is a corrupt one.
+    pub fn decode(bytes: &[u8]) -> Result<Self, CommandError> {
+        if bytes.len() < HEADER_LEN || &bytes[..4] != MAGIC {
+            return Err(CommandError::InvalidHll);
+        }
+        let encoding = match bytes[4] {
+            0 if bytes.len() == DENSE_LEN => Encoding::Dense,
+            1 => Encoding::Sparse,
+            _ => return Err(CommandError::InvalidHll),
+        };
+        let card: [u8; 8] = bytes[8..16].try_into().expect("header is 16 bytes");
+        let cached = (card[7] & 0x80 == 0).then(|| u64::from_le_bytes(card));
+
+        let body = &bytes[HEADER_LEN..];
+        let registers = match encoding {
+            Encoding::Dense => (0..REGISTERS).map(|index| dense_get(body, index)).collect(),
+            Encoding::Sparse => sparse_decode(body).ok_or(CommandError::CorruptHll)?,
+        };
+
+        Ok(HyperLogLog {
+            encoding,
+            registers,
+            cached,
+        })
+    }
+
+    /// Encodes the registers, switching to the dense form if they can no longer be sparse.
+    pub fn encode(&mut self) -> Bytes {
+        let body = match self.encoding {
+            Encoding::Sparse => match sparse_encode(&self.registers) {
+                Some(body) if HEADER_LEN + body.len() <= SPARSE_MAX_BYTES => body,
+                _ => {
+                    self.encoding = Encoding::Dense;
+                    self.dense_body()
+                }
+            },
+            Encoding::Dense => self.dense_body(),
+        };
+
+        let mut bytes = Vec::with_capacity(HEADER_LEN + body.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(match self.encoding {
+            Encoding::Dense => 0,
+            Encoding::Sparse => 1,
+        });
+        bytes.extend_from_slice(&[0; 3]);
+        match self.cached {
+            Some(count) => bytes.extend_from_slice(&count.to_le_bytes()),
+            // The top bit of the last byte marks the cached count as stale.
+            None => bytes.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0x80]),
+        }
+        bytes.extend_from_slice(&body);
+        Bytes::from(bytes)
+    }
+
+    fn dense_body(&self) -> Vec<u8> {
+        let mut body = vec![0; DENSE_LEN - HEADER_LEN];
+        for (index, &value) in self.registers.iter().enumerate() {
+            dense_set(&mut body, index, value);
+        }
+        body
+    }
+
+    /// Adds an element, returning whether that changed any register.
+    pub fn add(&mut self, element: &[u8]) -> bool {
+        let (index, count) = pattern(element);
+        if self.registers[index] >= count {
+            return false;
+        }
+        self.registers[index] = count;
+        self.cached = None;
+        true
+    }
+
+    /// Makes this the union of itself and `other`.
+    pub fn merge(&mut self, other: &HyperLogLog) {
+        for (register, &value) in self.registers.iter_mut().zip(&other.registers) {
+            if value > *register {
+                *register = value;
+                self.cached = None;
+            }
+        }
+    }
+
+    /// The estimated number of distinct elements added, using the cached count if it's current.
+    pub fn count(&mut self) -> u64 {
+        let count = self.cached.unwrap_or_else(|| estimate(&self.registers));
+        self.cached = Some(count);
+        count
+    }
+}
+
+// Hashes an element into the index of its register and the value it would set there: the number
+// of zero bits before the first set one in the rest of the hash, plus one.
+fn pattern(element: &[u8]) -> (usize, u8) {
+    let hash = murmur_hash64a(element, HASH_SEED);
+    let index = (hash & (REGISTERS as u64 - 1)) as usize;
+    // Setting a bit past the end bounds the count at Q + 1.
+    let rest = (hash >> P) | (1 << Q);
+    (index, rest.trailing_zeros() as u8 + 1)
+}
+
+/// Austin Appleby's MurmurHash64A, as Redis uses it for HyperLogLog, reading the input as little
+/// endian whatever the platform.
+fn murmur_hash64a(key: &[u8], seed: u32) -> u64 {
+    const M: u64 = 0xc6a4a7935bd1e995;
+    const R: u32 = 47;
+
+    let mut h = seed as u64 ^ (key.len() as u64).wrapping_mul(M);
+
+    let chunks = key.chunks_exact(8);
+    let tail = chunks.remainder();
+    for chunk in chunks {
+        let mut k = u64::from_le_bytes(chunk.try_into().expect("chunks are 8 bytes"));
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h ^= k;
+        h = h.wrapping_mul(M);
+    }
+
+    if !tail.is_empty() {
+        for (i, &byte) in tail.iter().enumerate() {
+            h ^= (byte as u64) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+
+    h ^= h >> R;
+    h = h.wrapping_mul(M);
+    h ^= h >> R;
+    h
+}
+
+// Estimates the cardinality from the registers with Otmar Ertl's improved estimator, which Redis
+// has used since 5.0, so counts agree exactly with what Redis reports.
+fn estimate(registers: &[u8]) -> u64 {
+    const ALPHA_INF: f64 = 0.721_347_520_444_481_7;
+
+    let m = REGISTERS as f64;
+    let mut histogram = [0u32; 64];
+    for &value in registers {
+        histogram[value as usize] += 1;
+    }
+
+    let mut z = m * tau((m - histogram[Q as usize + 1] as f64) / m);
+    for j in (1..=Q as usize).rev() {
+        z += histogram[j] as f64;
+        z *= 0.5;
+    }
+    z += m * sigma(histogram[0] as f64 / m);
+
+    (ALPHA_INF * m * m / z).round() as u64
+}
+
+fn sigma(mut x: f64) -> f64 {
+    if x == 1.0 {
+        return f64::INFINITY;
+    }
+    let mut y = 1.0;
+    let mut z = x;
+    loop {
+        x *= x;
+        let previous = z;
+        z += x * y;
+        y += y;
+        if previous == z {
+            return z;
+        }
+    }
+}
+
+fn tau(mut x: f64) -> f64 {
+    if x == 0.0 || x == 1.0 {
+        return 0.0;
+    }
+    let mut y = 1.0;
+    let mut z = 1.0 - x;
+    loop {
+        x = x.sqrt();
+        let previous = z;
+        y *= 0.5;
+        z -= (1.0 - x).powi(2) * y;
+        if previous == z {
+            return z / 3.0;
+        }
+    }
+}
+
+// Registers are packed least significant bit first, so one can straddle two bytes.
+fn dense_get(body: &[u8], index: usize) -> u8 {
+    let byte = index * REGISTER_BITS / 8;
+    let shift = index * REGISTER_BITS % 8;
+    let low = body[byte] as u16;
+    let high = body.get(byte + 1).copied().unwrap_or(0) as u16;
+    (((low | high << 8) >> shift) as u8) & REGISTER_MAX
+}
+
+fn dense_set(body: &mut [u8], index: usize, value: u8) {
+    let byte = index * REGISTER_BITS / 8;
+    let shift = index * REGISTER_BITS % 8;
+    let bits = (value as u16) << shift;
+    let mask = (REGISTER_MAX as u16) << shift;
+    body[byte] = (body[byte] & !(mask as u8)) | bits as u8;
+    if let Some(next) = body.get_mut(byte + 1) {
+        *next = (*next & !((mask >> 8) as u8)) | (bits >> 8) as u8;
+    }
+}
+
+// Expands the sparse opcodes into registers, or returns `None` if they don't cover exactly all of
+// them.
+fn sparse_decode(body: &[u8]) -> Option<Vec<u8>> {
+    let mut registers = Vec::with_capacity(REGISTERS);
+    let mut opcodes = body.iter();
+    while let Some(&opcode) = opcodes.next() {
+        let (value, len) = if opcode & SPARSE_VAL_BIT != 0 {
+            (((opcode >> 2) & 0x1f) + 1, (opcode & 0x3) as usize + 1)
+        } else if opcode & SPARSE_XZERO_BIT != 0 {
+            let low = *opcodes.next()?;
+            (0, (((opcode & 0x3f) as usize) << 8 | low as usize) + 1)
+        } else {
+            (0, (opcode & 0x3f) as usize + 1)
+        };
+        if registers.len() + len > REGISTERS {
+            return None;
+        }
+        registers.resize(registers.len() + len, value);
+    }
+    (registers.len() == REGISTERS).then_some(registers)
+}
+
+// Run-length encodes the registers, or returns `None` if one holds a value too large for the
+// sparse form.
+fn sparse_encode(registers: &[u8]) -> Option<Vec<u8>> {
+    let mut body = Vec::new();
+    let mut index = 0;
+    while index < registers.len() {
+        let value = registers[index];
+        let run = registers[index..]
+            .iter()
+            .take_while(|&&other| other == value)
+            .count();
+        index += run;
+
+        let mut remaining = run;
+        if value == 0 {
+            while remaining > 0 {
+                if remaining > SPARSE_ZERO_MAX_LEN {
+                    let len = remaining.min(SPARSE_XZERO_MAX_LEN);
+                    body.push(SPARSE_XZERO_BIT | ((len - 1) >> 8) as u8);
+                    body.push(((len - 1) & 0xff) as u8);
+                    remaining -= len;
+                } else {
+                    body.push((remaining - 1) as u8);
+                    remaining = 0;
+                }
+            }
+        } else {
+            if value > SPARSE_VAL_MAX_VALUE {
+                return None;
+            }
+            while remaining > 0 {
+                let len = remaining.min(SPARSE_VAL_MAX_LEN);
+                body.push(SPARSE_VAL_BIT | (value - 1) << 2 | (len - 1) as u8);
+                remaining -= len;
+            }
+        }
+    }
+    Some(body)
+}
+
+// Lists the sparse opcodes for PFDEBUG DECODE, in Redis's notation.
+fn sparse_describe(body: &[u8]) -> String {
+    let mut parts = Vec::new();
+    let mut opcodes = body.iter();
+    while let Some(&opcode) = opcodes.next() {
+        if opcode & SPARSE_VAL_BIT != 0 {
+            parts.push(format!(
+                "v:{},{}",
+                ((opcode >> 2) & 0x1f) + 1,
+                (opcode & 0x3) + 1
+            ));
+        } else if opcode & SPARSE_XZERO_BIT != 0 {
+            let low = opcodes.next().copied().unwrap_or(0);
+            parts.push(format!(
+                "Z:{}",
+                (((opcode & 0x3f) as usize) << 8 | low as usize) + 1
+            ));
+        } else {
+            parts.push(format!("z:{}", (opcode & 0x3f) + 1));
+        }
+    }
+    parts.join(" ")
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DebugCommand {
+    GetReg,
+    Decode,
+    Encoding,
+    ToDense,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum HyperLogLogCommand {
+    Add {
+        key: Bytes,
+        elements: Vec<Bytes>,
+    },
+    Count(Vec<Bytes>),
+    Merge {
+        destination: Bytes,
+        sources: Vec<Bytes>,
+    },
+    Debug {
+        subcommand: DebugCommand,
+        key: Bytes,
+    },
+}
+
+impl HyperLogLogCommand {
+    /// Parses the arguments of the HyperLogLog command `name`, which must be upper-case.
+    pub fn parse(name: &str, args: &mut CommandArgs) -> Result<Self, CommandError> {
+        let command = match name {
+            "PFADD" => HyperLogLogCommand::Add {
+                key: args.required()?,
+                elements: args.rest(),
+            },
+            "PFCOUNT" => HyperLogLogCommand::Count(args.at_least_one()?),
+            "PFMERGE" => HyperLogLogCommand::Merge {
+                destination: args.required()?,
+                sources: args.rest(),
+            },
+            "PFDEBUG" => {
+                let subcommand = args.subcommand()?;
+                let key = args.required()?;
+                let subcommand = match subcommand.as_str() {
+                    "GETREG" => DebugCommand::GetReg,
+                    "DECODE" => DebugCommand::Decode,
+                    "ENCODING" => DebugCommand::Encoding,
+                    "TODENSE" => DebugCommand::ToDense,
+                    _ => {
+                        return Err(CommandError::Other(format!(
+                            "Unknown PFDEBUG subcommand '{}'",
+                            subcommand.to_ascii_lowercase()
+                        )))
+                    }
+                };
+                HyperLogLogCommand::Debug { subcommand, key }
+            }
+            _ => unreachable!("not a HyperLogLog command: {}", name),
+        };
+
+        Ok(command)
+    }
+
+    pub fn execute(self, db: &mut Rdb) -> Result<Response, CommandError> {
+        let response = match self {
+            HyperLogLogCommand::Add { key, elements } => {
+                let (mut hll, mut changed) = match load(db, &key)? {
+                    Some(hll) => (hll, false),
+                    None => (HyperLogLog::default(), true),
+                };
+                for element in &elements {
+                    changed |= hll.add(element);
+                }
+                if changed {
+                    *string_or_insert(db, &key)? = hll.encode();
+                    db.mark_dirty(1);
+                }
+                Response::Echo(RESPValue::Integer(changed as i64))
+            }
+            HyperLogLogCommand::Count(keys) => {
+                if let [key] = keys.as_slice() {
+                    let Some(mut hll) = load(db, key)? else {
+                        return Ok(Response::Echo(RESPValue::Integer(0)));
+                    };
+                    // Counting updates the cached count in the header, if it was stale.
+                    let stale = hll.cached.is_none();
+                    let count = hll.count();
+                    if stale {
+                        *string_or_insert(db, key)? = hll.encode();
+                        db.mark_dirty(1);
+                    }
+                    return Ok(Response::Echo(RESPValue::Integer(count as i64)));
+                }
+
+                let mut union = HyperLogLog::default();
+                for key in &keys {
+                    if let Some(hll) = load(db, key)? {
+                        union.merge(&hll);
+                    }
+                }
+                union.cached = None;
+                Response::Echo(RESPValue::Integer(union.count() as i64))
+            }
+            HyperLogLogCommand::Merge {
+                destination,
+                sources,
+            } => {
+                // The destination is part of the union too, and if any of the inputs is dense,
+                // so is the result.
+                let mut merged = load(db, &destination)?.unwrap_or_default();
+                for key in &sources {
+                    if let Some(hll) = load(db, key)? {
+                        if hll.encoding == Encoding::Dense {
+                            merged.encoding = Encoding::Dense;
+                        }
+                        merged.merge(&hll);
+                    }
+                }
+                merged.cached = None;
+                *string_or_insert(db, &destination)? = merged.encode();
+                db.mark_dirty(1);
+                Response::Ok
+            }
+            HyperLogLogCommand::Debug { subcommand, key } => {
+                let mut hll = load(db, &key)?.ok_or_else(|| {
+                    CommandError::Other("The specified key does not exist".to_string())
+                })?;
+                match subcommand {
+                    // Redis converts to the dense form to read the registers, and so do we.
+                    DebugCommand::GetReg => {
+                        let converted = hll.encoding == Encoding::Sparse;
+                        hll.encoding = Encoding::Dense;
+                        if converted {
+                            *string_or_insert(db, &key)? = hll.encode();
+                            db.mark_dirty(1);
+                        }
+                        Response::Echo(RESPValue::Array(
+                            hll.registers
+                                .iter()
+                                .map(|&value| RESPValue::Integer(value as i64))
+                                .collect(),
+                        ))
+                    }
+                    DebugCommand::Decode => {
+                        if hll.encoding != Encoding::Sparse {
+                            return Err(CommandError::Other(
+                                "HLL encoding is not sparse".to_string(),
+                            ));
+                        }
+                        let bytes = hll.encode();
+                        Response::Echo(RESPValue::SimpleString(sparse_describe(
+                            &bytes[HEADER_LEN..],
+                        )))
+                    }
+                    DebugCommand::Encoding => {
+                        Response::Echo(RESPValue::SimpleString(hll.encoding.name().to_string()))
+                    }
+                    DebugCommand::ToDense => {
+                        let converted = hll.encoding == Encoding::Sparse;
+                        if converted {
+                            hll.encoding = Encoding::Dense;
+                            *string_or_insert(db, &key)? = hll.encode();
+                            db.mark_dirty(1);
+                        }
+                        Response::Echo(RESPValue::Integer(converted as i64))
+                    }
+                }
+            }
+        };
+
+        Ok(response)
+    }
+}
+
+// Loads the HyperLogLog at `key`, which must be a string holding one if it exists.
+fn load(db: &mut Rdb, key: &[u8]) -> Result<Option<HyperLogLog>, CommandError> {
+    match db.get(key) {
+        Some(entry) => Ok(Some(HyperLogLog::decode(entry.value().as_string()?)?)),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new_is_redis_empty_hll() {
+        let mut hll = HyperLogLog::default();
+        let bytes = hll.encode();
+        let mut expected = b"HYLL\x01".to_vec();
+        expected.extend_from_slice(&[0; 11]);
+        expected.extend_from_slice(&[0x7f, 0xff]);
+        assert_eq!(bytes.as_ref(), expected.as_slice());
+        assert_eq!(HyperLogLog::decode(&bytes), Ok(hll));
+    }
+
+    #[test]
+    fn test_count_and_encodings() {
+        let mut hll = HyperLogLog::default();
+        for i in 0..100 {
+            hll.add(format!("element:{}", i).as_bytes());
+        }
+        let count = hll.count();
+        assert!((98..=102).contains(&count), "{}", count);
+
+        let sparse = hll.encode();
+        assert_eq!(hll.encoding, Encoding::Sparse);
+        let decoded = HyperLogLog::decode(&sparse).unwrap();
+        assert_eq!(decoded.registers, hll.registers);
+        assert_eq!(decoded.cached, Some(count));
+
+        // Enough elements outgrow the sparse form.
+        for i in 100..20000 {
+            hll.add(format!("element:{}", i).as_bytes());
+        }
+        let dense = hll.encode();
+        assert_eq!(hll.encoding, Encoding::Dense);
+        assert_eq!(dense.len(), DENSE_LEN);
+        let mut decoded = HyperLogLog::decode(&dense).unwrap();
+        assert_eq!(decoded.registers, hll.registers);
+        let count = decoded.count() as f64;
+        assert!((count - 20000.0).abs() / 20000.0 < 0.02, "{}", count);
+    }
+
+    #[test]
+    fn test_rejects_invalid_values() {
+        assert_eq!(HyperLogLog::decode(b"hello"), Err(CommandError::InvalidHll));
+        let mut truncated = b"HYLL\x01".to_vec();
+        truncated.extend_from_slice(&[0; 11]);
+        truncated.push(0x7f);
+        assert_eq!(
+            HyperLogLog::decode(&truncated),
+            Err(CommandError::CorruptHll)
+        );
+    }
+}
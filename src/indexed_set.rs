@@ -0,0 +1,94 @@
+use std::{borrow::Borrow, collections::HashMap, hash::Hash};
+
+/// A set that, on top of constant-time inserts, removals and lookups, can also pick a uniformly
+/// random member in constant time, which a `HashSet` can't.
+///
+/// Members are kept densely packed in a `Vec`, with a map from each member to its position.
+/// Removal swaps the last member into the hole, so iteration order is arbitrary.
+#[derive(Clone, Debug, Default)]
+pub struct IndexedSet<T> {
+    members: Vec<T>,
+    positions: HashMap<T, usize>,
+}
+
+impl<T: Hash + Eq + Clone> IndexedSet<T> {
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    pub fn contains<Q>(&self, member: &Q) -> bool
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.positions.contains_key(member)
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, T> {
+        self.members.iter()
+    }
+
+    /// Adds a member, returning whether it was newly added.
+    pub fn insert(&mut self, member: T) -> bool {
+        if self.positions.contains_key(&member) {
+            return false;
+        }
+        self.positions.insert(member.clone(), self.members.len());
+        self.members.push(member);
+        true
+    }
+
+    /// Removes a member, returning whether it was present.
+    pub fn remove<Q>(&mut self, member: &Q) -> bool
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let Some(position) = self.positions.remove(member) else {
+            return false;
+        };
+        self.members.swap_remove(position);
+        if let Some(moved) = self.members.get(position) {
+            self.positions.insert(moved.clone(), position);
+        }
+        true
+    }
+
+    pub fn random(&self) -> Option<&T> {
+        if self.is_empty() {
+            None
+        } else {
+            Some(&self.members[crate::random::below(self.members.len())])
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remove_keeps_positions_consistent() {
+        let mut set = IndexedSet::default();
+        for i in 0..10 {
+            assert!(set.insert(i));
+        }
+        assert!(!set.insert(3));
+
+        assert!(set.remove(&0));
+        assert!(set.remove(&9));
+        assert!(set.remove(&4));
+        assert!(!set.remove(&4));
+
+        assert_eq!(set.len(), 7);
+        for i in [1, 2, 3, 5, 6, 7, 8] {
+            assert!(set.remove(&i));
+        }
+        assert!(set.is_empty());
+        assert_eq!(set.random(), None);
+    }
+}
@@ -0,0 +1,132 @@
+//! The text report returned by the INFO command.
+
+use std::{
+    fmt::Write,
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+
+use crate::{expiry, persistence, rdb::Rdb};
+
+static STARTED_AT: OnceLock<Instant> = OnceLock::new();
+
+/// Records the server start time that uptime is reported against.
+pub fn mark_started() {
+    STARTED_AT.get_or_init(Instant::now);
+}
+
+const SECTIONS: &[&str] = &["server", "persistence", "stats", "keyspace"];
+
+/// Renders the requested sections, or every section if none are named. Section names are
+/// case-insensitive, and ones we don't know are ignored.
+pub fn render(requested: &[String], db: &Rdb) -> String {
+    let all = requested.is_empty()
+        || requested
+            .iter()
+            .any(|section| matches!(section.as_str(), "all" | "default" | "everything"));
+
+    let mut out = String::new();
+    for section in SECTIONS {
+        if !all && !requested.iter().any(|requested| requested == section) {
+            continue;
+        }
+        if !out.is_empty() {
+            out.push_str("\r\n");
+        }
+
+        match *section {
+            "server" => server(&mut out),
+            "persistence" => persistence(&mut out, db),
+            "stats" => stats(&mut out, db),
+            "keyspace" => keyspace(&mut out, db),
+            _ => unreachable!(),
+        }
+    }
+    out
+}
+
+fn server(out: &mut String) {
+    let uptime = STARTED_AT
+        .get()
+        .map_or(0, |started| started.elapsed().as_secs());
+
+    out.push_str("# Server\r\n");
+    let _ = write!(out, "redis_version:{}\r\n", crate::REDIS_VERSION);
+    out.push_str("redis_mode:standalone\r\n");
+    let _ = write!(out, "os:{}\r\n", std::env::consts::OS);
+    let _ = write!(out, "arch_bits:{}\r\n", usize::BITS);
+    let _ = write!(out, "process_id:{}\r\n", std::process::id());
+    let _ = write!(out, "tcp_port:{}\r\n", crate::args().port);
+    let _ = write!(out, "uptime_in_seconds:{}\r\n", uptime);
+    let _ = write!(out, "uptime_in_days:{}\r\n", uptime / 86400);
+    let _ = write!(out, "hz:{}\r\n", expiry::HZ);
+}
+
+fn persistence(out: &mut String, db: &Rdb) {
+    let stats = persistence::stats();
+    let secs =
+        |duration: Option<Duration>| duration.map_or(-1, |duration| duration.as_secs() as i64);
+
+    out.push_str("# Persistence\r\n");
+    out.push_str("loading:0\r\n");
+    let _ = write!(out, "rdb_changes_since_last_save:{}\r\n", db.dirty());
+    let _ = write!(
+        out,
+        "rdb_bgsave_in_progress:{}\r\n",
+        stats.current_bgsave_duration.is_some() as u8
+    );
+    let _ = write!(out, "rdb_last_save_time:{}\r\n", stats.last_save);
+    let _ = write!(
+        out,
+        "rdb_last_bgsave_status:{}\r\n",
+        if stats.last_bgsave_ok { "ok" } else { "err" }
+    );
+    let _ = write!(
+        out,
+        "rdb_last_bgsave_time_sec:{}\r\n",
+        secs(stats.last_bgsave_duration)
+    );
+    let _ = write!(
+        out,
+        "rdb_current_bgsave_time_sec:{}\r\n",
+        secs(stats.current_bgsave_duration)
+    );
+    out.push_str("aof_enabled:0\r\n");
+}
+
+fn stats(out: &mut String, db: &Rdb) {
+    let stats = db.expiry_stats();
+
+    out.push_str("# Stats\r\n");
+    let _ = write!(out, "expired_keys:{}\r\n", stats.expired_keys);
+    let _ = write!(out, "expired_subkeys:{}\r\n", stats.expired_subkeys);
+    let _ = write!(
+        out,
+        "expired_stale_perc:{:.2}\r\n",
+        stats.expired_stale_perc * 100.0
+    );
+    let _ = write!(
+        out,
+        "expired_time_cap_reached_count:{}\r\n",
+        stats.expired_time_cap_reached_count
+    );
+    let _ = write!(
+        out,
+        "expire_cycle_cpu_milliseconds:{}\r\n",
+        stats.expire_cycle_cpu_milliseconds
+    );
+}
+
+fn keyspace(out: &mut String, db: &Rdb) {
+    out.push_str("# Keyspace\r\n");
+    if db.len() > 0 {
+        let _ = write!(
+            out,
+            "db0:keys={},expires={},avg_ttl={},subexpiry={}\r\n",
+            db.len(),
+            db.expires_len(),
+            db.expiry_stats().avg_ttl,
+            db.field_expires_len()
+        );
+    }
+}
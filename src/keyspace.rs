@@ -0,0 +1,282 @@
+//! Commands that work on keys whatever they hold: TYPE, RENAME, COPY, OBJECT and the like.
+
+use bytes::Bytes;
+
+use crate::{
+    error::CommandError,
+    listpack::canonical_integer,
+    protocol_parser::{parse_integer, CommandArgs, RESPValue, Response},
+    rdb::{DBEntry, Rdb, Value},
+};
+
+// The limits below which Redis keeps collections in a compact encoding, at their defaults.
+const LIST_MAX_LISTPACK_BYTES: usize = 8192;
+const MAX_LISTPACK_ENTRIES: usize = 128;
+const MAX_LISTPACK_VALUE: usize = 64;
+const SET_MAX_INTSET_ENTRIES: usize = 512;
+// Strings up to this long are allocated along with their object header.
+const EMBSTR_MAX_LEN: usize = 44;
+// Integers below this are shared objects that are never freed, which OBJECT REFCOUNT shows.
+const SHARED_INTEGERS: i64 = 10000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ObjectSubcommand {
+    Encoding,
+    IdleTime,
+    Freq,
+    RefCount,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum KeyspaceCommand {
+    Type(Bytes),
+    Rename {
+        key: Bytes,
+        new_key: Bytes,
+        only_if_new: bool,
+    },
+    Copy {
+        source: Bytes,
+        destination: Bytes,
+        replace: bool,
+    },
+    RandomKey,
+    DbSize,
+    Touch(Vec<Bytes>),
+    Object {
+        subcommand: ObjectSubcommand,
+        key: Bytes,
+    },
+}
+
+impl KeyspaceCommand {
+    /// Parses the arguments of the keyspace command `name`, which must be upper-case.
+    pub fn parse(name: &str, args: &mut CommandArgs) -> Result<Self, CommandError> {
+        let command = match name {
+            "TYPE" => KeyspaceCommand::Type(args.required()?),
+            "RENAME" | "RENAMENX" => KeyspaceCommand::Rename {
+                key: args.required()?,
+                new_key: args.required()?,
+                only_if_new: name == "RENAMENX",
+            },
+            "COPY" => {
+                let source = args.required()?;
+                let destination = args.required()?;
+                let mut replace = false;
+                while let Some(option) = args.next() {
+                    match option.to_ascii_uppercase().as_slice() {
+                        b"REPLACE" => replace = true,
+                        // There's only the one database.
+                        b"DB" => {
+                            let db: i64 = parse_integer(&args.option_value()?)?;
+                            if db != 0 {
+                                return Err(CommandError::Other(
+                                    "DB index is out of range".to_string(),
+                                ));
+                            }
+                        }
+                        _ => return Err(CommandError::Syntax),
+                    }
+                }
+                KeyspaceCommand::Copy {
+                    source,
+                    destination,
+                    replace,
+                }
+            }
+            "RANDOMKEY" => KeyspaceCommand::RandomKey,
+            "DBSIZE" => KeyspaceCommand::DbSize,
+            "TOUCH" => KeyspaceCommand::Touch(args.at_least_one()?),
+            "OBJECT" => {
+                let subcommand = args.subcommand()?;
+                let subcommand = match subcommand.as_str() {
+                    "ENCODING" => ObjectSubcommand::Encoding,
+                    "IDLETIME" => ObjectSubcommand::IdleTime,
+                    "FREQ" => ObjectSubcommand::Freq,
+                    "REFCOUNT" => ObjectSubcommand::RefCount,
+                    _ => {
+                        return Err(CommandError::UnknownSubcommand {
+                            command: "OBJECT".to_string(),
+                            subcommand,
+                        })
+                    }
+                };
+                KeyspaceCommand::Object {
+                    subcommand,
+                    key: args.required()?,
+                }
+            }
+            _ => unreachable!("not a keyspace command: {}", name),
+        };
+
+        Ok(command)
+    }
+
+    pub fn execute(self, db: &mut Rdb) -> Result<Response, CommandError> {
+        let response = match self {
+            KeyspaceCommand::Type(key) => {
+                let type_name = db.get_untouched(&key).map_or("none", DBEntry::type_name);
+                Response::Echo(RESPValue::SimpleString(type_name.to_string()))
+            }
+            KeyspaceCommand::Rename {
+                key,
+                new_key,
+                only_if_new,
+            } => {
+                if !db.contains_key(&key) {
+                    return Err(CommandError::NoSuchKey);
+                }
+                let renamed = if key == new_key || (only_if_new && db.contains_key(&new_key)) {
+                    false
+                } else {
+                    // The entry moves as it is, expiry and all.
+                    let entry = db.remove(&key).expect("key was just found");
+                    db.remove(&new_key);
+                    db.insert(new_key, entry);
+                    true
+                };
+                if only_if_new {
+                    Response::Echo(RESPValue::Integer(renamed as i64))
+                } else {
+                    Response::Ok
+                }
+            }
+            KeyspaceCommand::Copy {
+                source,
+                destination,
+                replace,
+            } => {
+                if source == destination {
+                    return Err(CommandError::Other(
+                        "source and destination objects are the same".to_string(),
+                    ));
+                }
+                let Some(entry) = db.get(&source) else {
+                    return Ok(Response::Echo(RESPValue::Integer(0)));
+                };
+                let copy = DBEntry::new(entry.value().clone(), entry.expires_at());
+                if db.contains_key(&destination) {
+                    if !replace {
+                        return Ok(Response::Echo(RESPValue::Integer(0)));
+                    }
+                    db.remove(&destination);
+                }
+                db.insert(destination, copy);
+                Response::Echo(RESPValue::Integer(1))
+            }
+            KeyspaceCommand::RandomKey => match db.random_key() {
+                Some(key) => Response::Echo(RESPValue::BulkString(key)),
+                None => Response::Null,
+            },
+            KeyspaceCommand::DbSize => Response::Echo(RESPValue::Integer(db.len() as i64)),
+            KeyspaceCommand::Touch(keys) => {
+                let touched = keys.iter().filter(|key| db.get(key).is_some()).count();
+                Response::Echo(RESPValue::Integer(touched as i64))
+            }
+            KeyspaceCommand::Object { subcommand, key } => {
+                let Some(entry) = db.get_untouched(&key) else {
+                    return Ok(Response::Null);
+                };
+                match subcommand {
+                    ObjectSubcommand::Encoding => {
+                        Response::Echo(RESPValue::BulkString(Bytes::from(encoding(entry.value()))))
+                    }
+                    ObjectSubcommand::IdleTime => {
+                        Response::Echo(RESPValue::Integer(entry.idle_time().as_secs() as i64))
+                    }
+                    // Access frequency is only tracked under an LFU eviction policy, and there's
+                    // no eviction at all.
+                    ObjectSubcommand::Freq => {
+                        return Err(CommandError::Other(
+                            "An LFU maxmemory policy is not selected, access frequency not \
+                             tracked. Please note that when switching between policies at \
+                             runtime LRU and LFU data will take some time to adjust."
+                                .to_string(),
+                        ))
+                    }
+                    ObjectSubcommand::RefCount => {
+                        let shared = match entry.value() {
+                            Value::String(value) => canonical_integer(value)
+                                .is_some_and(|value| (0..SHARED_INTEGERS).contains(&value)),
+                            _ => false,
+                        };
+                        let count = if shared { i32::MAX as i64 } else { 1 };
+                        Response::Echo(RESPValue::Integer(count))
+                    }
+                }
+            }
+        };
+
+        Ok(response)
+    }
+}
+
+/// The encoding Redis would use for a value, as OBJECT ENCODING reports it. Redis never converts a
+/// collection back to a compact encoding once it has outgrown it, but this looks only at what the
+/// value holds now.
+pub fn encoding(value: &Value) -> &'static str {
+    let small = |len: usize, mut values: Box<dyn Iterator<Item = &Bytes> + '_>| {
+        len <= MAX_LISTPACK_ENTRIES && values.all(|value| value.len() <= MAX_LISTPACK_VALUE)
+    };
+
+    match value {
+        Value::String(value) if canonical_integer(value).is_some() => "int",
+        Value::String(value) if value.len() <= EMBSTR_MAX_LEN => "embstr",
+        Value::String(_) => "raw",
+        Value::List(list) => {
+            // Roughly what each element takes in a listpack: its header, itself and its length.
+            let bytes: usize = list.iter().map(|value| value.len() + 2).sum();
+            if bytes + 7 <= LIST_MAX_LISTPACK_BYTES {
+                "listpack"
+            } else {
+                "quicklist"
+            }
+        }
+        Value::Hash(hash) => {
+            let fields = hash.iter().flat_map(|(name, value)| [name, value]);
+            match (
+                small(hash.len(), Box::new(fields)),
+                hash.has_expiring_fields(),
+            ) {
+                (true, false) => "listpack",
+                (true, true) => "listpackex",
+                (false, _) => "hashtable",
+            }
+        }
+        Value::Set(set) => {
+            if set.len() <= SET_MAX_INTSET_ENTRIES
+                && set.iter().all(|member| canonical_integer(member).is_some())
+            {
+                "intset"
+            } else if small(set.len(), Box::new(set.iter())) {
+                "listpack"
+            } else {
+                "hashtable"
+            }
+        }
+        Value::SortedSet(set) => {
+            if small(set.len(), Box::new(set.iter().map(|(member, _)| member))) {
+                "listpack"
+            } else {
+                "skiplist"
+            }
+        }
+        Value::Stream(_) => "stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_string_encodings() {
+        let encode = |value: &str| encoding(&Value::String(Bytes::from(value.to_string())));
+        assert_eq!(encode("12345"), "int");
+        assert_eq!(encode("-7"), "int");
+        assert_eq!(encode("007"), "embstr");
+        assert_eq!(encode("+7"), "embstr");
+        assert_eq!(encode(&"x".repeat(44)), "embstr");
+        assert_eq!(encode(&"x".repeat(45)), "raw");
+    }
+}
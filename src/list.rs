@@ -0,0 +1,551 @@
+//! The list type: a sequence of strings that can be pushed to and popped from either end.
+
+use std::collections::VecDeque;
+
+use bytes::Bytes;
+
+use crate::{
+    error::CommandError,
+    protocol_parser::{normalize_range, parse_integer, CommandArgs, RESPValue, Response},
+    rdb::{Rdb, Value},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum End {
+    Left,
+    Right,
+}
+
+impl End {
+    fn parse(arg: &[u8]) -> Result<Self, CommandError> {
+        match String::from_utf8_lossy(arg).to_ascii_uppercase().as_str() {
+            "LEFT" => Ok(End::Left),
+            "RIGHT" => Ok(End::Right),
+            _ => Err(CommandError::Syntax),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ListCommand {
+    Push {
+        key: Bytes,
+        values: Vec<Bytes>,
+        end: End,
+        /// LPUSHX and RPUSHX only push onto a list that already exists.
+        only_if_exists: bool,
+    },
+    Pop {
+        key: Bytes,
+        end: End,
+        count: Option<usize>,
+    },
+    Len(Bytes),
+    Range {
+        key: Bytes,
+        start: i64,
+        stop: i64,
+    },
+    Index {
+        key: Bytes,
+        index: i64,
+    },
+    Set {
+        key: Bytes,
+        index: i64,
+        value: Bytes,
+    },
+    Insert {
+        key: Bytes,
+        before: bool,
+        pivot: Bytes,
+        value: Bytes,
+    },
+    Rem {
+        key: Bytes,
+        count: i64,
+        value: Bytes,
+    },
+    Trim {
+        key: Bytes,
+        start: i64,
+        stop: i64,
+    },
+    Pos {
+        key: Bytes,
+        element: Bytes,
+        rank: i64,
+        count: Option<usize>,
+        maxlen: usize,
+    },
+    Move {
+        source: Bytes,
+        destination: Bytes,
+        from: End,
+        to: End,
+    },
+}
+
+impl ListCommand {
+    /// Parses the arguments of the list command `name`, which must be upper-case.
+    pub fn parse(name: &str, args: &mut CommandArgs) -> Result<Self, CommandError> {
+        let command = match name {
+            "LPUSH" | "RPUSH" | "LPUSHX" | "RPUSHX" => ListCommand::Push {
+                key: args.required()?,
+                values: args.at_least_one()?,
+                end: if name.starts_with('L') {
+                    End::Left
+                } else {
+                    End::Right
+                },
+                only_if_exists: name.ends_with('X'),
+            },
+            "LPOP" | "RPOP" => {
+                let key = args.required()?;
+                let count = match args.next() {
+                    Some(count) => Some(parse_positive(&count)?),
+                    None => None,
+                };
+                ListCommand::Pop {
+                    key,
+                    end: if name == "LPOP" {
+                        End::Left
+                    } else {
+                        End::Right
+                    },
+                    count,
+                }
+            }
+            "LLEN" => ListCommand::Len(args.required()?),
+            "LRANGE" => ListCommand::Range {
+                key: args.required()?,
+                start: parse_integer(&args.required()?)?,
+                stop: parse_integer(&args.required()?)?,
+            },
+            "LINDEX" => ListCommand::Index {
+                key: args.required()?,
+                index: parse_integer(&args.required()?)?,
+            },
+            "LSET" => ListCommand::Set {
+                key: args.required()?,
+                index: parse_integer(&args.required()?)?,
+                value: args.required()?,
+            },
+            "LINSERT" => {
+                let key = args.required()?;
+                let before = match String::from_utf8_lossy(&args.required()?)
+                    .to_ascii_uppercase()
+                    .as_str()
+                {
+                    "BEFORE" => true,
+                    "AFTER" => false,
+                    _ => return Err(CommandError::Syntax),
+                };
+                ListCommand::Insert {
+                    key,
+                    before,
+                    pivot: args.required()?,
+                    value: args.required()?,
+                }
+            }
+            "LREM" => ListCommand::Rem {
+                key: args.required()?,
+                count: parse_integer(&args.required()?)?,
+                value: args.required()?,
+            },
+            "LTRIM" => ListCommand::Trim {
+                key: args.required()?,
+                start: parse_integer(&args.required()?)?,
+                stop: parse_integer(&args.required()?)?,
+            },
+            "LPOS" => {
+                let key = args.required()?;
+                let element = args.required()?;
+                let mut rank = 1;
+                let mut count = None;
+                let mut maxlen = 0;
+
+                while let Some(option) = args.next() {
+                    match String::from_utf8_lossy(&option)
+                        .to_ascii_uppercase()
+                        .as_str()
+                    {
+                        "RANK" => {
+                            rank = parse_integer(&args.option_value()?)?;
+                            if rank == 0 {
+                                return Err(CommandError::Other(
+                                    "RANK can't be zero: use 1 to start from the first match, \
+                                     2 from the second ... or use negative to start from the end \
+                                     of the list"
+                                        .to_string(),
+                                ));
+                            }
+                        }
+                        "COUNT" => {
+                            count = Some(parse_integer(&args.option_value()?).map_err(|_| {
+                                CommandError::Other("COUNT can't be negative".to_string())
+                            })?);
+                        }
+                        "MAXLEN" => {
+                            maxlen = parse_integer(&args.option_value()?).map_err(|_| {
+                                CommandError::Other("MAXLEN can't be negative".to_string())
+                            })?;
+                        }
+                        _ => return Err(CommandError::Syntax),
+                    }
+                }
+
+                ListCommand::Pos {
+                    key,
+                    element,
+                    rank,
+                    count,
+                    maxlen,
+                }
+            }
+            "LMOVE" => ListCommand::Move {
+                source: args.required()?,
+                destination: args.required()?,
+                from: End::parse(&args.required()?)?,
+                to: End::parse(&args.required()?)?,
+            },
+            "RPOPLPUSH" => ListCommand::Move {
+                source: args.required()?,
+                destination: args.required()?,
+                from: End::Right,
+                to: End::Left,
+            },
+            _ => unreachable!("not a list command: {}", name),
+        };
+
+        Ok(command)
+    }
+
+    pub fn execute(self, db: &mut Rdb) -> Result<Response, CommandError> {
+        let response = match self {
+            ListCommand::Push {
+                key,
+                values,
+                end,
+                only_if_exists,
+            } => {
+                if only_if_exists && !db.contains_key(&key) {
+                    return Ok(integer(0));
+                }
+
+                let list = db
+                    .get_or_insert_with(&key, || Value::List(VecDeque::new()))
+                    .value_mut()
+                    .as_list_mut()?;
+                let pushed = values.len();
+                for value in values {
+                    match end {
+                        End::Left => list.push_front(value),
+                        End::Right => list.push_back(value),
+                    }
+                }
+                let len = list.len();
+                db.mark_dirty(pushed);
+                integer(len)
+            }
+            ListCommand::Pop { key, end, count } => {
+                let Some(list) = list_mut(db, &key)? else {
+                    return Ok(match count {
+                        Some(_) => Response::Echo(RESPValue::NullArray),
+                        None => Response::Null,
+                    });
+                };
+
+                let len = list.len();
+                let response = match count {
+                    None => match pop(list, end) {
+                        Some(value) => Response::Echo(RESPValue::BulkString(value)),
+                        None => Response::Null,
+                    },
+                    Some(count) => {
+                        let popped = std::iter::from_fn(|| pop(list, end))
+                            .take(count)
+                            .map(RESPValue::BulkString)
+                            .collect();
+                        Response::Echo(RESPValue::Array(popped))
+                    }
+                };
+                let popped = len - list.len();
+                db.mark_dirty(popped);
+                db.remove_if_empty(&key);
+                response
+            }
+            ListCommand::Len(key) => integer(list(db, &key)?.map_or(0, VecDeque::len)),
+            ListCommand::Range { key, start, stop } => {
+                let values = match list(db, &key)? {
+                    Some(list) => match normalize_range(start, stop, list.len()) {
+                        Some((start, stop)) => list
+                            .range(start..=stop)
+                            .cloned()
+                            .map(RESPValue::BulkString)
+                            .collect(),
+                        None => vec![],
+                    },
+                    None => vec![],
+                };
+                Response::Echo(RESPValue::Array(values))
+            }
+            ListCommand::Index { key, index } => {
+                let value = list(db, &key)?
+                    .and_then(|list| resolve_index(index, list.len()).and_then(|i| list.get(i)));
+                match value {
+                    Some(value) => Response::Echo(RESPValue::BulkString(value.clone())),
+                    None => Response::Null,
+                }
+            }
+            ListCommand::Set { key, index, value } => {
+                let Some(list) = list_mut(db, &key)? else {
+                    return Err(CommandError::NoSuchKey);
+                };
+                let Some(index) = resolve_index(index, list.len()) else {
+                    return Err(CommandError::Other("index out of range".to_string()));
+                };
+                list[index] = value;
+                db.mark_dirty(1);
+                Response::Ok
+            }
+            ListCommand::Insert {
+                key,
+                before,
+                pivot,
+                value,
+            } => {
+                let Some(list) = list_mut(db, &key)? else {
+                    return Ok(integer(0));
+                };
+                match list.iter().position(|item| *item == pivot) {
+                    Some(position) => {
+                        list.insert(if before { position } else { position + 1 }, value);
+                        let len = list.len();
+                        db.mark_dirty(1);
+                        integer(len)
+                    }
+                    None => Response::Echo(RESPValue::Integer(-1)),
+                }
+            }
+            ListCommand::Rem { key, count, value } => {
+                let Some(list) = list_mut(db, &key)? else {
+                    return Ok(integer(0));
+                };
+
+                // A positive count removes from the head, a negative one from the tail, and zero
+                // removes every occurrence.
+                let limit = if count == 0 {
+                    usize::MAX
+                } else {
+                    count.unsigned_abs() as usize
+                };
+                let mut removed = 0;
+                if count >= 0 {
+                    let mut i = 0;
+                    while i < list.len() && removed < limit {
+                        if list[i] == value {
+                            list.remove(i);
+                            removed += 1;
+                        } else {
+                            i += 1;
+                        }
+                    }
+                } else {
+                    let mut i = list.len();
+                    while i > 0 && removed < limit {
+                        i -= 1;
+                        if list[i] == value {
+                            list.remove(i);
+                            removed += 1;
+                        }
+                    }
+                }
+
+                db.mark_dirty(removed);
+                db.remove_if_empty(&key);
+                integer(removed)
+            }
+            ListCommand::Trim { key, start, stop } => {
+                let Some(list) = list_mut(db, &key)? else {
+                    return Ok(Response::Ok);
+                };
+                let len = list.len();
+                match normalize_range(start, stop, len) {
+                    Some((start, stop)) => {
+                        list.truncate(stop + 1);
+                        list.drain(..start);
+                    }
+                    None => list.clear(),
+                }
+                let trimmed = len - list.len();
+                db.mark_dirty(trimmed);
+                db.remove_if_empty(&key);
+                Response::Ok
+            }
+            ListCommand::Pos {
+                key,
+                element,
+                rank,
+                count,
+                maxlen,
+            } => {
+                let positions = match list(db, &key)? {
+                    Some(list) => find_positions(list, &element, rank, count, maxlen),
+                    None => vec![],
+                };
+                match count {
+                    Some(_) => Response::Echo(RESPValue::Array(
+                        positions.into_iter().map(integer_value).collect(),
+                    )),
+                    None => match positions.first() {
+                        Some(&position) => integer(position),
+                        None => Response::Null,
+                    },
+                }
+            }
+            ListCommand::Move {
+                source,
+                destination,
+                from,
+                to,
+            } => {
+                // Check the destination's type before popping anything off the source.
+                if let Some(entry) = db.get(&destination) {
+                    entry.value().as_list()?;
+                }
+                let Some(value) = list_mut(db, &source)?.and_then(|list| pop(list, from)) else {
+                    return Ok(Response::Null);
+                };
+
+                let list = db
+                    .get_or_insert_with(&destination, || Value::List(VecDeque::new()))
+                    .value_mut()
+                    .as_list_mut()?;
+                match to {
+                    End::Left => list.push_front(value.clone()),
+                    End::Right => list.push_back(value.clone()),
+                }
+
+                // Only now, in case the source and destination are the same list.
+                db.mark_dirty(1);
+                db.remove_if_empty(&source);
+                Response::Echo(RESPValue::BulkString(value))
+            }
+        };
+
+        Ok(response)
+    }
+}
+
+fn list<'a>(db: &'a mut Rdb, key: &[u8]) -> Result<Option<&'a VecDeque<Bytes>>, CommandError> {
+    db.get(key).map(|entry| entry.value().as_list()).transpose()
+}
+
+fn list_mut<'a>(
+    db: &'a mut Rdb,
+    key: &[u8],
+) -> Result<Option<&'a mut VecDeque<Bytes>>, CommandError> {
+    db.get_mut(key)
+        .map(|entry| entry.value_mut().as_list_mut())
+        .transpose()
+}
+
+fn pop(list: &mut VecDeque<Bytes>, end: End) -> Option<Bytes> {
+    match end {
+        End::Left => list.pop_front(),
+        End::Right => list.pop_back(),
+    }
+}
+
+// Resolves an index that may count back from the end, as -1 for the last item.
+fn resolve_index(index: i64, len: usize) -> Option<usize> {
+    let index = if index < 0 { len as i64 + index } else { index };
+    (0..len as i64).contains(&index).then_some(index as usize)
+}
+
+// Finds the positions of `element` for LPOS. A negative rank searches from the tail, and skips
+// that many matches minus one before reporting any.
+fn find_positions(
+    list: &VecDeque<Bytes>,
+    element: &[u8],
+    rank: i64,
+    count: Option<usize>,
+    maxlen: usize,
+) -> Vec<usize> {
+    let wanted = match count {
+        Some(0) => usize::MAX,
+        Some(count) => count,
+        None => 1,
+    };
+    let compared = if maxlen == 0 { list.len() } else { maxlen };
+
+    let indexes: Box<dyn Iterator<Item = usize>> = if rank > 0 {
+        Box::new(0..list.len())
+    } else {
+        Box::new((0..list.len()).rev())
+    };
+
+    indexes
+        .take(compared)
+        .filter(|&i| list[i] == element)
+        .skip(rank.unsigned_abs() as usize - 1)
+        .take(wanted)
+        .collect()
+}
+
+fn parse_positive(arg: &[u8]) -> Result<usize, CommandError> {
+    let value: i64 = parse_integer(arg)?;
+    usize::try_from(value).map_err(|_| CommandError::NotPositive)
+}
+
+fn integer(value: usize) -> Response {
+    Response::Echo(integer_value(value))
+}
+
+fn integer_value(value: usize) -> RESPValue {
+    RESPValue::Integer(value as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list(items: &[&'static str]) -> VecDeque<Bytes> {
+        items.iter().map(|item| Bytes::from(*item)).collect()
+    }
+
+    #[test]
+    fn test_find_positions() {
+        let list = list(&["a", "b", "c", "1", "2", "3", "c", "c"]);
+        assert_eq!(find_positions(&list, b"c", 1, None, 0), vec![2]);
+        assert_eq!(find_positions(&list, b"c", 2, None, 0), vec![6]);
+        assert_eq!(find_positions(&list, b"c", -1, None, 0), vec![7]);
+        assert_eq!(find_positions(&list, b"c", 1, Some(0), 0), vec![2, 6, 7]);
+        assert_eq!(find_positions(&list, b"c", -1, Some(2), 0), vec![7, 6]);
+        assert_eq!(find_positions(&list, b"c", 1, Some(0), 6), vec![2]);
+        assert!(find_positions(&list, b"z", 1, None, 0).is_empty());
+    }
+
+    #[test]
+    fn test_resolve_index() {
+        assert_eq!(resolve_index(0, 3), Some(0));
+        assert_eq!(resolve_index(-1, 3), Some(2));
+        assert_eq!(resolve_index(-3, 3), Some(0));
+        assert_eq!(resolve_index(-4, 3), None);
+        assert_eq!(resolve_index(3, 3), None);
+    }
+
+    #[test]
+    fn test_pop_missing_key() {
+        let pop = |args: &[&'static str]| {
+            let args: Vec<_> = args.iter().map(|&arg| Bytes::from(arg)).collect();
+            let command =
+                ListCommand::parse("LPOP", &mut CommandArgs::new(b"LPOP", args.into_iter()))
+                    .unwrap();
+            command.execute(&mut Rdb::default()).unwrap()
+        };
+        assert_eq!(pop(&["missing"]), Response::Null);
+        assert_eq!(pop(&["missing", "2"]), Response::Echo(RESPValue::NullArray));
+    }
+}
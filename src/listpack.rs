@@ -0,0 +1,271 @@
+//! Listpacks, the compact serialized lists Redis keeps small collections and stream entries in,
+//! and writes into RDB files as they are.
+//!
+//! A listpack is a header holding its total size and number of elements, the elements, and an end
+//! byte. Each element is an encoding byte, which may carry part of the length or value, the rest of
+//! the value, and then the length of all that, so the list can be walked backwards too.
+
+use bytes::Bytes;
+
+const HEADER_LEN: usize = 6;
+const END: u8 = 0xff;
+// The element count in the header saturates here, and then the list has to be walked to count.
+const COUNT_UNKNOWN: u16 = u16::MAX;
+
+const ENCODING_6BIT_STR: u8 = 0x80;
+const ENCODING_13BIT_INT: u8 = 0xc0;
+const ENCODING_12BIT_STR: u8 = 0xe0;
+const ENCODING_32BIT_STR: u8 = 0xf0;
+const ENCODING_16BIT_INT: u8 = 0xf1;
+const ENCODING_24BIT_INT: u8 = 0xf2;
+const ENCODING_32BIT_INT: u8 = 0xf3;
+const ENCODING_64BIT_INT: u8 = 0xf4;
+
+/// Builds a listpack one element at a time.
+#[derive(Debug)]
+pub struct ListpackWriter {
+    buf: Vec<u8>,
+    count: usize,
+}
+
+impl Default for ListpackWriter {
+    fn default() -> Self {
+        ListpackWriter {
+            buf: vec![0; HEADER_LEN],
+            count: 0,
+        }
+    }
+}
+
+impl ListpackWriter {
+    /// Appends a string, stored as an integer if it is exactly how that integer would be written,
+    /// as Redis does.
+    pub fn push(&mut self, value: &[u8]) {
+        match canonical_integer(value) {
+            Some(value) => self.push_integer(value),
+            None => self.push_string(value),
+        }
+    }
+
+    pub fn push_integer(&mut self, value: i64) {
+        let mut element = Vec::with_capacity(9);
+        match value {
+            0..=127 => element.push(value as u8),
+            -4096..=4095 => {
+                let v = (value as u16) & 0x1fff;
+                element.extend_from_slice(&[ENCODING_13BIT_INT | (v >> 8) as u8, v as u8]);
+            }
+            -32768..=32767 => {
+                element.push(ENCODING_16BIT_INT);
+                element.extend_from_slice(&(value as i16).to_le_bytes());
+            }
+            -8388608..=8388607 => {
+                element.push(ENCODING_24BIT_INT);
+                element.extend_from_slice(&(value as i32).to_le_bytes()[..3]);
+            }
+            -2147483648..=2147483647 => {
+                element.push(ENCODING_32BIT_INT);
+                element.extend_from_slice(&(value as i32).to_le_bytes());
+            }
+            _ => {
+                element.push(ENCODING_64BIT_INT);
+                element.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        self.push_element(&element);
+    }
+
+    fn push_string(&mut self, value: &[u8]) {
+        let len = value.len();
+        let mut element = Vec::with_capacity(len + 5);
+        if len < 64 {
+            element.push(ENCODING_6BIT_STR | len as u8);
+        } else if len < 4096 {
+            element.extend_from_slice(&[ENCODING_12BIT_STR | (len >> 8) as u8, len as u8]);
+        } else {
+            element.push(ENCODING_32BIT_STR);
+            element.extend_from_slice(&(len as u32).to_le_bytes());
+        }
+        element.extend_from_slice(value);
+        self.push_element(&element);
+    }
+
+    fn push_element(&mut self, element: &[u8]) {
+        self.buf.extend_from_slice(element);
+        encode_backlen(element.len(), &mut self.buf);
+        self.count += 1;
+    }
+
+    /// The finished listpack.
+    pub fn finish(mut self) -> Vec<u8> {
+        self.buf.push(END);
+        let total = self.buf.len() as u32;
+        let count = u16::try_from(self.count).unwrap_or(COUNT_UNKNOWN);
+        self.buf[..4].copy_from_slice(&total.to_le_bytes());
+        self.buf[4..6].copy_from_slice(&count.to_le_bytes());
+        self.buf
+    }
+}
+
+/// Reads every element of a listpack, with integers written out as strings. Returns `None` if
+/// it's corrupt.
+pub fn read(lp: &[u8]) -> Option<Vec<Bytes>> {
+    let total = u32::from_le_bytes(lp.get(..4)?.try_into().ok()?) as usize;
+    if total != lp.len() || lp.last() != Some(&END) {
+        return None;
+    }
+
+    let mut elements = Vec::new();
+    let mut at = HEADER_LEN;
+    loop {
+        let start = at;
+        let encoding = *lp.get(at)?;
+        if encoding == END {
+            break;
+        }
+        at += 1;
+        let element = match encoding {
+            0x00..=0x7f => Bytes::from(encoding.to_string()),
+            0x80..=0xbf => string(lp, &mut at, (encoding & 0x3f) as usize)?,
+            0xc0..=0xdf => {
+                let low = take(lp, &mut at, 1)?[0];
+                // Thirteen bits, shifted up to the top of an i16 and back down to extend the sign.
+                let value = (u16::from_be_bytes([encoding & 0x1f, low]) << 3) as i16 >> 3;
+                Bytes::from(value.to_string())
+            }
+            0xe0..=0xef => {
+                let low = take(lp, &mut at, 1)?[0];
+                let len = u16::from_be_bytes([encoding & 0x0f, low]);
+                string(lp, &mut at, len as usize)?
+            }
+            ENCODING_32BIT_STR => {
+                let len = u32::from_le_bytes(take(lp, &mut at, 4)?.try_into().ok()?);
+                string(lp, &mut at, len as usize)?
+            }
+            ENCODING_16BIT_INT => integer(lp, &mut at, 2)?,
+            ENCODING_24BIT_INT => integer(lp, &mut at, 3)?,
+            ENCODING_32BIT_INT => integer(lp, &mut at, 4)?,
+            ENCODING_64BIT_INT => integer(lp, &mut at, 8)?,
+            _ => return None,
+        };
+        elements.push(element);
+        at += backlen_size(at - start);
+    }
+
+    (at == lp.len() - 1).then_some(elements)
+}
+
+/// Takes the next `len` bytes from `at` on, moving `at` past them. Shared with ziplists, which
+/// are read the same way.
+pub fn take<'a>(bytes: &'a [u8], at: &mut usize, len: usize) -> Option<&'a [u8]> {
+    let taken = bytes.get(*at..at.checked_add(len)?)?;
+    *at += len;
+    Some(taken)
+}
+
+pub fn string(bytes: &[u8], at: &mut usize, len: usize) -> Option<Bytes> {
+    take(bytes, at, len).map(Bytes::copy_from_slice)
+}
+
+/// A little-endian signed integer `len` bytes long, written out as a string.
+pub fn integer(bytes: &[u8], at: &mut usize, len: usize) -> Option<Bytes> {
+    let mut buf = [0; 8];
+    buf[8 - len..].copy_from_slice(take(bytes, at, len)?);
+    // Read into the top of an i64 and shifted back down to extend the sign.
+    let value = i64::from_le_bytes(buf) >> (64 - 8 * len);
+    Some(Bytes::from(value.to_string()))
+}
+
+// How many bytes `encode_backlen` takes to write `len`.
+fn backlen_size(len: usize) -> usize {
+    let mut size = 1;
+    let mut rest = len >> 7;
+    while rest > 0 {
+        size += 1;
+        rest >>= 7;
+    }
+    size
+}
+
+// The length of an element, written after it most significant seven bits first, so that it reads
+// as a varint from right to left.
+fn encode_backlen(len: usize, out: &mut Vec<u8>) {
+    let mut groups = vec![(len & 0x7f) as u8];
+    let mut rest = len >> 7;
+    while rest > 0 {
+        groups.push((rest & 0x7f) as u8);
+        rest >>= 7;
+    }
+    let last = groups.len() - 1;
+    for (i, group) in groups.into_iter().enumerate().rev() {
+        out.push(if i == last { group } else { group | 0x80 });
+    }
+}
+
+/// The integer a string holds, if it is exactly how that integer is written, with no sign on
+/// positive numbers or leading zeros.
+pub fn canonical_integer(value: &[u8]) -> Option<i64> {
+    if value.is_empty() || value.len() > 20 {
+        return None;
+    }
+    let parsed: i64 = std::str::from_utf8(value).ok()?.parse().ok()?;
+    (parsed.to_string().as_bytes() == value).then_some(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encodings() {
+        let mut writer = ListpackWriter::default();
+        writer.push(b"hello");
+        writer.push(b"1024");
+        writer.push(b"-1");
+        writer.push(b"007");
+        let bytes = writer.finish();
+        assert_eq!(
+            bytes,
+            [
+                &25u32.to_le_bytes()[..],
+                &4u16.to_le_bytes(),
+                &[0x85, b'h', b'e', b'l', b'l', b'o', 6],
+                &[0xc4, 0x00, 2],
+                &[0xdf, 0xff, 2],
+                &[0x83, b'0', b'0', b'7', 4],
+                &[0xff],
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn test_read() {
+        let mut writer = ListpackWriter::default();
+        let values = [
+            "hello",
+            "1024",
+            "-1",
+            "007",
+            "-100000",
+            "9223372036854775807",
+        ];
+        for value in values {
+            writer.push(value.as_bytes());
+        }
+        writer.push(&b"x".repeat(200));
+        let bytes = writer.finish();
+
+        let mut expected: Vec<Bytes> = values.iter().map(|&value| Bytes::from(value)).collect();
+        expected.push(Bytes::from("x".repeat(200)));
+        assert_eq!(read(&bytes).unwrap(), expected);
+        assert_eq!(read(&bytes[..bytes.len() - 1]), None);
+    }
+
+    #[test]
+    fn test_long_backlen() {
+        let mut out = Vec::new();
+        encode_backlen(200, &mut out);
+        assert_eq!(out, [0x01, 0xc8]);
+    }
+}
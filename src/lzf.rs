@@ -0,0 +1,154 @@
+//! LZF, the small and fast compression scheme Redis uses for long strings in RDB files.
+//!
+//! Compressed data is a series of runs, each starting with a control byte. Below 32 it's a run of
+//! that many plus one literal bytes, which follow it. Otherwise its top three bits are the length
+//! of a back reference, less two, with a further length byte if they're all set, and its low five
+//! bits are the top of the distance back to copy from, whose low byte follows.
+
+const HASH_LOG: u32 = 14;
+// Literal runs and back references can't be any longer or further than these.
+const MAX_LITERAL: usize = 1 << 5;
+const MAX_OFFSET: usize = 1 << 13;
+const MAX_REFERENCE: usize = (1 << 8) + (1 << 3);
+
+/// Compresses `input`, or returns `None` if that would take more than `max_len` bytes.
+pub fn compress(input: &[u8], max_len: usize) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(max_len);
+    // Where each three byte sequence was last seen, plus one so zero can mean never.
+    let mut seen = vec![0usize; 1 << HASH_LOG];
+    // The control byte for the literal run being built, which is filled in once it ends.
+    let mut run_start = 0;
+    out.push(0);
+    let mut literals = 0;
+
+    let mut ip = 0;
+    while ip < input.len() {
+        if ip + 2 < input.len() {
+            let hash = hash(&input[ip..ip + 3]);
+            let candidate = std::mem::replace(&mut seen[hash], ip + 1);
+            if let Some(reference) = candidate.checked_sub(1) {
+                let offset = ip - reference - 1;
+                if offset < MAX_OFFSET && input[reference..reference + 3] == input[ip..ip + 3] {
+                    let max = MAX_REFERENCE.min(input.len() - ip);
+                    let len = (3..max)
+                        .find(|&len| input[reference + len] != input[ip + len])
+                        .unwrap_or(max);
+
+                    if literals > 0 {
+                        out[run_start] = (literals - 1) as u8;
+                    } else {
+                        out.pop();
+                    }
+                    let encoded_len = len - 2;
+                    if encoded_len < 7 {
+                        out.push(((encoded_len << 5) | (offset >> 8)) as u8);
+                    } else {
+                        out.push(((7 << 5) | (offset >> 8)) as u8);
+                        out.push((encoded_len - 7) as u8);
+                    }
+                    out.push(offset as u8);
+
+                    run_start = out.len();
+                    out.push(0);
+                    literals = 0;
+                    ip += len;
+                    if out.len() > max_len {
+                        return None;
+                    }
+                    continue;
+                }
+            }
+        }
+
+        out.push(input[ip]);
+        literals += 1;
+        ip += 1;
+        if literals == MAX_LITERAL {
+            out[run_start] = (literals - 1) as u8;
+            run_start = out.len();
+            out.push(0);
+            literals = 0;
+        }
+        if out.len() > max_len {
+            return None;
+        }
+    }
+
+    if literals > 0 {
+        out[run_start] = (literals - 1) as u8;
+    } else {
+        out.pop();
+    }
+    (out.len() <= max_len).then_some(out)
+}
+
+/// Decompresses `input`, which should come out `len` bytes long. Returns `None` if it's corrupt.
+pub fn decompress(input: &[u8], len: usize) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(len);
+    let mut ip = 0;
+
+    while ip < input.len() {
+        let control = input[ip] as usize;
+        ip += 1;
+
+        if control < MAX_LITERAL {
+            let literal = input.get(ip..ip + control + 1)?;
+            out.extend_from_slice(literal);
+            ip += control + 1;
+        } else {
+            let mut run = control >> 5;
+            if run == 7 {
+                run += *input.get(ip)? as usize;
+                ip += 1;
+            }
+            let offset = ((control & 0x1f) << 8) + *input.get(ip)? as usize + 1;
+            ip += 1;
+            let start = out.len().checked_sub(offset)?;
+            // The reference may run on into what it's copying, so copy a byte at a time.
+            for i in start..start + run + 2 {
+                out.push(out[i]);
+            }
+        }
+        if out.len() > len {
+            return None;
+        }
+    }
+
+    (out.len() == len).then_some(out)
+}
+
+fn hash(bytes: &[u8]) -> usize {
+    let value = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0]);
+    (value.wrapping_mul(2654435761) >> (32 - HASH_LOG)) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let inputs: [&[u8]; 3] = [
+            &b"abcabcabcabcabcabcabcabcabcabcabc hello hello hello"[..],
+            &b"x".repeat(1000),
+            &(0..=255).cycle().take(20000).collect::<Vec<u8>>(),
+        ];
+        for input in inputs {
+            let compressed = compress(input, input.len() - 4).unwrap();
+            assert!(compressed.len() < input.len());
+            assert_eq!(decompress(&compressed, input.len()).unwrap(), input);
+        }
+        assert_eq!(compress(b"abcdefghijklmnopqrstuvwxyz", 22), None);
+    }
+
+    #[test]
+    fn test_decompress() {
+        // One literal "a", then a back reference to it of 7 + 29 + 2 bytes.
+        let compressed = [0x00, b'a', 0xe0, 0x1d, 0x00];
+        assert_eq!(decompress(&compressed, 39).unwrap(), b"a".repeat(39));
+        assert_eq!(decompress(&compressed, 40), None);
+        // Truncated, or referring back past the start.
+        assert_eq!(decompress(&compressed[..4], 39), None);
+        assert_eq!(decompress(&compressed[2..], 38), None);
+    }
+}
@@ -1,26 +1,60 @@
+mod bitmap;
+mod client;
+mod crc64;
+mod error;
+mod expiry;
+mod geo;
+mod glob;
+mod hash;
+mod hyperloglog;
+mod indexed_set;
+mod info;
+mod keyspace;
+mod list;
+mod listpack;
+mod lzf;
+mod persistence;
 mod protocol_parser;
+mod random;
 mod rdb;
+mod rdb_writer;
+mod scan;
+mod set;
+mod skiplist;
+mod stream;
+mod string;
+mod ziplist;
+mod zset;
 
-use core::str;
-use protocol_parser::{parse_input, RESPValue, SetCondition, SetOpts};
-use rdb::{DBEntry, Rdb};
-use std::{
-    io::{self, Read, Write},
-    net::{Shutdown, TcpListener},
-    sync::{Mutex, OnceLock},
+use bytes::{Buf, Bytes, BytesMut};
+use client::Client;
+use error::CommandError;
+use protocol_parser::{parse_input, RESPValue, Response, SetCondition, SetOpts};
+use rdb::{DBEntry, Rdb, Value};
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    signal::unix::{signal, SignalKind},
 };
 
-// TODO: There are expired keys that will never be accessed again. These keys should be expired anyway, so periodically
-// Redis tests a few keys at random among keys with an expire set. All the keys that are already expired are deleted
-// from the keyspace.
 static DB: OnceLock<Mutex<Rdb>> = OnceLock::new();
 
 static CONFIG: OnceLock<Args> = OnceLock::new();
 
+/// The Redis version we report to clients, which determines the features they expect us to have.
+const REDIS_VERSION: &str = "7.4.0";
+
 struct Args {
     port: String,
     directory: String,
     dbfilename: String,
+    /// The save rules to start with, which CONFIG SET can change later.
+    save: String,
+    /// Whether to compress long strings when saving, which CONFIG SET can change later.
+    rdbcompression: bool,
+    /// Whether to load an RDB file even if it doesn't match its checksum.
+    rdb_skip_checksum: bool,
 }
 
 impl Default for Args {
@@ -29,11 +63,15 @@ impl Default for Args {
             port: "6379".to_string(),
             directory: ".".to_string(),
             dbfilename: "dump.rdb".to_string(),
+            save: persistence::DEFAULT_SAVE_RULES.to_string(),
+            rdbcompression: true,
+            rdb_skip_checksum: false,
         }
     }
 }
 
-fn main() {
+#[tokio::main]
+async fn main() {
     let mut args = std::env::args();
 
     // Ignore the first argument, which is the binary name.
@@ -50,6 +88,15 @@ fn main() {
                 "--port" => parsed_args.port = value.to_string(),
                 "--dir" => parsed_args.directory = value.to_string(),
                 "--dbfilename" => parsed_args.dbfilename = value.to_string(),
+                "--save" => parsed_args.save = value.to_string(),
+                "--rdbcompression" => {
+                    parsed_args.rdbcompression = parse_yes_no(&value)
+                        .unwrap_or_else(|| panic!("Invalid value for {}: {}", key, value))
+                }
+                "--rdb-skip-checksum" => {
+                    parsed_args.rdb_skip_checksum = parse_yes_no(&value)
+                        .unwrap_or_else(|| panic!("Invalid value for {}: {}", key, value))
+                }
                 other => panic!("Unknown flag: {}", other),
             }
             parsed_args
@@ -57,28 +104,64 @@ fn main() {
 
     CONFIG.get_or_init(|| parsed_args);
 
+    let save_rules = persistence::parse_save_rules(&crate::args().save)
+        .unwrap_or_else(|| panic!("Invalid save parameters: {}", crate::args().save));
+    persistence::set_save_rules(save_rules);
+    persistence::set_rdb_compression(crate::args().rdbcompression);
+
     let existing_data = rdb::load_db();
     match existing_data {
         Ok(data) => {
             DB.get_or_init(|| Mutex::new(data));
         }
+        // Starting empty would mean the next save overwrites whatever was in the file, so refuse
+        // to start at all, as Redis does.
         Err(e) => {
             println!("Error loading existing data: {:?}", e);
-            DB.get_or_init(|| Mutex::new(Rdb::default()));
+            println!("Fatal error loading the DB, check server logs. Exiting.");
+            std::process::exit(1);
         }
     }
 
-    bind_and_listen(crate::args().port.clone());
+    info::mark_started();
+    persistence::mark_loaded();
+    tokio::spawn(expiry::run_active_expiry());
+    tokio::spawn(persistence::run_save_rules());
+
+    tokio::spawn(bind_and_listen(crate::args().port.clone()));
+    wait_for_shutdown().await;
 }
 
-fn bind_and_listen(port: String) {
-    let listener = TcpListener::bind(format!("127.0.0.1:{}", port)).unwrap();
+/// Waits for SIGINT or SIGTERM and then for the final save, which has to succeed before the server
+/// exits.
+async fn wait_for_shutdown() {
+    let mut terminate = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
 
-    for stream in listener.incoming() {
-        println!("new connection");
-        match stream {
-            Ok(mut stream) => {
-                std::thread::spawn(move || handle_connection(&mut stream));
+    loop {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+        println!("Received shutdown signal, scheduling shutdown...");
+        // Saving blocks, as it does in Redis.
+        if persistence::save_on_shutdown() {
+            println!("Redis is now ready to exit, bye bye...");
+            return;
+        }
+        println!("Errors trying to shut down the server. Check the logs for more information.");
+    }
+}
+
+async fn bind_and_listen(port: String) {
+    let listener = TcpListener::bind(format!("127.0.0.1:{}", port))
+        .await
+        .unwrap();
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                println!("new connection");
+                tokio::spawn(handle_connection(stream));
             }
             Err(e) => {
                 println!("error: {}", e);
@@ -87,40 +170,53 @@ fn bind_and_listen(port: String) {
     }
 }
 
-fn handle_connection(stream: &mut std::net::TcpStream) {
+async fn handle_connection(mut stream: TcpStream) {
     const BUFFER_SIZE: usize = 1024;
-    let mut agg = String::new();
-    let mut buf = [0; BUFFER_SIZE];
-    let mut reader = io::BufReader::new(stream.try_clone().unwrap());
+    let mut agg = BytesMut::with_capacity(BUFFER_SIZE);
+    let mut client = Client::new();
 
     loop {
-        match reader.read(&mut buf) {
-            // This is the last segment, either a partial buffer or
-            // completely empty if the last full buffer was a perfect fit.
-            Ok(n) if n < BUFFER_SIZE => {
-                // If the buffer is empty and we didn't read anything last time,
-                // we're just holding the connection open for more commands.
-                if agg.is_empty() && n == 0 {
-                    continue;
-                }
+        match stream.read_buf(&mut agg).await {
+            // The client has closed its side of the connection.
+            Ok(0) => break,
+            Ok(_) => {
+                // Decode every complete frame we have so far; whatever is left over is the start
+                // of a frame whose remainder hasn't arrived yet, so keep it for the next read.
+                let (inputs, consumed) = match parse_input(&agg) {
+                    Ok(parsed) => parsed,
+                    Err(e) => {
+                        let reply =
+                            RESPValue::Error(CommandError::Protocol(e.to_string()).to_string());
+                        let _ = stream.write_all(&reply.to_bytes(client.protocol())).await;
+                        break;
+                    }
+                };
+                agg.advance(consumed);
 
-                let s = str::from_utf8(&buf[..n]).unwrap();
-                agg.push_str(s);
-                println!("agg: {:?}", agg);
-                let inputs = parse_input(&agg);
+                let mut out = BytesMut::new();
                 for input in inputs {
-                    let command = input.into_command();
-                    let response = command.as_response();
-                    command.execute();
-                    stream.write_all(response.to_string().as_bytes()).unwrap();
+                    // Empty inline lines and zero-length arrays are silently ignored.
+                    if input == RESPValue::Array(vec![]) {
+                        continue;
+                    }
+
+                    let response = match input.into_command() {
+                        Ok(command) => command.run(&mut client).await,
+                        Err(e) => Err(e),
+                    };
+
+                    match response {
+                        Ok(response) => response.encode(client.protocol(), &mut out),
+                        Err(e) => {
+                            RESPValue::Error(e.to_string()).encode(client.protocol(), &mut out)
+                        }
+                    }
+                }
+
+                if let Err(e) = stream.write_all(&out).await {
+                    println!("error: {}", e);
+                    break;
                 }
-                agg.clear();
-            }
-            // This is a full buffer, we need to keep reading.
-            Ok(n) => {
-                let s = str::from_utf8(&buf[..n]).unwrap();
-                agg.push_str(s);
-                buf.fill(0);
             }
             Err(e) => {
                 println!("error: {}", e);
@@ -129,50 +225,139 @@ fn handle_connection(stream: &mut std::net::TcpStream) {
         }
     }
 
-    stream.flush().unwrap();
-    stream.shutdown(Shutdown::Both).unwrap();
+    let _ = stream.shutdown().await;
 }
 
-fn db_set(key: String, value: RESPValue, opts: &SetOpts) {
-    let mut guard = DB.get().unwrap().lock().unwrap();
-    let key_exists = guard.data_mut().contains_key(&key);
-    let condition = opts.condition();
+/// Applies a SET under a single lock acquisition and builds its reply, so the NX/XX conditions
+/// and the old value returned by GET all see the same state the write is applied to.
+fn db_set(key: Bytes, value: Bytes, opts: &SetOpts) -> Result<Response, CommandError> {
+    let mut guard = db();
 
-    if key_exists && *condition == SetCondition::IfNotExists {
-        return;
+    let (exists, previous, previous_expiry) = match guard.get(&key) {
+        Some(entry) => {
+            // GET aborts the whole SET if there's something other than a string in the way.
+            let previous = if opts.get() {
+                Some(entry.value().as_string()?.clone())
+            } else {
+                None
+            };
+            (true, previous, entry.expires_at())
+        }
+        None => (false, None, None),
+    };
+
+    let condition_met = match opts.condition() {
+        SetCondition::IfNotExists => !exists,
+        SetCondition::IfExists => exists,
+        SetCondition::Always => true,
+    };
+
+    if condition_met {
+        let expires_at = if opts.keep_ttl() {
+            previous_expiry
+        } else {
+            opts.expires_at()
+        };
+        let new_entry = DBEntry::new(Value::String(value), expires_at);
+        guard.insert(key, new_entry);
+        if cfg!(debug_assertions) {
+            println!("DB contents: {:?}", guard);
+        }
     }
 
-    if !key_exists && *condition == SetCondition::IfExists {
-        return;
+    Ok(match (opts.get(), previous) {
+        (true, Some(previous)) => Response::Echo(RESPValue::BulkString(previous)),
+        (true, None) => Response::Null,
+        (false, _) if condition_met => Response::Ok,
+        (false, _) => Response::Null,
+    })
+}
+
+fn db_get(key: Bytes) -> Result<Option<Bytes>, CommandError> {
+    let mut guard = db();
+    match guard.get(&key) {
+        Some(entry) => Ok(Some(entry.value().as_string()?.clone())),
+        None => Ok(None),
     }
+}
 
-    let new_entry = DBEntry::new(value, opts.expires_at());
-    guard.data_mut().insert(key, new_entry);
-    if cfg!(debug_assertions) {
-        println!("DB contents: {:?}", guard);
+fn config_get(key: &str) -> Option<String> {
+    match key {
+        "dir" => Some(args().directory.clone()),
+        "dbfilename" => Some(args().dbfilename.clone()),
+        "save" => Some(persistence::save_rules()),
+        "rdbcompression" => Some(
+            if persistence::rdb_compression() {
+                "yes"
+            } else {
+                "no"
+            }
+            .to_string(),
+        ),
+        _ => None,
     }
 }
 
-fn db_get(key: String) -> Option<RESPValue> {
-    let mut guard = DB.get().unwrap().lock().unwrap();
-    let entry = guard.data_mut().get(&key).cloned();
-    if let Some(entry) = entry {
-        if entry.is_expired() {
-            guard.data_mut().remove(&key);
-            return None;
+/// Applies CONFIG SET, changing nothing unless every parameter is known and valid.
+fn config_set(params: &[(String, Bytes)]) -> Result<(), CommandError> {
+    let mut save_rules = None;
+    let mut rdb_compression = None;
+    for (name, value) in params {
+        let invalid = |reason: &str| {
+            CommandError::Other(format!(
+                "CONFIG SET failed (possibly related to argument '{}') - {}",
+                name, reason
+            ))
+        };
+        match name.as_str() {
+            "save" => {
+                let rules = std::str::from_utf8(value)
+                    .ok()
+                    .and_then(persistence::parse_save_rules)
+                    .ok_or_else(|| invalid("Invalid save parameters"))?;
+                save_rules = Some(rules);
+            }
+            "rdbcompression" => {
+                let compress = std::str::from_utf8(value)
+                    .ok()
+                    .and_then(parse_yes_no)
+                    .ok_or_else(|| invalid("argument must be 'yes' or 'no'"))?;
+                rdb_compression = Some(compress);
+            }
+            _ => {
+                return Err(CommandError::Other(format!(
+                    "Unknown option or number of arguments for CONFIG SET - '{}'",
+                    name
+                )))
+            }
         }
-        Some(entry.value().clone())
+    }
+
+    if let Some(rules) = save_rules {
+        persistence::set_save_rules(rules);
+    }
+    if let Some(compress) = rdb_compression {
+        persistence::set_rdb_compression(compress);
+    }
+    Ok(())
+}
+
+/// Parses a yes/no configuration value.
+fn parse_yes_no(value: &str) -> Option<bool> {
+    if value.eq_ignore_ascii_case("yes") {
+        Some(true)
+    } else if value.eq_ignore_ascii_case("no") {
+        Some(false)
     } else {
         None
     }
 }
 
-fn config_get(key: String) -> Option<String> {
-    match key.as_str() {
-        "dir" => Some(args().directory.clone()),
-        "dbfilename" => Some(args().dbfilename.clone()),
-        _ => None,
-    }
+fn db() -> MutexGuard<'static, Rdb> {
+    DB.get()
+        .expect("DB not initialized, did you call this too early?")
+        .lock()
+        .unwrap()
 }
 
 fn args() -> &'static Args {
@@ -180,3 +365,37 @@ fn args() -> &'static Args {
         .get()
         .expect("Args not initialized, did you call this too early?")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_connection_survives_errors() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            handle_connection(stream).await;
+        });
+
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let exchanges: [(&[u8], &[u8]); 3] = [
+            (
+                b"*1\r\n$4\r\nNOPE\r\n",
+                b"-ERR unknown command 'NOPE', with args beginning with: \r\n",
+            ),
+            (
+                b"*1\r\n$3\r\nGET\r\n",
+                b"-ERR wrong number of arguments for 'get' command\r\n",
+            ),
+            (b"*1\r\n$4\r\nPING\r\n", b"+PONG\r\n"),
+        ];
+        for (request, expected) in exchanges {
+            stream.write_all(request).await.unwrap();
+            let mut reply = vec![0; expected.len()];
+            stream.read_exact(&mut reply).await.unwrap();
+            assert_eq!(reply, expected);
+        }
+    }
+}
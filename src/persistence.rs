@@ -0,0 +1,397 @@
+//! Saving the keyspace to the RDB file named by `--dir` and `--dbfilename`: on demand with SAVE and
+//! BGSAVE, in the background whenever a save rule is met, and once more on the way out.
+//!
+//! A save writes the whole file under a temporary name and then renames it over the old one, so
+//! the file on disk is always a complete snapshot, whether or not a save was interrupted.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, BufWriter},
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+    time::{Duration, Instant, SystemTime},
+};
+
+use bytes::Bytes;
+
+use crate::{
+    error::CommandError,
+    expiry,
+    protocol_parser::{CommandArgs, RESPValue, Response},
+    rdb::{unix_millis, DBEntry, Rdb},
+    rdb_writer,
+};
+
+/// The save rules Redis starts with: after an hour if anything changed, after five minutes if at
+/// least 100 keys did, and after a minute if at least 10000 did.
+pub const DEFAULT_SAVE_RULES: &str = "3600 1 300 100 60 10000";
+// After a background save fails, the save rules wait this many seconds before trying again.
+const BGSAVE_RETRY_DELAY: i64 = 5;
+
+// Only ever locked after the database, if that is locked at all.
+static STATE: Mutex<State> = Mutex::new(State {
+    save_rules: Vec::new(),
+    rdb_compression: true,
+    last_save: 0,
+    last_bgsave_ok: true,
+    last_bgsave_try: 0,
+    last_bgsave_duration: None,
+    bgsave_started_at: None,
+    bgsave_scheduled: false,
+    shutting_down: false,
+});
+
+#[derive(Debug)]
+struct State {
+    save_rules: Vec<SaveRule>,
+    /// Whether to compress long strings in the file.
+    rdb_compression: bool,
+    /// When the file on disk last matched the keyspace, in seconds since the Unix epoch.
+    last_save: i64,
+    last_bgsave_ok: bool,
+    /// When a background save was last started, in seconds since the Unix epoch.
+    last_bgsave_try: i64,
+    last_bgsave_duration: Option<Duration>,
+    /// When the running background save started, if there is one.
+    bgsave_started_at: Option<Instant>,
+    /// Whether to start another background save as soon as the running one finishes.
+    bgsave_scheduled: bool,
+    /// Set once the final save is done, after which a background save must not replace its file.
+    shutting_down: bool,
+}
+
+/// Saves in the background once there have been at least `changes` writes and more than `seconds`
+/// have passed since the last save.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SaveRule {
+    pub seconds: u64,
+    pub changes: u64,
+}
+
+/// Parses save rules given as pairs of seconds and changes, like `"3600 1 300 100"`. An empty
+/// string means no rules, so nothing is saved unless asked.
+pub fn parse_save_rules(spec: &str) -> Option<Vec<SaveRule>> {
+    let numbers: Vec<u64> = spec
+        .split_whitespace()
+        .map(|number| number.parse().ok())
+        .collect::<Option<_>>()?;
+    let pairs = numbers.chunks_exact(2);
+    if !pairs.remainder().is_empty() {
+        return None;
+    }
+    pairs
+        .map(|pair| {
+            (pair[0] > 0).then_some(SaveRule {
+                seconds: pair[0],
+                changes: pair[1],
+            })
+        })
+        .collect()
+}
+
+pub fn set_save_rules(rules: Vec<SaveRule>) {
+    state().save_rules = rules;
+}
+
+/// The save rules as CONFIG GET shows them.
+pub fn save_rules() -> String {
+    state()
+        .save_rules
+        .iter()
+        .map(|rule| format!("{} {}", rule.seconds, rule.changes))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+pub fn set_rdb_compression(compress: bool) {
+    state().rdb_compression = compress;
+}
+
+pub fn rdb_compression() -> bool {
+    state().rdb_compression
+}
+
+/// What the persistence section of INFO reports.
+#[derive(Debug)]
+pub struct PersistenceStats {
+    pub last_save: i64,
+    pub last_bgsave_ok: bool,
+    pub last_bgsave_duration: Option<Duration>,
+    /// How long the running background save has taken so far, if there is one.
+    pub current_bgsave_duration: Option<Duration>,
+}
+
+pub fn stats() -> PersistenceStats {
+    let state = state();
+    PersistenceStats {
+        last_save: state.last_save,
+        last_bgsave_ok: state.last_bgsave_ok,
+        last_bgsave_duration: state.last_bgsave_duration,
+        current_bgsave_duration: state.bgsave_started_at.map(|started| started.elapsed()),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum PersistenceCommand {
+    Save,
+    BgSave { schedule: bool },
+    LastSave,
+}
+
+impl PersistenceCommand {
+    /// Parses the arguments of the persistence command `name`, which must be upper-case.
+    pub fn parse(name: &str, args: &mut CommandArgs) -> Result<Self, CommandError> {
+        let command = match name {
+            "SAVE" => PersistenceCommand::Save,
+            "BGSAVE" => {
+                let schedule = match args.next() {
+                    Some(option) if option.eq_ignore_ascii_case(b"SCHEDULE") => true,
+                    Some(_) => return Err(CommandError::Syntax),
+                    None => false,
+                };
+                PersistenceCommand::BgSave { schedule }
+            }
+            "LASTSAVE" => PersistenceCommand::LastSave,
+            _ => unreachable!("not a persistence command: {}", name),
+        };
+
+        Ok(command)
+    }
+
+    pub fn execute(self, db: &mut Rdb) -> Result<Response, CommandError> {
+        let mut state = state();
+        let response = match self {
+            // Holding the database for the whole save blocks every other client, as SAVE does in
+            // Redis.
+            PersistenceCommand::Save => {
+                if state.bgsave_started_at.is_some() {
+                    return Err(bgsave_in_progress());
+                }
+                save(db, &mut state)
+                    .map_err(|e| CommandError::Other(format!("Error saving DB on disk: {}", e)))?;
+                Response::Ok
+            }
+            PersistenceCommand::BgSave { schedule } => {
+                if state.bgsave_started_at.is_none() {
+                    start_bgsave(db, &mut state);
+                    Response::Echo(RESPValue::SimpleString(
+                        "Background saving started".to_string(),
+                    ))
+                } else if schedule {
+                    state.bgsave_scheduled = true;
+                    Response::Echo(RESPValue::SimpleString(
+                        "Background saving scheduled".to_string(),
+                    ))
+                } else {
+                    return Err(bgsave_in_progress());
+                }
+            }
+            PersistenceCommand::LastSave => Response::Echo(RESPValue::Integer(state.last_save)),
+        };
+
+        Ok(response)
+    }
+}
+
+/// Records the keyspace loaded at startup as saved, since it's just what the file holds.
+pub fn mark_loaded() {
+    state().last_save = now_secs();
+}
+
+/// Checks the save rules every time the expiry cycle runs, starting a background save when one is
+/// met.
+pub async fn run_save_rules() {
+    let mut interval = tokio::time::interval(Duration::from_millis(1000 / expiry::HZ));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        interval.tick().await;
+        apply_save_rules();
+    }
+}
+
+fn apply_save_rules() {
+    let db = crate::db();
+    let mut state = state();
+    if state.bgsave_started_at.is_some() || state.shutting_down {
+        return;
+    }
+
+    let now = now_secs();
+    // Don't keep hammering a disk that just failed.
+    if !state.last_bgsave_ok && now - state.last_bgsave_try <= BGSAVE_RETRY_DELAY {
+        return;
+    }
+    let due = state
+        .save_rules
+        .iter()
+        .find(|rule| db.dirty() >= rule.changes && now - state.last_save > rule.seconds as i64)
+        .copied();
+    if let Some(rule) = due {
+        println!(
+            "{} changes in {} seconds. Saving...",
+            rule.changes, rule.seconds
+        );
+        start_bgsave(&db, &mut state);
+    }
+}
+
+/// Saves for the last time before the server exits, if there are any save rules, and returns
+/// whether it's safe to exit. Like Redis, the server stays up if the save fails rather than lose
+/// writes.
+pub fn save_on_shutdown() -> bool {
+    let mut db = crate::db();
+    let mut state = state();
+    if state.save_rules.is_empty() {
+        state.shutting_down = true;
+        return true;
+    }
+
+    println!("Saving the final RDB snapshot before exiting.");
+    match save(&mut db, &mut state) {
+        Ok(()) => {
+            println!("DB saved on disk");
+            state.shutting_down = true;
+            true
+        }
+        Err(e) => {
+            println!("Error trying to save the DB, can't exit: {}", e);
+            false
+        }
+    }
+}
+
+// Saves in the foreground, holding the database throughout.
+fn save(db: &mut Rdb, state: &mut State) -> io::Result<()> {
+    let temp = format!("temp-{}.rdb", std::process::id());
+    let temp = write_temp(
+        &temp,
+        db.metadata(),
+        db.live_entries(),
+        state.rdb_compression,
+    )?;
+    rename_into_place(&temp)?;
+
+    db.mark_saved(db.dirty());
+    state.last_save = now_secs();
+    state.last_bgsave_ok = true;
+    Ok(())
+}
+
+// Redis forks to get a snapshot that later writes can't change, so copying the keyspace is the
+// only part of a background save that holds up other clients here.
+fn start_bgsave(db: &Rdb, state: &mut State) {
+    let metadata = db.metadata().clone();
+    let entries: Vec<(Bytes, DBEntry)> = db
+        .live_entries()
+        .map(|(key, entry)| (key.clone(), entry.clone()))
+        .collect();
+    let dirty = db.dirty();
+    let compress = state.rdb_compression;
+    state.bgsave_started_at = Some(Instant::now());
+    state.last_bgsave_try = now_secs();
+
+    std::thread::spawn(move || {
+        let temp = format!("temp-bgsave-{}.rdb", std::process::id());
+        let written = write_temp(
+            &temp,
+            &metadata,
+            entries.iter().map(|(key, entry)| (key, entry)),
+            compress,
+        );
+
+        let mut db = crate::db();
+        let mut state = self::state();
+        let started_at = state.bgsave_started_at.take();
+        if state.shutting_down {
+            if let Ok(temp) = written {
+                let _ = fs::remove_file(temp);
+            }
+            return;
+        }
+
+        match written.and_then(|temp| rename_into_place(&temp)) {
+            Ok(()) => {
+                println!("Background saving terminated with success");
+                db.mark_saved(dirty);
+                state.last_save = now_secs();
+                state.last_bgsave_ok = true;
+            }
+            Err(e) => {
+                println!("Background saving error: {}", e);
+                state.last_bgsave_ok = false;
+            }
+        }
+        state.last_bgsave_duration = started_at.map(|started| started.elapsed());
+
+        if std::mem::take(&mut state.bgsave_scheduled) {
+            start_bgsave(&db, &mut state);
+        }
+    });
+}
+
+// Writes the file under a temporary name in the same directory as the real one, so it can be
+// renamed into place.
+fn write_temp<'a>(
+    name: &str,
+    metadata: &HashMap<String, String>,
+    entries: impl Iterator<Item = (&'a Bytes, &'a DBEntry)> + Clone,
+    compress: bool,
+) -> io::Result<PathBuf> {
+    let path = Path::new(&crate::args().directory).join(name);
+    let written = File::create(&path).and_then(|file| {
+        let mut out = BufWriter::new(file);
+        rdb_writer::write_rdb(&mut out, metadata, entries, compress)?;
+        out.into_inner().map_err(|e| e.into_error())?.sync_all()
+    });
+    if let Err(e) = written {
+        let _ = fs::remove_file(&path);
+        return Err(e);
+    }
+    Ok(path)
+}
+
+fn rename_into_place(temp: &Path) -> io::Result<()> {
+    let config = crate::args();
+    let dir = Path::new(&config.directory);
+    if let Err(e) = fs::rename(temp, dir.join(&config.dbfilename)) {
+        let _ = fs::remove_file(temp);
+        return Err(e);
+    }
+    // The rename only survives a crash once the directory itself is synced.
+    if let Ok(dir) = File::open(dir) {
+        let _ = dir.sync_all();
+    }
+    Ok(())
+}
+
+fn bgsave_in_progress() -> CommandError {
+    CommandError::Other("Background save already in progress".to_string())
+}
+
+fn now_secs() -> i64 {
+    unix_millis(SystemTime::now()) / 1000
+}
+
+fn state() -> MutexGuard<'static, State> {
+    STATE.lock().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_save_rules() {
+        assert_eq!(
+            parse_save_rules(DEFAULT_SAVE_RULES).unwrap(),
+            [(3600, 1), (300, 100), (60, 10000)]
+                .map(|(seconds, changes)| SaveRule { seconds, changes })
+        );
+        assert_eq!(parse_save_rules(""), Some(vec![]));
+        assert_eq!(parse_save_rules("60"), None);
+        assert_eq!(parse_save_rules("0 1"), None);
+        assert_eq!(parse_save_rules("60 -1"), None);
+    }
+}
@@ -1,8 +1,28 @@
 #![allow(dead_code)]
 
-use std::{fmt::Display, time::SystemTime};
+use std::time::SystemTime;
 
 use anyhow::{bail, Result};
+use bytes::{Bytes, BytesMut};
+
+use crate::{
+    bitmap::BitmapCommand,
+    client::Client,
+    error::CommandError,
+    geo::GeoCommand,
+    glob::glob_match,
+    hash::HashCommand,
+    hyperloglog::HyperLogLogCommand,
+    keyspace::KeyspaceCommand,
+    list::ListCommand,
+    persistence::PersistenceCommand,
+    rdb::unix_millis,
+    scan::{scan_reply, ScanOptions},
+    set::SetCommand,
+    stream::{self, StreamCommand},
+    string::StringCommand,
+    zset::SortedSetCommand,
+};
 
 const SEPARATOR: &str = "\r\n";
 const SIMPLE_STRING_PREFIX: char = '+';
@@ -27,6 +47,31 @@ pub enum SetCondition {
     Always,
 }
 
+/// The NX, XX, GT and LT options of EXPIRE and friends, comparing the new expiry with the current
+/// one. XX can be combined with GT or LT, and then both have to hold.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ExpireCondition {
+    /// Only if the key has no expiry.
+    pub nx: bool,
+    /// Only if the key already has an expiry.
+    pub xx: bool,
+    /// Only if the new expiry is later than the current one. No expiry counts as infinitely late.
+    pub gt: bool,
+    /// Only if the new expiry is earlier than the current one.
+    pub lt: bool,
+}
+
+impl ExpireCondition {
+    /// Whether the condition lets the expiry change from `current` to `at`, both in milliseconds
+    /// since the Unix epoch.
+    pub fn allows(self, current: Option<i64>, at: i64) -> bool {
+        match current {
+            None => !(self.xx || self.gt),
+            Some(current) => !self.nx && (!self.gt || at > current) && (!self.lt || at < current),
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct SetOpts {
     expires_at: Option<SystemTime>,
@@ -35,6 +80,17 @@ pub struct SetOpts {
     get: bool,
 }
 
+impl Default for SetOpts {
+    fn default() -> Self {
+        SetOpts {
+            expires_at: None,
+            condition: SetCondition::Always,
+            keep_ttl: false,
+            get: false,
+        }
+    }
+}
+
 impl SetOpts {
     pub fn expires_at(&self) -> Option<SystemTime> {
         self.expires_at
@@ -55,91 +111,274 @@ impl SetOpts {
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Command {
-    Ping,
+    Ping(Option<Bytes>),
     Echo(RESPValue),
     #[allow(clippy::enum_variant_names)]
     Command,
     Set {
-        key: String,
-        value: RESPValue,
+        key: Bytes,
+        value: Bytes,
         opts: SetOpts,
     },
-    Get(String),
-    Keys(String),
+    Get(Bytes),
+    Keys(Bytes),
+    Scan {
+        cursor: u64,
+        options: ScanOptions,
+        type_name: Option<String>,
+    },
     ConfigGet(String),
+    /// Parameter names, lower-cased, with the values to set them to.
+    ConfigSet(Vec<(String, Bytes)>),
+    Hello {
+        protover: Option<i64>,
+        auth: Option<(Bytes, Bytes)>,
+        setname: Option<Bytes>,
+    },
+    Del(Vec<Bytes>),
+    Unlink(Vec<Bytes>),
+    Exists(Vec<Bytes>),
+    Expire {
+        key: Bytes,
+        /// Milliseconds since the Unix epoch, which may be in the past.
+        at: i64,
+        condition: ExpireCondition,
+    },
+    Ttl {
+        key: Bytes,
+        milliseconds: bool,
+    },
+    ExpireTime {
+        key: Bytes,
+        milliseconds: bool,
+    },
+    Persist(Bytes),
+    Info(Vec<String>),
+    Keyspace(KeyspaceCommand),
+    List(ListCommand),
+    Hash(HashCommand),
+    /// The set type's commands, not to be confused with SET.
+    SetType(SetCommand),
+    SortedSet(SortedSetCommand),
+    Stream(StreamCommand),
+    String(StringCommand),
+    Bitmap(BitmapCommand),
+    HyperLogLog(HyperLogLogCommand),
+    Geo(GeoCommand),
+    Persistence(PersistenceCommand),
 }
 
 impl Command {
-    pub fn as_response(&self) -> Response {
+    /// Runs the command, first waiting for there to be something to reply with if it's one that
+    /// blocks. Everything else runs straight away, as with [`Command::execute`].
+    pub async fn run(self, client: &mut Client) -> Result<Response, CommandError> {
         match self {
-            Command::Ping => Response::Pong,
-            Command::Echo(s) => Response::Echo(s.clone()),
-            Command::Command => Response::Ok,
-            Command::Set {
-                key,
-                value: _,
-                opts,
-            } => {
-                if opts.get {
-                    let res = super::db_get(key.clone());
-                    match res {
-                        Some(value) => Response::Echo(value),
-                        None => Response::Null,
-                    }
-                } else {
-                    Response::Ok
-                }
+            Command::Stream(StreamCommand::Read(read)) if read.blocks() => {
+                stream::read_blocking(read, client.protocol()).await
             }
+            command => command.execute(client),
+        }
+    }
+
+    /// Runs the command and builds its reply. Anything the command reads or writes in the
+    /// keyspace happens under a single lock acquisition, so the reply always reflects exactly
+    /// what the command did.
+    pub fn execute(self, client: &mut Client) -> Result<Response, CommandError> {
+        if cfg!(debug_assertions) {
+            println!("Executing: {:?}", self);
+        }
+
+        let response = match self {
+            Command::Ping(None) => Response::Pong,
+            Command::Ping(Some(message)) => Response::Echo(RESPValue::BulkString(message)),
+            Command::Echo(s) => Response::Echo(s),
+            Command::Command => Response::Ok,
+            Command::Set { key, value, opts } => super::db_set(key, value, &opts)?,
             Command::Get(key) => {
-                let res = super::db_get(key.clone());
+                let res = super::db_get(key)?;
                 match res {
-                    Some(value) => Response::Echo(value),
+                    Some(value) => Response::Echo(RESPValue::BulkString(value)),
                     None => Response::Null,
                 }
             }
             Command::ConfigGet(key) => {
-                let res = super::config_get(key.clone());
-                match res {
-                    Some(value) => Response::Echo(RESPValue::Array(vec![
-                        RESPValue::BulkString(key.clone()),
-                        RESPValue::BulkString(value),
-                    ])),
-                    None => Response::Null,
+                let res = super::config_get(&key);
+                let pairs = match res {
+                    Some(value) => vec![(
+                        RESPValue::BulkString(Bytes::from(key)),
+                        RESPValue::BulkString(Bytes::from(value)),
+                    )],
+                    None => vec![],
+                };
+                Response::Echo(RESPValue::Map(pairs))
+            }
+            Command::ConfigSet(params) => {
+                super::config_set(&params)?;
+                Response::Ok
+            }
+            Command::Hello {
+                protover,
+                auth,
+                setname,
+            } => {
+                let protocol = match protover {
+                    None => client.protocol(),
+                    Some(2) => Protocol::Resp2,
+                    Some(3) => Protocol::Resp3,
+                    Some(_) => return Err(CommandError::NoProto),
+                };
+
+                // There is no ACL support, so the only user is the passwordless default one.
+                if let Some((username, _)) = auth {
+                    if username.as_ref() != b"default" {
+                        return Err(CommandError::WrongPass);
+                    }
                 }
+
+                if let Some(name) = setname {
+                    if !name.iter().all(|c| (b'!'..=b'~').contains(c)) {
+                        return Err(CommandError::Other(
+                            "Client names cannot contain spaces, newlines or special characters."
+                                .to_string(),
+                        ));
+                    }
+                    client.set_name((!name.is_empty()).then_some(name));
+                }
+                client.set_protocol(protocol);
+
+                let field = |name: &str| RESPValue::BulkString(Bytes::from(name.to_string()));
+                Response::Echo(RESPValue::Map(vec![
+                    (field("server"), field("redis")),
+                    (field("version"), field(super::REDIS_VERSION)),
+                    (field("proto"), RESPValue::Integer(protocol.version())),
+                    (field("id"), RESPValue::Integer(client.id() as i64)),
+                    (field("mode"), field("standalone")),
+                    (field("role"), field("master")),
+                    (field("modules"), RESPValue::Array(vec![])),
+                ]))
             }
             Command::Keys(pattern) => {
+                let keys = super::db()
+                    .live_keys()
+                    .filter(|key| glob_match(&pattern, key))
+                    .map(|key| RESPValue::BulkString(key.clone()))
+                    .collect();
+
+                Response::Echo(RESPValue::Array(keys))
+            }
+            Command::Scan {
+                cursor,
+                options,
+                type_name,
+            } => {
+                let mut db = super::db();
+                let (cursor, visited) = db.scan(cursor, options.count());
+
                 let mut keys = Vec::new();
-                let mut guard = super::DB.get().unwrap().lock().unwrap();
-                for key in guard.data_mut().keys() {
-                    if key.contains(pattern) || pattern == "*" {
-                        keys.push(RESPValue::BulkString(key.clone()));
+                for key in visited {
+                    // Looking the key up deletes it if it turns out to have expired.
+                    let Some(entry) = db.get(&key) else {
+                        continue;
+                    };
+                    if type_name
+                        .as_ref()
+                        .is_some_and(|type_name| !type_name.eq_ignore_ascii_case(entry.type_name()))
+                    {
+                        continue;
                     }
+                    if !options.matches(&key) {
+                        continue;
+                    }
+                    keys.push(RESPValue::BulkString(key));
                 }
 
-                Response::Echo(RESPValue::Array(keys))
+                scan_reply(cursor, keys)
             }
-        }
-    }
+            // Values are freed as soon as they're unlinked either way, so UNLINK is just DEL.
+            Command::Del(keys) | Command::Unlink(keys) => {
+                let mut db = super::db();
+                let removed = keys.iter().filter(|key| db.remove(key).is_some()).count();
+                Response::Echo(RESPValue::Integer(removed as i64))
+            }
+            Command::Exists(keys) => {
+                let mut db = super::db();
+                let found = keys.iter().filter(|key| db.contains_key(key)).count();
+                Response::Echo(RESPValue::Integer(found as i64))
+            }
+            Command::Expire { key, at, condition } => {
+                let mut db = super::db();
+                let Some(entry) = db.get(&key) else {
+                    return Ok(Response::Echo(RESPValue::Integer(0)));
+                };
 
-    pub fn execute(&self) {
-        match self {
-            Command::Ping => println!("PONG"),
-            Command::Echo(s) => println!("{}", s),
-            Command::Command => println!("COMMAND"),
-            Command::Set { key, value, opts } => {
-                println!("SET {} {:?}", key, value);
-                super::db_set(key.clone(), value.clone(), opts);
+                let current = entry.expires_at().map(unix_millis);
+                if !condition.allows(current, at) {
+                    Response::Echo(RESPValue::Integer(0))
+                } else {
+                    // An expiry that has already passed deletes the key straight away.
+                    if at <= unix_millis(SystemTime::now()) {
+                        db.remove(&key);
+                    } else {
+                        let expires_at =
+                            SystemTime::UNIX_EPOCH + std::time::Duration::from_millis(at as u64);
+                        db.set_expiry(&key, Some(expires_at));
+                    }
+                    Response::Echo(RESPValue::Integer(1))
+                }
             }
-            Command::Get(key) => {
-                println!("GET {}", key);
+            Command::Ttl { key, milliseconds } => {
+                let ttl = match super::db().get(&key) {
+                    None => -2,
+                    Some(entry) => match entry.ttl_millis() {
+                        None => -1,
+                        Some(ttl) if milliseconds => ttl,
+                        Some(ttl) => (ttl + 500) / 1000,
+                    },
+                };
+                Response::Echo(RESPValue::Integer(ttl))
             }
-            Command::ConfigGet(key) => {
-                println!("CONFIG GET {}", key);
+            Command::ExpireTime { key, milliseconds } => {
+                let time = match super::db().get(&key) {
+                    None => -2,
+                    Some(entry) => match entry.expires_at().map(unix_millis) {
+                        None => -1,
+                        Some(at) if milliseconds => at,
+                        Some(at) => (at + 500) / 1000,
+                    },
+                };
+                Response::Echo(RESPValue::Integer(time))
             }
-            Command::Keys(pattern) => {
-                println!("KEYS {}", pattern);
+            Command::Persist(key) => {
+                let mut db = super::db();
+                let had_expiry = db
+                    .get(&key)
+                    .is_some_and(|entry| entry.expires_at().is_some());
+                if had_expiry {
+                    db.set_expiry(&key, None);
+                }
+                Response::Echo(RESPValue::Integer(had_expiry as i64))
             }
-        }
+            Command::Info(sections) => {
+                let text = crate::info::render(&sections, &super::db());
+                Response::Echo(RESPValue::VerbatimString {
+                    format: *b"txt",
+                    text: Bytes::from(text),
+                })
+            }
+            Command::Keyspace(command) => command.execute(&mut super::db())?,
+            Command::List(command) => command.execute(&mut super::db())?,
+            Command::Hash(command) => command.execute(&mut super::db(), client.protocol())?,
+            Command::SetType(command) => command.execute(&mut super::db())?,
+            Command::SortedSet(command) => command.execute(&mut super::db(), client.protocol())?,
+            Command::Stream(command) => command.execute(&mut super::db(), client.protocol())?,
+            Command::String(command) => command.execute(&mut super::db())?,
+            Command::Bitmap(command) => command.execute(&mut super::db())?,
+            Command::HyperLogLog(command) => command.execute(&mut super::db())?,
+            Command::Geo(command) => command.execute(&mut super::db())?,
+            Command::Persistence(command) => command.execute(&mut super::db())?,
+        };
+
+        Ok(response)
     }
 }
 
@@ -151,13 +390,30 @@ pub enum Response {
     Null,
 }
 
-impl Display for Response {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl Response {
+    pub fn encode(&self, protocol: Protocol, out: &mut BytesMut) {
+        match self {
+            Response::Ok => out.extend_from_slice(b"+OK\r\n"),
+            Response::Pong => out.extend_from_slice(b"+PONG\r\n"),
+            Response::Echo(s) => s.encode(protocol, out),
+            Response::Null => RESPValue::Null.encode(protocol, out),
+        }
+    }
+}
+
+/// The wire protocol version negotiated by a connection via `HELLO`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Protocol {
+    #[default]
+    Resp2,
+    Resp3,
+}
+
+impl Protocol {
+    pub fn version(&self) -> i64 {
         match self {
-            Response::Ok => write!(f, "+OK\r\n"),
-            Response::Pong => write!(f, "+PONG\r\n"),
-            Response::Echo(s) => write!(f, "{}", s),
-            Response::Null => write!(f, "$-1\r\n"),
+            Protocol::Resp2 => 2,
+            Protocol::Resp3 => 3,
         }
     }
 }
@@ -167,203 +423,872 @@ pub enum RESPValue {
     SimpleString(String),
     Error(String),
     Integer(i64),
-    BulkString(String),
+    BulkString(Bytes),
     Array(Vec<RESPValue>),
+    Null,
+    /// A null where an array is expected, which RESP2 writes differently from a null bulk string.
+    NullArray,
+    Boolean(bool),
+    Double(f64),
+    BigNumber(String),
+    BulkError(String),
+    VerbatimString {
+        format: [u8; 3],
+        text: Bytes,
+    },
+    Map(Vec<(RESPValue, RESPValue)>),
+    Set(Vec<RESPValue>),
+    Push(Vec<RESPValue>),
 }
 
-impl Display for RESPValue {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl RESPValue {
+    /// Serializes this value onto the end of `out` in wire format.
+    ///
+    /// RESP3-only types are downgraded to their closest RESP2 equivalent when `protocol` is RESP2,
+    /// the same way Redis does: maps become flat arrays of alternating keys and values, sets and
+    /// pushes become arrays, booleans become integers and doubles become bulk strings.
+    pub fn encode(&self, protocol: Protocol, out: &mut BytesMut) {
+        let resp3 = protocol == Protocol::Resp3;
+
         match self {
-            RESPValue::SimpleString(s) => write!(f, "+{}\r\n", s),
-            RESPValue::Error(s) => write!(f, "-{}\r\n", s),
-            RESPValue::Integer(i) => write!(f, ":{}\r\n", i),
-            RESPValue::BulkString(s) => write!(f, "${}\r\n{}\r\n", s.len(), s),
-            RESPValue::Array(values) => {
-                write!(f, "*{}\r\n", values.len())?;
-                for value in values {
-                    write!(f, "{}", value)?;
+            RESPValue::SimpleString(s) => write_line(out, SIMPLE_STRING_PREFIX, s.as_bytes()),
+            RESPValue::Error(s) => write_line(out, SIMPLE_ERROR_PREFIX, s.as_bytes()),
+            RESPValue::Integer(i) => write_line(out, INTEGER_PREFIX, i.to_string().as_bytes()),
+            RESPValue::BulkString(s) => write_blob(out, BULK_STRING_PREFIX, s),
+            RESPValue::Array(values) => write_aggregate(out, ARRAY_PREFIX, values, protocol),
+            RESPValue::Null | RESPValue::NullArray if resp3 => write_line(out, NULL_PREFIX, b""),
+            RESPValue::Null => out.extend_from_slice(b"$-1\r\n"),
+            RESPValue::NullArray => out.extend_from_slice(b"*-1\r\n"),
+            RESPValue::Boolean(b) if resp3 => {
+                write_line(out, BOOLEAN_PREFIX, if *b { b"t" } else { b"f" })
+            }
+            RESPValue::Boolean(b) => write_line(out, INTEGER_PREFIX, if *b { b"1" } else { b"0" }),
+            RESPValue::Double(d) if resp3 => {
+                write_line(out, DOUBLE_PREFIX, format_double(*d).as_bytes())
+            }
+            RESPValue::Double(d) => {
+                write_blob(out, BULK_STRING_PREFIX, format_double(*d).as_bytes())
+            }
+            RESPValue::BigNumber(n) if resp3 => write_line(out, BIG_NUMBER_PREFIX, n.as_bytes()),
+            RESPValue::BigNumber(n) => write_blob(out, BULK_STRING_PREFIX, n.as_bytes()),
+            RESPValue::BulkError(s) if resp3 => write_blob(out, BULK_ERROR_PREFIX, s.as_bytes()),
+            // Simple errors can't span lines, so flatten any line breaks.
+            RESPValue::BulkError(s) => write_line(
+                out,
+                SIMPLE_ERROR_PREFIX,
+                s.replace(['\r', '\n'], " ").as_bytes(),
+            ),
+            RESPValue::VerbatimString { format, text } if resp3 => {
+                let mut blob = Vec::with_capacity(format.len() + 1 + text.len());
+                blob.extend_from_slice(format);
+                blob.push(b':');
+                blob.extend_from_slice(text);
+                write_blob(out, VERBATIM_STRING_PREFIX, &blob);
+            }
+            RESPValue::VerbatimString { text, .. } => write_blob(out, BULK_STRING_PREFIX, text),
+            RESPValue::Map(pairs) => {
+                if resp3 {
+                    write_line(out, MAP_PREFIX, pairs.len().to_string().as_bytes());
+                } else {
+                    write_line(out, ARRAY_PREFIX, (pairs.len() * 2).to_string().as_bytes());
                 }
-                Ok(())
+                for (key, value) in pairs {
+                    key.encode(protocol, out);
+                    value.encode(protocol, out);
+                }
+            }
+            RESPValue::Set(values) if resp3 => write_aggregate(out, SET_PREFIX, values, protocol),
+            RESPValue::Push(values) if resp3 => write_aggregate(out, PUSH_PREFIX, values, protocol),
+            RESPValue::Set(values) | RESPValue::Push(values) => {
+                write_aggregate(out, ARRAY_PREFIX, values, protocol)
             }
         }
     }
-}
 
-impl RESPValue {
-    pub fn into_command(self) -> Command {
-        match self {
-            RESPValue::SimpleString(command) => match command.as_str() {
-                "PING" => Command::Ping,
-                "COMMAND" => Command::Command,
-                _ => unimplemented!(),
-            },
-            RESPValue::Array(values) => {
-                let mut iter = values.into_iter().peekable();
-                let first = iter.next().unwrap();
-
-                match first {
-                    RESPValue::BulkString(command) => match command.to_ascii_uppercase().as_str() {
-                        "ECHO" => Command::Echo(iter.next().unwrap()),
-                        "PING" => Command::Ping,
-                        "COMMAND" => Command::Command,
-                        "SET" => {
-                            let key = match iter.next().unwrap() {
-                                RESPValue::BulkString(s) => s,
-                                _ => unimplemented!(),
-                            };
-                            let value = iter.next().unwrap();
-                            let mut opts = SetOpts {
-                                expires_at: None,
-                                condition: SetCondition::Always,
-                                keep_ttl: false,
-                                get: false,
-                            };
-
-                            // EX seconds -- Set the specified expire time, in seconds (a positive integer).
-                            // PX milliseconds -- Set the specified expire time, in milliseconds (a positive integer).
-                            // EXAT timestamp-seconds -- Set the specified Unix time at which the key will expire, in seconds (a positive integer).
-                            // PXAT timestamp-milliseconds -- Set the specified Unix time at which the key will expire, in milliseconds (a positive integer).
-
-                            // NX -- Only set the key if it does not already exist.
-                            // XX -- Only set the key if it already exists.
-
-                            // KEEPTTL -- Retain the time to live associated with the key.
-                            // GET -- Return the old string stored at key, or nil if key did not exist. An error is returned and SET aborted if the value stored at key is not a string.
-
-                            while let Some(val) = iter.next() {
-                                match val {
-                                    RESPValue::BulkString(s) => {
-                                        match s.to_ascii_uppercase().as_str() {
-                                            "EX" => {
-                                                let seconds = match iter.next().unwrap() {
-                                                    RESPValue::BulkString(s) => s.parse().unwrap(),
-                                                    _ => unimplemented!(),
-                                                };
-                                                opts.expires_at = Some(
-                                                    SystemTime::now()
-                                                        + std::time::Duration::from_secs(seconds),
-                                                );
-                                            }
-                                            "PX" => {
-                                                let milliseconds = match iter.next().unwrap() {
-                                                    RESPValue::BulkString(s) => s.parse().unwrap(),
-                                                    _ => unimplemented!(),
-                                                };
-                                                opts.expires_at = Some(
-                                                    SystemTime::now()
-                                                        + std::time::Duration::from_millis(
-                                                            milliseconds,
-                                                        ),
-                                                );
-                                            }
-                                            "NX" => {
-                                                opts.condition = SetCondition::IfNotExists;
-                                            }
-                                            "XX" => {
-                                                opts.condition = SetCondition::IfExists;
-                                            }
-                                            "KEEPTTL" => {
-                                                opts.keep_ttl = true;
-                                            }
-                                            "GET" => {
-                                                opts.get = true;
-                                            }
-                                            _ => unimplemented!(),
-                                        }
-                                    }
-                                    _ => unimplemented!(),
+    pub fn to_bytes(&self, protocol: Protocol) -> Bytes {
+        let mut out = BytesMut::new();
+        self.encode(protocol, &mut out);
+        out.freeze()
+    }
+
+    pub fn into_command(self) -> Result<Command, CommandError> {
+        let parts = match self {
+            RESPValue::SimpleString(command) => vec![Bytes::from(command)],
+            RESPValue::Array(values) => values
+                .into_iter()
+                .map(|value| match value {
+                    RESPValue::BulkString(s) => Ok(s),
+                    other => Err(CommandError::Protocol(format!(
+                        "expected bulk string, got {:?}",
+                        other
+                    ))),
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+            other => {
+                return Err(CommandError::Protocol(format!(
+                    "expected array, got {:?}",
+                    other
+                )))
+            }
+        };
+
+        let mut parts = parts.into_iter();
+        let Some(name) = parts.next() else {
+            return Err(CommandError::Protocol("empty command".to_string()));
+        };
+        let mut args = CommandArgs::new(&name, parts);
+
+        let upper = args.name.to_ascii_uppercase();
+        let command =
+            match upper.as_str() {
+                "ECHO" => Command::Echo(RESPValue::BulkString(args.required()?)),
+                "PING" => Command::Ping(args.next()),
+                "COMMAND" => {
+                    // Only used by clients to discover commands, which they cope without.
+                    args.skip_rest();
+                    Command::Command
+                }
+                "SET" => {
+                    let key = args.required()?;
+                    let value = args.required()?;
+                    let mut opts = SetOpts::default();
+
+                    // EX seconds -- Set the specified expire time, in seconds (a positive integer).
+                    // PX milliseconds -- Set the specified expire time, in milliseconds (a positive integer).
+                    // EXAT timestamp-seconds -- Set the specified Unix time at which the key will expire, in seconds (a positive integer).
+                    // PXAT timestamp-milliseconds -- Set the specified Unix time at which the key will expire, in milliseconds (a positive integer).
+
+                    // NX -- Only set the key if it does not already exist.
+                    // XX -- Only set the key if it already exists.
+
+                    // KEEPTTL -- Retain the time to live associated with the key.
+                    // GET -- Return the old string stored at key, or nil if key did not exist. An error is returned and SET aborted if the value stored at key is not a string.
+
+                    while let Some(option) = args.next() {
+                        let option = String::from_utf8_lossy(&option).to_ascii_uppercase();
+                        match option.as_str() {
+                            "EX" | "PX" | "EXAT" | "PXAT" => {
+                                // Only one way of setting the expiry can be given, and none alongside
+                                // a request to keep the existing one.
+                                if opts.expires_at.is_some() || opts.keep_ttl {
+                                    return Err(CommandError::Syntax);
                                 }
+
+                                let format = match option.as_str() {
+                                    "EX" => ExpiryFormat::Seconds,
+                                    "PX" => ExpiryFormat::Milliseconds,
+                                    "EXAT" => ExpiryFormat::UnixSeconds,
+                                    _ => ExpiryFormat::UnixMilliseconds,
+                                };
+                                opts.expires_at =
+                                    Some(parse_expiry(&args.option_value()?, format, "set")?);
+                            }
+                            "NX" if opts.condition != SetCondition::IfExists => {
+                                opts.condition = SetCondition::IfNotExists;
                             }
+                            "XX" if opts.condition != SetCondition::IfNotExists => {
+                                opts.condition = SetCondition::IfExists;
+                            }
+                            "KEEPTTL" if opts.expires_at.is_none() => {
+                                opts.keep_ttl = true;
+                            }
+                            "GET" => {
+                                opts.get = true;
+                            }
+                            _ => return Err(CommandError::Syntax),
+                        }
+                    }
+
+                    Command::Set { key, value, opts }
+                }
+                // These are all SET with particular options.
+                "SETEX" | "PSETEX" => {
+                    let key = args.required()?;
+                    let format = if upper == "SETEX" {
+                        ExpiryFormat::Seconds
+                    } else {
+                        ExpiryFormat::Milliseconds
+                    };
+                    let expires_at =
+                        parse_expiry(&args.required()?, format, &upper.to_ascii_lowercase())?;
+                    let opts = SetOpts {
+                        expires_at: Some(expires_at),
+                        ..SetOpts::default()
+                    };
+                    Command::Set {
+                        key,
+                        value: args.required()?,
+                        opts,
+                    }
+                }
+                "GETSET" => Command::Set {
+                    key: args.required()?,
+                    value: args.required()?,
+                    opts: SetOpts {
+                        get: true,
+                        ..SetOpts::default()
+                    },
+                },
+                "GET" => Command::Get(args.required()?),
+                "DEL" => Command::Del(args.at_least_one()?),
+                "UNLINK" => Command::Unlink(args.at_least_one()?),
+                "EXISTS" => Command::Exists(args.at_least_one()?),
+                "EXPIRE" => parse_expire(&mut args, ExpiryFormat::Seconds)?,
+                "PEXPIRE" => parse_expire(&mut args, ExpiryFormat::Milliseconds)?,
+                "EXPIREAT" => parse_expire(&mut args, ExpiryFormat::UnixSeconds)?,
+                "PEXPIREAT" => parse_expire(&mut args, ExpiryFormat::UnixMilliseconds)?,
+                "TTL" => Command::Ttl {
+                    key: args.required()?,
+                    milliseconds: false,
+                },
+                "PTTL" => Command::Ttl {
+                    key: args.required()?,
+                    milliseconds: true,
+                },
+                "EXPIRETIME" => Command::ExpireTime {
+                    key: args.required()?,
+                    milliseconds: false,
+                },
+                "PEXPIRETIME" => Command::ExpireTime {
+                    key: args.required()?,
+                    milliseconds: true,
+                },
+                "PERSIST" => Command::Persist(args.required()?),
+                "INFO" => {
+                    let mut sections = Vec::new();
+                    while let Some(section) = args.next() {
+                        sections.push(String::from_utf8_lossy(&section).to_ascii_lowercase());
+                    }
+                    Command::Info(sections)
+                }
+                "TYPE" | "RENAME" | "RENAMENX" | "COPY" | "RANDOMKEY" | "DBSIZE" | "TOUCH"
+                | "OBJECT" => Command::Keyspace(KeyspaceCommand::parse(&upper, &mut args)?),
+                "LPUSH" | "RPUSH" | "LPUSHX" | "RPUSHX" | "LPOP" | "RPOP" | "LLEN" | "LRANGE"
+                | "LINDEX" | "LSET" | "LINSERT" | "LREM" | "LTRIM" | "LPOS" | "LMOVE"
+                | "RPOPLPUSH" => Command::List(ListCommand::parse(&upper, &mut args)?),
+                "HSET" | "HMSET" | "HSETNX" | "HGET" | "HMGET" | "HDEL" | "HEXISTS" | "HLEN"
+                | "HKEYS" | "HVALS" | "HGETALL" | "HINCRBY" | "HINCRBYFLOAT" | "HSTRLEN"
+                | "HRANDFIELD" | "HSCAN" | "HEXPIRE" | "HPEXPIRE" | "HEXPIREAT" | "HPEXPIREAT"
+                | "HTTL" | "HPTTL" | "HEXPIRETIME" | "HPEXPIRETIME" | "HPERSIST" => {
+                    Command::Hash(HashCommand::parse(&upper, &mut args)?)
+                }
+                "SADD" | "SREM" | "SISMEMBER" | "SMISMEMBER" | "SMEMBERS" | "SCARD" | "SPOP"
+                | "SRANDMEMBER" | "SMOVE" | "SINTER" | "SUNION" | "SDIFF" | "SINTERSTORE"
+                | "SUNIONSTORE" | "SDIFFSTORE" | "SINTERCARD" | "SSCAN" => {
+                    Command::SetType(SetCommand::parse(&upper, &mut args)?)
+                }
+                "ZADD" | "ZREM" | "ZSCORE" | "ZMSCORE" | "ZINCRBY" | "ZCARD" | "ZCOUNT"
+                | "ZRANK" | "ZREVRANK" | "ZRANGE" | "ZRANGESTORE" | "ZPOPMIN" | "ZPOPMAX"
+                | "ZUNIONSTORE" | "ZINTERSTORE" | "ZDIFFSTORE" | "ZSCAN" => {
+                    Command::SortedSet(SortedSetCommand::parse(&upper, &mut args)?)
+                }
+                "INCR" | "DECR" | "INCRBY" | "DECRBY" | "INCRBYFLOAT" | "APPEND" | "STRLEN"
+                | "GETRANGE" | "SUBSTR" | "SETRANGE" | "GETDEL" | "GETEX" | "SETNX" | "MGET"
+                | "MSET" | "MSETNX" | "LCS" => {
+                    Command::String(StringCommand::parse(&upper, &mut args)?)
+                }
+                "SETBIT" | "GETBIT" | "BITCOUNT" | "BITPOS" | "BITOP" | "BITFIELD"
+                | "BITFIELD_RO" => Command::Bitmap(BitmapCommand::parse(&upper, &mut args)?),
+                "PFADD" | "PFCOUNT" | "PFMERGE" | "PFDEBUG" => {
+                    Command::HyperLogLog(HyperLogLogCommand::parse(&upper, &mut args)?)
+                }
+                "GEOADD" | "GEOPOS" | "GEODIST" | "GEOHASH" | "GEOSEARCH" | "GEOSEARCHSTORE" => {
+                    Command::Geo(GeoCommand::parse(&upper, &mut args)?)
+                }
+                "XADD" | "XLEN" | "XRANGE" | "XREVRANGE" | "XDEL" | "XTRIM" | "XREAD"
+                | "XREADGROUP" | "XGROUP" | "XACK" | "XPENDING" | "XCLAIM" | "XAUTOCLAIM"
+                | "XINFO" => Command::Stream(StreamCommand::parse(&upper, &mut args)?),
+                "SAVE" | "BGSAVE" | "LASTSAVE" => {
+                    Command::Persistence(PersistenceCommand::parse(&upper, &mut args)?)
+                }
+                "KEYS" => Command::Keys(args.required()?),
+                "SCAN" => {
+                    let cursor = parse_cursor(&args.required()?)?;
+                    let mut options = ScanOptions::default();
+                    let mut type_name = None;
 
-                            Command::Set { key, value, opts }
+                    while let Some(option) = args.next() {
+                        match String::from_utf8_lossy(&option)
+                            .to_ascii_uppercase()
+                            .as_str()
+                        {
+                            "TYPE" => {
+                                let value = args.option_value()?;
+                                type_name = Some(String::from_utf8_lossy(&value).into_owned());
+                            }
+                            option if options.parse_option(option, &mut args)? => {}
+                            _ => return Err(CommandError::Syntax),
                         }
-                        "GET" => {
-                            let key = match iter.next().unwrap() {
-                                RESPValue::BulkString(s) => s,
-                                _ => unimplemented!(),
-                            };
+                    }
+
+                    Command::Scan {
+                        cursor,
+                        options,
+                        type_name,
+                    }
+                }
+                "HELLO" => {
+                    let mut protover = None;
+                    let mut auth = None;
+                    let mut setname = None;
+
+                    if let Some(version) = args.next() {
+                        protover = Some(parse_integer(&version).map_err(|_| {
+                            CommandError::Other(
+                                "Protocol version is not an integer or out of range".to_string(),
+                            )
+                        })?);
 
-                            Command::Get(key)
+                        while let Some(option) = args.next() {
+                            match String::from_utf8_lossy(&option)
+                                .to_ascii_uppercase()
+                                .as_str()
+                            {
+                                "AUTH" => {
+                                    let username = args.option_value()?;
+                                    let password = args.option_value()?;
+                                    auth = Some((username, password));
+                                }
+                                "SETNAME" => {
+                                    setname = Some(args.option_value()?);
+                                }
+                                _ => return Err(CommandError::Syntax),
+                            }
                         }
-                        "KEYS" => {
-                            let pattern = match iter.next().unwrap() {
-                                RESPValue::BulkString(s) => s,
-                                _ => unimplemented!(),
-                            };
+                    }
+
+                    Command::Hello {
+                        protover,
+                        auth,
+                        setname,
+                    }
+                }
+                "CONFIG" => {
+                    let subcommand = args.subcommand()?;
 
-                            Command::Keys(pattern)
+                    match subcommand.as_str() {
+                        "GET" => {
+                            let key = args.required()?;
+                            Command::ConfigGet(String::from_utf8_lossy(&key).into_owned())
                         }
-                        "CONFIG" => {
-                            let subcommand = match iter.next().unwrap() {
-                                RESPValue::BulkString(s) => s,
-                                _ => unimplemented!(),
-                            };
-
-                            match subcommand.to_ascii_uppercase().as_str() {
-                                "GET" => {
-                                    let key = match iter.next().unwrap() {
-                                        RESPValue::BulkString(s) => s,
-                                        _ => unimplemented!(),
-                                    };
-
-                                    Command::ConfigGet(key)
-                                }
-                                _ => unimplemented!(),
+                        "SET" => {
+                            let params = args.rest();
+                            let pairs = params.chunks_exact(2);
+                            if params.is_empty() || !pairs.remainder().is_empty() {
+                                return Err(args.arity_error());
                             }
+                            let params = pairs
+                                .map(|pair| {
+                                    let name = String::from_utf8_lossy(&pair[0]);
+                                    (name.to_ascii_lowercase(), pair[1].clone())
+                                })
+                                .collect();
+                            Command::ConfigSet(params)
                         }
-                        _ => unimplemented!(),
-                    },
-                    _ => unimplemented!(),
+                        _ => {
+                            return Err(CommandError::UnknownSubcommand {
+                                command: "CONFIG".to_string(),
+                                subcommand,
+                            })
+                        }
+                    }
                 }
+                _ => return Err(CommandError::unknown_command(&name, args.args.as_slice())),
+            };
+
+        args.finish()?;
+        Ok(command)
+    }
+
+    /// Decodes a single RESP value from the front of `data`.
+    ///
+    /// Returns `Ok(None)` if `data` does not yet hold a complete frame, in which case the caller
+    /// should read more bytes and try again. Otherwise returns the value along with the number of
+    /// bytes it occupied, so any trailing bytes can be kept for the next frame.
+    pub fn decode(data: &[u8]) -> Result<Option<(RESPValue, usize)>> {
+        match data.first() {
+            None => Ok(None),
+            Some(&prefix) if is_prefix(prefix) => decode_value(data, 0),
+            Some(_) => decode_inline(data),
+        }
+    }
+}
+
+/// The arguments of a command, consumed front to back as the command is parsed.
+///
+/// Running out of arguments that the command requires, or having some left over once it has been
+/// parsed, is reported as an arity error naming the command (and subcommand, if any).
+pub struct CommandArgs {
+    name: String,
+    args: std::vec::IntoIter<Bytes>,
+}
+
+impl CommandArgs {
+    pub fn new(name: &[u8], args: std::vec::IntoIter<Bytes>) -> Self {
+        CommandArgs {
+            name: String::from_utf8_lossy(name).into_owned(),
+            args,
+        }
+    }
+
+    pub fn next(&mut self) -> Option<Bytes> {
+        self.args.next()
+    }
+
+    pub fn len(&self) -> usize {
+        self.args.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.args.len() == 0
+    }
+
+    /// Takes the next argument, which the command can't do without.
+    pub fn required(&mut self) -> Result<Bytes, CommandError> {
+        self.args.next().ok_or_else(|| self.arity_error())
+    }
+
+    /// Takes the value following an option flag, such as the seconds after `EX`.
+    pub fn option_value(&mut self) -> Result<Bytes, CommandError> {
+        self.args.next().ok_or(CommandError::Syntax)
+    }
+
+    /// Takes the subcommand name, upper-cased, and records it in the name used for arity errors.
+    pub fn subcommand(&mut self) -> Result<String, CommandError> {
+        let subcommand = self.required()?;
+        let subcommand = String::from_utf8_lossy(&subcommand).to_ascii_uppercase();
+        self.name = format!("{}|{}", self.name, subcommand);
+        Ok(subcommand)
+    }
+
+    /// Takes every remaining argument, of which there must be at least one.
+    pub fn at_least_one(&mut self) -> Result<Vec<Bytes>, CommandError> {
+        let first = self.required()?;
+        Ok(std::iter::once(first).chain(self.args.by_ref()).collect())
+    }
+
+    /// Takes every remaining argument, if there are any.
+    pub fn rest(&mut self) -> Vec<Bytes> {
+        self.args.by_ref().collect()
+    }
+
+    pub fn skip_rest(&mut self) {
+        self.args.by_ref().for_each(drop);
+    }
+
+    /// Checks that every argument was consumed.
+    pub fn finish(&mut self) -> Result<(), CommandError> {
+        if self.is_empty() {
+            Ok(())
+        } else {
+            Err(self.arity_error())
+        }
+    }
+
+    pub fn arity_error(&self) -> CommandError {
+        CommandError::WrongArity(self.name.to_ascii_lowercase())
+    }
+}
+
+/// The ways an expiry can be given: a time to live relative to now, or an absolute Unix timestamp,
+/// each in either seconds or milliseconds.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExpiryFormat {
+    Seconds,
+    Milliseconds,
+    UnixSeconds,
+    UnixMilliseconds,
+}
+
+/// Parses an expiry argument, as given to SET or GETEX, into the time at which the key expires.
+///
+/// The value must be a positive integer, and the resulting time must fit in a signed 64-bit count
+/// of milliseconds since the epoch, otherwise it's an invalid expire time for `command`.
+pub fn parse_expiry(
+    arg: &[u8],
+    format: ExpiryFormat,
+    command: &str,
+) -> Result<SystemTime, CommandError> {
+    let value: i64 = parse_integer(arg)?;
+    let invalid = || CommandError::InvalidExpireTime(command.to_string());
+
+    if value <= 0 {
+        return Err(invalid());
+    }
+
+    let milliseconds = match format {
+        ExpiryFormat::Seconds | ExpiryFormat::UnixSeconds => {
+            value.checked_mul(1000).ok_or_else(invalid)?
+        }
+        ExpiryFormat::Milliseconds | ExpiryFormat::UnixMilliseconds => value,
+    };
+
+    let unix_milliseconds = match format {
+        ExpiryFormat::Seconds | ExpiryFormat::Milliseconds => unix_millis(SystemTime::now())
+            .checked_add(milliseconds)
+            .ok_or_else(invalid)?,
+        ExpiryFormat::UnixSeconds | ExpiryFormat::UnixMilliseconds => milliseconds,
+    };
+
+    Ok(SystemTime::UNIX_EPOCH + std::time::Duration::from_millis(unix_milliseconds as u64))
+}
+
+// Parses `EXPIRE key time [NX | XX | GT | LT]` and its millisecond and absolute variants.
+fn parse_expire(args: &mut CommandArgs, format: ExpiryFormat) -> Result<Command, CommandError> {
+    let key = args.required()?;
+    // Unlike SET, zero and negative times are allowed here, and simply delete the key.
+    let at = parse_expire_at(&args.required()?, format, &args.name)?;
+
+    let mut condition = ExpireCondition::default();
+    while let Some(option) = args.next() {
+        match String::from_utf8_lossy(&option)
+            .to_ascii_uppercase()
+            .as_str()
+        {
+            "NX" => condition.nx = true,
+            "XX" => condition.xx = true,
+            "GT" => condition.gt = true,
+            "LT" => condition.lt = true,
+            _ => {
+                return Err(CommandError::Other(format!(
+                    "Unsupported option {}",
+                    String::from_utf8_lossy(&option)
+                )))
             }
-            _ => unimplemented!(),
         }
     }
 
-    pub fn decode(data: &[u8]) -> Result<RESPValue> {
-        let s = std::str::from_utf8(data)?;
+    if condition.nx && (condition.xx || condition.gt || condition.lt) {
+        return Err(CommandError::Other(
+            "NX and XX, GT or LT options at the same time are not compatible".to_string(),
+        ));
+    }
+    if condition.gt && condition.lt {
+        return Err(CommandError::Other(
+            "GT and LT options at the same time are not compatible".to_string(),
+        ));
+    }
+
+    Ok(Command::Expire { key, at, condition })
+}
 
-        println!("Decoding: {}", s);
+/// Parses the time argument of EXPIRE and its relatives into milliseconds since the Unix epoch,
+/// which may be in the past.
+pub fn parse_expire_at(
+    arg: &[u8],
+    format: ExpiryFormat,
+    command: &str,
+) -> Result<i64, CommandError> {
+    let time: i64 = parse_integer(arg)?;
+    let invalid = || CommandError::InvalidExpireTime(command.to_ascii_lowercase());
 
-        bail!("Not implemented");
+    let milliseconds = match format {
+        ExpiryFormat::Seconds | ExpiryFormat::UnixSeconds => {
+            time.checked_mul(1000).ok_or_else(invalid)?
+        }
+        ExpiryFormat::Milliseconds | ExpiryFormat::UnixMilliseconds => time,
+    };
+    match format {
+        ExpiryFormat::Seconds | ExpiryFormat::Milliseconds => unix_millis(SystemTime::now())
+            .checked_add(milliseconds)
+            .ok_or_else(invalid),
+        ExpiryFormat::UnixSeconds | ExpiryFormat::UnixMilliseconds => Ok(milliseconds),
     }
 }
 
-pub fn parse_input(input: &str) -> Vec<RESPValue> {
-    let mut parts = input.split(SEPARATOR).peekable();
+/// Parses the cursor argument of SCAN and its relatives.
+pub fn parse_cursor(arg: &[u8]) -> Result<u64, CommandError> {
+    parse_integer(arg).map_err(|_| CommandError::Other("invalid cursor".to_string()))
+}
+
+/// Converts the inclusive `start` and `stop` indexes of a range over `len` items, either of which
+/// may count back from the end, into in-bounds indexes. Returns `None` if the range is empty.
+pub fn normalize_range(start: i64, stop: i64, len: usize) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let start = if start < 0 {
+        (len + start).max(0)
+    } else {
+        start
+    };
+    let stop = if stop < 0 {
+        len + stop
+    } else {
+        stop.min(len - 1)
+    };
+
+    (start <= stop && start < len).then_some((start as usize, stop as usize))
+}
+
+/// Parses a floating point argument. Infinities are fine but NaN isn't, as in Redis.
+pub fn parse_float(arg: &[u8]) -> Result<f64, CommandError> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|s| s.parse::<f64>().ok())
+        .filter(|value| !value.is_nan())
+        .ok_or(CommandError::NotFloat)
+}
+
+/// Parses an integer argument, rejecting anything Redis wouldn't accept as one.
+pub fn parse_integer<T: std::str::FromStr>(arg: &[u8]) -> Result<T, CommandError> {
+    // `FromStr` allows a leading '+', Redis doesn't.
+    if arg.first() == Some(&b'+') {
+        return Err(CommandError::NotInteger);
+    }
+
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or(CommandError::NotInteger)
+}
+
+/// The most picks a negative HRANDFIELD or SRANDMEMBER count may ask for, as they are all made
+/// and replied with while the database is locked.
+pub const MAX_RANDOM_REPEATS: i64 = 1 << 20;
+
+/// Parses the count HRANDFIELD and SRANDMEMBER take, where a negative count asks for that many
+/// picks with repeats allowed.
+pub fn parse_random_count(arg: &[u8]) -> Result<i64, CommandError> {
+    let count: i64 = parse_integer(arg)?;
+    if count < -MAX_RANDOM_REPEATS {
+        return Err(CommandError::Other(format!(
+            "value is out of range, value must between {} and {}",
+            -MAX_RANDOM_REPEATS,
+            i64::MAX
+        )));
+    }
+    Ok(count)
+}
+
+/// Decodes every complete frame in `input`, returning the values and the number of bytes consumed.
+/// Any bytes past the consumed count belong to a frame that has not been fully received yet.
+pub fn parse_input(input: &[u8]) -> Result<(Vec<RESPValue>, usize)> {
     let mut values = Vec::new();
+    let mut consumed = 0;
 
-    while parts.peek().is_some() && !parts.peek().unwrap().is_empty() {
-        values.push(parse_input_segments(&mut parts));
+    while let Some((value, len)) = RESPValue::decode(&input[consumed..])? {
+        values.push(value);
+        consumed += len;
     }
 
-    values
+    Ok((values, consumed))
 }
 
-fn parse_input_segments<'a>(parts: &mut impl Iterator<Item = &'a str>) -> RESPValue {
-    let mut chars = parts.next().unwrap().chars();
-    let prefix = chars.next().unwrap();
-    let rest = chars.as_str();
+// Upper bounds on what a client may declare, matching Redis' defaults, so that a bogus header
+// can't make us reserve gigabytes of memory before a single payload byte arrives.
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+const MAX_ARRAY_LEN: usize = 1024 * 1024 * 1024;
+const MAX_INLINE_LEN: usize = 64 * 1024;
 
-    match prefix {
-        SIMPLE_STRING_PREFIX => RESPValue::SimpleString(rest.to_string()),
-        SIMPLE_ERROR_PREFIX => RESPValue::Error(rest.to_string()),
-        INTEGER_PREFIX => RESPValue::Integer(rest.parse().unwrap()),
-        // We could use the number to double check here, but we already split by the line break,
-        // so we know the entire next value is the string we want.
-        BULK_STRING_PREFIX => RESPValue::BulkString(parts.next().unwrap().to_string()),
-        ARRAY_PREFIX => {
-            let len: usize = rest.parse().unwrap();
-            let mut values = Vec::new();
+fn write_line(out: &mut BytesMut, prefix: char, line: &[u8]) {
+    out.extend_from_slice(&[prefix as u8]);
+    out.extend_from_slice(line);
+    out.extend_from_slice(SEPARATOR.as_bytes());
+}
+
+fn write_blob(out: &mut BytesMut, prefix: char, blob: &[u8]) {
+    write_line(out, prefix, blob.len().to_string().as_bytes());
+    out.extend_from_slice(blob);
+    out.extend_from_slice(SEPARATOR.as_bytes());
+}
+
+fn write_aggregate(out: &mut BytesMut, prefix: char, values: &[RESPValue], protocol: Protocol) {
+    write_line(out, prefix, values.len().to_string().as_bytes());
+    for value in values {
+        value.encode(protocol, out);
+    }
+}
 
-            for _ in 0..len {
-                let value = parse_input_segments(parts);
-                values.push(value);
+/// Formats a double the way Redis replies with one: the shortest representation that round-trips,
+/// without a trailing `.0` for integral values, and `inf`/`-inf`/`nan` for the special cases.
+/// Like `%.17g`, very large and very small magnitudes are written with an exponent.
+pub fn format_double(d: f64) -> String {
+    if d.is_nan() {
+        return "nan".to_string();
+    } else if d.is_infinite() {
+        return if d > 0.0 { "inf" } else { "-inf" }.to_string();
+    }
+
+    let scientific = format!("{:e}", d);
+    let (mantissa, exponent) = scientific.split_once('e').unwrap();
+    let exponent: i32 = exponent.parse().unwrap();
+    if (-4..17).contains(&exponent) {
+        d.to_string()
+    } else {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        format!("{}e{}{:02}", mantissa, sign, exponent.abs())
+    }
+}
+
+fn is_prefix(byte: u8) -> bool {
+    matches!(
+        byte as char,
+        SIMPLE_STRING_PREFIX
+            | SIMPLE_ERROR_PREFIX
+            | INTEGER_PREFIX
+            | BULK_STRING_PREFIX
+            | ARRAY_PREFIX
+            | NULL_PREFIX
+            | BOOLEAN_PREFIX
+            | DOUBLE_PREFIX
+            | BIG_NUMBER_PREFIX
+            | BULK_ERROR_PREFIX
+            | VERBATIM_STRING_PREFIX
+            | MAP_PREFIX
+            | SET_PREFIX
+            | PUSH_PREFIX
+    )
+}
+
+// Finds the line starting at `pos`, returning its contents (without the separator) and the
+// position just past the separator.
+fn read_line(data: &[u8], pos: usize) -> Result<Option<(&[u8], usize)>> {
+    let rest = &data[pos..];
+    match rest
+        .windows(SEPARATOR.len())
+        .position(|w| w == SEPARATOR.as_bytes())
+    {
+        Some(end) => Ok(Some((&rest[..end], pos + end + SEPARATOR.len()))),
+        None if rest.len() > MAX_INLINE_LEN => bail!("too big inline request"),
+        None => Ok(None),
+    }
+}
+
+fn parse_line<T: std::str::FromStr>(line: &[u8]) -> Result<T> {
+    std::str::from_utf8(line)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "invalid length or integer: {:?}",
+                String::from_utf8_lossy(line)
+            )
+        })
+}
+
+// Decodes the value starting at `pos`, returning it along with the position just past its end.
+fn decode_value(data: &[u8], pos: usize) -> Result<Option<(RESPValue, usize)>> {
+    let Some(&prefix) = data.get(pos) else {
+        return Ok(None);
+    };
+    let Some((line, next)) = read_line(data, pos + 1)? else {
+        return Ok(None);
+    };
+
+    let value = match prefix as char {
+        SIMPLE_STRING_PREFIX => RESPValue::SimpleString(String::from_utf8(line.to_vec())?),
+        SIMPLE_ERROR_PREFIX => RESPValue::Error(String::from_utf8(line.to_vec())?),
+        INTEGER_PREFIX => RESPValue::Integer(parse_line(line)?),
+        NULL_PREFIX if line.is_empty() => RESPValue::Null,
+        BOOLEAN_PREFIX => match line {
+            b"t" => RESPValue::Boolean(true),
+            b"f" => RESPValue::Boolean(false),
+            _ => bail!("invalid boolean"),
+        },
+        DOUBLE_PREFIX => RESPValue::Double(parse_line(line)?),
+        BIG_NUMBER_PREFIX => {
+            let digits = line.strip_prefix(b"-").unwrap_or(line);
+            if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+                bail!("invalid big number");
             }
-            RESPValue::Array(values)
+            RESPValue::BigNumber(String::from_utf8(line.to_vec())?)
         }
-        _ => panic!("Unknown prefix: {}", prefix),
-    }
+        BULK_STRING_PREFIX | BULK_ERROR_PREFIX | VERBATIM_STRING_PREFIX => {
+            let len: i64 = parse_line(line)?;
+            if len == -1 && prefix as char == BULK_STRING_PREFIX {
+                return Ok(Some((RESPValue::Null, next)));
+            }
+            if len < 0 || len as usize > MAX_BULK_LEN {
+                bail!("invalid bulk length");
+            }
+
+            // Take exactly the declared number of bytes, whatever they contain, then expect the
+            // trailing separator.
+            let end = next + len as usize;
+            if data.len() < end + SEPARATOR.len() {
+                return Ok(None);
+            }
+            if &data[end..end + SEPARATOR.len()] != SEPARATOR.as_bytes() {
+                bail!("expected CRLF after bulk string payload");
+            }
+
+            let blob = &data[next..end];
+            let value = match prefix as char {
+                BULK_ERROR_PREFIX => RESPValue::BulkError(String::from_utf8(blob.to_vec())?),
+                VERBATIM_STRING_PREFIX => {
+                    if blob.len() < 4 || blob[3] != b':' {
+                        bail!("invalid verbatim string");
+                    }
+                    RESPValue::VerbatimString {
+                        format: [blob[0], blob[1], blob[2]],
+                        text: Bytes::copy_from_slice(&blob[4..]),
+                    }
+                }
+                _ => RESPValue::BulkString(Bytes::copy_from_slice(blob)),
+            };
+            return Ok(Some((value, end + SEPARATOR.len())));
+        }
+        ARRAY_PREFIX | SET_PREFIX | PUSH_PREFIX | MAP_PREFIX => {
+            let len: i64 = parse_line(line)?;
+            if len == -1 && prefix as char == ARRAY_PREFIX {
+                return Ok(Some((RESPValue::NullArray, next)));
+            }
+            if len < 0 || len as usize > MAX_ARRAY_LEN {
+                bail!("invalid multibulk length");
+            }
+
+            // Maps are sent as a flat run of alternating keys and values.
+            let count = if prefix as char == MAP_PREFIX {
+                len as usize * 2
+            } else {
+                len as usize
+            };
+
+            let mut values = Vec::new();
+            let mut pos = next;
+            for _ in 0..count {
+                match decode_value(data, pos)? {
+                    Some((value, end)) => {
+                        values.push(value);
+                        pos = end;
+                    }
+                    None => return Ok(None),
+                }
+            }
+
+            let value = match prefix as char {
+                SET_PREFIX => RESPValue::Set(values),
+                PUSH_PREFIX => RESPValue::Push(values),
+                MAP_PREFIX => {
+                    let mut values = values.into_iter();
+                    let mut pairs = Vec::with_capacity(len as usize);
+                    while let (Some(key), Some(value)) = (values.next(), values.next()) {
+                        pairs.push((key, value));
+                    }
+                    RESPValue::Map(pairs)
+                }
+                _ => RESPValue::Array(values),
+            };
+            return Ok(Some((value, pos)));
+        }
+        _ => bail!("unknown prefix: {:?}", prefix as char),
+    };
+
+    Ok(Some((value, next)))
+}
+
+// Inline commands are plain space-separated lines, as sent by e.g. `telnet` or `nc`.
+fn decode_inline(data: &[u8]) -> Result<Option<(RESPValue, usize)>> {
+    let Some((line, next)) = read_line(data, 0)? else {
+        return Ok(None);
+    };
+
+    let values = line
+        .split(|b| b.is_ascii_whitespace())
+        .filter(|part| !part.is_empty())
+        .map(|part| RESPValue::BulkString(Bytes::copy_from_slice(part)))
+        .collect();
+
+    Ok(Some((RESPValue::Array(values), next)))
 }
 
 #[cfg(test)]
@@ -372,40 +1297,299 @@ mod tests {
 
     #[test]
     fn test_parse_ping() {
-        let input = "+PING\r\n";
+        let input = b"+PING\r\n";
         assert_eq!(
-            parse_input(input),
-            vec![RESPValue::SimpleString(String::from("PING"))]
+            parse_input(input).unwrap(),
+            (
+                vec![RESPValue::SimpleString(String::from("PING"))],
+                input.len()
+            )
         );
     }
 
     #[test]
     fn test_echo() {
-        let input = "*2\r\n$4\r\nECHO\r\n$3\r\nhey\r\n";
+        let input = b"*2\r\n$4\r\nECHO\r\n$3\r\nhey\r\n";
         assert_eq!(
-            parse_input(input),
-            vec![RESPValue::Array(vec![
-                RESPValue::BulkString(String::from("ECHO")),
-                RESPValue::BulkString(String::from("hey"))
-            ])]
+            parse_input(input).unwrap(),
+            (
+                vec![RESPValue::Array(vec![
+                    RESPValue::BulkString(Bytes::from("ECHO")),
+                    RESPValue::BulkString(Bytes::from("hey"))
+                ])],
+                input.len()
+            )
         );
     }
 
     #[test]
     fn test_multiple_commands() {
-        let input = "*2\r\n$4\r\nECHO\r\n$3\r\nhey\r\n*2\r\n$4\r\nECHO\r\n$3\r\nyou\r\n";
+        let input = b"*2\r\n$4\r\nECHO\r\n$3\r\nhey\r\n*2\r\n$4\r\nECHO\r\n$3\r\nyou\r\n";
         assert_eq!(
-            parse_input(input),
+            parse_input(input).unwrap().0,
             vec![
                 RESPValue::Array(vec![
-                    RESPValue::BulkString(String::from("ECHO")),
-                    RESPValue::BulkString(String::from("hey"))
+                    RESPValue::BulkString(Bytes::from("ECHO")),
+                    RESPValue::BulkString(Bytes::from("hey"))
                 ]),
                 RESPValue::Array(vec![
-                    RESPValue::BulkString(String::from("ECHO")),
-                    RESPValue::BulkString(String::from("you"))
+                    RESPValue::BulkString(Bytes::from("ECHO")),
+                    RESPValue::BulkString(Bytes::from("you"))
                 ])
             ]
         );
     }
+
+    #[test]
+    fn test_bulk_string_containing_separator() {
+        let input = b"*2\r\n$4\r\nECHO\r\n$8\r\nhey\r\nyou\r\n";
+        assert_eq!(
+            parse_input(input).unwrap().0,
+            vec![RESPValue::Array(vec![
+                RESPValue::BulkString(Bytes::from("ECHO")),
+                RESPValue::BulkString(Bytes::from("hey\r\nyou"))
+            ])]
+        );
+    }
+
+    #[test]
+    fn test_partial_frame() {
+        let input = b"*2\r\n$4\r\nECHO\r\n$3\r\nhey\r\n*2\r\n$4\r\nEC";
+        let (values, consumed) = parse_input(input).unwrap();
+        assert_eq!(values.len(), 1);
+        assert_eq!(consumed, 23);
+
+        for end in 0..23 {
+            assert_eq!(RESPValue::decode(&input[..end]).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn test_inline_command() {
+        assert_eq!(
+            RESPValue::decode(b"ECHO  hey\r\n").unwrap(),
+            Some((
+                RESPValue::Array(vec![
+                    RESPValue::BulkString(Bytes::from("ECHO")),
+                    RESPValue::BulkString(Bytes::from("hey"))
+                ]),
+                11
+            ))
+        );
+    }
+
+    #[test]
+    fn test_bad_bulk_length() {
+        assert!(RESPValue::decode(b"$3\r\nhello\r\n").is_err());
+        assert!(RESPValue::decode(b"$-5\r\n").is_err());
+    }
+
+    #[test]
+    fn test_resp3_round_trip() {
+        let value = RESPValue::Map(vec![
+            (
+                RESPValue::BulkString(Bytes::from("double")),
+                RESPValue::Double(1.5),
+            ),
+            (RESPValue::Boolean(true), RESPValue::Null),
+            (
+                RESPValue::BigNumber(String::from("-12345678901234567890")),
+                RESPValue::Set(vec![RESPValue::BulkError(String::from("ERR bad"))]),
+            ),
+            (
+                RESPValue::VerbatimString {
+                    format: *b"txt",
+                    text: Bytes::from("hi"),
+                },
+                RESPValue::Push(vec![RESPValue::Integer(1)]),
+            ),
+        ]);
+
+        let encoded = value.to_bytes(Protocol::Resp3);
+        assert_eq!(
+            RESPValue::decode(&encoded).unwrap(),
+            Some((value, encoded.len()))
+        );
+    }
+
+    #[test]
+    fn test_resp2_downgrade() {
+        let value = RESPValue::Map(vec![
+            (
+                RESPValue::BulkString(Bytes::from("dir")),
+                RESPValue::Double(2.0),
+            ),
+            (RESPValue::Boolean(false), RESPValue::Null),
+        ]);
+
+        assert_eq!(
+            value.to_bytes(Protocol::Resp2),
+            Bytes::from("*4\r\n$3\r\ndir\r\n$1\r\n2\r\n:0\r\n$-1\r\n")
+        );
+    }
+
+    fn command(args: &[&str]) -> Result<Command, CommandError> {
+        let args = args
+            .iter()
+            .map(|arg| RESPValue::BulkString(Bytes::from(arg.to_string())))
+            .collect();
+        RESPValue::Array(args).into_command()
+    }
+
+    #[test]
+    fn test_error_replies() {
+        let error = |args: &[&str]| command(args).err().map(|error| error.to_string());
+        assert_eq!(
+            error(&["NOPE", "a", "b"]).unwrap(),
+            "ERR unknown command 'NOPE', with args beginning with: 'a' 'b' "
+        );
+        assert_eq!(
+            error(&["GET"]).unwrap(),
+            "ERR wrong number of arguments for 'get' command"
+        );
+        assert_eq!(
+            error(&["SET", "k", "v", "SOMETIMES"]).unwrap(),
+            "ERR syntax error"
+        );
+
+        crate::DB.get_or_init(Default::default);
+        let mut client = Client::new();
+        command(&["RPUSH", "error-replies-list", "a"])
+            .unwrap()
+            .execute(&mut client)
+            .unwrap();
+        let error = command(&["GET", "error-replies-list"])
+            .unwrap()
+            .execute(&mut client)
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "WRONGTYPE Operation against a key holding the wrong kind of value"
+        );
+    }
+
+    #[test]
+    fn test_set_conflicting_options() {
+        for options in [
+            &["EX", "10", "PX", "100"][..],
+            &["EXAT", "1", "PXAT", "1000"],
+            &["NX", "XX"],
+            &["KEEPTTL", "EX", "10"],
+            &["PX", "100", "KEEPTTL"],
+        ] {
+            let args = [&["SET", "k", "v"][..], options].concat();
+            assert_eq!(command(&args).err(), Some(CommandError::Syntax));
+        }
+    }
+
+    #[test]
+    fn test_set_invalid_expire_time() {
+        for (option, time) in [
+            ("EX", "0"),
+            ("PX", "-5"),
+            ("EXAT", "-1"),
+            ("EX", "9223372036854775807"),
+            ("PX", "9223372036854775807"),
+        ] {
+            assert_eq!(
+                command(&["SET", "k", "v", option, time]).err(),
+                Some(CommandError::InvalidExpireTime("set".to_string()))
+            );
+        }
+        assert_eq!(
+            command(&["SET", "k", "v", "EX", "soon"]).err(),
+            Some(CommandError::NotInteger)
+        );
+    }
+
+    #[test]
+    fn test_set_keepttl() {
+        crate::DB.get_or_init(Default::default);
+        let mut client = Client::new();
+        let mut set = |args: &[&str]| {
+            let args = [&["SET", "set-keepttl"][..], args].concat();
+            command(&args).unwrap().execute(&mut client).unwrap()
+        };
+        let expiry = || crate::db().get(b"set-keepttl").unwrap().expires_at();
+
+        set(&["a", "PXAT", "99999999999999"]);
+        let expected = SystemTime::UNIX_EPOCH + std::time::Duration::from_millis(99999999999999);
+        assert_eq!(expiry(), Some(expected));
+
+        assert_eq!(set(&["b", "KEEPTTL"]), Response::Ok);
+        assert_eq!(expiry(), Some(expected));
+
+        set(&["c"]);
+        assert_eq!(expiry(), None);
+    }
+
+    #[test]
+    fn test_format_double() {
+        assert_eq!(format_double(1.5), "1.5");
+        assert_eq!(format_double(-3.0), "-3");
+        assert_eq!(format_double(0.0001), "0.0001");
+        assert_eq!(format_double(1e16), "10000000000000000");
+        assert_eq!(format_double(1e17), "1e+17");
+        assert_eq!(format_double(1e300), "1e+300");
+        assert_eq!(format_double(-2.5e-300), "-2.5e-300");
+        assert_eq!(format_double(1e-300), "1e-300");
+        assert_eq!(format_double(1e-5), "1e-05");
+        assert_eq!(format_double(f64::NEG_INFINITY), "-inf");
+    }
+
+    #[test]
+    fn test_null_array() {
+        assert_eq!(
+            RESPValue::NullArray.to_bytes(Protocol::Resp2),
+            Bytes::from("*-1\r\n")
+        );
+        assert_eq!(
+            RESPValue::NullArray.to_bytes(Protocol::Resp3),
+            Bytes::from("_\r\n")
+        );
+        assert_eq!(
+            parse_input(b"*-1\r\n").unwrap(),
+            (vec![RESPValue::NullArray], 5)
+        );
+    }
+
+    #[test]
+    fn test_normalize_range() {
+        assert_eq!(normalize_range(0, -1, 5), Some((0, 4)));
+        assert_eq!(normalize_range(-100, 100, 5), Some((0, 4)));
+        assert_eq!(normalize_range(1, 2, 5), Some((1, 2)));
+        assert_eq!(normalize_range(3, 1, 5), None);
+        assert_eq!(normalize_range(5, 10, 5), None);
+        assert_eq!(normalize_range(0, -6, 5), None);
+        assert_eq!(normalize_range(0, -1, 0), None);
+    }
+
+    #[test]
+    fn test_expire_xx_with_gt_or_lt() {
+        use crate::rdb::{DBEntry, Value};
+
+        crate::DB.get_or_init(Default::default);
+        let key = Bytes::from("expire-xx-without-ttl");
+        crate::db().insert(
+            key.clone(),
+            DBEntry::new(Value::String(Bytes::from("v")), None),
+        );
+
+        // Without a TTL, XX doesn't hold, whatever GT or LT would make of it.
+        for option in ["GT", "LT"] {
+            let args = vec![
+                key.clone(),
+                Bytes::from("100"),
+                Bytes::from("XX"),
+                Bytes::from(option),
+            ];
+            let mut args = CommandArgs::new(b"EXPIRE", args.into_iter());
+            let command = parse_expire(&mut args, ExpiryFormat::Seconds).unwrap();
+            assert_eq!(
+                command.execute(&mut Client::new()).unwrap(),
+                Response::Echo(RESPValue::Integer(0))
+            );
+            assert_eq!(crate::db().get(&key).unwrap().expires_at(), None);
+        }
+    }
 }
@@ -0,0 +1,47 @@
+//! A small non-cryptographic random number generator (xorshift64*), for things like sampling keys
+//! where speed matters far more than quality.
+
+use std::{
+    cell::Cell,
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+};
+
+thread_local! {
+    static STATE: Cell<u64> = Cell::new(seed());
+}
+
+// `RandomState` is seeded from the OS once per thread, which saves us reaching for it ourselves.
+fn seed() -> u64 {
+    let seed = RandomState::new().build_hasher().finish();
+    if seed == 0 {
+        0x9E37_79B9_7F4A_7C15
+    } else {
+        seed
+    }
+}
+
+pub fn next_u64() -> u64 {
+    STATE.with(|state| {
+        let mut x = state.get();
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        state.set(x);
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    })
+}
+
+/// A random index in `0..n`, which must not be empty.
+pub fn below(n: usize) -> usize {
+    (next_u64() % n as u64) as usize
+}
+
+/// Moves `count` items, chosen uniformly at random, to the front of `items` in a random order. This
+/// is a partial Fisher-Yates shuffle, so it only does as much work as `count` needs.
+pub fn shuffle_prefix<T>(items: &mut [T], count: usize) {
+    for i in 0..count.min(items.len()) {
+        let j = i + below(items.len() - i);
+        items.swap(i, j);
+    }
+}
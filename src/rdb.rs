@@ -1,19 +1,232 @@
-use crate::protocol_parser::RESPValue;
+use crate::{
+    crc64,
+    error::CommandError,
+    expiry::ExpiryStats,
+    hash::Hash,
+    indexed_set::IndexedSet,
+    listpack, lzf,
+    scan::ScanIndex,
+    set::Set,
+    stream::{ConsumerGroup, Fields, Stream, StreamId},
+    ziplist,
+    zset::SortedSet,
+};
 use anyhow::{bail, Result};
+use bytes::Bytes;
 use core::str;
-use std::{collections::HashMap, fs::File, io::Read, time::SystemTime, vec};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fs::File,
+    io::{BufReader, Read},
+    time::{Duration, Instant, SystemTime},
+    vec,
+};
 
 const MAGIC_STRING: &str = "REDIS";
 
+// Sections that can come where the type of the next key would.
+pub const OPCODE_SLOT_INFO: u8 = 0xf4;
+pub const OPCODE_FUNCTION2: u8 = 0xf5;
+pub const OPCODE_FUNCTION_PRE_GA: u8 = 0xf6;
+pub const OPCODE_MODULE_AUX: u8 = 0xf7;
+pub const OPCODE_IDLE: u8 = 0xf8;
+pub const OPCODE_FREQ: u8 = 0xf9;
+pub const OPCODE_AUX: u8 = 0xfa;
+pub const OPCODE_RESIZEDB: u8 = 0xfb;
+pub const OPCODE_EXPIRETIME_MS: u8 = 0xfc;
+pub const OPCODE_EXPIRETIME: u8 = 0xfd;
+pub const OPCODE_SELECTDB: u8 = 0xfe;
+pub const OPCODE_EOF: u8 = 0xff;
+
+// The types a value can be written as. Each new encoding Redis has kept a collection in got a type
+// of its own, and files may hold any of them.
+pub const TYPE_STRING: u8 = 0;
+pub const TYPE_LIST: u8 = 1;
+pub const TYPE_SET: u8 = 2;
+pub const TYPE_ZSET: u8 = 3;
+pub const TYPE_HASH: u8 = 4;
+pub const TYPE_ZSET_2: u8 = 5;
+pub const TYPE_MODULE_2: u8 = 7;
+pub const TYPE_ZIPMAP: u8 = 9;
+pub const TYPE_LIST_ZIPLIST: u8 = 10;
+pub const TYPE_INTSET: u8 = 11;
+pub const TYPE_ZSET_ZIPLIST: u8 = 12;
+pub const TYPE_HASH_ZIPLIST: u8 = 13;
+pub const TYPE_QUICKLIST: u8 = 14;
+pub const TYPE_STREAM_LISTPACKS: u8 = 15;
+pub const TYPE_HASH_LISTPACK: u8 = 16;
+pub const TYPE_ZSET_LISTPACK: u8 = 17;
+pub const TYPE_QUICKLIST_2: u8 = 18;
+pub const TYPE_STREAM_LISTPACKS_2: u8 = 19;
+pub const TYPE_SET_LISTPACK: u8 = 20;
+pub const TYPE_STREAM_LISTPACKS_3: u8 = 21;
+pub const TYPE_HASH_METADATA_PRE_GA: u8 = 22;
+pub const TYPE_HASH_LISTPACK_EX_PRE_GA: u8 = 23;
+pub const TYPE_HASH_METADATA: u8 = 24;
+pub const TYPE_HASH_LISTPACK_EX: u8 = 25;
+
+// How each node of a quicklist is stored: a single element on its own, or a listpack of them.
+const QUICKLIST_NODE_PLAIN: usize = 1;
+const QUICKLIST_NODE_PACKED: usize = 2;
+
+const STREAM_ITEM_FLAG_DELETED: i64 = 1;
+pub const STREAM_ITEM_FLAG_SAMEFIELDS: i64 = 2;
+
+// The types of the fields a module writes its values as.
+const MODULE_OPCODE_EOF: usize = 0;
+const MODULE_OPCODE_SINT: usize = 1;
+const MODULE_OPCODE_UINT: usize = 2;
+const MODULE_OPCODE_FLOAT: usize = 3;
+const MODULE_OPCODE_DOUBLE: usize = 4;
+const MODULE_OPCODE_STRING: usize = 5;
+
+/// A value held in the keyspace. Commands only work on keys holding the type they expect, and reply
+/// with a WRONGTYPE error otherwise.
 #[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(Bytes),
+    List(VecDeque<Bytes>),
+    Hash(Hash),
+    Set(Set),
+    SortedSet(SortedSet),
+    Stream(Stream),
+}
+
+impl Value {
+    /// The name of the type, as reported by TYPE and matched by SCAN's TYPE filter.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::List(_) => "list",
+            Value::Hash(_) => "hash",
+            Value::Set(_) => "set",
+            Value::SortedSet(_) => "zset",
+            Value::Stream(_) => "stream",
+        }
+    }
+
+    /// Whether this is a collection with nothing left in it. Strings are never empty in this
+    /// sense, as an empty string is still a value, and neither are streams, which Redis keeps
+    /// around once their last entry is gone along with their groups and last ID.
+    pub fn is_empty(&self) -> bool {
+        match self {
+            Value::String(_) => false,
+            Value::List(list) => list.is_empty(),
+            Value::Hash(hash) => hash.is_empty(),
+            Value::Set(set) => set.is_empty(),
+            Value::SortedSet(set) => set.is_empty(),
+            Value::Stream(_) => false,
+        }
+    }
+
+    pub fn as_string(&self) -> Result<&Bytes, CommandError> {
+        match self {
+            Value::String(s) => Ok(s),
+            _ => Err(CommandError::WrongType),
+        }
+    }
+
+    pub fn as_string_mut(&mut self) -> Result<&mut Bytes, CommandError> {
+        match self {
+            Value::String(s) => Ok(s),
+            _ => Err(CommandError::WrongType),
+        }
+    }
+
+    pub fn as_list(&self) -> Result<&VecDeque<Bytes>, CommandError> {
+        match self {
+            Value::List(list) => Ok(list),
+            _ => Err(CommandError::WrongType),
+        }
+    }
+
+    pub fn as_list_mut(&mut self) -> Result<&mut VecDeque<Bytes>, CommandError> {
+        match self {
+            Value::List(list) => Ok(list),
+            _ => Err(CommandError::WrongType),
+        }
+    }
+
+    pub fn as_hash(&self) -> Result<&Hash, CommandError> {
+        match self {
+            Value::Hash(hash) => Ok(hash),
+            _ => Err(CommandError::WrongType),
+        }
+    }
+
+    pub fn as_hash_mut(&mut self) -> Result<&mut Hash, CommandError> {
+        match self {
+            Value::Hash(hash) => Ok(hash),
+            _ => Err(CommandError::WrongType),
+        }
+    }
+
+    pub fn as_set(&self) -> Result<&Set, CommandError> {
+        match self {
+            Value::Set(set) => Ok(set),
+            _ => Err(CommandError::WrongType),
+        }
+    }
+
+    pub fn as_set_mut(&mut self) -> Result<&mut Set, CommandError> {
+        match self {
+            Value::Set(set) => Ok(set),
+            _ => Err(CommandError::WrongType),
+        }
+    }
+
+    pub fn as_sorted_set(&self) -> Result<&SortedSet, CommandError> {
+        match self {
+            Value::SortedSet(set) => Ok(set),
+            _ => Err(CommandError::WrongType),
+        }
+    }
+
+    pub fn as_sorted_set_mut(&mut self) -> Result<&mut SortedSet, CommandError> {
+        match self {
+            Value::SortedSet(set) => Ok(set),
+            _ => Err(CommandError::WrongType),
+        }
+    }
+
+    pub fn as_stream(&self) -> Result<&Stream, CommandError> {
+        match self {
+            Value::Stream(stream) => Ok(stream),
+            _ => Err(CommandError::WrongType),
+        }
+    }
+
+    pub fn as_stream_mut(&mut self) -> Result<&mut Stream, CommandError> {
+        match self {
+            Value::Stream(stream) => Ok(stream),
+            _ => Err(CommandError::WrongType),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct DBEntry {
-    value: RESPValue,
+    value: Value,
     expires_at: Option<SystemTime>,
+    // When a command last read or wrote the entry, for OBJECT IDLETIME.
+    accessed_at: Instant,
+}
+
+// Two entries are equal when they hold the same value and expire at the same time, however
+// recently each was used.
+impl PartialEq for DBEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.value == other.value && self.expires_at == other.expires_at
+    }
 }
 
 impl DBEntry {
-    pub fn new(value: RESPValue, expires_at: Option<SystemTime>) -> Self {
-        DBEntry { value, expires_at }
+    pub fn new(value: Value, expires_at: Option<SystemTime>) -> Self {
+        DBEntry {
+            value,
+            expires_at,
+            accessed_at: Instant::now(),
+        }
     }
     pub fn is_expired(&self) -> bool {
         if let Some(expiry) = self.expires_at {
@@ -23,9 +236,35 @@ impl DBEntry {
             false
         }
     }
-    pub fn value(&self) -> &RESPValue {
+    pub fn value(&self) -> &Value {
         &self.value
     }
+    pub fn value_mut(&mut self) -> &mut Value {
+        &mut self.value
+    }
+    pub fn expires_at(&self) -> Option<SystemTime> {
+        self.expires_at
+    }
+    pub fn type_name(&self) -> &'static str {
+        self.value.type_name()
+    }
+    /// How long it's been since a command last used the entry.
+    pub fn idle_time(&self) -> Duration {
+        self.accessed_at.elapsed()
+    }
+    /// Milliseconds left until the entry expires, or `None` if it never does.
+    pub fn ttl_millis(&self) -> Option<i64> {
+        self.expires_at
+            .map(|expiry| (unix_millis(expiry) - unix_millis(SystemTime::now())).max(0))
+    }
+}
+
+/// Milliseconds since the Unix epoch, negative for times before it.
+pub fn unix_millis(time: SystemTime) -> i64 {
+    match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(since) => since.as_millis() as i64,
+        Err(e) => -(e.duration().as_millis() as i64),
+    }
 }
 
 #[derive(Debug, Default)]
@@ -35,18 +274,335 @@ pub struct Rdb {
     db_hash_table_size: usize,
     expiry_hash_table_size: usize,
     selected_db: u32,
-    data: HashMap<String, DBEntry>,
+    data: HashMap<Bytes, DBEntry>,
+    // Every key in `data` that has an expiry set, so the active expiry cycle can sample them
+    // without walking the whole keyspace.
+    expires: IndexedSet<Bytes>,
+    // Every key in `data` holding a hash that has had fields given an expiry, for the active expiry
+    // cycle to sweep. Hashes whose field expiries have since all gone are dropped when sampled.
+    field_expires: IndexedSet<Bytes>,
+    // Every key in `data`, ordered by a hash of the key, which is what SCAN cursors point into.
+    scan_index: ScanIndex,
+    expiry_stats: ExpiryStats,
+    // Roughly how many writes there have been since the last successful save, which decides when
+    // the save rules kick in.
+    dirty: u64,
     original_checksum: u64,
 }
 
+/// The outcome of sampling keys with an expiry set, see [`Rdb::expire_sample`].
+#[derive(Debug, Default)]
+pub struct ExpireSample {
+    pub sampled: usize,
+    pub expired: usize,
+    /// The summed remaining time to live of the sampled keys that were still live, in milliseconds.
+    pub ttl_sum: i64,
+}
+
 impl Rdb {
-    pub fn data_mut(&mut self) -> &mut HashMap<String, DBEntry> {
-        &mut self.data
+    /// Looks up a live entry, counting as a use of it. An entry that has expired is deleted on the
+    /// spot and treated as though it never existed, so callers never see stale data.
+    pub fn get(&mut self, key: &[u8]) -> Option<&DBEntry> {
+        self.expire_if_needed(key);
+        let entry = self.data.get_mut(key)?;
+        entry.accessed_at = Instant::now();
+        Some(entry)
+    }
+
+    /// Looks up a live entry like [`Rdb::get`], but without counting as a use of it, for commands
+    /// like TYPE and OBJECT that only inspect the key.
+    pub fn get_untouched(&mut self, key: &[u8]) -> Option<&DBEntry> {
+        self.expire_if_needed(key);
+        self.data.get(key)
+    }
+
+    /// Looks up a live entry without deleting it if it has expired, for commands that need to read
+    /// several keys at once. Hash fields that have expired are still there.
+    pub fn peek(&self, key: &[u8]) -> Option<&DBEntry> {
+        self.data.get(key).filter(|entry| !entry.is_expired())
+    }
+
+    /// Looks up a live entry to change it. Whatever the caller changes, it counts with
+    /// [`Rdb::mark_dirty`].
+    pub fn get_mut(&mut self, key: &[u8]) -> Option<&mut DBEntry> {
+        self.expire_if_needed(key);
+        let entry = self.data.get_mut(key)?;
+        entry.accessed_at = Instant::now();
+        Some(entry)
+    }
+
+    /// Looks up a live entry, first creating one without an expiry that holds `default()` if
+    /// there isn't one. This is how write commands create the collection they add to, counting
+    /// what they add with [`Rdb::mark_dirty`].
+    pub fn get_or_insert_with(
+        &mut self,
+        key: &Bytes,
+        default: impl FnOnce() -> Value,
+    ) -> &mut DBEntry {
+        if !self.contains_key(key) {
+            self.link(key.clone(), DBEntry::new(default(), None));
+        }
+        let entry = self.data.get_mut(key.as_ref()).unwrap();
+        entry.accessed_at = Instant::now();
+        entry
+    }
+
+    pub fn contains_key(&mut self, key: &[u8]) -> bool {
+        self.get_untouched(key).is_some()
+    }
+
+    pub fn insert(&mut self, key: Bytes, entry: DBEntry) {
+        self.link(key, entry);
+        self.dirty += 1;
+    }
+
+    /// Removes a key, returning its entry if it was live.
+    pub fn remove(&mut self, key: &[u8]) -> Option<DBEntry> {
+        self.expire_if_needed(key);
+        let entry = self.unlink(key)?;
+        self.dirty += 1;
+        Some(entry)
+    }
+
+    /// Removes a key holding a collection that a command has just emptied, since Redis never
+    /// keeps empty collections around. The command's count of what it removed covers this.
+    pub fn remove_if_empty(&mut self, key: &[u8]) {
+        if self
+            .data
+            .get(key)
+            .is_some_and(|entry| entry.value.is_empty())
+        {
+            self.unlink(key);
+        }
+    }
+
+    /// Changes when a live key expires, returning whether there was such a key.
+    pub fn set_expiry(&mut self, key: &[u8], expires_at: Option<SystemTime>) -> bool {
+        self.expire_if_needed(key);
+        let Some((key, _)) = self.data.get_key_value(key) else {
+            return false;
+        };
+        let key = key.clone();
+
+        if expires_at.is_some() {
+            self.expires.insert(key.clone());
+        } else {
+            self.expires.remove(&key);
+        }
+        if let Some(entry) = self.data.get_mut(&key) {
+            entry.expires_at = expires_at;
+        }
+        self.dirty += 1;
+        true
+    }
+
+    /// How many writes there have been since the last successful save.
+    pub fn dirty(&self) -> u64 {
+        self.dirty
+    }
+
+    /// Counts changes a command made to a value in place, such as members added to a set. Whole
+    /// keys being inserted, removed or given an expiry are counted as they happen.
+    pub fn mark_dirty(&mut self, changes: usize) {
+        self.dirty += changes as u64;
+    }
+
+    /// Records that a save has captured the first `saved` writes counted by [`Rdb::dirty`], leaving
+    /// any made since the save started.
+    pub fn mark_saved(&mut self, saved: u64) {
+        self.dirty = self.dirty.saturating_sub(saved);
+    }
+
+    /// The auxiliary fields read from the RDB file, such as the version of Redis that wrote it.
+    pub fn metadata(&self) -> &HashMap<String, String> {
+        &self.metadata
+    }
+
+    /// Every key that is still live along with its entry, without deleting any expired ones.
+    pub fn live_entries(&self) -> impl Iterator<Item = (&Bytes, &DBEntry)> + Clone {
+        self.data.iter().filter(|(_, entry)| !entry.is_expired())
+    }
+
+    /// Every key that is still live, without deleting any expired ones along the way.
+    pub fn live_keys(&self) -> impl Iterator<Item = &Bytes> {
+        self.data
+            .iter()
+            .filter(|(_, entry)| !entry.is_expired())
+            .map(|(key, _)| key)
+    }
+
+    /// Visits the next `count` keys from `cursor`, returning the cursor to continue from (zero once
+    /// every key has been visited) and the keys visited, which may include expired ones.
+    ///
+    /// Keys are visited in order of a hash of the key, and the cursor is the hash to resume from.
+    /// That way every key present for the whole of an iteration is returned exactly once, however
+    /// the keyspace changes in the meantime.
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<Bytes>) {
+        self.scan_index.scan(cursor, count)
+    }
+
+    /// A live key picked at random, deleting any expired ones it comes across first.
+    pub fn random_key(&mut self) -> Option<Bytes> {
+        loop {
+            let key = self.scan_index.random()?;
+            if self.get_untouched(&key).is_some() {
+                return Some(key);
+            }
+        }
+    }
+
+    /// The number of keys, including any that have expired but not yet been deleted.
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// The number of keys with an expiry set.
+    pub fn expires_len(&self) -> usize {
+        self.expires.len()
+    }
+
+    /// The number of keys holding a hash with fields that have an expiry set.
+    pub fn field_expires_len(&self) -> usize {
+        self.field_expires
+            .iter()
+            .filter(|key| has_expiring_fields(&self.data[*key]))
+            .count()
+    }
+
+    /// Records that the hash at `key` has been given field expiries, so the active expiry cycle
+    /// sweeps it.
+    pub fn track_field_expiries(&mut self, key: &Bytes) {
+        if self.data.get(key).is_some_and(has_expiring_fields) {
+            self.field_expires.insert(key.clone());
+        }
+    }
+
+    pub fn expiry_stats(&self) -> &ExpiryStats {
+        &self.expiry_stats
+    }
+
+    pub fn expiry_stats_mut(&mut self) -> &mut ExpiryStats {
+        &mut self.expiry_stats
+    }
+
+    /// Picks up to `count` keys at random from those with an expiry set, deleting any that have
+    /// expired.
+    pub fn expire_sample(&mut self, count: usize) -> ExpireSample {
+        let mut sample = ExpireSample::default();
+        let now = SystemTime::now();
+
+        for _ in 0..count.min(self.expires.len()) {
+            let Some(key) = self.expires.random().cloned() else {
+                break;
+            };
+            sample.sampled += 1;
+
+            match self.data.get(&key).and_then(DBEntry::expires_at) {
+                Some(expiry) if expiry < now => {
+                    self.delete_expired(&key);
+                    sample.expired += 1;
+                }
+                Some(expiry) => {
+                    // Expiry times can be as far off as the commands allow, so a few can add up
+                    // to more than fits.
+                    let ttl = unix_millis(expiry) - unix_millis(now);
+                    sample.ttl_sum = sample.ttl_sum.saturating_add(ttl);
+                }
+                None => {}
+            }
+        }
+
+        sample
+    }
+
+    /// Picks up to `count` hashes at random from those with field expiries, deleting any of their
+    /// fields that have expired, and returns how many fields that was.
+    pub fn expire_fields_sample(&mut self, count: usize) -> usize {
+        let now = SystemTime::now();
+        let mut expired = 0;
+
+        for _ in 0..count.min(self.field_expires.len()) {
+            let Some(key) = self.field_expires.random().cloned() else {
+                break;
+            };
+            let Some(Value::Hash(hash)) = self.data.get_mut(&key).map(DBEntry::value_mut) else {
+                self.field_expires.remove(&key);
+                continue;
+            };
+
+            let removed = hash.remove_expired(now);
+            let (empty, done) = (hash.is_empty(), !hash.has_expiring_fields());
+            expired += removed;
+            self.expiry_stats.expired_subkeys += removed as u64;
+            if empty {
+                self.unlink(&key);
+            } else if done {
+                self.field_expires.remove(&key);
+            }
+        }
+
+        expired
+    }
+
+    // Deletes the key if it has expired, or otherwise any hash fields of it that have. A hash left
+    // with no fields goes too, though it doesn't count as an expired key.
+    fn expire_if_needed(&mut self, key: &[u8]) {
+        let Some(entry) = self.data.get_mut(key) else {
+            return;
+        };
+        if entry.is_expired() {
+            self.delete_expired(key);
+            return;
+        }
+
+        if let Value::Hash(hash) = &mut entry.value {
+            let expired = hash.remove_expired(SystemTime::now());
+            self.expiry_stats.expired_subkeys += expired as u64;
+            if hash.is_empty() {
+                self.unlink(key);
+            }
+        }
+    }
+
+    fn delete_expired(&mut self, key: &[u8]) {
+        if self.unlink(key).is_some() {
+            self.expiry_stats.expired_keys += 1;
+        }
+    }
+
+    // Stores an entry and its index entries without counting it as a write.
+    fn link(&mut self, key: Bytes, entry: DBEntry) {
+        if entry.expires_at.is_some() {
+            self.expires.insert(key.clone());
+        } else {
+            self.expires.remove(&key);
+        }
+        if has_expiring_fields(&entry) {
+            self.field_expires.insert(key.clone());
+        } else {
+            self.field_expires.remove(&key);
+        }
+        if !self.data.contains_key(&key) {
+            self.scan_index.insert(key.clone());
+        }
+        self.data.insert(key, entry);
+    }
+
+    // Removes a key and its index entries, whether or not it has expired.
+    fn unlink(&mut self, key: &[u8]) -> Option<DBEntry> {
+        let (key, entry) = self.data.remove_entry(key)?;
+        self.expires.remove(&key);
+        self.field_expires.remove(&key);
+        self.scan_index.remove(key);
+        Some(entry)
     }
 }
 
+fn has_expiring_fields(entry: &DBEntry) -> bool {
+    matches!(&entry.value, Value::Hash(hash) if hash.has_expiring_fields())
+}
+
 pub fn load_db() -> Result<Rdb> {
-    let mut db_data = Rdb::default();
     let config = crate::args();
     let path = format!("{}/{}", config.directory, config.dbfilename);
 
@@ -55,10 +611,21 @@ pub fn load_db() -> Result<Rdb> {
             "No RDB file found at {}. Starting with empty database.",
             path
         );
-        return Ok(db_data);
+        return Ok(Rdb::default());
     }
 
-    let mut file = File::open(path)?;
+    let file = BufReader::new(File::open(path)?);
+    read_rdb(file, !config.rdb_skip_checksum)
+}
+
+/// Reads a whole RDB file, checking it against the checksum at the end if `verify_checksum` is
+/// set.
+fn read_rdb(file: impl Read, verify_checksum: bool) -> Result<Rdb> {
+    let mut db_data = Rdb::default();
+    let mut file = ChecksumReader {
+        inner: file,
+        crc: 0,
+    };
 
     // Fetch the header section. This should be the magic string "REDIS" followed by a four-digit version number.
     let mut buf = [0; 9];
@@ -72,159 +639,200 @@ pub fn load_db() -> Result<Rdb> {
     }
 
     // Begin iterating sections
+    // The expiry and idle time of the next key come in sections of their own just before it.
+    let mut expiry = None;
+    let mut idle = None;
     loop {
         let mut buf = [0; 1];
         file.read_exact(&mut buf)?;
 
-        if buf[0] == 0xFA {
-            // Fetch the metadata section
-            // FA                             // Indicates the start of a metadata subsection.
-            // 09 72 65 64 69 73 2D 76 65 72  // The name of the metadata attribute (string encoded): "redis-ver".
-            // 06 36 2E 30 2E 31 36           // The value of the metadata attribute (string encoded): "6.0.16".
-            //
-            // There may be zero or more metadata subsections.
-            // Each subsection starts with the byte 0xFA and is followed by a null-terminated string that represents the name of the metadata attribute.
-            // The value of the attribute is also a null-terminated string.
-            // The metadata section is terminated by a null byte.
-            if cfg!(debug_assertions) {
-                println!("Found metadata section");
-            }
-
-            let mut buf = [0; 1];
-            file.read_exact(&mut buf)?;
-            let key = extract_value(buf[0], &mut file, LengthEncodedKind::String)?;
+        match buf[0] {
+            OPCODE_AUX => {
+                // Fetch the metadata section
+                // FA                             // Indicates the start of a metadata subsection.
+                // 09 72 65 64 69 73 2D 76 65 72  // The name of the metadata attribute (string encoded): "redis-ver".
+                // 06 36 2E 30 2E 31 36           // The value of the metadata attribute (string encoded): "6.0.16".
+                //
+                // There may be zero or more metadata subsections.
+                // Each subsection starts with the byte 0xFA and is followed by a null-terminated string that represents the name of the metadata attribute.
+                // The value of the attribute is also a null-terminated string.
+                // The metadata section is terminated by a null byte.
+                if cfg!(debug_assertions) {
+                    println!("Found metadata section");
+                }
 
-            // Read the next byte, which should be the length of the value
-            file.read_exact(&mut buf)?;
-            let value = extract_value(buf[0], &mut file, LengthEncodedKind::String)?;
+                let key = read_string(&mut file)?;
+                let value = read_string(&mut file)?;
 
-            db_data.metadata.insert(key, value);
-        } else if buf[0] == 0xFE {
-            // Fetch the database selector section
-            // FE <db>, where db is a variable-length integer that represents the selected database.
-            if cfg!(debug_assertions) {
-                println!("Found database selector section");
+                db_data.metadata.insert(
+                    String::from_utf8_lossy(&key).into_owned(),
+                    String::from_utf8_lossy(&value).into_owned(),
+                );
             }
-            let selected_db = {
+            OPCODE_SELECTDB => {
+                // Fetch the database selector section
+                // FE <db>, where db is a variable-length integer that represents the selected database.
+                if cfg!(debug_assertions) {
+                    println!("Found database selector section");
+                }
+                let selected_db = {
+                    let mut buf = [0; 1];
+                    file.read_exact(&mut buf)?;
+                    extract_value(buf[0], &mut file, LengthEncodedKind::Integer)?
+                };
+                db_data.selected_db = str::from_utf8(&selected_db)?.parse().unwrap_or(0);
+            }
+            OPCODE_RESIZEDB => {
+                // Fetch the resize database section
+                // FB <db-size> <expires-size>
+                // db-size is the size of the hash table for the key-value pairs (i.e. the number of entries in the DB).
+                // expires-size is the size of the hash table for the expiry times (i.e. the number of entries in the expiry set).
+
+                if cfg!(debug_assertions) {
+                    println!("Found resize database section");
+                }
+
                 let mut buf = [0; 1];
+
                 file.read_exact(&mut buf)?;
-                extract_value(buf[0], &mut file, LengthEncodedKind::Integer)?
-            };
-            db_data.selected_db = selected_db.parse().unwrap_or(0);
-        } else if buf[0] == 0xFB {
-            // Fetch the resize database section
-            // FB <db-size> <expires-size>
-            // db-size is the size of the hash table for the key-value pairs (i.e. the number of entries in the DB).
-            // expires-size is the size of the hash table for the expiry times (i.e. the number of entries in the expiry set).
+                if cfg!(debug_assertions) {
+                    println!("DB size length: {}", buf[0]);
+                }
+                let db_size = extract_value(buf[0], &mut file, LengthEncodedKind::Integer)?;
+                db_data.db_hash_table_size = str::from_utf8(&db_size)?.parse().unwrap_or(0);
 
-            if cfg!(debug_assertions) {
-                println!("Found resize database section");
-            }
+                if cfg!(debug_assertions) {
+                    println!("Database size: {}", db_data.db_hash_table_size);
+                }
 
-            let mut buf = [0; 1];
+                file.read_exact(&mut buf)?;
+                let expires_size = extract_value(buf[0], &mut file, LengthEncodedKind::Integer)?;
+                db_data.expiry_hash_table_size =
+                    str::from_utf8(&expires_size)?.parse().unwrap_or(0);
 
-            file.read_exact(&mut buf)?;
-            if cfg!(debug_assertions) {
-                println!("DB size length: {}", buf[0]);
+                if cfg!(debug_assertions) {
+                    println!("Expiry size: {}", db_data.expiry_hash_table_size);
+                }
             }
-            let db_size = extract_value(buf[0], &mut file, LengthEncodedKind::Integer)?;
-            db_data.db_hash_table_size = db_size.parse().unwrap_or(0);
-
-            if cfg!(debug_assertions) {
-                println!("Database size: {}", db_size);
+            OPCODE_EXPIRETIME => {
+                if cfg!(debug_assertions) {
+                    println!("Found expiry section in seconds");
+                }
+                let mut buf = [0; 4];
+                file.read_exact(&mut buf)?;
+                let secs = u32::from_le_bytes(buf);
+                expiry =
+                    (secs != 0).then(|| SystemTime::UNIX_EPOCH + Duration::from_secs(secs as u64));
             }
-
-            file.read_exact(&mut buf)?;
-            let expires_size = extract_value(buf[0], &mut file, LengthEncodedKind::Integer)?;
-            db_data.expiry_hash_table_size = expires_size.parse().unwrap_or(0);
-
-            if cfg!(debug_assertions) {
-                println!("Expiry size: {}", expires_size);
+            OPCODE_EXPIRETIME_MS => {
+                if cfg!(debug_assertions) {
+                    println!("Found expiry section in milliseconds");
+                }
+                let mut buf = [0; 8];
+                file.read_exact(&mut buf)?;
+                let millis = u64::from_le_bytes(buf);
+                expiry =
+                    (millis != 0).then(|| SystemTime::UNIX_EPOCH + Duration::from_millis(millis));
             }
-        } else if buf[0] == 0xFF {
-            // Fetch the end of file checksum section
-            // FF <checksum>
-            // checksum is an 8-byte integer that represents the CRC checksum of the entire RDB file.
-            // NOTE: Redis does not use the standard CRC64-ECMA or ISO, but a special "Jones" variant instead.
-            if cfg!(debug_assertions) {
-                println!("Found end of file checksum section");
+            OPCODE_IDLE => {
+                // How long the next key had gone unused, in seconds.
+                idle = Some(Duration::from_secs(read_length(&mut file)? as u64));
             }
-
-            let mut buf = [0; 8];
-            file.read_exact(&mut buf)?;
-            let checksum = u64::from_le_bytes(buf);
-            db_data.original_checksum = checksum;
-
-            break;
-        } else {
-            if cfg!(debug_assertions) {
-                println!("Found data section");
+            OPCODE_FREQ => {
+                // How often the next key is used, for LFU eviction, which isn't tracked here.
+                file.read_exact(&mut buf)?;
             }
-
-            let expiry = match buf[0] {
-                0xFD => {
-                    if cfg!(debug_assertions) {
-                        println!("Found expiry section in milliseconds");
-                    }
-                    let mut buf = [0; 4];
-                    file.read_exact(&mut buf)?;
-                    let expiry = u32::from_le_bytes(buf);
-                    if expiry == 0 {
-                        None
-                    } else {
-                        Some(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(expiry as u64))
-                    }
+            OPCODE_SLOT_INFO => {
+                // The slot, its size and how many keys in it expire, which only matter to a
+                // cluster.
+                for _ in 0..3 {
+                    read_length(&mut file)?;
                 }
-                0xFC => {
-                    if cfg!(debug_assertions) {
-                        println!("Found expiry section in seconds");
-                    }
-                    let mut buf = [0; 8];
-                    file.read_exact(&mut buf)?;
-                    let expiry = u64::from_le_bytes(buf);
-                    if expiry == 0 {
-                        None
-                    } else {
-                        Some(SystemTime::UNIX_EPOCH + std::time::Duration::from_millis(expiry))
-                    }
+            }
+            OPCODE_MODULE_AUX => {
+                // Data a module saves about itself rather than any key. With no modules to hand it
+                // to, there's nothing to do with it.
+                let id = read_length(&mut file)? as u64;
+                let when_opcode = read_length(&mut file)?;
+                if when_opcode != MODULE_OPCODE_UINT {
+                    bail!("Bad when opcode {} in module aux data", when_opcode);
+                }
+                read_length(&mut file)?;
+                skip_module_value(&mut file)?;
+                println!(
+                    "Skipping aux data of module '{}', which isn't loaded",
+                    module_name(id)
+                );
+            }
+            OPCODE_FUNCTION2 => {
+                // The code of a function library, which is saved as the FUNCTION LOAD that creates
+                // it. Functions aren't supported here.
+                read_string(&mut file)?;
+                println!("Skipping function library, as functions aren't supported");
+            }
+            OPCODE_FUNCTION_PRE_GA => {
+                bail!("Pre-release function format not supported");
+            }
+            OPCODE_EOF => {
+                // Fetch the end of file checksum section
+                // FF <checksum>
+                // checksum is an 8-byte integer that represents the CRC checksum of the entire RDB file.
+                // NOTE: Redis does not use the standard CRC64-ECMA or ISO, but a special "Jones" variant instead.
+                if cfg!(debug_assertions) {
+                    println!("Found end of file checksum section");
                 }
-                _ => None,
-            };
 
-            if buf[0] == 0xFC || buf[0] == 0xFD {
+                let computed = file.crc;
+                let mut buf = [0; 8];
                 file.read_exact(&mut buf)?;
-            }
+                let checksum = u64::from_le_bytes(buf);
+                db_data.original_checksum = checksum;
+
+                // Files written with checksums turned off have zero there, and ones older than
+                // version 5 have no checksum at all.
+                let version: u32 = db_data.version.parse().unwrap_or(0);
+                if verify_checksum && version >= 5 && checksum != 0 && checksum != computed {
+                    bail!(
+                        "Wrong RDB checksum expected: ({:x}) got ({:x})",
+                        checksum,
+                        computed
+                    );
+                }
 
-            let data_type = extract_datatype(buf[0]);
-            if cfg!(debug_assertions) {
-                println!("Data type: {}", data_type);
+                break;
             }
+            object_type => {
+                if cfg!(debug_assertions) {
+                    println!("Found data section");
+                    println!("Data type: {}", extract_datatype(object_type));
+                }
 
-            let key = {
-                let key_start_byte = {
-                    file.read_exact(&mut buf)?;
-                    buf[0]
-                };
+                let key = read_string(&mut file)?;
 
-                extract_value(key_start_byte, &mut file, LengthEncodedKind::String)?
-            };
+                if cfg!(debug_assertions) {
+                    println!("Key: {:?}", key);
+                }
 
-            if cfg!(debug_assertions) {
-                println!("Key: {}", key);
-            }
+                let value = read_object(object_type, &mut file)?;
 
-            let value = {
-                file.read_exact(&mut buf)?;
-                let value = extract_value(buf[0], &mut file, LengthEncodedKind::String)?;
-                // TODO: not everything is a string, this needs correcting
-                RESPValue::SimpleString(value)
-            };
+                if cfg!(debug_assertions) {
+                    println!("Value: {:?}", value);
+                }
 
-            if cfg!(debug_assertions) {
-                println!("Value: {}", value);
+                // Collections can come out empty if every hash field had expired, and Redis skips
+                // those rather than creating a key with nothing in it.
+                let (expiry, idle) = (expiry.take(), idle.take());
+                if value.is_empty() {
+                    continue;
+                }
+                let mut entry = DBEntry::new(value, expiry);
+                if let Some(accessed_at) = idle.and_then(|idle| entry.accessed_at.checked_sub(idle))
+                {
+                    entry.accessed_at = accessed_at;
+                }
+                // What was loaded is already saved, so it isn't a change.
+                db_data.link(key, entry);
             }
-
-            db_data.data.insert(key, DBEntry::new(value, expiry));
         }
     }
     if cfg!(debug_assertions) {
@@ -234,34 +842,488 @@ pub fn load_db() -> Result<Rdb> {
     Ok(db_data)
 }
 
-// 0 = String Encoding
-// 1 = List Encoding
-// 2 = Set Encoding
-// 3 = Sorted Set Encoding
-// 4 = Hash Encoding
-// 9 = Zipmap Encoding
-// 10 = Ziplist Encoding
-// 11 = Intset Encoding
-// 12 = Sorted Set in Ziplist Encoding
-// 13 = Hashmap in Ziplist Encoding (Introduced in RDB version 4)
-// 14 = List in Quicklist encoding (Introduced in RDB version 7)
 fn extract_datatype(byte: u8) -> &'static str {
     match byte {
-        0 => "String Encoding",
-        1 => "List Encoding",
-        2 => "Set Encoding",
-        3 => "Sorted Set Encoding",
-        4 => "Hash Encoding",
-        9 => "Zipmap Encoding",
-        10 => "Ziplist Encoding",
-        11 => "Intset Encoding",
-        12 => "Sorted Set in Ziplist Encoding",
-        13 => "Hashmap in Ziplist Encoding",
-        14 => "List in Quicklist encoding",
+        TYPE_STRING => "String Encoding",
+        TYPE_LIST => "List Encoding",
+        TYPE_SET => "Set Encoding",
+        TYPE_ZSET => "Sorted Set Encoding",
+        TYPE_HASH => "Hash Encoding",
+        TYPE_ZSET_2 => "Sorted Set with Binary Scores Encoding",
+        TYPE_MODULE_2 => "Module Encoding",
+        TYPE_ZIPMAP => "Zipmap Encoding",
+        TYPE_LIST_ZIPLIST => "Ziplist Encoding",
+        TYPE_INTSET => "Intset Encoding",
+        TYPE_ZSET_ZIPLIST => "Sorted Set in Ziplist Encoding",
+        TYPE_HASH_ZIPLIST => "Hashmap in Ziplist Encoding",
+        TYPE_QUICKLIST => "List in Quicklist encoding",
+        TYPE_STREAM_LISTPACKS | TYPE_STREAM_LISTPACKS_2 | TYPE_STREAM_LISTPACKS_3 => {
+            "Stream in Listpacks Encoding"
+        }
+        TYPE_HASH_LISTPACK => "Hashmap in Listpack Encoding",
+        TYPE_ZSET_LISTPACK => "Sorted Set in Listpack Encoding",
+        TYPE_QUICKLIST_2 => "List in Quicklist of Listpacks Encoding",
+        TYPE_SET_LISTPACK => "Set in Listpack Encoding",
+        TYPE_HASH_METADATA_PRE_GA | TYPE_HASH_METADATA => "Hashmap with Field Expiries Encoding",
+        TYPE_HASH_LISTPACK_EX_PRE_GA | TYPE_HASH_LISTPACK_EX => {
+            "Hashmap with Field Expiries in Listpack Encoding"
+        }
         _ => "Unknown",
     }
 }
 
+/// Reads a value of the given type, in whichever of the encodings Redis has used for it over the
+/// versions, into the type commands work on.
+fn read_object(object_type: u8, file: &mut impl Read) -> Result<Value> {
+    let value = match object_type {
+        TYPE_STRING => Value::String(read_string(file)?),
+        TYPE_LIST => Value::List(read_strings(file)?.into()),
+        TYPE_SET => Value::Set(read_strings(file)?.into_iter().collect()),
+        TYPE_ZSET | TYPE_ZSET_2 => {
+            let len = read_length(file)?;
+            let mut entries = Vec::new();
+            for _ in 0..len {
+                let member = read_string(file)?;
+                let score = if object_type == TYPE_ZSET_2 {
+                    let mut buf = [0; 8];
+                    file.read_exact(&mut buf)?;
+                    f64::from_le_bytes(buf)
+                } else {
+                    read_double(file)?
+                };
+                entries.push((member, score));
+            }
+            sorted_set(entries)?
+        }
+        TYPE_HASH => {
+            let len = read_length(file)?;
+            let mut fields = Vec::new();
+            for _ in 0..len {
+                fields.push((read_string(file)?, read_string(file)?, None));
+            }
+            hash(fields)
+        }
+        TYPE_HASH_METADATA_PRE_GA | TYPE_HASH_METADATA => {
+            // Field expiry times are absolute in the older format, and relative to the soonest of
+            // them in the newer one. Either way zero means the field doesn't expire.
+            let min_expiry = if object_type == TYPE_HASH_METADATA {
+                Some(read_millis(file)? as u64)
+            } else {
+                None
+            };
+            let len = read_length(file)?;
+            let mut fields = Vec::new();
+            for _ in 0..len {
+                let expires_at = match (read_length(file)? as u64, min_expiry) {
+                    (0, _) => None,
+                    (ttl, Some(min_expiry)) => Some(from_unix_millis(min_expiry + ttl - 1)),
+                    (expiry, None) => Some(from_unix_millis(expiry)),
+                };
+                fields.push((read_string(file)?, read_string(file)?, expires_at));
+            }
+            hash(fields)
+        }
+        TYPE_ZIPMAP => {
+            let Some(pairs) = ziplist::read_zipmap(&read_string(file)?) else {
+                bail!("Invalid zipmap");
+            };
+            hash(pairs.into_iter().map(|(field, value)| (field, value, None)))
+        }
+        TYPE_LIST_ZIPLIST => Value::List(read_ziplist(file)?.into()),
+        TYPE_INTSET => {
+            let Some(members) = read_intset(&read_string(file)?) else {
+                bail!("Invalid intset");
+            };
+            Value::Set(members.into_iter().collect())
+        }
+        TYPE_SET_LISTPACK => Value::Set(read_listpack(file)?.into_iter().collect()),
+        TYPE_ZSET_ZIPLIST | TYPE_ZSET_LISTPACK => {
+            let elements = if object_type == TYPE_ZSET_ZIPLIST {
+                read_ziplist(file)?
+            } else {
+                read_listpack(file)?
+            };
+            let mut entries = Vec::new();
+            for (member, score) in pairs(elements)? {
+                entries.push((member, str::from_utf8(&score)?.parse()?));
+            }
+            sorted_set(entries)?
+        }
+        TYPE_HASH_ZIPLIST | TYPE_HASH_LISTPACK => {
+            let elements = if object_type == TYPE_HASH_ZIPLIST {
+                read_ziplist(file)?
+            } else {
+                read_listpack(file)?
+            };
+            hash(
+                pairs(elements)?
+                    .into_iter()
+                    .map(|(field, value)| (field, value, None)),
+            )
+        }
+        TYPE_HASH_LISTPACK_EX_PRE_GA | TYPE_HASH_LISTPACK_EX => {
+            // The newer format starts with the soonest expiry, which the fields' own make
+            // redundant here.
+            if object_type == TYPE_HASH_LISTPACK_EX {
+                read_millis(file)?;
+            }
+            let elements = read_listpack(file)?;
+            let triplets = elements.chunks_exact(3);
+            if !triplets.remainder().is_empty() {
+                bail!("Hash listpack with a field missing its value or expiry");
+            }
+            let mut fields = Vec::new();
+            for triplet in triplets {
+                let expiry: u64 = str::from_utf8(&triplet[2])?.parse()?;
+                let expires_at = (expiry != 0).then(|| from_unix_millis(expiry));
+                fields.push((triplet[0].clone(), triplet[1].clone(), expires_at));
+            }
+            hash(fields)
+        }
+        TYPE_QUICKLIST => {
+            let nodes = read_length(file)?;
+            let mut list = VecDeque::new();
+            for _ in 0..nodes {
+                list.extend(read_ziplist(file)?);
+            }
+            Value::List(list)
+        }
+        TYPE_QUICKLIST_2 => {
+            let nodes = read_length(file)?;
+            let mut list = VecDeque::new();
+            for _ in 0..nodes {
+                match read_length(file)? {
+                    QUICKLIST_NODE_PLAIN => list.push_back(read_string(file)?),
+                    QUICKLIST_NODE_PACKED => list.extend(read_listpack(file)?),
+                    container => bail!("Unknown quicklist node container {}", container),
+                }
+            }
+            Value::List(list)
+        }
+        TYPE_STREAM_LISTPACKS | TYPE_STREAM_LISTPACKS_2 | TYPE_STREAM_LISTPACKS_3 => {
+            Value::Stream(read_stream(object_type, file)?)
+        }
+        TYPE_MODULE_2 => {
+            let id = read_length(file)? as u64;
+            bail!(
+                "The RDB file contains module data I can't load: no matching module '{}'",
+                module_name(id)
+            );
+        }
+        _ => bail!("Unknown RDB encoding type {}", object_type),
+    };
+    Ok(value)
+}
+
+fn read_string(file: &mut impl Read) -> Result<Bytes> {
+    let mut buf = [0; 1];
+    file.read_exact(&mut buf)?;
+    extract_value(buf[0], file, LengthEncodedKind::String)
+}
+
+// Reads a count, then that many strings.
+fn read_strings(file: &mut impl Read) -> Result<Vec<Bytes>> {
+    let len = read_length(file)?;
+    let mut strings = Vec::new();
+    for _ in 0..len {
+        strings.push(read_string(file)?);
+    }
+    Ok(strings)
+}
+
+fn read_ziplist(file: &mut impl Read) -> Result<Vec<Bytes>> {
+    match ziplist::read(&read_string(file)?) {
+        Some(elements) => Ok(elements),
+        None => bail!("Invalid ziplist"),
+    }
+}
+
+fn read_listpack(file: &mut impl Read) -> Result<Vec<Bytes>> {
+    match listpack::read(&read_string(file)?) {
+        Some(elements) => Ok(elements),
+        None => bail!("Invalid listpack"),
+    }
+}
+
+// Milliseconds since the Unix epoch, as eight little-endian bytes.
+fn read_millis(file: &mut impl Read) -> Result<i64> {
+    let mut buf = [0; 8];
+    file.read_exact(&mut buf)?;
+    Ok(i64::from_le_bytes(buf))
+}
+
+fn from_unix_millis(millis: u64) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_millis(millis)
+}
+
+// A score as the oldest sorted set encoding writes it: as text, after a length byte, with the
+// lengths no text could have standing for NaN and the infinities.
+fn read_double(file: &mut impl Read) -> Result<f64> {
+    let mut buf = [0; 1];
+    file.read_exact(&mut buf)?;
+    match buf[0] {
+        253 => Ok(f64::NAN),
+        254 => Ok(f64::INFINITY),
+        255 => Ok(f64::NEG_INFINITY),
+        len => {
+            let mut text = vec![0; len as usize];
+            file.read_exact(&mut text)?;
+            Ok(str::from_utf8(&text)?.parse()?)
+        }
+    }
+}
+
+// Splits the elements of a ziplist or listpack holding a map into its pairs.
+fn pairs(elements: Vec<Bytes>) -> Result<Vec<(Bytes, Bytes)>> {
+    let chunks = elements.chunks_exact(2);
+    if !chunks.remainder().is_empty() {
+        bail!("Odd number of elements in a map encoding");
+    }
+    Ok(chunks
+        .map(|pair| (pair[0].clone(), pair[1].clone()))
+        .collect())
+}
+
+fn sorted_set(entries: Vec<(Bytes, f64)>) -> Result<Value> {
+    if entries.iter().any(|(_, score)| score.is_nan()) {
+        bail!("Sorted set with a NaN score");
+    }
+    Ok(Value::SortedSet(entries.into_iter().collect()))
+}
+
+// Builds a hash from its fields, each with when it expires if it does. Fields that expired while
+// the file sat on disk are dropped straight away.
+fn hash(fields: impl IntoIterator<Item = (Bytes, Bytes, Option<SystemTime>)>) -> Value {
+    let mut hash = Hash::default();
+    for (field, value, expires_at) in fields {
+        hash.insert(field.clone(), value, false);
+        if expires_at.is_some() {
+            hash.set_expiry(&field, expires_at);
+        }
+    }
+    hash.remove_expired(SystemTime::now());
+    Value::Hash(hash)
+}
+
+// The integers of an intset: how many bytes each takes, how many there are, and then the integers
+// themselves, all little-endian.
+fn read_intset(intset: &[u8]) -> Option<Vec<Bytes>> {
+    let width = u32::from_le_bytes(intset.get(..4)?.try_into().ok()?) as usize;
+    let len = u32::from_le_bytes(intset.get(4..8)?.try_into().ok()?) as usize;
+    if !matches!(width, 2 | 4 | 8) || intset.len() != 8 + width * len {
+        return None;
+    }
+    let members = intset[8..].chunks_exact(width).map(|bytes| {
+        let value = match width {
+            2 => i16::from_le_bytes(bytes.try_into().unwrap()) as i64,
+            4 => i32::from_le_bytes(bytes.try_into().unwrap()) as i64,
+            _ => i64::from_le_bytes(bytes.try_into().unwrap()),
+        };
+        Bytes::from(value.to_string())
+    });
+    Some(members.collect())
+}
+
+// A stream is its entries, in listpacks each keyed by the ID of its first entry, then its own
+// bookkeeping, then its consumer groups with their pending entries and consumers. Each version of
+// the format added to the bookkeeping.
+fn read_stream(object_type: u8, file: &mut impl Read) -> Result<Stream> {
+    let mut entries = BTreeMap::new();
+    let nodes = read_length(file)?;
+    for _ in 0..nodes {
+        let master_id = stream_id(&read_string(file)?)?;
+        let elements = read_listpack(file)?;
+        read_stream_listpack(master_id, elements, &mut entries)?;
+    }
+
+    read_length(file)?;
+    let last_id = read_stream_id_lengths(file)?;
+    let (max_deleted_id, entries_added) = if object_type >= TYPE_STREAM_LISTPACKS_2 {
+        // The first ID can be worked out from the entries.
+        read_stream_id_lengths(file)?;
+        let max_deleted_id = read_stream_id_lengths(file)?;
+        (max_deleted_id, read_length(file)? as u64)
+    } else {
+        (StreamId::MIN, entries.len() as u64)
+    };
+    let mut stream = Stream::restore(entries, last_id, max_deleted_id, entries_added);
+
+    let groups = read_length(file)?;
+    for _ in 0..groups {
+        let name = read_string(file)?;
+        let last_delivered = read_stream_id_lengths(file)?;
+        let entries_read = if object_type >= TYPE_STREAM_LISTPACKS_2 {
+            Some(read_length(file)? as u64).filter(|&read| read != u64::MAX)
+        } else {
+            None
+        };
+        let mut group = ConsumerGroup::new(last_delivered, entries_read);
+
+        // The group's pending entries come with the details, and each consumer's with just the
+        // IDs, every one of which should be in the group's.
+        let mut pending = BTreeMap::new();
+        for _ in 0..read_length(file)? {
+            let id = read_raw_stream_id(file)?;
+            let delivered_at = read_millis(file)?;
+            let deliveries = read_length(file)? as u64;
+            pending.insert(id, (delivered_at, deliveries));
+        }
+
+        for _ in 0..read_length(file)? {
+            let consumer = read_string(file)?;
+            let seen_at = read_millis(file)?;
+            let active_at = if object_type >= TYPE_STREAM_LISTPACKS_3 {
+                Some(read_millis(file)?).filter(|&active_at| active_at != -1)
+            } else {
+                Some(seen_at)
+            };
+            let mut owned = Vec::new();
+            for _ in 0..read_length(file)? {
+                let id = read_raw_stream_id(file)?;
+                let Some((delivered_at, deliveries)) = pending.remove(&id) else {
+                    bail!("Consumer pending entry {} not in its group's", id);
+                };
+                owned.push((id, delivered_at, deliveries));
+            }
+            group.restore_consumer(consumer, seen_at, active_at, owned);
+        }
+        if let Some(id) = pending.keys().next() {
+            bail!("Group pending entry {} has no consumer", id);
+        }
+        stream.restore_group(name, group);
+    }
+    Ok(stream)
+}
+
+// Adds the entries in one of a stream's listpacks. It starts with a master entry, holding the
+// number of entries, the number deleted and the field names of the first entry. Each entry then
+// has some flags, its ID as a difference from the master ID, its values, along with its field
+// names when they differ from the master entry's, and finally how many elements it took up.
+fn read_stream_listpack(
+    master_id: StreamId,
+    elements: Vec<Bytes>,
+    entries: &mut BTreeMap<StreamId, Fields>,
+) -> Result<()> {
+    let mut elements = elements.into_iter();
+    let mut next = || match elements.next() {
+        Some(element) => Ok(element),
+        None => bail!("Stream listpack ended early"),
+    };
+    let integer = |element: Bytes| -> Result<i64> { Ok(str::from_utf8(&element)?.parse()?) };
+
+    let count = integer(next()?)? + integer(next()?)?;
+    let mut master_fields = Vec::new();
+    for _ in 0..integer(next()?)? {
+        master_fields.push(next()?);
+    }
+    if integer(next()?)? != 0 {
+        bail!("Stream master entry not terminated");
+    }
+
+    for _ in 0..count {
+        let flags = integer(next()?)?;
+        let id = StreamId {
+            ms: master_id.ms.wrapping_add(integer(next()?)? as u64),
+            seq: master_id.seq.wrapping_add(integer(next()?)? as u64),
+        };
+        let mut fields = Vec::new();
+        if flags & STREAM_ITEM_FLAG_SAMEFIELDS != 0 {
+            for field in &master_fields {
+                fields.push((field.clone(), next()?));
+            }
+        } else {
+            for _ in 0..integer(next()?)? {
+                fields.push((next()?, next()?));
+            }
+        }
+        next()?;
+        if flags & STREAM_ITEM_FLAG_DELETED == 0 {
+            entries.insert(id, fields);
+        }
+    }
+    if next().is_ok() {
+        bail!("Stream listpack has more entries than its master entry says");
+    }
+    Ok(())
+}
+
+// A stream ID written as two lengths.
+fn read_stream_id_lengths(file: &mut impl Read) -> Result<StreamId> {
+    let ms = read_length(file)? as u64;
+    let seq = read_length(file)? as u64;
+    Ok(StreamId { ms, seq })
+}
+
+fn read_raw_stream_id(file: &mut impl Read) -> Result<StreamId> {
+    let mut raw = [0; 16];
+    file.read_exact(&mut raw)?;
+    stream_id(&raw)
+}
+
+// A stream ID in its binary form, big-endian milliseconds then sequence number.
+fn stream_id(raw: &[u8]) -> Result<StreamId> {
+    if raw.len() != 16 {
+        bail!("Stream ID {} bytes long", raw.len());
+    }
+    Ok(StreamId {
+        ms: u64::from_be_bytes(raw[..8].try_into().unwrap()),
+        seq: u64::from_be_bytes(raw[8..].try_into().unwrap()),
+    })
+}
+
+// Reads past a module's serialized value, which is a series of typed fields ending in an EOF
+// marker.
+fn skip_module_value(file: &mut impl Read) -> Result<()> {
+    loop {
+        match read_length(file)? {
+            MODULE_OPCODE_EOF => return Ok(()),
+            MODULE_OPCODE_SINT | MODULE_OPCODE_UINT => {
+                read_length(file)?;
+            }
+            MODULE_OPCODE_FLOAT => file.read_exact(&mut [0; 4])?,
+            MODULE_OPCODE_DOUBLE => file.read_exact(&mut [0; 8])?,
+            MODULE_OPCODE_STRING => {
+                read_string(file)?;
+            }
+            opcode => bail!("Unknown module value opcode {}", opcode),
+        }
+    }
+}
+
+// The name a module registered its type under, nine characters of six bits each which make up
+// the top of the type's ID. The rest is the version of its encoding.
+fn module_name(id: u64) -> String {
+    const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+    (0..9)
+        .map(|i| CHARSET[((id >> (58 - 6 * i)) & 0x3f) as usize] as char)
+        .collect()
+}
+
+// Reads a length, which unlike a string can't be in one of the special encodings.
+fn read_length(file: &mut impl Read) -> Result<usize> {
+    let mut buf = [0; 1];
+    file.read_exact(&mut buf)?;
+    if buf[0] & 0b11000000 == 0b11000000 {
+        bail!("Expected a length, found encoding {:#04x}", buf[0]);
+    }
+    let length = extract_value(buf[0], file, LengthEncodedKind::Integer)?;
+    Ok(str::from_utf8(&length)?.parse()?)
+}
+
+// Keeps a running checksum of everything read through it.
+struct ChecksumReader<R> {
+    inner: R,
+    crc: u64,
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.crc = crc64::update(self.crc, &buf[..read]);
+        Ok(read)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 enum LengthEncodedKind {
     Integer,
@@ -272,7 +1334,9 @@ enum LengthEncodedKind {
 //
 // 00 	The next 6 bits represent the length
 // 01 	Read one additional byte. The combined 14 bits represent the length
-// 10 	Discard the remaining 6 bits. The next 4 bytes from the stream represent the length
+// 10 	The remaining 6 bits say how long the length is:
+//   0 indicates that the next 4 bytes from the stream represent the length
+//   1 indicates that the next 8 bytes from the stream represent the length
 // 11 	The next object is encoded in a special format. The remaining 6 bits indicate the format:
 //   0 indicates that an 8 bit integer follows
 //   1 indicates that a 16 bit integer follows
@@ -282,52 +1346,55 @@ enum LengthEncodedKind {
 //     The uncompressed length is read from the stream using Length Encoding
 //     The next clen bytes are read from the stream
 //     Finally, these bytes are decompressed using LZF algorithm
-fn extract_value(byte: u8, file: &mut File, lek: LengthEncodedKind) -> Result<String> {
+fn extract_value(byte: u8, file: &mut impl Read, lek: LengthEncodedKind) -> Result<Bytes> {
     let nullified = byte & 0b11000000;
 
     match nullified {
         0b00000000 => {
             let remaining_bits = byte & 0b00111111;
             let length = remaining_bits as usize;
-            if length == 0 {
-                return Ok(String::from("0"));
-            }
             if lek == LengthEncodedKind::Integer {
-                return Ok(length.to_string());
+                return Ok(Bytes::from(length.to_string()));
             }
             let mut val = vec![0; length];
             file.read_exact(&mut val)?;
-            // In this specific case, we need to convert 0 bytes to '0' bytes
-            // because otherwise they get interpreted as string terminators
-            // rather than integer values.
-            val = val.iter().map(|&x| if x == 0 { b'0' } else { x }).collect();
-            Ok(String::from_utf8(val)?)
+            Ok(Bytes::from(val))
         }
         0b01000000 => {
             let remaining_bits = byte & 0b00111111;
             let mut buf = [0; 1];
             file.read_exact(&mut buf)?;
 
-            let length = u16::from_le_bytes([remaining_bits, buf[0]]) as usize;
+            let length = u16::from_be_bytes([remaining_bits, buf[0]]) as usize;
             if lek == LengthEncodedKind::Integer {
-                return Ok(length.to_string());
+                return Ok(Bytes::from(length.to_string()));
             }
             let mut val = vec![0; length];
             file.read_exact(&mut val)?;
-            Ok(String::from_utf8(val)?)
+            Ok(Bytes::from(val))
         }
         0b10000000 => {
-            let mut buf = [0; 4];
-            file.read_exact(&mut buf)?;
-            let length = u32::from_le_bytes(buf) as usize;
+            let length = match byte {
+                0x80 => {
+                    let mut buf = [0; 4];
+                    file.read_exact(&mut buf)?;
+                    u32::from_be_bytes(buf) as usize
+                }
+                0x81 => {
+                    let mut buf = [0; 8];
+                    file.read_exact(&mut buf)?;
+                    u64::from_be_bytes(buf) as usize
+                }
+                _ => bail!("Unknown length encoding {:#04x}", byte),
+            };
             if lek == LengthEncodedKind::Integer {
-                return Ok(length.to_string());
+                return Ok(Bytes::from(length.to_string()));
             }
             let mut val = vec![0; length];
             file.read_exact(&mut val)?;
-            Ok(String::from_utf8(val)?)
+            Ok(Bytes::from(val))
         }
-        0b11000000..=0b11000010 => {
+        0b11000000 if byte & 0b00111111 <= 2 => {
             let upcoming_bytes = match byte & 0b00111111 {
                 0 => 1,
                 1 => 2,
@@ -337,25 +1404,235 @@ fn extract_value(byte: u8, file: &mut File, lek: LengthEncodedKind) -> Result<St
             let mut buf = vec![0; upcoming_bytes];
             file.read_exact(&mut buf)?;
             let slice = &buf[0..upcoming_bytes];
+            // Integer-encoded strings are signed.
             let encoded = match upcoming_bytes {
-                1 => u8::from_le_bytes(slice.try_into().unwrap()) as usize,
-                2 => u16::from_le_bytes(slice.try_into().unwrap()) as usize,
-                4 => u32::from_le_bytes(slice.try_into().unwrap()) as usize,
+                1 => i8::from_le_bytes(slice.try_into().unwrap()) as i64,
+                2 => i16::from_le_bytes(slice.try_into().unwrap()) as i64,
+                4 => i32::from_le_bytes(slice.try_into().unwrap()) as i64,
                 _ => unreachable!(),
             };
-            Ok(encoded.to_string())
+            Ok(Bytes::from(encoded.to_string()))
         }
-        0b11000011 => {
-            let mut buf = [0; 1];
-            file.read_exact(&mut buf)?;
-            let clen = extract_value(buf[0], file, LengthEncodedKind::String)?.parse::<usize>()?;
-            //let ulen = extract_value(buf[0], file)?.parse::<usize>()?;
+        0b11000000 if byte & 0b00111111 == 3 => {
+            let clen = read_length(file)?;
+            let ulen = read_length(file)?;
             let mut compressed = vec![0; clen];
             file.read_exact(&mut compressed)?;
-            //let mut uncompressed = vec![0; ulen];
-            //lzf::decompress(&compressed, &mut uncompressed)?;
-            Ok(String::from_utf8(compressed)?)
+            match lzf::decompress(&compressed, ulen) {
+                Some(uncompressed) => Ok(Bytes::from(uncompressed)),
+                None => bail!("Invalid LZF compressed string"),
+            }
         }
-        _ => unreachable!(),
+        _ => bail!("Unknown string encoding {:#04x}", byte),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expire_sample_far_future() {
+        let mut db = Rdb::default();
+        let expiry = SystemTime::UNIX_EPOCH + Duration::from_millis(i64::MAX as u64);
+        for key in ["a", "b"] {
+            let entry = DBEntry::new(Value::String(Bytes::from("1")), Some(expiry));
+            db.insert(Bytes::from(key), entry);
+        }
+        let sample = db.expire_sample(20);
+        assert_eq!(sample.expired, 0);
+        assert_eq!(sample.ttl_sum, i64::MAX);
+    }
+
+    #[test]
+    fn test_expire_fields_sample() {
+        let mut db = Rdb::default();
+        let past = SystemTime::now() - Duration::from_secs(1);
+        let future = SystemTime::now() + Duration::from_secs(3600);
+        let insert = |db: &mut Rdb, key: &'static str, expiries: &[SystemTime]| {
+            let mut hash = Hash::default();
+            for (i, &expiry) in expiries.iter().enumerate() {
+                let field = Bytes::from(i.to_string());
+                hash.insert(field.clone(), Bytes::from("v"), false);
+                hash.set_expiry(&field, Some(expiry));
+            }
+            db.insert(Bytes::from(key), DBEntry::new(Value::Hash(hash), None));
+        };
+
+        insert(&mut db, "some", &[past, future]);
+        assert_eq!(db.field_expires_len(), 1);
+        assert_eq!(db.expire_fields_sample(20), 1);
+        assert_eq!(db.field_expires_len(), 1);
+
+        // A hash left with no fields goes altogether.
+        insert(&mut db, "all", &[past]);
+        assert_eq!(db.field_expires_len(), 2);
+        while db.peek(b"all").is_some() {
+            db.expire_fields_sample(20);
+        }
+        assert_eq!(db.field_expires_len(), 1);
+        assert_eq!(db.expiry_stats().expired_subkeys, 2);
+    }
+
+    #[test]
+    fn test_checksum_verified() {
+        let key = Bytes::from_static(b"greeting");
+        let entry = DBEntry::new(Value::String(Bytes::from_static(b"hello")), None);
+        let mut file = Vec::new();
+        crate::rdb_writer::write_rdb(
+            &mut file,
+            &HashMap::new(),
+            [(&key, &entry)].into_iter(),
+            false,
+        )
+        .unwrap();
+
+        let mut db = read_rdb(file.as_slice(), true).unwrap();
+        assert_eq!(db.get(b"greeting"), Some(&entry));
+        assert_eq!(db.dirty(), 0);
+
+        // Flip a bit of the value.
+        let at = file
+            .windows(5)
+            .position(|window| window == b"hello")
+            .unwrap();
+        file[at] ^= 1;
+        assert!(read_rdb(file.as_slice(), true).is_err());
+        assert!(read_rdb(file.as_slice(), false).is_ok());
+
+        // A zero checksum means it wasn't computed.
+        let len = file.len();
+        file[len - 8..].fill(0);
+        assert!(read_rdb(file.as_slice(), true).is_ok());
+    }
+
+    #[test]
+    fn test_compressed_strings() {
+        let key = Bytes::from_static(b"long");
+        let value = Bytes::from("abcdefgh".repeat(10));
+        let entry = DBEntry::new(Value::String(value.clone()), None);
+        let mut file = Vec::new();
+        crate::rdb_writer::write_rdb(
+            &mut file,
+            &HashMap::new(),
+            [(&key, &entry)].into_iter(),
+            true,
+        )
+        .unwrap();
+        assert!(!file.windows(value.len()).any(|window| window == value));
+
+        let mut db = read_rdb(file.as_slice(), true).unwrap();
+        assert_eq!(db.get(b"long"), Some(&entry));
+    }
+
+    #[test]
+    fn test_every_type_round_trips() {
+        let mut hash = Hash::default();
+        hash.insert(Bytes::from("forever"), Bytes::from("1"), false);
+        hash.insert(Bytes::from("later"), Bytes::from("2"), false);
+        let later = SystemTime::now() + Duration::from_secs(3600);
+        hash.set_expiry(b"later", Some(from_unix_millis(unix_millis(later) as u64)));
+
+        let id = |ms| StreamId { ms, seq: 0 };
+        let fields = |value: &str| vec![(Bytes::from("field"), Bytes::from(value.to_string()))];
+        let entries = BTreeMap::from([(id(1), fields("a")), (id(2), fields("b")), (id(5), vec![])]);
+        let mut stream = Stream::restore(entries, id(5), id(3), 4);
+        let mut group = ConsumerGroup::new(id(2), Some(2));
+        group.restore_consumer(Bytes::from("alice"), 10, Some(9), vec![(id(1), 8, 2)]);
+        group.restore_consumer(Bytes::from("bob"), 11, None, vec![]);
+        stream.restore_group(Bytes::from("group"), group);
+
+        let values = [
+            Value::String(Bytes::from("hello")),
+            Value::List(["a", "1", "b"].into_iter().map(Bytes::from).collect()),
+            Value::Set(["x", "-7"].into_iter().map(Bytes::from).collect()),
+            Value::SortedSet(
+                [("low", f64::NEG_INFINITY), ("mid", 1.5)]
+                    .into_iter()
+                    .map(|(member, score)| (Bytes::from(member), score))
+                    .collect(),
+            ),
+            Value::Hash(hash),
+            Value::Stream(stream),
+        ];
+        let keys: Vec<_> = (0..values.len())
+            .map(|i| Bytes::from(i.to_string()))
+            .collect();
+        let entries: Vec<_> = values
+            .into_iter()
+            .map(|value| DBEntry::new(value, None))
+            .collect();
+        let mut file = Vec::new();
+        crate::rdb_writer::write_rdb(&mut file, &HashMap::new(), keys.iter().zip(&entries), true)
+            .unwrap();
+
+        let mut db = read_rdb(file.as_slice(), true).unwrap();
+        for (key, entry) in keys.iter().zip(&entries) {
+            assert_eq!(db.get(key), Some(entry));
+        }
+    }
+
+    #[test]
+    fn test_compact_encodings() {
+        fn string(file: &mut Vec<u8>, value: &[u8]) {
+            file.push(value.len() as u8);
+            file.extend_from_slice(value);
+        }
+        fn listpack(file: &mut Vec<u8>, elements: &[&str]) {
+            let mut writer = listpack::ListpackWriter::default();
+            for element in elements {
+                writer.push(element.as_bytes());
+            }
+            string(file, &writer.finish());
+        }
+
+        let mut file = b"REDIS0011".to_vec();
+        file.push(TYPE_INTSET);
+        string(&mut file, b"intset");
+        let mut intset = [2u32.to_le_bytes(), 3u32.to_le_bytes()].concat();
+        for member in [-2i16, 1, 300] {
+            intset.extend_from_slice(&member.to_le_bytes());
+        }
+        string(&mut file, &intset);
+
+        file.push(TYPE_HASH_LISTPACK);
+        string(&mut file, b"hash");
+        listpack(&mut file, &["f", "v", "n", "12"]);
+
+        file.push(TYPE_ZSET_LISTPACK);
+        string(&mut file, b"zset");
+        listpack(&mut file, &["a", "1.5", "b", "-3"]);
+
+        file.extend_from_slice(&[TYPE_QUICKLIST_2]);
+        string(&mut file, b"list");
+        file.push(2);
+        file.push(QUICKLIST_NODE_PACKED as u8);
+        listpack(&mut file, &["x", "7"]);
+        file.push(QUICKLIST_NODE_PLAIN as u8);
+        string(&mut file, b"z");
+
+        // One field that never expires, and one that expired long ago.
+        file.push(TYPE_HASH_LISTPACK_EX);
+        string(&mut file, b"expiring");
+        file.extend_from_slice(&1000u64.to_le_bytes());
+        listpack(&mut file, &["keep", "1", "0", "gone", "2", "1000"]);
+
+        file.push(OPCODE_EOF);
+        file.extend_from_slice(&[0; 8]);
+
+        let mut db = read_rdb(file.as_slice(), true).unwrap();
+        let set = db.get(b"intset").unwrap().value().as_set().unwrap();
+        assert!(["-2", "1", "300"]
+            .iter()
+            .all(|member| set.contains(member.as_bytes())));
+        let hash = db.get(b"hash").unwrap().value().as_hash().unwrap();
+        assert_eq!(hash.get(b"n"), Some(&Bytes::from("12")));
+        let zset = db.get(b"zset").unwrap().value().as_sorted_set().unwrap();
+        assert_eq!(zset.score(b"b"), Some(-3.0));
+        let list = db.get(b"list").unwrap().value().as_list().unwrap();
+        assert_eq!(list, &["x", "7", "z"]);
+        let hash = db.get(b"expiring").unwrap().value().as_hash().unwrap();
+        assert_eq!(hash.len(), 1);
+        assert_eq!(hash.expires_at(b"keep"), Some(None));
     }
 }
@@ -0,0 +1,395 @@
+//! Serializes the keyspace into an RDB file, in the format Redis 7.4 writes and loads.
+//!
+//! The file is a header naming the format version, some auxiliary fields describing the server
+//! that wrote it, the keys of each database, and an end marker followed by a checksum of
+//! everything before it. Collections are written in their plain encodings, one element after
+//! another, which Redis loads into whatever encoding suits them.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, Write},
+    time::SystemTime,
+};
+
+use bytes::Bytes;
+
+use crate::{
+    crc64,
+    hash::Hash,
+    listpack::ListpackWriter,
+    lzf,
+    rdb::{
+        unix_millis, DBEntry, Value, OPCODE_AUX, OPCODE_EOF, OPCODE_EXPIRETIME_MS, OPCODE_RESIZEDB,
+        OPCODE_SELECTDB, STREAM_ITEM_FLAG_SAMEFIELDS, TYPE_HASH, TYPE_HASH_METADATA, TYPE_LIST,
+        TYPE_SET, TYPE_STREAM_LISTPACKS_3, TYPE_STRING, TYPE_ZSET_2,
+    },
+    stream::{Fields, Stream, StreamId},
+};
+
+/// The version of the format that Redis 7.4 writes.
+pub const RDB_VERSION: u32 = 12;
+
+// Strings that are integers this short are written as the integer rather than the digits.
+const MAX_INTEGER_STRING_LEN: usize = 11;
+const ENCODING_INT8: u8 = 0xc0;
+const ENCODING_INT16: u8 = 0xc1;
+const ENCODING_INT32: u8 = 0xc2;
+const ENCODING_LZF: u8 = 0xc3;
+// Strings this short aren't worth trying to compress.
+const MIN_COMPRESSED_STRING_LEN: usize = 21;
+
+// Redis starts a new listpack for a stream's entries once the last one holds this many.
+const STREAM_NODE_MAX_ENTRIES: usize = 100;
+
+/// Writes a whole RDB file holding `entries` to `out`, compressing long strings if `compress` is
+/// set. The auxiliary fields read from the file the keyspace was loaded from are carried over,
+/// with the ones describing this server brought up to date.
+pub fn write_rdb<'a>(
+    out: impl Write,
+    metadata: &HashMap<String, String>,
+    entries: impl Iterator<Item = (&'a Bytes, &'a DBEntry)> + Clone,
+    compress: bool,
+) -> io::Result<()> {
+    let mut rdb = RdbWriter {
+        out,
+        crc: 0,
+        compress,
+    };
+    rdb.write_all(format!("REDIS{:04}", RDB_VERSION).as_bytes())?;
+
+    let ctime = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    let mut aux: BTreeMap<&str, String> = metadata
+        .iter()
+        .map(|(key, value)| (key.as_str(), value.clone()))
+        .collect();
+    aux.insert("redis-ver", crate::REDIS_VERSION.to_string());
+    aux.insert("redis-bits", usize::BITS.to_string());
+    aux.insert("ctime", ctime.to_string());
+    aux.insert("aof-base", "0".to_string());
+    for (key, value) in aux {
+        rdb.write_all(&[OPCODE_AUX])?;
+        rdb.write_string(key.as_bytes())?;
+        rdb.write_string(value.as_bytes())?;
+    }
+
+    // There's only ever the one database.
+    rdb.write_all(&[OPCODE_SELECTDB])?;
+    rdb.write_length(0)?;
+    let expires = entries
+        .clone()
+        .filter(|(_, entry)| entry.expires_at().is_some())
+        .count();
+    rdb.write_all(&[OPCODE_RESIZEDB])?;
+    rdb.write_length(entries.clone().count() as u64)?;
+    rdb.write_length(expires as u64)?;
+
+    for (key, entry) in entries {
+        if let Some(expires_at) = entry.expires_at() {
+            rdb.write_all(&[OPCODE_EXPIRETIME_MS])?;
+            rdb.write_all(&unix_millis(expires_at).to_le_bytes())?;
+        }
+        rdb.write_entry(key, entry.value())?;
+    }
+
+    rdb.write_all(&[OPCODE_EOF])?;
+    let crc = rdb.crc;
+    rdb.out.write_all(&crc.to_le_bytes())?;
+    rdb.out.flush()
+}
+
+// Checksums everything written through it.
+struct RdbWriter<W> {
+    out: W,
+    crc: u64,
+    compress: bool,
+}
+
+impl<W: Write> Write for RdbWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.out.write(buf)?;
+        self.crc = crc64::update(self.crc, &buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+impl<W: Write> RdbWriter<W> {
+    /// Writes a length, or any other unsigned number, in as few bytes as the format allows.
+    fn write_length(&mut self, len: u64) -> io::Result<()> {
+        if len < 1 << 6 {
+            self.write_all(&[len as u8])
+        } else if len < 1 << 14 {
+            self.write_all(&[0x40 | (len >> 8) as u8, len as u8])
+        } else if len <= u32::MAX as u64 {
+            self.write_all(&[0x80])?;
+            self.write_all(&(len as u32).to_be_bytes())
+        } else {
+            self.write_all(&[0x81])?;
+            self.write_all(&len.to_be_bytes())
+        }
+    }
+
+    /// Writes a string, as an integer if it is a short one that reads back the same, or compressed
+    /// if it's long and compression is on and saves at least a few bytes.
+    fn write_string(&mut self, value: &[u8]) -> io::Result<()> {
+        if value.len() <= MAX_INTEGER_STRING_LEN {
+            if let Some(integer) = crate::listpack::canonical_integer(value) {
+                if let Ok(integer) = i8::try_from(integer) {
+                    return self.write_all(&[ENCODING_INT8, integer as u8]);
+                }
+                if let Ok(integer) = i16::try_from(integer) {
+                    self.write_all(&[ENCODING_INT16])?;
+                    return self.write_all(&integer.to_le_bytes());
+                }
+                if let Ok(integer) = i32::try_from(integer) {
+                    self.write_all(&[ENCODING_INT32])?;
+                    return self.write_all(&integer.to_le_bytes());
+                }
+            }
+        }
+        if self.compress && value.len() >= MIN_COMPRESSED_STRING_LEN {
+            if let Some(compressed) = lzf::compress(value, value.len() - 4) {
+                self.write_all(&[ENCODING_LZF])?;
+                self.write_length(compressed.len() as u64)?;
+                self.write_length(value.len() as u64)?;
+                return self.write_all(&compressed);
+            }
+        }
+        self.write_length(value.len() as u64)?;
+        self.write_all(value)
+    }
+
+    fn write_entry(&mut self, key: &[u8], value: &Value) -> io::Result<()> {
+        match value {
+            Value::String(value) => {
+                self.write_all(&[TYPE_STRING])?;
+                self.write_string(key)?;
+                self.write_string(value)
+            }
+            Value::List(list) => {
+                self.write_all(&[TYPE_LIST])?;
+                self.write_string(key)?;
+                self.write_length(list.len() as u64)?;
+                list.iter().try_for_each(|value| self.write_string(value))
+            }
+            Value::Set(set) => {
+                self.write_all(&[TYPE_SET])?;
+                self.write_string(key)?;
+                self.write_length(set.len() as u64)?;
+                set.iter().try_for_each(|member| self.write_string(member))
+            }
+            Value::SortedSet(set) => {
+                self.write_all(&[TYPE_ZSET_2])?;
+                self.write_string(key)?;
+                self.write_length(set.len() as u64)?;
+                set.iter().try_for_each(|(member, score)| {
+                    self.write_string(member)?;
+                    self.write_all(&score.to_le_bytes())
+                })
+            }
+            Value::Hash(hash) if hash.has_expiring_fields() => {
+                self.write_all(&[TYPE_HASH_METADATA])?;
+                self.write_string(key)?;
+                self.write_hash_with_ttls(hash)
+            }
+            Value::Hash(hash) => {
+                self.write_all(&[TYPE_HASH])?;
+                self.write_string(key)?;
+                self.write_length(hash.len() as u64)?;
+                hash.iter().try_for_each(|(field, value)| {
+                    self.write_string(field)?;
+                    self.write_string(value)
+                })
+            }
+            Value::Stream(stream) => {
+                self.write_all(&[TYPE_STREAM_LISTPACKS_3])?;
+                self.write_string(key)?;
+                self.write_stream(stream)
+            }
+        }
+    }
+
+    // Field expiry times are written relative to the soonest of them, plus one so that zero can
+    // stand for a field that never expires.
+    fn write_hash_with_ttls(&mut self, hash: &Hash) -> io::Result<()> {
+        let expiry = |field: &[u8]| hash.expires_at(field).flatten().map(unix_millis);
+        let min_expiry = hash
+            .iter()
+            .filter_map(|(field, _)| expiry(field))
+            .min()
+            .unwrap_or(0);
+        self.write_all(&min_expiry.to_le_bytes())?;
+        self.write_length(hash.len() as u64)?;
+        for (field, value) in hash.iter() {
+            let ttl = expiry(field).map_or(0, |expiry| (expiry - min_expiry) as u64 + 1);
+            self.write_length(ttl)?;
+            self.write_string(field)?;
+            self.write_string(value)?;
+        }
+        Ok(())
+    }
+
+    // Entries go in listpacks keyed by the ID of their first entry, followed by the stream's own
+    // bookkeeping and then its consumer groups.
+    fn write_stream(&mut self, stream: &Stream) -> io::Result<()> {
+        let entries: Vec<_> = stream.entries().collect();
+        let nodes = entries.chunks(STREAM_NODE_MAX_ENTRIES);
+        self.write_length(nodes.len() as u64)?;
+        for node in nodes {
+            let master_id = *node[0].0;
+            self.write_string(&raw_stream_id(master_id))?;
+            self.write_string(&stream_listpack(node))?;
+        }
+
+        self.write_length(stream.len() as u64)?;
+        for id in [stream.last_id(), stream.first_id(), stream.max_deleted_id()] {
+            self.write_length(id.ms)?;
+            self.write_length(id.seq)?;
+        }
+        self.write_length(stream.entries_added())?;
+
+        let groups: Vec<_> = stream.groups().collect();
+        self.write_length(groups.len() as u64)?;
+        for (name, group) in groups {
+            self.write_string(name)?;
+            self.write_length(group.last_delivered().ms)?;
+            self.write_length(group.last_delivered().seq)?;
+            self.write_length(group.entries_read().unwrap_or(u64::MAX))?;
+
+            let pending: Vec<_> = group.pending().collect();
+            self.write_length(pending.len() as u64)?;
+            for (id, entry) in pending {
+                self.write_all(&raw_stream_id(*id))?;
+                self.write_all(&entry.delivered_at().to_le_bytes())?;
+                self.write_length(entry.deliveries())?;
+            }
+
+            let consumers: Vec<_> = group.consumers().collect();
+            self.write_length(consumers.len() as u64)?;
+            for (name, consumer) in consumers {
+                self.write_string(name)?;
+                self.write_all(&consumer.seen_at().to_le_bytes())?;
+                self.write_all(&consumer.active_at().unwrap_or(-1).to_le_bytes())?;
+                // The group's list has the details, so each consumer's only needs the IDs.
+                let pending: Vec<_> = consumer.pending().collect();
+                self.write_length(pending.len() as u64)?;
+                for id in pending {
+                    self.write_all(&raw_stream_id(*id))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// A stream ID as it's written in binary, big-endian so that IDs sort as their bytes do.
+fn raw_stream_id(id: StreamId) -> [u8; 16] {
+    let mut raw = [0; 16];
+    raw[..8].copy_from_slice(&id.ms.to_be_bytes());
+    raw[8..].copy_from_slice(&id.seq.to_be_bytes());
+    raw
+}
+
+// A run of stream entries as a listpack. It starts with a master entry, holding the number of
+// entries, the number deleted and the field names of the first entry. Each entry then has some
+// flags, its ID as a difference from the first one's, its values, along with its field names
+// when they differ from the master entry's, and finally how many elements it took up.
+fn stream_listpack(entries: &[(&StreamId, &Fields)]) -> Vec<u8> {
+    let (master_id, master_fields) = entries[0];
+    let mut lp = ListpackWriter::default();
+    lp.push_integer(entries.len() as i64);
+    lp.push_integer(0);
+    lp.push_integer(master_fields.len() as i64);
+    for (field, _) in master_fields {
+        lp.push(field);
+    }
+    lp.push_integer(0);
+
+    for (id, fields) in entries {
+        let same_fields = fields.len() == master_fields.len()
+            && fields
+                .iter()
+                .zip(master_fields)
+                .all(|((field, _), (master_field, _))| field == master_field);
+        lp.push_integer(if same_fields {
+            STREAM_ITEM_FLAG_SAMEFIELDS
+        } else {
+            0
+        });
+        lp.push_integer(id.ms.wrapping_sub(master_id.ms) as i64);
+        lp.push_integer(id.seq.wrapping_sub(master_id.seq) as i64);
+        if !same_fields {
+            lp.push_integer(fields.len() as i64);
+        }
+        for (field, value) in fields.iter() {
+            if !same_fields {
+                lp.push(field);
+            }
+            lp.push(value);
+        }
+        let mut count = fields.len() as i64 + 3;
+        if !same_fields {
+            count += fields.len() as i64 + 1;
+        }
+        lp.push_integer(count);
+    }
+    lp.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_string_file() {
+        let key = Bytes::from_static(b"count");
+        let entry = DBEntry::new(Value::String(Bytes::from_static(b"300")), None);
+        let mut out = Vec::new();
+        write_rdb(
+            &mut out,
+            &HashMap::new(),
+            [(&key, &entry)].into_iter(),
+            true,
+        )
+        .unwrap();
+
+        assert!(out.starts_with(b"REDIS0012\xfa"));
+        let body = [
+            &[OPCODE_SELECTDB, 0, OPCODE_RESIZEDB, 1, 0, TYPE_STRING][..],
+            b"\x05count",
+            &[ENCODING_INT16, 0x2c, 0x01, OPCODE_EOF],
+        ]
+        .concat();
+        let (contents, checksum) = out.split_at(out.len() - 8);
+        assert!(contents.ends_with(&body));
+        assert_eq!(
+            checksum,
+            crc64::update(0, contents).to_le_bytes().as_slice()
+        );
+    }
+
+    #[test]
+    fn test_lengths() {
+        let mut rdb = RdbWriter {
+            out: Vec::new(),
+            crc: 0,
+            compress: false,
+        };
+        for len in [10, 700, 70000, 1 << 40] {
+            rdb.write_length(len).unwrap();
+        }
+        assert_eq!(
+            rdb.out,
+            [
+                &[10, 0x42, 0xbc, 0x80, 0, 1, 0x11, 0x70, 0x81][..],
+                &(1u64 << 40).to_be_bytes(),
+            ]
+            .concat()
+        );
+    }
+}
@@ -0,0 +1,126 @@
+//! Cursor-based iteration for SCAN and the HSCAN, SSCAN and ZSCAN commands on collections.
+//!
+//! Members are kept ordered by a hash of their name, and a cursor is simply the hash to resume
+//! from. Members added or removed between calls never shift the position of the others, so a
+//! full iteration still returns every member that was present throughout exactly once.
+
+use std::{collections::BTreeSet, hash::Hasher};
+
+use bytes::Bytes;
+
+use crate::{
+    error::CommandError,
+    glob::glob_match,
+    protocol_parser::{parse_integer, CommandArgs, RESPValue, Response},
+};
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ScanIndex {
+    members: BTreeSet<(u64, Bytes)>,
+}
+
+impl ScanIndex {
+    pub fn insert(&mut self, member: Bytes) {
+        self.members.insert((scan_hash(&member), member));
+    }
+
+    pub fn remove(&mut self, member: Bytes) {
+        self.members.remove(&(scan_hash(&member), member));
+    }
+
+    /// Returns at least `count` members from `cursor` onwards, if there are that many, along with
+    /// the cursor to continue from. A returned cursor of 0 means the iteration is complete.
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<Bytes>) {
+        let mut members = Vec::new();
+        let mut last_hash = None;
+
+        for (hash, member) in self.members.range((cursor, Bytes::new())..) {
+            // Never stop partway through members sharing a hash, as the cursor couldn't resume
+            // there.
+            if members.len() >= count && last_hash != Some(*hash) {
+                return (*hash, members);
+            }
+            members.push(member.clone());
+            last_hash = Some(*hash);
+        }
+
+        (0, members)
+    }
+
+    /// A member picked at random: the first at or after a random hash. Members following a wider
+    /// gap between hashes are a little more likely to be picked.
+    pub fn random(&self) -> Option<Bytes> {
+        let start = (crate::random::next_u64(), Bytes::new());
+        self.members
+            .range(start..)
+            .chain(&self.members)
+            .next()
+            .map(|(_, member)| member.clone())
+    }
+}
+
+// The hash that orders members. It only has to be stable for the life of the process, since
+// cursors don't survive a restart.
+fn scan_hash(member: &[u8]) -> u64 {
+    let mut hasher = std::hash::DefaultHasher::new();
+    hasher.write(member);
+    hasher.finish()
+}
+
+/// The MATCH and COUNT options shared by every scan command.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScanOptions {
+    pattern: Option<Bytes>,
+    count: usize,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        ScanOptions {
+            pattern: None,
+            count: 10,
+        }
+    }
+}
+
+impl ScanOptions {
+    /// Consumes the value of `option`, which must be upper-case, if it is MATCH or COUNT.
+    /// Returns whether it was, so the caller can handle its own options or reject the rest.
+    pub fn parse_option(
+        &mut self,
+        option: &str,
+        args: &mut CommandArgs,
+    ) -> Result<bool, CommandError> {
+        match option {
+            "MATCH" => self.pattern = Some(args.option_value()?),
+            "COUNT" => {
+                self.count = parse_integer(&args.option_value()?)?;
+                if self.count < 1 {
+                    return Err(CommandError::Syntax);
+                }
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Whether a member's name passes the MATCH filter, if there is one.
+    pub fn matches(&self, member: &[u8]) -> bool {
+        match &self.pattern {
+            Some(pattern) => glob_match(pattern, member),
+            None => true,
+        }
+    }
+}
+
+/// Builds the reply to a scan command: the next cursor, then the items found.
+pub fn scan_reply(cursor: u64, items: Vec<RESPValue>) -> Response {
+    Response::Echo(RESPValue::Array(vec![
+        RESPValue::BulkString(Bytes::from(cursor.to_string())),
+        RESPValue::Array(items),
+    ]))
+}
@@ -0,0 +1,589 @@
+//! The set type: an unordered collection of distinct strings, with the set algebra commands.
+
+use bytes::Bytes;
+
+use crate::{
+    error::CommandError,
+    indexed_set::IndexedSet,
+    protocol_parser::{
+        parse_cursor, parse_integer, parse_random_count, CommandArgs, RESPValue, Response,
+    },
+    rdb::{DBEntry, Rdb, Value},
+    scan::{scan_reply, ScanIndex, ScanOptions},
+};
+
+#[derive(Clone, Debug, Default)]
+pub struct Set {
+    members: IndexedSet<Bytes>,
+    scan_index: ScanIndex,
+}
+
+// Two sets are equal when they have the same members, whatever order they were added in.
+impl PartialEq for Set {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().all(|member| other.contains(member))
+    }
+}
+
+impl FromIterator<Bytes> for Set {
+    fn from_iter<I: IntoIterator<Item = Bytes>>(members: I) -> Self {
+        let mut set = Set::default();
+        for member in members {
+            set.insert(member);
+        }
+        set
+    }
+}
+
+impl Set {
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    pub fn contains(&self, member: &[u8]) -> bool {
+        self.members.contains(member)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Bytes> {
+        self.members.iter()
+    }
+
+    /// Adds a member, returning whether it was newly added.
+    pub fn insert(&mut self, member: Bytes) -> bool {
+        if !self.members.insert(member.clone()) {
+            return false;
+        }
+        self.scan_index.insert(member);
+        true
+    }
+
+    /// Removes a member, returning whether it was there.
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        if !self.members.remove(member) {
+            return false;
+        }
+        self.scan_index.remove(Bytes::copy_from_slice(member));
+        true
+    }
+
+    pub fn random(&self) -> Option<&Bytes> {
+        self.members.random()
+    }
+
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<Bytes>) {
+        self.scan_index.scan(cursor, count)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SetOperation {
+    Intersection,
+    Union,
+    Difference,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SetCommand {
+    Add {
+        key: Bytes,
+        members: Vec<Bytes>,
+    },
+    Rem {
+        key: Bytes,
+        members: Vec<Bytes>,
+    },
+    IsMember {
+        key: Bytes,
+        member: Bytes,
+    },
+    MIsMember {
+        key: Bytes,
+        members: Vec<Bytes>,
+    },
+    Members(Bytes),
+    Card(Bytes),
+    Pop {
+        key: Bytes,
+        count: Option<usize>,
+    },
+    RandMember {
+        key: Bytes,
+        /// Without a count, a single member is returned rather than an array. A negative count
+        /// allows the same member to be returned more than once.
+        count: Option<i64>,
+    },
+    Move {
+        source: Bytes,
+        destination: Bytes,
+        member: Bytes,
+    },
+    /// SINTER, SUNION and SDIFF, and with a destination their STORE variants.
+    Combine {
+        operation: SetOperation,
+        keys: Vec<Bytes>,
+        destination: Option<Bytes>,
+    },
+    InterCard {
+        keys: Vec<Bytes>,
+        /// Stop counting once the intersection is known to be at least this big. Zero means no
+        /// limit.
+        limit: usize,
+    },
+    Scan {
+        key: Bytes,
+        cursor: u64,
+        options: ScanOptions,
+    },
+}
+
+impl SetCommand {
+    /// Parses the arguments of the set command `name`, which must be upper-case.
+    pub fn parse(name: &str, args: &mut CommandArgs) -> Result<Self, CommandError> {
+        let command = match name {
+            "SADD" => SetCommand::Add {
+                key: args.required()?,
+                members: args.at_least_one()?,
+            },
+            "SREM" => SetCommand::Rem {
+                key: args.required()?,
+                members: args.at_least_one()?,
+            },
+            "SISMEMBER" => SetCommand::IsMember {
+                key: args.required()?,
+                member: args.required()?,
+            },
+            "SMISMEMBER" => SetCommand::MIsMember {
+                key: args.required()?,
+                members: args.at_least_one()?,
+            },
+            "SMEMBERS" => SetCommand::Members(args.required()?),
+            "SCARD" => SetCommand::Card(args.required()?),
+            "SPOP" => {
+                let key = args.required()?;
+                let count = match args.next() {
+                    Some(count) => {
+                        let count: i64 = parse_integer(&count)?;
+                        Some(usize::try_from(count).map_err(|_| CommandError::NotPositive)?)
+                    }
+                    None => None,
+                };
+                SetCommand::Pop { key, count }
+            }
+            "SRANDMEMBER" => {
+                let key = args.required()?;
+                let count = match args.next() {
+                    Some(count) => Some(parse_random_count(&count)?),
+                    None => None,
+                };
+                SetCommand::RandMember { key, count }
+            }
+            "SMOVE" => SetCommand::Move {
+                source: args.required()?,
+                destination: args.required()?,
+                member: args.required()?,
+            },
+            "SINTER" | "SUNION" | "SDIFF" | "SINTERSTORE" | "SUNIONSTORE" | "SDIFFSTORE" => {
+                let operation = match name.trim_end_matches("STORE") {
+                    "SINTER" => SetOperation::Intersection,
+                    "SUNION" => SetOperation::Union,
+                    _ => SetOperation::Difference,
+                };
+                let destination = if name.ends_with("STORE") {
+                    Some(args.required()?)
+                } else {
+                    None
+                };
+                SetCommand::Combine {
+                    operation,
+                    keys: args.at_least_one()?,
+                    destination,
+                }
+            }
+            "SINTERCARD" => {
+                let numkeys: i64 = parse_integer(&args.required()?)?;
+                if numkeys <= 0 {
+                    return Err(CommandError::Other(
+                        "numkeys should be greater than 0".to_string(),
+                    ));
+                }
+                if numkeys as usize > args.len() {
+                    return Err(CommandError::Other(
+                        "Number of keys can't be greater than number of args".to_string(),
+                    ));
+                }
+                let keys = (0..numkeys)
+                    .map(|_| args.required())
+                    .collect::<Result<_, _>>()?;
+
+                let mut limit = 0;
+                while let Some(option) = args.next() {
+                    if !option.eq_ignore_ascii_case(b"LIMIT") {
+                        return Err(CommandError::Syntax);
+                    }
+                    limit = parse_integer(&args.option_value()?)
+                        .map_err(|_| CommandError::Other("LIMIT can't be negative".to_string()))?;
+                }
+
+                SetCommand::InterCard { keys, limit }
+            }
+            "SSCAN" => {
+                let key = args.required()?;
+                let cursor = parse_cursor(&args.required()?)?;
+                let mut options = ScanOptions::default();
+                while let Some(option) = args.next() {
+                    let option = String::from_utf8_lossy(&option).to_ascii_uppercase();
+                    if !options.parse_option(&option, args)? {
+                        return Err(CommandError::Syntax);
+                    }
+                }
+                SetCommand::Scan {
+                    key,
+                    cursor,
+                    options,
+                }
+            }
+            _ => unreachable!("not a set command: {}", name),
+        };
+
+        Ok(command)
+    }
+
+    pub fn execute(self, db: &mut Rdb) -> Result<Response, CommandError> {
+        let response = match self {
+            SetCommand::Add { key, members } => {
+                let set = set_or_insert(db, &key)?;
+                let added = members
+                    .into_iter()
+                    .filter(|member| set.insert(member.clone()))
+                    .count();
+                db.mark_dirty(added);
+                Response::Echo(RESPValue::Integer(added as i64))
+            }
+            SetCommand::Rem { key, members } => {
+                let Some(set) = set_mut(db, &key)? else {
+                    return Ok(Response::Echo(RESPValue::Integer(0)));
+                };
+                let removed = members.iter().filter(|member| set.remove(member)).count();
+                db.mark_dirty(removed);
+                db.remove_if_empty(&key);
+                Response::Echo(RESPValue::Integer(removed as i64))
+            }
+            SetCommand::IsMember { key, member } => {
+                let is_member = set(db, &key)?.is_some_and(|set| set.contains(&member));
+                Response::Echo(RESPValue::Integer(is_member as i64))
+            }
+            SetCommand::MIsMember { key, members } => {
+                let set = set(db, &key)?;
+                let replies = members
+                    .iter()
+                    .map(|member| {
+                        let is_member = set.is_some_and(|set| set.contains(member));
+                        RESPValue::Integer(is_member as i64)
+                    })
+                    .collect();
+                Response::Echo(RESPValue::Array(replies))
+            }
+            SetCommand::Members(key) => {
+                let members = set(db, &key)?.map(member_values).unwrap_or_default();
+                Response::Echo(RESPValue::Set(members))
+            }
+            SetCommand::Card(key) => {
+                let len = set(db, &key)?.map_or(0, Set::len);
+                Response::Echo(RESPValue::Integer(len as i64))
+            }
+            SetCommand::Pop { key, count } => {
+                let Some(set) = set_mut(db, &key)? else {
+                    return Ok(match count {
+                        Some(_) => Response::Echo(RESPValue::Set(vec![])),
+                        None => Response::Null,
+                    });
+                };
+
+                let mut popped = Vec::new();
+                for _ in 0..count.unwrap_or(1) {
+                    let Some(member) = set.random().cloned() else {
+                        break;
+                    };
+                    set.remove(&member);
+                    popped.push(RESPValue::BulkString(member));
+                }
+                db.mark_dirty(popped.len());
+                db.remove_if_empty(&key);
+
+                match count {
+                    Some(_) => Response::Echo(RESPValue::Set(popped)),
+                    None => match popped.pop() {
+                        Some(member) => Response::Echo(member),
+                        None => Response::Null,
+                    },
+                }
+            }
+            SetCommand::RandMember { key, count } => {
+                let Some(set) = set(db, &key)? else {
+                    return Ok(match count {
+                        Some(_) => Response::Echo(RESPValue::Array(vec![])),
+                        None => Response::Null,
+                    });
+                };
+
+                let chosen: Vec<&Bytes> = match count {
+                    None => set.random().into_iter().collect(),
+                    Some(count) if count >= 0 => {
+                        let count = (count as usize).min(set.len());
+                        let mut members: Vec<_> = set.iter().collect();
+                        crate::random::shuffle_prefix(&mut members, count);
+                        members.truncate(count);
+                        members
+                    }
+                    Some(count) => (0..count.unsigned_abs())
+                        .filter_map(|_| set.random())
+                        .collect(),
+                };
+
+                let mut chosen = chosen.into_iter().cloned().map(RESPValue::BulkString);
+                match count {
+                    Some(_) => Response::Echo(RESPValue::Array(chosen.collect())),
+                    None => Response::Echo(chosen.next().unwrap()),
+                }
+            }
+            SetCommand::Move {
+                source,
+                destination,
+                member,
+            } => {
+                // Both keys have to hold sets, if they exist, before anything is moved.
+                if let Some(entry) = db.get(&destination) {
+                    entry.value().as_set()?;
+                }
+                let Some(source_set) = set_mut(db, &source)? else {
+                    return Ok(Response::Echo(RESPValue::Integer(0)));
+                };
+                if source == destination {
+                    let is_member = source_set.contains(&member);
+                    return Ok(Response::Echo(RESPValue::Integer(is_member as i64)));
+                }
+                if !source_set.remove(&member) {
+                    return Ok(Response::Echo(RESPValue::Integer(0)));
+                }
+                db.remove_if_empty(&source);
+                set_or_insert(db, &destination)?.insert(member);
+                db.mark_dirty(1);
+                Response::Echo(RESPValue::Integer(1))
+            }
+            SetCommand::Combine {
+                operation,
+                keys,
+                destination,
+            } => {
+                let result = combine(db, operation, &keys)?;
+                match destination {
+                    Some(destination) => {
+                        let len = result.len();
+                        store(db, destination, result);
+                        Response::Echo(RESPValue::Integer(len as i64))
+                    }
+                    None => Response::Echo(RESPValue::Set(
+                        result.into_iter().map(RESPValue::BulkString).collect(),
+                    )),
+                }
+            }
+            SetCommand::InterCard { keys, limit } => {
+                let limit = if limit == 0 { usize::MAX } else { limit };
+                let sets = lookup_all(db, &keys)?;
+                let len = match intersection(&sets) {
+                    Some(members) => members.take(limit).count(),
+                    None => 0,
+                };
+                Response::Echo(RESPValue::Integer(len as i64))
+            }
+            SetCommand::Scan {
+                key,
+                cursor,
+                options,
+            } => {
+                let Some(set) = set(db, &key)? else {
+                    return Ok(scan_reply(0, vec![]));
+                };
+                let (cursor, visited) = set.scan(cursor, options.count());
+                let members = visited
+                    .into_iter()
+                    .filter(|member| set.contains(member) && options.matches(member))
+                    .map(RESPValue::BulkString)
+                    .collect();
+                scan_reply(cursor, members)
+            }
+        };
+
+        Ok(response)
+    }
+}
+
+fn set<'a>(db: &'a mut Rdb, key: &[u8]) -> Result<Option<&'a Set>, CommandError> {
+    db.get(key).map(|entry| entry.value().as_set()).transpose()
+}
+
+fn set_mut<'a>(db: &'a mut Rdb, key: &[u8]) -> Result<Option<&'a mut Set>, CommandError> {
+    db.get_mut(key)
+        .map(|entry| entry.value_mut().as_set_mut())
+        .transpose()
+}
+
+fn set_or_insert<'a>(db: &'a mut Rdb, key: &Bytes) -> Result<&'a mut Set, CommandError> {
+    db.get_or_insert_with(key, || Value::Set(Set::default()))
+        .value_mut()
+        .as_set_mut()
+}
+
+fn member_values(set: &Set) -> Vec<RESPValue> {
+    set.iter().cloned().map(RESPValue::BulkString).collect()
+}
+
+// Looks up every key, treating missing ones as empty sets. Any key holding another type fails the
+// whole command.
+fn lookup_all<'a>(db: &'a Rdb, keys: &[Bytes]) -> Result<Vec<Option<&'a Set>>, CommandError> {
+    keys.iter()
+        .map(|key| db.peek(key).map(|entry| entry.value().as_set()).transpose())
+        .collect()
+}
+
+// The members common to every set, or `None` if one of them doesn't exist. Walks the smallest set
+// and checks its members against the rest.
+fn intersection<'a>(sets: &[Option<&'a Set>]) -> Option<impl Iterator<Item = &'a Bytes> + 'a> {
+    let mut sets: Vec<&Set> = sets.iter().copied().collect::<Option<_>>()?;
+    sets.sort_by_key(|set| set.len());
+    let (smallest, rest) = sets.split_first()?;
+    let rest = rest.to_vec();
+    Some(
+        smallest
+            .iter()
+            .filter(move |member| rest.iter().all(|set| set.contains(member))),
+    )
+}
+
+fn combine(db: &Rdb, operation: SetOperation, keys: &[Bytes]) -> Result<Vec<Bytes>, CommandError> {
+    let sets = lookup_all(db, keys)?;
+    let members = match operation {
+        SetOperation::Intersection => match intersection(&sets) {
+            Some(members) => members.cloned().collect(),
+            None => vec![],
+        },
+        SetOperation::Union => {
+            let union: Set = sets
+                .iter()
+                .flatten()
+                .flat_map(|set| set.iter())
+                .cloned()
+                .collect();
+            union.iter().cloned().collect()
+        }
+        SetOperation::Difference => match sets.split_first() {
+            Some((Some(first), rest)) => first
+                .iter()
+                .filter(|member| rest.iter().flatten().all(|set| !set.contains(member)))
+                .cloned()
+                .collect(),
+            _ => vec![],
+        },
+    };
+    Ok(members)
+}
+
+// Replaces whatever `destination` held with a set of `members`, or deletes it if there are none,
+// as the STORE commands do.
+fn store(db: &mut Rdb, destination: Bytes, members: Vec<Bytes>) {
+    db.remove(&destination);
+    if !members.is_empty() {
+        let set = members.into_iter().collect();
+        db.insert(destination, DBEntry::new(Value::Set(set), None));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol_parser::MAX_RANDOM_REPEATS;
+
+    fn set(members: &[&'static str]) -> Set {
+        members.iter().map(|member| Bytes::from(*member)).collect()
+    }
+
+    #[test]
+    fn test_intersection() {
+        let (a, b, c) = (set(&["1", "2", "3"]), set(&["2", "3", "4"]), set(&["3"]));
+        let common: Vec<_> = intersection(&[Some(&a), Some(&b), Some(&c)])
+            .unwrap()
+            .collect();
+        assert_eq!(common, vec![&Bytes::from("3")]);
+        assert!(intersection(&[Some(&a), None]).is_none());
+    }
+
+    #[test]
+    fn test_remove_updates_scan_index() {
+        let mut set = set(&["a", "b", "c"]);
+        assert!(set.remove(b"b"));
+        assert!(!set.remove(b"b"));
+
+        let (cursor, members) = set.scan(0, 10);
+        assert_eq!(cursor, 0);
+        assert_eq!(members.len(), 2);
+        assert!(!members.contains(&Bytes::from("b")));
+    }
+
+    #[test]
+    fn test_rand_member_count_range() {
+        let parse = |count: &str| {
+            let args = vec![Bytes::from("s"), Bytes::from(count.to_string())];
+            SetCommand::parse(
+                "SRANDMEMBER",
+                &mut CommandArgs::new(b"SRANDMEMBER", args.into_iter()),
+            )
+        };
+        assert!(parse("-9223372036854775807").is_err());
+        assert!(parse(&(-MAX_RANDOM_REPEATS - 1).to_string()).is_err());
+        assert!(parse(&(-MAX_RANDOM_REPEATS).to_string()).is_ok());
+        assert!(parse("9223372036854775807").is_ok());
+
+        let mut db = Rdb::default();
+        db.insert(
+            Bytes::from("s"),
+            DBEntry::new(Value::Set(set(&["a"])), None),
+        );
+        let picks = match parse("-3").unwrap().execute(&mut db).unwrap() {
+            Response::Echo(RESPValue::Array(picks)) => picks,
+            other => panic!("unexpected reply {:?}", other),
+        };
+        assert_eq!(picks, vec![RESPValue::BulkString(Bytes::from("a")); 3]);
+    }
+
+    #[test]
+    fn test_only_changes_are_dirty() {
+        let mut db = Rdb::default();
+        db.insert(
+            Bytes::from("str"),
+            DBEntry::new(Value::String(Bytes::from("x")), None),
+        );
+        let mut run = |name: &str, args: &[&str]| {
+            let args: Vec<Bytes> = args
+                .iter()
+                .map(|arg| Bytes::from(arg.to_string()))
+                .collect();
+            let command = SetCommand::parse(
+                name,
+                &mut CommandArgs::new(name.as_bytes(), args.into_iter()),
+            )
+            .unwrap();
+            let _ = command.execute(&mut db);
+            db.dirty()
+        };
+
+        let before = run("SADD", &["s", "a", "b"]);
+        assert_eq!(run("SADD", &["s", "a"]), before);
+        assert_eq!(run("SREM", &["s", "c"]), before);
+        assert_eq!(run("SADD", &["str", "a"]), before);
+        assert_eq!(run("SADD", &["s", "a", "c"]), before + 1);
+    }
+}
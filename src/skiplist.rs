@@ -0,0 +1,349 @@
+//! The ordered index behind sorted sets: a skiplist of `(score, member)` pairs, ordered by score
+//! and then by member, the same structure Redis uses.
+//!
+//! Every link also records its span, the number of entries it skips over, so the rank of an entry
+//! and the entry at a given rank can both be found in O(log n) by adding up spans on the way down.
+//!
+//! Nodes live in a `Vec` and link to each other by index, with removed slots reused, which keeps
+//! this in safe Rust without reference counting. Slot 0 is the header, which holds no entry.
+
+use std::cmp::Ordering;
+
+use bytes::Bytes;
+
+const MAX_LEVEL: usize = 32;
+// The chance of a node reaching each level above the first, as in Redis.
+const LEVEL_PROBABILITY: f64 = 0.25;
+const HEADER: usize = 0;
+
+#[derive(Clone, Debug)]
+pub struct SkipList {
+    nodes: Vec<Node>,
+    free: Vec<usize>,
+    level: usize,
+    len: usize,
+    tail: Option<usize>,
+}
+
+#[derive(Clone, Debug)]
+struct Node {
+    member: Bytes,
+    score: f64,
+    levels: Vec<Link>,
+    backward: Option<usize>,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Link {
+    forward: Option<usize>,
+    span: usize,
+}
+
+impl Default for SkipList {
+    fn default() -> Self {
+        SkipList {
+            nodes: vec![Node {
+                member: Bytes::new(),
+                score: 0.0,
+                levels: vec![Link::default(); MAX_LEVEL],
+                backward: None,
+            }],
+            free: Vec::new(),
+            level: 1,
+            len: 0,
+            tail: None,
+        }
+    }
+}
+
+impl SkipList {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Adds an entry, which must not already be in the list.
+    pub fn insert(&mut self, score: f64, member: Bytes) {
+        let mut update = [HEADER; MAX_LEVEL];
+        let mut rank = [0; MAX_LEVEL];
+
+        let mut x = HEADER;
+        for i in (0..self.level).rev() {
+            rank[i] = if i == self.level - 1 { 0 } else { rank[i + 1] };
+            while let Some(next) = self.nodes[x].levels[i].forward {
+                if self.compare(next, score, &member) != Ordering::Less {
+                    break;
+                }
+                rank[i] += self.nodes[x].levels[i].span;
+                x = next;
+            }
+            update[i] = x;
+        }
+
+        let level = random_level();
+        if level > self.level {
+            for i in self.level..level {
+                rank[i] = 0;
+                update[i] = HEADER;
+                self.nodes[HEADER].levels[i].span = self.len;
+            }
+            self.level = level;
+        }
+
+        let node = Node {
+            member,
+            score,
+            levels: vec![Link::default(); level],
+            backward: (update[0] != HEADER).then_some(update[0]),
+        };
+        let x = match self.free.pop() {
+            Some(slot) => {
+                self.nodes[slot] = node;
+                slot
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        };
+
+        for i in 0..level {
+            let previous = self.nodes[update[i]].levels[i];
+            let skipped = rank[0] - rank[i];
+            self.nodes[x].levels[i] = Link {
+                forward: previous.forward,
+                span: previous.span - skipped,
+            };
+            self.nodes[update[i]].levels[i] = Link {
+                forward: Some(x),
+                span: skipped + 1,
+            };
+        }
+        // The links above the new node's height now skip over one more entry.
+        for (i, &x) in update.iter().enumerate().take(self.level).skip(level) {
+            self.nodes[x].levels[i].span += 1;
+        }
+
+        match self.nodes[x].levels[0].forward {
+            Some(next) => self.nodes[next].backward = Some(x),
+            None => self.tail = Some(x),
+        }
+        self.len += 1;
+    }
+
+    /// Removes an entry, returning whether it was there.
+    pub fn remove(&mut self, score: f64, member: &[u8]) -> bool {
+        let mut update = [HEADER; MAX_LEVEL];
+
+        let mut x = HEADER;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.nodes[x].levels[i].forward {
+                if self.compare(next, score, member) != Ordering::Less {
+                    break;
+                }
+                x = next;
+            }
+            update[i] = x;
+        }
+
+        let Some(x) = self.nodes[x].levels[0].forward else {
+            return false;
+        };
+        if self.compare(x, score, member) != Ordering::Equal {
+            return false;
+        }
+
+        for (i, &previous) in update.iter().enumerate().take(self.level) {
+            if self.nodes[previous].levels[i].forward == Some(x) {
+                let removed = self.nodes[x].levels[i];
+                let link = &mut self.nodes[previous].levels[i];
+                link.span = link.span + removed.span - 1;
+                link.forward = removed.forward;
+            } else {
+                self.nodes[previous].levels[i].span -= 1;
+            }
+        }
+        let backward = self.nodes[x].backward;
+        match self.nodes[x].levels[0].forward {
+            Some(next) => self.nodes[next].backward = backward,
+            None => self.tail = backward,
+        }
+        while self.level > 1 && self.nodes[HEADER].levels[self.level - 1].forward.is_none() {
+            self.level -= 1;
+        }
+
+        self.nodes[x].member = Bytes::new();
+        self.nodes[x].levels = Vec::new();
+        self.free.push(x);
+        self.len -= 1;
+        true
+    }
+
+    /// Counts the entries at the start of the list for which `before` holds, which must be a
+    /// prefix of the list: once `before` is false for one entry, it must be for every later one.
+    ///
+    /// This is how positions are found: the rank of an entry is the number of entries ordered
+    /// before it, and the first entry in a score range is at the rank of the number below it.
+    pub fn count_while(&self, before: impl Fn(f64, &Bytes) -> bool) -> usize {
+        let mut x = HEADER;
+        let mut rank = 0;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.nodes[x].levels[i].forward {
+                let node = &self.nodes[next];
+                if !before(node.score, &node.member) {
+                    break;
+                }
+                rank += self.nodes[x].levels[i].span;
+                x = next;
+            }
+        }
+        rank
+    }
+
+    /// Iterates from the entry at `rank`, counted from zero, towards the end of the list.
+    pub fn iter_from(&self, rank: usize) -> Iter<'_> {
+        Iter {
+            list: self,
+            next: self.node_at(rank),
+            reverse: false,
+        }
+    }
+
+    /// Iterates from the entry at `rank`, counted from zero, back towards the start of the list.
+    pub fn iter_back_from(&self, rank: usize) -> Iter<'_> {
+        Iter {
+            list: self,
+            next: self.node_at(rank),
+            reverse: true,
+        }
+    }
+
+    // Finds the node at `rank` by following the links whose spans don't overshoot it.
+    fn node_at(&self, rank: usize) -> Option<usize> {
+        if rank >= self.len {
+            return None;
+        }
+        if rank == self.len - 1 {
+            return self.tail;
+        }
+
+        let target = rank + 1;
+        let mut traversed = 0;
+        let mut x = HEADER;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.nodes[x].levels[i].forward {
+                if traversed + self.nodes[x].levels[i].span > target {
+                    break;
+                }
+                traversed += self.nodes[x].levels[i].span;
+                x = next;
+            }
+            if traversed == target {
+                return Some(x);
+            }
+        }
+        None
+    }
+
+    // How the node at `x` is ordered relative to the entry `(score, member)`.
+    fn compare(&self, x: usize, score: f64, member: &[u8]) -> Ordering {
+        let node = &self.nodes[x];
+        node.score
+            .partial_cmp(&score)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| node.member.as_ref().cmp(member))
+    }
+}
+
+fn random_level() -> usize {
+    let threshold = (LEVEL_PROBABILITY * u32::MAX as f64) as u64;
+    let mut level = 1;
+    while level < MAX_LEVEL && (crate::random::next_u64() & 0xFFFF_FFFF) < threshold {
+        level += 1;
+    }
+    level
+}
+
+pub struct Iter<'a> {
+    list: &'a SkipList,
+    next: Option<usize>,
+    reverse: bool,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a Bytes, f64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = &self.list.nodes[self.next?];
+        self.next = if self.reverse {
+            node.backward
+        } else {
+            node.levels[0].forward
+        };
+        Some((&node.member, node.score))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(list: &SkipList) -> Vec<(String, f64)> {
+        list.iter_from(0)
+            .map(|(member, score)| (String::from_utf8_lossy(member).into_owned(), score))
+            .collect()
+    }
+
+    #[test]
+    fn test_order_and_ranks() {
+        let mut list = SkipList::default();
+        for i in 0..200 {
+            // Interleave the inserts so the list isn't built in order.
+            let n = (i * 37) % 200;
+            list.insert((n / 2) as f64, Bytes::from(format!("m{:03}", n)));
+        }
+        assert_eq!(list.len(), 200);
+
+        let all = entries(&list);
+        for (rank, (member, score)) in all.iter().enumerate() {
+            assert_eq!(*member, format!("m{:03}", rank));
+            assert_eq!(*score, (rank / 2) as f64);
+
+            let member = member.as_bytes();
+            let before = list.count_while(|s, m| (s, m.as_ref()) < (*score, member));
+            assert_eq!(before, rank);
+            assert_eq!(list.iter_from(rank).next().unwrap().0, member);
+        }
+
+        let backwards: Vec<_> = list.iter_back_from(199).map(|(_, score)| score).collect();
+        assert_eq!(backwards.len(), 200);
+        assert_eq!(backwards[0], 99.0);
+        assert!(list.iter_from(200).next().is_none());
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut list = SkipList::default();
+        for n in 0..100 {
+            list.insert(n as f64, Bytes::from(n.to_string()));
+        }
+        for n in (0..100).step_by(3) {
+            assert!(list.remove(n as f64, n.to_string().as_bytes()));
+        }
+        assert!(!list.remove(0.0, b"0"));
+        assert!(!list.remove(1.0, b"2"));
+
+        let remaining: Vec<f64> = (0..100).filter(|n| n % 3 != 0).map(|n| n as f64).collect();
+        assert_eq!(list.len(), remaining.len());
+        for (rank, score) in remaining.iter().enumerate() {
+            assert_eq!(list.iter_from(rank).next().unwrap().1, *score);
+            assert_eq!(list.count_while(|s, _| s < *score), rank);
+        }
+        assert_eq!(list.iter_back_from(list.len() - 1).next().unwrap().1, 98.0);
+
+        // Freed slots get reused rather than growing the arena.
+        let slots = list.nodes.len();
+        list.insert(0.5, Bytes::from("new"));
+        assert_eq!(list.nodes.len(), slots);
+        assert_eq!(list.iter_from(0).next().unwrap().0.as_ref(), b"new");
+    }
+}
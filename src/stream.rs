@@ -0,0 +1,2024 @@
+//! The stream type: an append-only log of entries, each a list of field-value pairs filed under an
+//! ID that grows with every entry, along with the consumer groups reading from it.
+//!
+//! A consumer group remembers the last entry it handed out and which of its consumers each
+//! delivered entry is pending with, until that consumer acknowledges it.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    ops::Bound,
+    time::{Duration, SystemTime},
+};
+
+use bytes::Bytes;
+use tokio::sync::Notify;
+
+use crate::{
+    error::CommandError,
+    protocol_parser::{parse_integer, CommandArgs, Protocol, RESPValue, Response},
+    rdb::{unix_millis, Rdb, Value},
+};
+
+/// Woken whenever an entry is added to any stream, so blocked XREAD and XREADGROUP calls can check
+/// whether there's anything for them yet.
+static NEW_ENTRIES: Notify = Notify::const_new();
+
+// How many entries XINFO STREAM FULL and XAUTOCLAIM return when no COUNT is given.
+const DEFAULT_INFO_COUNT: usize = 10;
+const DEFAULT_AUTOCLAIM_COUNT: usize = 100;
+
+/// The ID of a stream entry: the milliseconds part, normally the time the entry was added, and a
+/// sequence number telling apart entries added in the same millisecond.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+    pub const MAX: StreamId = StreamId {
+        ms: u64::MAX,
+        seq: u64::MAX,
+    };
+
+    /// Parses an ID given as `ms-seq`, or as just `ms` with `missing_seq` as its sequence number.
+    pub fn parse(arg: &[u8], missing_seq: u64) -> Result<Self, CommandError> {
+        let (ms, seq) = match arg.iter().position(|&c| c == b'-') {
+            Some(dash) => (&arg[..dash], Some(&arg[dash + 1..])),
+            None => (arg, None),
+        };
+        let ms = parse_integer(ms).map_err(|_| invalid_id())?;
+        let seq = match seq {
+            Some(seq) => parse_integer(seq).map_err(|_| invalid_id())?,
+            None => missing_seq,
+        };
+        Ok(StreamId { ms, seq })
+    }
+
+    /// The smallest ID after this one, if there is one.
+    fn successor(self) -> Option<Self> {
+        match self.seq.checked_add(1) {
+            Some(seq) => Some(StreamId { seq, ..self }),
+            None => self.ms.checked_add(1).map(|ms| StreamId { ms, seq: 0 }),
+        }
+    }
+
+    /// The largest ID before this one, if there is one.
+    fn predecessor(self) -> Option<Self> {
+        match self.seq.checked_sub(1) {
+            Some(seq) => Some(StreamId { seq, ..self }),
+            None => self
+                .ms
+                .checked_sub(1)
+                .map(|ms| StreamId { ms, seq: u64::MAX }),
+        }
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+pub type Fields = Vec<(Bytes, Bytes)>;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Stream {
+    log: Log,
+    groups: BTreeMap<Bytes, ConsumerGroup>,
+}
+
+// The entries themselves, kept apart from the groups so a group can be updated while it reads them.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Log {
+    entries: BTreeMap<StreamId, Fields>,
+    last_id: StreamId,
+    /// The greatest ID ever deleted by XDEL, which tells whether a group's read count can still be
+    /// worked out by counting entries.
+    max_deleted_id: StreamId,
+    /// How many entries have ever been added, including ones since deleted or trimmed.
+    entries_added: u64,
+}
+
+impl Stream {
+    pub fn len(&self) -> usize {
+        self.log.entries.len()
+    }
+
+    pub fn last_id(&self) -> StreamId {
+        self.log.last_id
+    }
+
+    /// Every entry, oldest first.
+    pub fn entries(&self) -> impl Iterator<Item = (&StreamId, &Fields)> {
+        self.log.entries.iter()
+    }
+
+    /// The ID of the oldest entry, or 0-0 if there are none.
+    pub fn first_id(&self) -> StreamId {
+        self.log.first_id()
+    }
+
+    pub fn max_deleted_id(&self) -> StreamId {
+        self.log.max_deleted_id
+    }
+
+    pub fn entries_added(&self) -> u64 {
+        self.log.entries_added
+    }
+
+    pub fn groups(&self) -> impl Iterator<Item = (&Bytes, &ConsumerGroup)> {
+        self.groups.iter()
+    }
+
+    /// Rebuilds a stream as it was saved, with no groups yet.
+    pub fn restore(
+        entries: BTreeMap<StreamId, Fields>,
+        last_id: StreamId,
+        max_deleted_id: StreamId,
+        entries_added: u64,
+    ) -> Self {
+        Stream {
+            log: Log {
+                entries,
+                last_id,
+                max_deleted_id,
+                entries_added,
+            },
+            groups: BTreeMap::new(),
+        }
+    }
+
+    /// Adds a group read back from a saved stream.
+    pub fn restore_group(&mut self, name: Bytes, group: ConsumerGroup) {
+        self.groups.insert(name, group);
+    }
+
+    /// Works out the ID of the next entry XADD adds, which must be greater than any before it.
+    fn next_id(&self, spec: IdSpec) -> Result<StreamId, CommandError> {
+        let last = self.log.last_id;
+        let id = match spec {
+            IdSpec::Auto => {
+                let now = unix_millis(SystemTime::now()).max(0) as u64;
+                if now > last.ms {
+                    Some(StreamId { ms: now, seq: 0 })
+                } else {
+                    last.successor()
+                }
+            }
+            IdSpec::AutoSeq(ms) if ms > last.ms => Some(StreamId { ms, seq: 0 }),
+            IdSpec::AutoSeq(ms) if ms == last.ms => {
+                last.seq.checked_add(1).map(|seq| StreamId { ms, seq })
+            }
+            IdSpec::AutoSeq(_) => None,
+            IdSpec::Explicit(id) => (id > last).then_some(id),
+        };
+
+        id.ok_or_else(|| match spec {
+            IdSpec::Auto => CommandError::Other(
+                "The stream has exhausted the last possible ID, unable to add more items"
+                    .to_string(),
+            ),
+            _ => CommandError::Other(
+                "The ID specified in XADD is equal or smaller than the target stream top item"
+                    .to_string(),
+            ),
+        })
+    }
+
+    fn add(&mut self, id: StreamId, fields: Fields) {
+        self.log.entries.insert(id, fields);
+        self.log.last_id = id;
+        self.log.entries_added += 1;
+    }
+
+    fn delete(&mut self, id: StreamId) -> bool {
+        let deleted = self.log.entries.remove(&id).is_some();
+        if deleted {
+            self.log.max_deleted_id = self.log.max_deleted_id.max(id);
+        }
+        deleted
+    }
+
+    /// Removes entries from the start of the stream, returning how many went.
+    fn trim(&mut self, trim: &Trim) -> usize {
+        // Entries are always trimmed exactly, so `~` only matters in allowing a LIMIT.
+        let limit = trim.limit.unwrap_or(usize::MAX);
+        let mut removed = 0;
+        while removed < limit {
+            let Some(&first) = self.log.entries.keys().next() else {
+                break;
+            };
+            let excess = match trim.threshold {
+                Threshold::MaxLen(max) => self.log.entries.len() > max,
+                Threshold::MinId(min) => first < min,
+            };
+            if !excess {
+                break;
+            }
+            self.log.entries.remove(&first);
+            removed += 1;
+        }
+        removed
+    }
+}
+
+impl Log {
+    fn first_id(&self) -> StreamId {
+        self.entries.keys().next().copied().unwrap_or(StreamId::MIN)
+    }
+
+    fn range(
+        &self,
+        start: StreamId,
+        end: StreamId,
+    ) -> impl DoubleEndedIterator<Item = (&StreamId, &Fields)> {
+        // `BTreeMap::range` panics on a backwards range rather than returning nothing.
+        if start <= end {
+            self.entries.range(start..=end)
+        } else {
+            self.entries.range(StreamId::MIN..StreamId::MIN)
+        }
+    }
+
+    fn after(&self, id: StreamId) -> impl Iterator<Item = (&StreamId, &Fields)> {
+        self.entries.range((Bound::Excluded(id), Bound::Unbounded))
+    }
+
+    // Whether an entry at or after `start` was deleted, meaning counting entries from there no
+    // longer tells how many were ever added.
+    fn has_tombstones(&self, start: StreamId) -> bool {
+        if self.entries.is_empty() || self.max_deleted_id == StreamId::MIN {
+            return false;
+        }
+        start <= self.max_deleted_id && self.last_id >= self.max_deleted_id
+    }
+
+    // Works out how many entries had been added up to and including `id`, if that can be known.
+    fn entries_read_at(&self, id: StreamId) -> Option<u64> {
+        if self.entries_added == 0 {
+            return Some(0);
+        }
+        if self.entries.is_empty() && id <= self.last_id {
+            return Some(self.entries_added);
+        }
+        match id.cmp(&self.last_id) {
+            std::cmp::Ordering::Equal => return Some(self.entries_added),
+            std::cmp::Ordering::Greater => return None,
+            std::cmp::Ordering::Less => {}
+        }
+
+        // With no deletions past the first entry, everything before it was trimmed away.
+        let first = self.first_id();
+        let unfragmented = self.max_deleted_id == StreamId::MIN || self.max_deleted_id < first;
+        (unfragmented && id < first).then(|| self.entries_added - self.entries.len() as u64)
+    }
+
+    // How many entries a group has yet to be handed, if that can be known.
+    fn lag(&self, group: &ConsumerGroup) -> Option<u64> {
+        if self.entries_added == 0 {
+            return Some(0);
+        }
+        let read = match group.entries_read {
+            Some(read) if !self.has_tombstones(group.last_delivered) => Some(read),
+            _ => self.entries_read_at(group.last_delivered),
+        };
+        read.map(|read| self.entries_added.saturating_sub(read))
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConsumerGroup {
+    last_delivered: StreamId,
+    /// How many entries the group has been handed, or `None` when that can't be known, as after
+    /// its position was set by ID.
+    entries_read: Option<u64>,
+    /// The entries delivered but not yet acknowledged, across every consumer.
+    pending: BTreeMap<StreamId, PendingEntry>,
+    consumers: BTreeMap<Bytes, Consumer>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PendingEntry {
+    consumer: Bytes,
+    /// Milliseconds since the Unix epoch.
+    delivered_at: i64,
+    deliveries: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Consumer {
+    /// When the consumer last tried to do anything, in milliseconds since the Unix epoch.
+    seen_at: i64,
+    /// When the consumer last actually read or claimed an entry.
+    active_at: Option<i64>,
+    pending: BTreeSet<StreamId>,
+}
+
+impl PendingEntry {
+    pub fn delivered_at(&self) -> i64 {
+        self.delivered_at
+    }
+
+    pub fn deliveries(&self) -> u64 {
+        self.deliveries
+    }
+}
+
+impl Consumer {
+    pub fn seen_at(&self) -> i64 {
+        self.seen_at
+    }
+
+    pub fn active_at(&self) -> Option<i64> {
+        self.active_at
+    }
+
+    pub fn pending(&self) -> impl Iterator<Item = &StreamId> {
+        self.pending.iter()
+    }
+}
+
+impl ConsumerGroup {
+    pub fn last_delivered(&self) -> StreamId {
+        self.last_delivered
+    }
+
+    pub fn entries_read(&self) -> Option<u64> {
+        self.entries_read
+    }
+
+    pub fn pending(&self) -> impl Iterator<Item = (&StreamId, &PendingEntry)> {
+        self.pending.iter()
+    }
+
+    pub fn consumers(&self) -> impl Iterator<Item = (&Bytes, &Consumer)> {
+        self.consumers.iter()
+    }
+
+    pub fn new(last_delivered: StreamId, entries_read: Option<u64>) -> Self {
+        ConsumerGroup {
+            last_delivered,
+            entries_read,
+            ..Default::default()
+        }
+    }
+
+    /// Adds a consumer read back from a saved stream, along with the entries pending with it, each
+    /// with when it was last delivered and how many times.
+    pub fn restore_consumer(
+        &mut self,
+        name: Bytes,
+        seen_at: i64,
+        active_at: Option<i64>,
+        pending: Vec<(StreamId, i64, u64)>,
+    ) {
+        self.consumers.insert(
+            name.clone(),
+            Consumer {
+                seen_at,
+                active_at,
+                pending: BTreeSet::new(),
+            },
+        );
+        for (id, delivered_at, deliveries) in pending {
+            self.assign(id, &name, delivered_at, deliveries);
+        }
+    }
+
+    /// Looks up a consumer, creating it if this is the first we've heard of it, and records that
+    /// it was seen.
+    fn consumer(&mut self, name: &Bytes, now: i64) -> &mut Consumer {
+        let consumer = self
+            .consumers
+            .entry(name.clone())
+            .or_insert_with(|| Consumer {
+                seen_at: now,
+                active_at: None,
+                pending: BTreeSet::new(),
+            });
+        consumer.seen_at = now;
+        consumer
+    }
+
+    // Makes an entry pending with `consumer`, which must exist, taking it off whichever consumer
+    // it was pending with before.
+    fn assign(&mut self, id: StreamId, consumer: &Bytes, delivered_at: i64, deliveries: u64) {
+        let entry = PendingEntry {
+            consumer: consumer.clone(),
+            delivered_at,
+            deliveries,
+        };
+        if let Some(previous) = self.pending.insert(id, entry) {
+            if let Some(owner) = self.consumers.get_mut(&previous.consumer) {
+                owner.pending.remove(&id);
+            }
+        }
+        if let Some(owner) = self.consumers.get_mut(consumer) {
+            owner.pending.insert(id);
+        }
+    }
+
+    fn ack(&mut self, id: StreamId) -> bool {
+        let Some(entry) = self.pending.remove(&id) else {
+            return false;
+        };
+        if let Some(owner) = self.consumers.get_mut(&entry.consumer) {
+            owner.pending.remove(&id);
+        }
+        true
+    }
+
+    /// Hands `consumer` the entries after the last one delivered to the group.
+    fn deliver_new(
+        &mut self,
+        log: &Log,
+        consumer: &Bytes,
+        count: usize,
+        noack: bool,
+        now: i64,
+    ) -> Vec<RESPValue> {
+        let mut replies = Vec::new();
+        for (&id, fields) in log.after(self.last_delivered).take(count) {
+            // The read count can be kept up by counting, unless entries past the group's position
+            // have been deleted, in which case it has to be worked out afresh.
+            self.entries_read = match self.entries_read {
+                Some(read) if !log.has_tombstones(self.last_delivered) => Some(read + 1),
+                _ => log.entries_read_at(id),
+            };
+            self.last_delivered = id;
+            if !noack {
+                self.assign(id, consumer, now, 1);
+            }
+            replies.push(entry_value(id, fields));
+        }
+
+        if !replies.is_empty() {
+            self.consumer(consumer, now).active_at = Some(now);
+        }
+        replies
+    }
+
+    /// Hands `consumer` the entries after `after` that are already pending with it, counting them
+    /// as delivered again. Entries that have since been deleted come back without their fields.
+    fn deliver_pending(
+        &mut self,
+        log: &Log,
+        consumer: &Bytes,
+        after: StreamId,
+        count: usize,
+        now: i64,
+    ) -> Vec<RESPValue> {
+        let ids: Vec<StreamId> = match self.consumers.get(consumer) {
+            Some(owner) => owner
+                .pending
+                .range((Bound::Excluded(after), Bound::Unbounded))
+                .take(count)
+                .copied()
+                .collect(),
+            None => Vec::new(),
+        };
+
+        ids.into_iter()
+            .map(|id| {
+                if let Some(entry) = self.pending.get_mut(&id) {
+                    entry.delivered_at = now;
+                    entry.deliveries += 1;
+                }
+                match log.entries.get(&id) {
+                    Some(fields) => entry_value(id, fields),
+                    None => RESPValue::Array(vec![id_value(id), RESPValue::Null]),
+                }
+            })
+            .collect()
+    }
+}
+
+/// How XADD was asked to pick the new entry's ID.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IdSpec {
+    /// `*`: the current time, or just after the last entry if the clock is behind it.
+    Auto,
+    /// `ms-*`: the given milliseconds, with the next free sequence number.
+    AutoSeq(u64),
+    Explicit(StreamId),
+}
+
+impl IdSpec {
+    fn parse(arg: &[u8]) -> Result<Self, CommandError> {
+        if arg == b"*" {
+            return Ok(IdSpec::Auto);
+        }
+        if let Some(ms) = arg.strip_suffix(b"-*") {
+            return Ok(IdSpec::AutoSeq(
+                parse_integer(ms).map_err(|_| invalid_id())?,
+            ));
+        }
+
+        let id = StreamId::parse(arg, 0)?;
+        if id == StreamId::MIN {
+            return Err(CommandError::Other(
+                "The ID specified in XADD must be greater than 0-0".to_string(),
+            ));
+        }
+        Ok(IdSpec::Explicit(id))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Threshold {
+    MaxLen(usize),
+    MinId(StreamId),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Trim {
+    threshold: Threshold,
+    limit: Option<usize>,
+}
+
+// The trimming options XADD and XTRIM share, gathered in whatever order they're given.
+#[derive(Default)]
+struct TrimArgs {
+    threshold: Option<Threshold>,
+    approximate: bool,
+    limit: Option<usize>,
+}
+
+impl TrimArgs {
+    /// Parses `option`, which must be upper-case, if it's one of the trimming options, returning
+    /// whether it was.
+    fn parse_option(&mut self, option: &str, args: &mut CommandArgs) -> Result<bool, CommandError> {
+        match option {
+            "MAXLEN" | "MINID" => {
+                let mut value = args.option_value()?;
+                if value.as_ref() == b"=" || value.as_ref() == b"~" {
+                    self.approximate = value.as_ref() == b"~";
+                    value = args.option_value()?;
+                }
+                self.threshold = Some(if option == "MAXLEN" {
+                    let max: i64 = parse_integer(&value)?;
+                    Threshold::MaxLen(usize::try_from(max).map_err(|_| {
+                        CommandError::Other("The MAXLEN argument must be >= 0.".to_string())
+                    })?)
+                } else {
+                    Threshold::MinId(StreamId::parse(&value, 0)?)
+                });
+            }
+            "LIMIT" => {
+                let limit: i64 = parse_integer(&args.option_value()?)?;
+                self.limit = Some(usize::try_from(limit).map_err(|_| {
+                    CommandError::Other("The LIMIT argument must be >= 0.".to_string())
+                })?);
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    fn finish(self) -> Result<Option<Trim>, CommandError> {
+        if self.limit.is_some() && !self.approximate {
+            return Err(CommandError::Other(
+                "syntax error, LIMIT cannot be used without the special ~ option".to_string(),
+            ));
+        }
+        Ok(self.threshold.map(|threshold| Trim {
+            threshold,
+            limit: self.limit,
+        }))
+    }
+}
+
+/// Where XREAD or XREADGROUP starts reading a stream from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReadStart {
+    After(StreamId),
+    /// `$`: only entries added from now on.
+    New,
+    /// `+`: the last entry.
+    Last,
+    /// `>`: the entries never delivered to the group.
+    Undelivered,
+}
+
+impl ReadStart {
+    fn parse(arg: &[u8], group: bool) -> Result<Self, CommandError> {
+        match (arg, group) {
+            (b"$", false) => Ok(ReadStart::New),
+            (b"+", false) => Ok(ReadStart::Last),
+            (b">", true) => Ok(ReadStart::Undelivered),
+            (b"$", true) => Err(CommandError::Other(
+                "The $ ID is meaningless in the context of XREADGROUP: you want to read the \
+                 history of this consumer by specifying a proper ID, or use the > ID to get new \
+                 messages. The $ ID would just return an empty result set."
+                    .to_string(),
+            )),
+            (b">", false) => Err(CommandError::Other(
+                "The > ID can be specified only when calling XREADGROUP using the GROUP <group> \
+                 <consumer> option."
+                    .to_string(),
+            )),
+            _ => Ok(ReadStart::After(StreamId::parse(arg, 0)?)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct GroupRead {
+    group: Bytes,
+    consumer: Bytes,
+    noack: bool,
+}
+
+/// An XREAD or XREADGROUP call, which may block until there are entries to return.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Read {
+    streams: Vec<(Bytes, ReadStart)>,
+    count: Option<usize>,
+    /// How long to wait for entries, where zero means for as long as it takes.
+    block: Option<Duration>,
+    group: Option<GroupRead>,
+}
+
+impl Read {
+    /// Whether the call should wait for entries if there aren't any yet.
+    pub fn blocks(&self) -> bool {
+        self.block.is_some()
+    }
+
+    fn parse(args: &mut CommandArgs, group: Option<GroupRead>) -> Result<Self, CommandError> {
+        let mut read = Read {
+            streams: Vec::new(),
+            count: None,
+            block: None,
+            group,
+        };
+
+        loop {
+            let option = String::from_utf8_lossy(&args.required()?).to_ascii_uppercase();
+            match option.as_str() {
+                "COUNT" => {
+                    // Redis treats a count of zero or less as no limit at all.
+                    let count: i64 = parse_integer(&args.option_value()?)?;
+                    read.count = (count > 0).then_some(count as usize);
+                }
+                "BLOCK" => {
+                    let timeout: i64 = parse_integer(&args.option_value()?).map_err(|_| {
+                        CommandError::Other("timeout is not an integer or out of range".to_string())
+                    })?;
+                    let timeout = u64::try_from(timeout)
+                        .map_err(|_| CommandError::Other("timeout is negative".to_string()))?;
+                    read.block = Some(Duration::from_millis(timeout));
+                }
+                "NOACK" if read.group.is_some() => {
+                    if let Some(group) = read.group.as_mut() {
+                        group.noack = true;
+                    }
+                }
+                "STREAMS" => break,
+                _ => return Err(CommandError::Syntax),
+            }
+        }
+
+        let rest = args.at_least_one()?;
+        if rest.len() % 2 != 0 {
+            let command = if read.group.is_some() {
+                "xreadgroup"
+            } else {
+                "xread"
+            };
+            return Err(CommandError::Other(format!(
+                "Unbalanced '{}' list of streams: for each stream key an ID or '$' must be \
+                 specified.",
+                command
+            )));
+        }
+        let (keys, ids) = rest.split_at(rest.len() / 2);
+        for (key, id) in keys.iter().zip(ids) {
+            let start = ReadStart::parse(id, read.group.is_some())?;
+            read.streams.push((key.clone(), start));
+        }
+
+        Ok(read)
+    }
+
+    fn execute(self, db: &mut Rdb, protocol: Protocol) -> Result<Response, CommandError> {
+        let count = self.count.unwrap_or(usize::MAX);
+        let replies = match &self.group {
+            None => read_streams(db, &self.streams, count)?,
+            Some(group) => read_group(db, &self.streams, group, count)?,
+        };
+
+        if replies.is_empty() {
+            return Ok(Response::Echo(RESPValue::NullArray));
+        }
+        let reply = match protocol {
+            Protocol::Resp3 => RESPValue::Map(replies),
+            Protocol::Resp2 => RESPValue::Array(
+                replies
+                    .into_iter()
+                    .map(|(key, entries)| RESPValue::Array(vec![key, entries]))
+                    .collect(),
+            ),
+        };
+        Ok(Response::Echo(reply))
+    }
+}
+
+/// Runs a blocking XREAD or XREADGROUP: if there's nothing to return yet, waits for entries to be
+/// added until there is, or until the timeout runs out and the reply is null.
+pub async fn read_blocking(mut read: Read, protocol: Protocol) -> Result<Response, CommandError> {
+    let deadline = read
+        .block
+        .filter(|timeout| !timeout.is_zero())
+        .map(|timeout| tokio::time::Instant::now() + timeout);
+
+    // `$` means whatever gets added after the call, so pin it down before waiting.
+    {
+        let mut db = crate::db();
+        for (key, start) in &mut read.streams {
+            if *start == ReadStart::New {
+                let last_id = stream(&mut db, key)?.map_or(StreamId::MIN, Stream::last_id);
+                *start = ReadStart::After(last_id);
+            }
+        }
+    }
+
+    loop {
+        // Register for the wakeup before looking, so an entry added in between isn't missed.
+        let notified = NEW_ENTRIES.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+
+        let response = read.clone().execute(&mut crate::db(), protocol)?;
+        if response != Response::Echo(RESPValue::NullArray) {
+            return Ok(response);
+        }
+
+        match deadline {
+            Some(deadline) => {
+                if tokio::time::timeout_at(deadline, notified).await.is_err() {
+                    return Ok(Response::Echo(RESPValue::NullArray));
+                }
+            }
+            None => notified.await,
+        }
+    }
+}
+
+// Reads each stream from where XREAD was asked to, leaving out streams with nothing to return.
+fn read_streams(
+    db: &mut Rdb,
+    streams: &[(Bytes, ReadStart)],
+    count: usize,
+) -> Result<Vec<(RESPValue, RESPValue)>, CommandError> {
+    let mut replies = Vec::new();
+    for (key, start) in streams {
+        let Some(stream) = stream(db, key)? else {
+            continue;
+        };
+        let entries: Vec<RESPValue> = match *start {
+            ReadStart::After(id) => stream
+                .log
+                .after(id)
+                .take(count)
+                .map(|(&id, fields)| entry_value(id, fields))
+                .collect(),
+            ReadStart::Last => stream
+                .log
+                .entries
+                .iter()
+                .next_back()
+                .map(|(&id, fields)| entry_value(id, fields))
+                .into_iter()
+                .collect(),
+            ReadStart::New | ReadStart::Undelivered => Vec::new(),
+        };
+        if !entries.is_empty() {
+            replies.push((bulk(key), RESPValue::Array(entries)));
+        }
+    }
+    Ok(replies)
+}
+
+// Reads each stream through a consumer group. New entries are only replied with when there are
+// some, but a consumer's history always is, even when it's empty.
+fn read_group(
+    db: &mut Rdb,
+    streams: &[(Bytes, ReadStart)],
+    read: &GroupRead,
+    count: usize,
+) -> Result<Vec<(RESPValue, RESPValue)>, CommandError> {
+    let no_group = |key: &Bytes| {
+        CommandError::NoGroup(format!(
+            "No such key '{}' or consumer group '{}' in XREADGROUP with GROUP option",
+            String::from_utf8_lossy(key),
+            String::from_utf8_lossy(&read.group)
+        ))
+    };
+
+    // Every group has to exist before any of them is read from.
+    for (key, _) in streams {
+        let exists = stream(db, key)?.is_some_and(|stream| stream.groups.contains_key(&read.group));
+        if !exists {
+            return Err(no_group(key));
+        }
+    }
+
+    let now = now_millis();
+    let mut replies = Vec::new();
+    // Delivering entries changes what the group has pending, and a new consumer is saved too.
+    let mut changes = 0;
+    for (key, start) in streams {
+        let Stream { log, groups } = stream_mut(db, key)?.ok_or_else(|| no_group(key))?;
+        let group = groups.get_mut(&read.group).ok_or_else(|| no_group(key))?;
+        if !group.consumers.contains_key(&read.consumer) {
+            changes += 1;
+        }
+        group.consumer(&read.consumer, now);
+
+        match *start {
+            ReadStart::After(after) => {
+                let entries = group.deliver_pending(log, &read.consumer, after, count, now);
+                changes += entries.len();
+                replies.push((bulk(key), RESPValue::Array(entries)));
+            }
+            _ => {
+                let entries = group.deliver_new(log, &read.consumer, count, read.noack, now);
+                changes += entries.len();
+                if !entries.is_empty() {
+                    replies.push((bulk(key), RESPValue::Array(entries)));
+                }
+            }
+        }
+    }
+    db.mark_dirty(changes);
+    Ok(replies)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum GroupCommand {
+    Create {
+        key: Bytes,
+        group: Bytes,
+        /// `None` for `$`, the stream's last entry.
+        id: Option<StreamId>,
+        mkstream: bool,
+        entries_read: Option<u64>,
+    },
+    SetId {
+        key: Bytes,
+        group: Bytes,
+        id: Option<StreamId>,
+        entries_read: Option<u64>,
+    },
+    Destroy {
+        key: Bytes,
+        group: Bytes,
+    },
+    CreateConsumer {
+        key: Bytes,
+        group: Bytes,
+        consumer: Bytes,
+    },
+    DelConsumer {
+        key: Bytes,
+        group: Bytes,
+        consumer: Bytes,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum InfoCommand {
+    Stream {
+        key: Bytes,
+        /// Set for FULL, with the number of entries and pending entries to include.
+        full: Option<usize>,
+    },
+    Groups(Bytes),
+    Consumers {
+        key: Bytes,
+        group: Bytes,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PendingRange {
+    min_idle: Option<i64>,
+    start: StreamId,
+    end: StreamId,
+    count: usize,
+    consumer: Option<Bytes>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClaimOptions {
+    /// When the claimed entries count as delivered, in milliseconds since the Unix epoch.
+    delivered_at: Option<i64>,
+    retry_count: Option<u64>,
+    force: bool,
+    just_id: bool,
+    last_id: Option<StreamId>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum StreamCommand {
+    Add {
+        key: Bytes,
+        id: IdSpec,
+        fields: Fields,
+        nomkstream: bool,
+        trim: Option<Trim>,
+    },
+    Len(Bytes),
+    Range {
+        key: Bytes,
+        start: StreamId,
+        end: StreamId,
+        count: Option<usize>,
+        rev: bool,
+    },
+    Del {
+        key: Bytes,
+        ids: Vec<StreamId>,
+    },
+    Trim {
+        key: Bytes,
+        trim: Trim,
+    },
+    Read(Read),
+    Group(GroupCommand),
+    Ack {
+        key: Bytes,
+        group: Bytes,
+        ids: Vec<StreamId>,
+    },
+    Pending {
+        key: Bytes,
+        group: Bytes,
+        /// Set for the extended form, which lists pending entries rather than summarising them.
+        range: Option<PendingRange>,
+    },
+    Claim {
+        key: Bytes,
+        group: Bytes,
+        consumer: Bytes,
+        min_idle: i64,
+        ids: Vec<StreamId>,
+        options: ClaimOptions,
+    },
+    AutoClaim {
+        key: Bytes,
+        group: Bytes,
+        consumer: Bytes,
+        min_idle: i64,
+        start: StreamId,
+        count: usize,
+        just_id: bool,
+    },
+    Info(InfoCommand),
+}
+
+impl StreamCommand {
+    /// Parses the arguments of the stream command `name`, which must be upper-case.
+    pub fn parse(name: &str, args: &mut CommandArgs) -> Result<Self, CommandError> {
+        let command = match name {
+            "XADD" => {
+                let key = args.required()?;
+                let mut nomkstream = false;
+                let mut trim = TrimArgs::default();
+                // The options come before the ID, and the first argument that isn't one is it.
+                let id = loop {
+                    let arg = args.required()?;
+                    let option = String::from_utf8_lossy(&arg).to_ascii_uppercase();
+                    if option == "NOMKSTREAM" {
+                        nomkstream = true;
+                    } else if !trim.parse_option(&option, args)? {
+                        break IdSpec::parse(&arg)?;
+                    }
+                };
+                let trim = trim.finish()?;
+
+                let mut fields = Vec::new();
+                while let Some(field) = args.next() {
+                    fields.push((field, args.required()?));
+                }
+                if fields.is_empty() {
+                    return Err(args.arity_error());
+                }
+
+                StreamCommand::Add {
+                    key,
+                    id,
+                    fields,
+                    nomkstream,
+                    trim,
+                }
+            }
+            "XLEN" => StreamCommand::Len(args.required()?),
+            "XRANGE" | "XREVRANGE" => {
+                let rev = name == "XREVRANGE";
+                let key = args.required()?;
+                let (first, second) = (args.required()?, args.required()?);
+                let (start, end) = if rev {
+                    (second, first)
+                } else {
+                    (first, second)
+                };
+                let start = parse_range_bound(&start, false)?;
+                let end = parse_range_bound(&end, true)?;
+
+                let count = match args.next() {
+                    Some(option) if option.eq_ignore_ascii_case(b"COUNT") => {
+                        let count: i64 = parse_integer(&args.option_value()?)?;
+                        Some(count.max(0) as usize)
+                    }
+                    Some(_) => return Err(CommandError::Syntax),
+                    None => None,
+                };
+
+                StreamCommand::Range {
+                    key,
+                    start,
+                    end,
+                    count,
+                    rev,
+                }
+            }
+            "XDEL" => StreamCommand::Del {
+                key: args.required()?,
+                ids: parse_ids(args.at_least_one()?)?,
+            },
+            "XTRIM" => {
+                let key = args.required()?;
+                let mut trim = TrimArgs::default();
+                while let Some(arg) = args.next() {
+                    let option = String::from_utf8_lossy(&arg).to_ascii_uppercase();
+                    if !trim.parse_option(&option, args)? {
+                        return Err(CommandError::Syntax);
+                    }
+                }
+                let trim = trim.finish()?.ok_or(CommandError::Syntax)?;
+                StreamCommand::Trim { key, trim }
+            }
+            "XREAD" => StreamCommand::Read(Read::parse(args, None)?),
+            "XREADGROUP" => {
+                if !args.required()?.eq_ignore_ascii_case(b"GROUP") {
+                    return Err(CommandError::Syntax);
+                }
+                let group = GroupRead {
+                    group: args.required()?,
+                    consumer: args.required()?,
+                    noack: false,
+                };
+                StreamCommand::Read(Read::parse(args, Some(group))?)
+            }
+            "XGROUP" => StreamCommand::Group(parse_group_command(args)?),
+            "XACK" => StreamCommand::Ack {
+                key: args.required()?,
+                group: args.required()?,
+                ids: parse_ids(args.at_least_one()?)?,
+            },
+            "XPENDING" => {
+                let key = args.required()?;
+                let group = args.required()?;
+                let range = match args.next() {
+                    None => None,
+                    Some(mut arg) => {
+                        let mut min_idle = None;
+                        if arg.eq_ignore_ascii_case(b"IDLE") {
+                            min_idle = Some(parse_integer(&args.option_value()?)?);
+                            arg = args.required()?;
+                        }
+                        let start = parse_range_bound(&arg, false)?;
+                        let end = parse_range_bound(&args.required()?, true)?;
+                        let count: i64 = parse_integer(&args.required()?)?;
+                        Some(PendingRange {
+                            min_idle,
+                            start,
+                            end,
+                            count: count.max(0) as usize,
+                            consumer: args.next(),
+                        })
+                    }
+                };
+                StreamCommand::Pending { key, group, range }
+            }
+            "XCLAIM" => {
+                let key = args.required()?;
+                let group = args.required()?;
+                let consumer = args.required()?;
+                let min_idle = parse_min_idle(&args.required()?, name)?;
+
+                // The IDs run until the first argument that isn't one, which starts the options.
+                let mut ids = vec![StreamId::parse(&args.required()?, 0)?];
+                let mut option = None;
+                while let Some(arg) = args.next() {
+                    match StreamId::parse(&arg, 0) {
+                        Ok(id) => ids.push(id),
+                        Err(_) => {
+                            option = Some(arg);
+                            break;
+                        }
+                    }
+                }
+
+                let mut options = ClaimOptions {
+                    delivered_at: None,
+                    retry_count: None,
+                    force: false,
+                    just_id: false,
+                    last_id: None,
+                };
+                while let Some(arg) = option.take().or_else(|| args.next()) {
+                    match String::from_utf8_lossy(&arg).to_ascii_uppercase().as_str() {
+                        "IDLE" => {
+                            let idle: i64 = parse_integer(&args.option_value()?)?;
+                            options.delivered_at = Some(now_millis() - idle.max(0));
+                        }
+                        "TIME" => {
+                            options.delivered_at = Some(parse_integer(&args.option_value()?)?)
+                        }
+                        "RETRYCOUNT" => {
+                            options.retry_count = Some(parse_integer(&args.option_value()?)?)
+                        }
+                        "FORCE" => options.force = true,
+                        "JUSTID" => options.just_id = true,
+                        "LASTID" => {
+                            options.last_id = Some(StreamId::parse(&args.option_value()?, 0)?)
+                        }
+                        _ => {
+                            return Err(CommandError::Other(format!(
+                                "Unrecognized XCLAIM option '{}'",
+                                String::from_utf8_lossy(&arg)
+                            )))
+                        }
+                    }
+                }
+
+                StreamCommand::Claim {
+                    key,
+                    group,
+                    consumer,
+                    min_idle,
+                    ids,
+                    options,
+                }
+            }
+            "XAUTOCLAIM" => {
+                let key = args.required()?;
+                let group = args.required()?;
+                let consumer = args.required()?;
+                let min_idle = parse_min_idle(&args.required()?, name)?;
+                let start = parse_range_bound(&args.required()?, false)?;
+
+                let mut count = DEFAULT_AUTOCLAIM_COUNT;
+                let mut just_id = false;
+                while let Some(arg) = args.next() {
+                    match String::from_utf8_lossy(&arg).to_ascii_uppercase().as_str() {
+                        "COUNT" => {
+                            let value: i64 = parse_integer(&args.option_value()?)?;
+                            count = usize::try_from(value)
+                                .ok()
+                                .filter(|&count| count > 0 && count <= i64::MAX as usize / 10)
+                                .ok_or_else(|| {
+                                    CommandError::Other("COUNT must be > 0".to_string())
+                                })?;
+                        }
+                        "JUSTID" => just_id = true,
+                        _ => return Err(CommandError::Syntax),
+                    }
+                }
+
+                StreamCommand::AutoClaim {
+                    key,
+                    group,
+                    consumer,
+                    min_idle,
+                    start,
+                    count,
+                    just_id,
+                }
+            }
+            "XINFO" => {
+                let subcommand = args.subcommand()?;
+                let info = match subcommand.as_str() {
+                    "STREAM" => {
+                        let key = args.required()?;
+                        let full = match args.next() {
+                            None => None,
+                            Some(arg) if arg.eq_ignore_ascii_case(b"FULL") => {
+                                match args.next() {
+                                    None => Some(DEFAULT_INFO_COUNT),
+                                    Some(arg) if arg.eq_ignore_ascii_case(b"COUNT") => {
+                                        let count: i64 = parse_integer(&args.option_value()?)?;
+                                        // A count of zero asks for everything.
+                                        Some(if count <= 0 {
+                                            usize::MAX
+                                        } else {
+                                            count as usize
+                                        })
+                                    }
+                                    Some(_) => return Err(CommandError::Syntax),
+                                }
+                            }
+                            Some(_) => return Err(CommandError::Syntax),
+                        };
+                        InfoCommand::Stream { key, full }
+                    }
+                    "GROUPS" => InfoCommand::Groups(args.required()?),
+                    "CONSUMERS" => InfoCommand::Consumers {
+                        key: args.required()?,
+                        group: args.required()?,
+                    },
+                    _ => {
+                        return Err(CommandError::UnknownSubcommand {
+                            command: "XINFO".to_string(),
+                            subcommand,
+                        })
+                    }
+                };
+                StreamCommand::Info(info)
+            }
+            _ => unreachable!("not a stream command: {}", name),
+        };
+
+        Ok(command)
+    }
+
+    pub fn execute(self, db: &mut Rdb, protocol: Protocol) -> Result<Response, CommandError> {
+        let response = match self {
+            StreamCommand::Add {
+                key,
+                id,
+                fields,
+                nomkstream,
+                trim,
+            } => {
+                let stream = if nomkstream {
+                    match stream_mut(db, &key)? {
+                        Some(stream) => stream,
+                        None => return Ok(Response::Null),
+                    }
+                } else {
+                    stream_or_insert(db, &key)?
+                };
+
+                let id = stream.next_id(id)?;
+                stream.add(id, fields);
+                let trimmed = trim.map_or(0, |trim| stream.trim(&trim));
+                db.mark_dirty(1 + trimmed);
+                NEW_ENTRIES.notify_waiters();
+                Response::Echo(id_value(id))
+            }
+            StreamCommand::Len(key) => {
+                let len = stream(db, &key)?.map_or(0, Stream::len);
+                Response::Echo(RESPValue::Integer(len as i64))
+            }
+            StreamCommand::Range {
+                key,
+                start,
+                end,
+                count,
+                rev,
+            } => {
+                let Some(stream) = stream(db, &key)? else {
+                    return Ok(Response::Echo(RESPValue::Array(vec![])));
+                };
+                let count = count.unwrap_or(usize::MAX);
+                let range = stream.log.range(start, end);
+                let entries = if rev {
+                    range
+                        .rev()
+                        .take(count)
+                        .map(|(&id, fields)| entry_value(id, fields))
+                        .collect()
+                } else {
+                    range
+                        .take(count)
+                        .map(|(&id, fields)| entry_value(id, fields))
+                        .collect()
+                };
+                Response::Echo(RESPValue::Array(entries))
+            }
+            StreamCommand::Del { key, ids } => {
+                let deleted = match stream_mut(db, &key)? {
+                    Some(stream) => ids.into_iter().filter(|&id| stream.delete(id)).count(),
+                    None => 0,
+                };
+                db.mark_dirty(deleted);
+                Response::Echo(RESPValue::Integer(deleted as i64))
+            }
+            StreamCommand::Trim { key, trim } => {
+                let removed = match stream_mut(db, &key)? {
+                    Some(stream) => stream.trim(&trim),
+                    None => 0,
+                };
+                db.mark_dirty(removed);
+                Response::Echo(RESPValue::Integer(removed as i64))
+            }
+            StreamCommand::Read(read) => read.execute(db, protocol)?,
+            StreamCommand::Group(command) => execute_group_command(db, command)?,
+            StreamCommand::Ack { key, group, ids } => {
+                let acked = match stream_mut(db, &key)?.and_then(|s| s.groups.get_mut(&group)) {
+                    Some(group) => ids.into_iter().filter(|&id| group.ack(id)).count(),
+                    None => 0,
+                };
+                db.mark_dirty(acked);
+                Response::Echo(RESPValue::Integer(acked as i64))
+            }
+            StreamCommand::Pending { key, group, range } => {
+                let Some(group) = stream(db, &key)?.and_then(|s| s.groups.get(&group)) else {
+                    return Err(CommandError::NoGroup(format!(
+                        "No such key '{}' or consumer group '{}'",
+                        String::from_utf8_lossy(&key),
+                        String::from_utf8_lossy(&group)
+                    )));
+                };
+                match range {
+                    None => pending_summary(group),
+                    Some(range) => pending_entries(group, &range),
+                }
+            }
+            StreamCommand::Claim {
+                key,
+                group,
+                consumer,
+                min_idle,
+                ids,
+                options,
+            } => {
+                let Stream { log, groups } = existing_group(db, &key, &group)?;
+                let group = groups.get_mut(&group).expect("group checked above");
+                let now = now_millis();
+                let delivered_at = options.delivered_at.unwrap_or(now);
+
+                let mut changes = 0;
+                if let Some(last_id) = options.last_id {
+                    if last_id > group.last_delivered {
+                        group.last_delivered = last_id;
+                        changes += 1;
+                    }
+                }
+
+                let mut claimed = Vec::new();
+                for id in ids {
+                    // Entries that no longer exist can't be claimed, and stop being pending.
+                    let Some(fields) = log.entries.get(&id) else {
+                        changes += group.ack(id) as usize;
+                        continue;
+                    };
+                    let deliveries = match group.pending.get(&id) {
+                        Some(entry) if now - entry.delivered_at < min_idle => continue,
+                        Some(entry) => entry.deliveries,
+                        None if options.force => 0,
+                        None => continue,
+                    };
+
+                    let deliveries = match options.retry_count {
+                        Some(count) => count,
+                        None if options.just_id => deliveries,
+                        None => deliveries + 1,
+                    };
+                    group.consumer(&consumer, now).active_at = Some(now);
+                    group.assign(id, &consumer, delivered_at, deliveries);
+                    claimed.push(if options.just_id {
+                        id_value(id)
+                    } else {
+                        entry_value(id, fields)
+                    });
+                }
+                group.consumer(&consumer, now);
+                db.mark_dirty(changes + claimed.len());
+
+                Response::Echo(RESPValue::Array(claimed))
+            }
+            StreamCommand::AutoClaim {
+                key,
+                group,
+                consumer,
+                min_idle,
+                start,
+                count,
+                just_id,
+            } => {
+                let Stream { log, groups } = existing_group(db, &key, &group)?;
+                let group = groups.get_mut(&group).expect("group checked above");
+                let now = now_millis();
+                group.consumer(&consumer, now);
+
+                // Look at up to ten pending entries for every one that may be claimed, so a long
+                // run of entries that aren't idle enough doesn't make this scan the whole list.
+                let mut attempts = count * 10;
+                let mut claimed = Vec::new();
+                let mut deleted = Vec::new();
+                let mut next = StreamId::MIN;
+                // One more than can be attempted, to find where the next call should start.
+                let candidates: Vec<(StreamId, u64, i64)> = group
+                    .pending
+                    .range(start..)
+                    .take(attempts + 1)
+                    .map(|(&id, entry)| (id, entry.deliveries, entry.delivered_at))
+                    .collect();
+
+                for (id, deliveries, delivered_at) in candidates {
+                    if attempts == 0 || claimed.len() == count {
+                        next = id;
+                        break;
+                    }
+                    attempts -= 1;
+
+                    let Some(fields) = log.entries.get(&id) else {
+                        group.ack(id);
+                        deleted.push(id_value(id));
+                        continue;
+                    };
+                    if now - delivered_at < min_idle {
+                        continue;
+                    }
+
+                    let deliveries = if just_id { deliveries } else { deliveries + 1 };
+                    group.consumer(&consumer, now).active_at = Some(now);
+                    group.assign(id, &consumer, now, deliveries);
+                    claimed.push(if just_id {
+                        id_value(id)
+                    } else {
+                        entry_value(id, fields)
+                    });
+                }
+
+                db.mark_dirty(claimed.len() + deleted.len());
+                Response::Echo(RESPValue::Array(vec![
+                    id_value(next),
+                    RESPValue::Array(claimed),
+                    RESPValue::Array(deleted),
+                ]))
+            }
+            StreamCommand::Info(command) => execute_info_command(db, command)?,
+        };
+
+        Ok(response)
+    }
+}
+
+fn parse_group_command(args: &mut CommandArgs) -> Result<GroupCommand, CommandError> {
+    let subcommand = args.subcommand()?;
+    let command = match subcommand.as_str() {
+        "CREATE" | "SETID" => {
+            let key = args.required()?;
+            let group = args.required()?;
+            let id = args.required()?;
+            let id = match id.as_ref() {
+                b"$" => None,
+                _ => Some(StreamId::parse(&id, 0)?),
+            };
+
+            let mut mkstream = false;
+            let mut entries_read = None;
+            while let Some(arg) = args.next() {
+                match String::from_utf8_lossy(&arg).to_ascii_uppercase().as_str() {
+                    "MKSTREAM" if subcommand == "CREATE" => mkstream = true,
+                    "ENTRIESREAD" => {
+                        let value: i64 = parse_integer(&args.option_value()?)?;
+                        if value < -1 {
+                            return Err(CommandError::Other(
+                                "value for ENTRIESREAD must be positive or -1".to_string(),
+                            ));
+                        }
+                        entries_read = u64::try_from(value).ok();
+                    }
+                    _ => return Err(CommandError::Syntax),
+                }
+            }
+
+            if subcommand == "CREATE" {
+                GroupCommand::Create {
+                    key,
+                    group,
+                    id,
+                    mkstream,
+                    entries_read,
+                }
+            } else {
+                GroupCommand::SetId {
+                    key,
+                    group,
+                    id,
+                    entries_read,
+                }
+            }
+        }
+        "DESTROY" => GroupCommand::Destroy {
+            key: args.required()?,
+            group: args.required()?,
+        },
+        "CREATECONSUMER" => GroupCommand::CreateConsumer {
+            key: args.required()?,
+            group: args.required()?,
+            consumer: args.required()?,
+        },
+        "DELCONSUMER" => GroupCommand::DelConsumer {
+            key: args.required()?,
+            group: args.required()?,
+            consumer: args.required()?,
+        },
+        _ => {
+            return Err(CommandError::UnknownSubcommand {
+                command: "XGROUP".to_string(),
+                subcommand,
+            })
+        }
+    };
+    args.finish()?;
+
+    Ok(command)
+}
+
+fn execute_group_command(db: &mut Rdb, command: GroupCommand) -> Result<Response, CommandError> {
+    let response = match command {
+        GroupCommand::Create {
+            key,
+            group,
+            id,
+            mkstream,
+            entries_read,
+        } => {
+            let stream = if mkstream {
+                stream_or_insert(db, &key)?
+            } else {
+                stream_mut(db, &key)?.ok_or_else(missing_key)?
+            };
+            if stream.groups.contains_key(&group) {
+                return Err(CommandError::BusyGroup);
+            }
+            let id = id.unwrap_or(stream.last_id());
+            stream
+                .groups
+                .insert(group, ConsumerGroup::new(id, entries_read));
+            db.mark_dirty(1);
+            Response::Ok
+        }
+        GroupCommand::SetId {
+            key,
+            group,
+            id,
+            entries_read,
+        } => {
+            let stream = stream_mut(db, &key)?.ok_or_else(missing_key)?;
+            let id = id.unwrap_or(stream.last_id());
+            let group = stream
+                .groups
+                .get_mut(&group)
+                .ok_or_else(|| no_such_group(&key, &group))?;
+            group.last_delivered = id;
+            group.entries_read = entries_read;
+            db.mark_dirty(1);
+            Response::Ok
+        }
+        GroupCommand::Destroy { key, group } => {
+            let stream = stream_mut(db, &key)?.ok_or_else(missing_key)?;
+            let destroyed = stream.groups.remove(&group).is_some();
+            db.mark_dirty(destroyed as usize);
+            Response::Echo(RESPValue::Integer(destroyed as i64))
+        }
+        GroupCommand::CreateConsumer {
+            key,
+            group,
+            consumer,
+        } => {
+            let stream = stream_mut(db, &key)?.ok_or_else(missing_key)?;
+            let group = stream
+                .groups
+                .get_mut(&group)
+                .ok_or_else(|| no_such_group(&key, &group))?;
+            let created = !group.consumers.contains_key(&consumer);
+            group.consumer(&consumer, now_millis());
+            db.mark_dirty(created as usize);
+            Response::Echo(RESPValue::Integer(created as i64))
+        }
+        GroupCommand::DelConsumer {
+            key,
+            group,
+            consumer,
+        } => {
+            let stream = stream_mut(db, &key)?.ok_or_else(missing_key)?;
+            let group = stream
+                .groups
+                .get_mut(&group)
+                .ok_or_else(|| no_such_group(&key, &group))?;
+            // Whatever was pending with the consumer stops being pending with anyone.
+            let removed = group.consumers.remove(&consumer);
+            let pending = match &removed {
+                Some(removed) => {
+                    for id in &removed.pending {
+                        group.pending.remove(id);
+                    }
+                    removed.pending.len()
+                }
+                None => 0,
+            };
+            db.mark_dirty(removed.is_some() as usize);
+            Response::Echo(RESPValue::Integer(pending as i64))
+        }
+    };
+
+    Ok(response)
+}
+
+fn execute_info_command(db: &mut Rdb, command: InfoCommand) -> Result<Response, CommandError> {
+    let now = now_millis();
+    let reply = match command {
+        InfoCommand::Stream { key, full } => {
+            let stream = stream(db, &key)?.ok_or(CommandError::NoSuchKey)?;
+            let log = &stream.log;
+            let mut fields = vec![
+                (text("length"), integer(log.entries.len() as u64)),
+                (text("last-generated-id"), id_value(log.last_id)),
+                (text("max-deleted-entry-id"), id_value(log.max_deleted_id)),
+                (text("entries-added"), integer(log.entries_added)),
+                (text("recorded-first-entry-id"), id_value(log.first_id())),
+            ];
+
+            match full {
+                None => {
+                    let entry = |entry: Option<(&StreamId, &Fields)>| {
+                        entry.map_or(RESPValue::Null, |(&id, fields)| entry_value(id, fields))
+                    };
+                    fields.push((text("groups"), integer(stream.groups.len() as u64)));
+                    fields.push((text("first-entry"), entry(log.entries.iter().next())));
+                    fields.push((text("last-entry"), entry(log.entries.iter().next_back())));
+                }
+                Some(count) => {
+                    let entries = log
+                        .entries
+                        .iter()
+                        .take(count)
+                        .map(|(&id, fields)| entry_value(id, fields))
+                        .collect();
+                    let groups = stream
+                        .groups
+                        .iter()
+                        .map(|(name, group)| full_group_info(log, name, group, count))
+                        .collect();
+                    fields.push((text("entries"), RESPValue::Array(entries)));
+                    fields.push((text("groups"), RESPValue::Array(groups)));
+                }
+            }
+            RESPValue::Map(fields)
+        }
+        InfoCommand::Groups(key) => {
+            let stream = stream(db, &key)?.ok_or(CommandError::NoSuchKey)?;
+            let groups = stream
+                .groups
+                .iter()
+                .map(|(name, group)| {
+                    RESPValue::Map(vec![
+                        (text("name"), bulk(name)),
+                        (text("consumers"), integer(group.consumers.len() as u64)),
+                        (text("pending"), integer(group.pending.len() as u64)),
+                        (text("last-delivered-id"), id_value(group.last_delivered)),
+                        (text("entries-read"), optional_integer(group.entries_read)),
+                        (text("lag"), optional_integer(stream.log.lag(group))),
+                    ])
+                })
+                .collect();
+            RESPValue::Array(groups)
+        }
+        InfoCommand::Consumers { key, group } => {
+            let stream = stream(db, &key)?.ok_or(CommandError::NoSuchKey)?;
+            let group = stream
+                .groups
+                .get(&group)
+                .ok_or_else(|| no_such_group(&key, &group))?;
+            let consumers = group
+                .consumers
+                .iter()
+                .map(|(name, consumer)| {
+                    let inactive = consumer.active_at.map_or(-1, |at| (now - at).max(0));
+                    RESPValue::Map(vec![
+                        (text("name"), bulk(name)),
+                        (text("pending"), integer(consumer.pending.len() as u64)),
+                        (
+                            text("idle"),
+                            RESPValue::Integer((now - consumer.seen_at).max(0)),
+                        ),
+                        (text("inactive"), RESPValue::Integer(inactive)),
+                    ])
+                })
+                .collect();
+            RESPValue::Array(consumers)
+        }
+    };
+
+    Ok(Response::Echo(reply))
+}
+
+// One group's part of XINFO STREAM FULL, listing up to `count` of its pending entries.
+fn full_group_info(log: &Log, name: &Bytes, group: &ConsumerGroup, count: usize) -> RESPValue {
+    let pending = group
+        .pending
+        .iter()
+        .take(count)
+        .map(|(&id, entry)| {
+            RESPValue::Array(vec![
+                id_value(id),
+                bulk(&entry.consumer),
+                RESPValue::Integer(entry.delivered_at),
+                integer(entry.deliveries),
+            ])
+        })
+        .collect();
+
+    let consumers = group
+        .consumers
+        .iter()
+        .map(|(name, consumer)| {
+            let pending = consumer
+                .pending
+                .iter()
+                .take(count)
+                .filter_map(|id| {
+                    let entry = group.pending.get(id)?;
+                    Some(RESPValue::Array(vec![
+                        id_value(*id),
+                        RESPValue::Integer(entry.delivered_at),
+                        integer(entry.deliveries),
+                    ]))
+                })
+                .collect();
+            RESPValue::Map(vec![
+                (text("name"), bulk(name)),
+                (text("seen-time"), RESPValue::Integer(consumer.seen_at)),
+                (
+                    text("active-time"),
+                    RESPValue::Integer(consumer.active_at.unwrap_or(-1)),
+                ),
+                (text("pel-count"), integer(consumer.pending.len() as u64)),
+                (text("pending"), RESPValue::Array(pending)),
+            ])
+        })
+        .collect();
+
+    RESPValue::Map(vec![
+        (text("name"), bulk(name)),
+        (text("last-delivered-id"), id_value(group.last_delivered)),
+        (text("entries-read"), optional_integer(group.entries_read)),
+        (text("lag"), optional_integer(log.lag(group))),
+        (text("pel-count"), integer(group.pending.len() as u64)),
+        (text("pending"), RESPValue::Array(pending)),
+        (text("consumers"), RESPValue::Array(consumers)),
+    ])
+}
+
+// XPENDING's summary form: the number of pending entries, the lowest and highest of their IDs,
+// and how many each consumer has.
+fn pending_summary(group: &ConsumerGroup) -> Response {
+    let (Some(first), Some(last)) = (
+        group.pending.keys().next(),
+        group.pending.keys().next_back(),
+    ) else {
+        return Response::Echo(RESPValue::Array(vec![
+            RESPValue::Integer(0),
+            RESPValue::Null,
+            RESPValue::Null,
+            RESPValue::NullArray,
+        ]));
+    };
+
+    let consumers = group
+        .consumers
+        .iter()
+        .filter(|(_, consumer)| !consumer.pending.is_empty())
+        .map(|(name, consumer)| {
+            RESPValue::Array(vec![bulk(name), text(&consumer.pending.len().to_string())])
+        })
+        .collect();
+
+    Response::Echo(RESPValue::Array(vec![
+        integer(group.pending.len() as u64),
+        id_value(*first),
+        id_value(*last),
+        RESPValue::Array(consumers),
+    ]))
+}
+
+// XPENDING's extended form: each pending entry in the range, with who has it, how long ago it was
+// delivered and how many times.
+fn pending_entries(group: &ConsumerGroup, range: &PendingRange) -> Response {
+    let now = now_millis();
+    let entries = if range.start <= range.end {
+        group.pending.range(range.start..=range.end)
+    } else {
+        group.pending.range(StreamId::MIN..StreamId::MIN)
+    };
+
+    let entries = entries
+        .filter(|(_, entry)| {
+            range
+                .consumer
+                .iter()
+                .all(|consumer| entry.consumer == consumer)
+        })
+        .filter(|(_, entry)| {
+            range
+                .min_idle
+                .iter()
+                .all(|&min| now - entry.delivered_at >= min)
+        })
+        .take(range.count)
+        .map(|(&id, entry)| {
+            RESPValue::Array(vec![
+                id_value(id),
+                bulk(&entry.consumer),
+                RESPValue::Integer((now - entry.delivered_at).max(0)),
+                integer(entry.deliveries),
+            ])
+        })
+        .collect();
+
+    Response::Echo(RESPValue::Array(entries))
+}
+
+fn stream<'a>(db: &'a mut Rdb, key: &[u8]) -> Result<Option<&'a Stream>, CommandError> {
+    db.get(key)
+        .map(|entry| entry.value().as_stream())
+        .transpose()
+}
+
+fn stream_mut<'a>(db: &'a mut Rdb, key: &[u8]) -> Result<Option<&'a mut Stream>, CommandError> {
+    db.get_mut(key)
+        .map(|entry| entry.value_mut().as_stream_mut())
+        .transpose()
+}
+
+fn stream_or_insert<'a>(db: &'a mut Rdb, key: &Bytes) -> Result<&'a mut Stream, CommandError> {
+    db.get_or_insert_with(key, || Value::Stream(Stream::default()))
+        .value_mut()
+        .as_stream_mut()
+}
+
+// Looks up a stream for XCLAIM or XAUTOCLAIM, which need both it and the group to exist.
+fn existing_group<'a>(
+    db: &'a mut Rdb,
+    key: &Bytes,
+    group: &Bytes,
+) -> Result<&'a mut Stream, CommandError> {
+    match stream_mut(db, key)? {
+        Some(stream) if stream.groups.contains_key(group) => Ok(stream),
+        _ => Err(CommandError::NoGroup(format!(
+            "No such key '{}' or consumer group '{}'",
+            String::from_utf8_lossy(key),
+            String::from_utf8_lossy(group)
+        ))),
+    }
+}
+
+// Parses a bound of an XRANGE-style interval: `-` and `+` for the smallest and largest IDs, and a
+// leading `(` to leave the ID itself out. An end bound given without a sequence number covers the
+// whole millisecond.
+fn parse_range_bound(arg: &[u8], end: bool) -> Result<StreamId, CommandError> {
+    match arg {
+        b"-" => Ok(StreamId::MIN),
+        b"+" => Ok(StreamId::MAX),
+        [b'(', id @ ..] => {
+            let id = StreamId::parse(id, if end { u64::MAX } else { 0 })?;
+            let id = if end {
+                id.predecessor()
+            } else {
+                id.successor()
+            };
+            id.ok_or_else(|| {
+                CommandError::Other(format!(
+                    "invalid {} ID for the interval",
+                    if end { "end" } else { "start" }
+                ))
+            })
+        }
+        _ => StreamId::parse(arg, if end { u64::MAX } else { 0 }),
+    }
+}
+
+fn parse_ids(args: Vec<Bytes>) -> Result<Vec<StreamId>, CommandError> {
+    args.iter().map(|arg| StreamId::parse(arg, 0)).collect()
+}
+
+fn parse_min_idle(arg: &[u8], command: &str) -> Result<i64, CommandError> {
+    let min_idle: i64 = parse_integer(arg).map_err(|_| {
+        CommandError::Other(format!("Invalid min-idle-time argument for {}", command))
+    })?;
+    Ok(min_idle.max(0))
+}
+
+fn invalid_id() -> CommandError {
+    CommandError::Other("Invalid stream ID specified as stream command argument".to_string())
+}
+
+fn missing_key() -> CommandError {
+    CommandError::Other(
+        "The XGROUP subcommand requires the key to exist. Note that for CREATE you may want to \
+         use the MKSTREAM option to create an empty stream automatically."
+            .to_string(),
+    )
+}
+
+fn no_such_group(key: &[u8], group: &[u8]) -> CommandError {
+    CommandError::NoGroup(format!(
+        "No such consumer group '{}' for key name '{}'",
+        String::from_utf8_lossy(group),
+        String::from_utf8_lossy(key)
+    ))
+}
+
+fn now_millis() -> i64 {
+    unix_millis(SystemTime::now())
+}
+
+fn entry_value(id: StreamId, fields: &Fields) -> RESPValue {
+    let fields = fields
+        .iter()
+        .flat_map(|(field, value)| [bulk(field), bulk(value)])
+        .collect();
+    RESPValue::Array(vec![id_value(id), RESPValue::Array(fields)])
+}
+
+fn id_value(id: StreamId) -> RESPValue {
+    RESPValue::BulkString(Bytes::from(id.to_string()))
+}
+
+fn bulk(value: &Bytes) -> RESPValue {
+    RESPValue::BulkString(value.clone())
+}
+
+fn text(value: &str) -> RESPValue {
+    RESPValue::BulkString(Bytes::from(value.to_string()))
+}
+
+fn integer(value: u64) -> RESPValue {
+    RESPValue::Integer(value as i64)
+}
+
+fn optional_integer(value: Option<u64>) -> RESPValue {
+    value.map_or(RESPValue::Null, integer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(ms: u64, seq: u64) -> StreamId {
+        StreamId { ms, seq }
+    }
+
+    #[test]
+    fn test_ids() {
+        assert_eq!(StreamId::parse(b"5-3", 0), Ok(id(5, 3)));
+        assert_eq!(StreamId::parse(b"5", u64::MAX), Ok(id(5, u64::MAX)));
+        assert!(StreamId::parse(b"5-", 0).is_err());
+        assert!(StreamId::parse(b"-1", 0).is_err());
+        assert!(StreamId::parse(b"+5-1", 0).is_err());
+
+        assert_eq!(id(1, u64::MAX).successor(), Some(id(2, 0)));
+        assert_eq!(id(2, 0).predecessor(), Some(id(1, u64::MAX)));
+        assert_eq!(StreamId::MAX.successor(), None);
+        assert_eq!(id(3, 1).to_string(), "3-1");
+
+        assert_eq!(parse_range_bound(b"(5-3", false), Ok(id(5, 4)));
+        assert_eq!(parse_range_bound(b"5", true), Ok(id(5, u64::MAX)));
+        assert!(parse_range_bound(b"(0-0", true).is_err());
+    }
+
+    #[test]
+    fn test_entries_read_and_lag() {
+        let mut stream = Stream::default();
+        for ms in 1..=5 {
+            stream.add(id(ms, 0), vec![]);
+        }
+        let group = ConsumerGroup::new(id(2, 0), None);
+        assert_eq!(stream.log.lag(&group), None);
+
+        // Once the entries before the group's position have been trimmed, counting works again.
+        stream.trim(&Trim {
+            threshold: Threshold::MaxLen(3),
+            limit: None,
+        });
+        assert_eq!(stream.log.entries_read_at(id(2, 0)), Some(2));
+        assert_eq!(stream.log.lag(&group), Some(3));
+
+        // A deletion after the group's position makes the count unknowable, but not one before.
+        let mut counted = ConsumerGroup::new(id(3, 0), Some(3));
+        assert_eq!(stream.log.lag(&counted), Some(2));
+        stream.delete(id(4, 0));
+        assert_eq!(stream.log.lag(&counted), None);
+
+        let consumer = Bytes::from("alice");
+        counted.consumer(&consumer, 0);
+        let delivered = counted.deliver_new(&stream.log, &consumer, 10, false, 0);
+        assert_eq!(delivered.len(), 1);
+        assert_eq!(counted.last_delivered, id(5, 0));
+        assert_eq!(stream.log.lag(&counted), Some(0));
+        assert_eq!(counted.consumers[&consumer].pending.len(), 1);
+    }
+}
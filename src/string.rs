@@ -0,0 +1,572 @@
+//! The string commands beyond GET and SET: counters, in-place edits, and reading or writing several
+//! keys at once. They all work on the same [`Value::String`] values SET stores, and any command that
+//! changes a string in place leaves its expiry alone, as in Redis.
+
+use std::time::SystemTime;
+
+use bytes::{Bytes, BytesMut};
+
+use crate::{
+    error::CommandError,
+    protocol_parser::{
+        format_double, parse_expiry, parse_float, parse_integer, CommandArgs, ExpiryFormat,
+        RESPValue, Response,
+    },
+    rdb::{DBEntry, Rdb, Value},
+};
+
+// The largest string SETRANGE will make, Redis's default proto-max-bulk-len.
+const MAX_STRING_LEN: usize = 512 * 1024 * 1024;
+
+/// What GETEX does to the key's expiry after reading it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GetExpiry {
+    At(SystemTime),
+    Persist,
+}
+
+/// What LCS replies with: the common subsequence itself, just its length, or where it was found.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LcsReply {
+    String,
+    Len,
+    Idx {
+        min_match_len: usize,
+        with_match_len: bool,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum StringCommand {
+    IncrBy {
+        key: Bytes,
+        increment: i64,
+    },
+    IncrByFloat {
+        key: Bytes,
+        increment: f64,
+    },
+    Append {
+        key: Bytes,
+        value: Bytes,
+    },
+    StrLen(Bytes),
+    GetRange {
+        key: Bytes,
+        start: i64,
+        end: i64,
+    },
+    SetRange {
+        key: Bytes,
+        offset: usize,
+        value: Bytes,
+    },
+    GetDel(Bytes),
+    GetEx {
+        key: Bytes,
+        expiry: Option<GetExpiry>,
+    },
+    SetNx {
+        key: Bytes,
+        value: Bytes,
+    },
+    MGet(Vec<Bytes>),
+    MSet {
+        pairs: Vec<(Bytes, Bytes)>,
+        /// MSETNX sets nothing at all if any of the keys already exists.
+        only_if_none_exist: bool,
+    },
+    Lcs {
+        first: Bytes,
+        second: Bytes,
+        reply: LcsReply,
+    },
+}
+
+impl StringCommand {
+    /// Parses the arguments of the string command `name`, which must be upper-case.
+    pub fn parse(name: &str, args: &mut CommandArgs) -> Result<Self, CommandError> {
+        let command = match name {
+            "INCR" | "DECR" => StringCommand::IncrBy {
+                key: args.required()?,
+                increment: if name == "INCR" { 1 } else { -1 },
+            },
+            "INCRBY" => StringCommand::IncrBy {
+                key: args.required()?,
+                increment: parse_integer(&args.required()?)?,
+            },
+            "DECRBY" => {
+                let key = args.required()?;
+                let decrement: i64 = parse_integer(&args.required()?)?;
+                let increment = decrement
+                    .checked_neg()
+                    .ok_or_else(|| CommandError::Other("decrement would overflow".to_string()))?;
+                StringCommand::IncrBy { key, increment }
+            }
+            "INCRBYFLOAT" => StringCommand::IncrByFloat {
+                key: args.required()?,
+                increment: parse_float(&args.required()?)?,
+            },
+            "APPEND" => StringCommand::Append {
+                key: args.required()?,
+                value: args.required()?,
+            },
+            "STRLEN" => StringCommand::StrLen(args.required()?),
+            // SUBSTR is GETRANGE's name from before Redis 2.0, still accepted by it.
+            "GETRANGE" | "SUBSTR" => StringCommand::GetRange {
+                key: args.required()?,
+                start: parse_integer(&args.required()?)?,
+                end: parse_integer(&args.required()?)?,
+            },
+            "SETRANGE" => {
+                let key = args.required()?;
+                let offset: i64 = parse_integer(&args.required()?)?;
+                let offset = usize::try_from(offset)
+                    .map_err(|_| CommandError::Other("offset is out of range".to_string()))?;
+                StringCommand::SetRange {
+                    key,
+                    offset,
+                    value: args.required()?,
+                }
+            }
+            "GETDEL" => StringCommand::GetDel(args.required()?),
+            "GETEX" => {
+                let key = args.required()?;
+                let mut expiry = None;
+                while let Some(option) = args.next() {
+                    let option = String::from_utf8_lossy(&option).to_ascii_uppercase();
+                    // Only one of the options can be given.
+                    if expiry.is_some() {
+                        return Err(CommandError::Syntax);
+                    }
+                    let format = match option.as_str() {
+                        "EX" => ExpiryFormat::Seconds,
+                        "PX" => ExpiryFormat::Milliseconds,
+                        "EXAT" => ExpiryFormat::UnixSeconds,
+                        "PXAT" => ExpiryFormat::UnixMilliseconds,
+                        "PERSIST" => {
+                            expiry = Some(GetExpiry::Persist);
+                            continue;
+                        }
+                        _ => return Err(CommandError::Syntax),
+                    };
+                    let at = parse_expiry(&args.option_value()?, format, "getex")?;
+                    expiry = Some(GetExpiry::At(at));
+                }
+                StringCommand::GetEx { key, expiry }
+            }
+            "SETNX" => StringCommand::SetNx {
+                key: args.required()?,
+                value: args.required()?,
+            },
+            "MGET" => StringCommand::MGet(args.at_least_one()?),
+            "MSET" | "MSETNX" => {
+                let mut pairs = vec![(args.required()?, args.required()?)];
+                while let Some(key) = args.next() {
+                    pairs.push((key, args.required()?));
+                }
+                StringCommand::MSet {
+                    pairs,
+                    only_if_none_exist: name == "MSETNX",
+                }
+            }
+            "LCS" => {
+                let first = args.required()?;
+                let second = args.required()?;
+
+                let (mut len, mut idx, mut with_match_len) = (false, false, false);
+                let mut min_match_len = 0;
+                while let Some(option) = args.next() {
+                    match String::from_utf8_lossy(&option)
+                        .to_ascii_uppercase()
+                        .as_str()
+                    {
+                        "LEN" => len = true,
+                        "IDX" => idx = true,
+                        "WITHMATCHLEN" => with_match_len = true,
+                        "MINMATCHLEN" => {
+                            let value: i64 = parse_integer(&args.option_value()?)?;
+                            min_match_len = value.max(0) as usize;
+                        }
+                        _ => return Err(CommandError::Syntax),
+                    }
+                }
+
+                let reply = match (len, idx) {
+                    (true, true) => {
+                        return Err(CommandError::Other(
+                            "If you want both the length and indexes, please just use IDX."
+                                .to_string(),
+                        ))
+                    }
+                    (true, false) => LcsReply::Len,
+                    (false, true) => LcsReply::Idx {
+                        min_match_len,
+                        with_match_len,
+                    },
+                    (false, false) => LcsReply::String,
+                };
+                StringCommand::Lcs {
+                    first,
+                    second,
+                    reply,
+                }
+            }
+            _ => unreachable!("not a string command: {}", name),
+        };
+
+        Ok(command)
+    }
+
+    pub fn execute(self, db: &mut Rdb) -> Result<Response, CommandError> {
+        let response = match self {
+            StringCommand::IncrBy { key, increment } => {
+                let current = match string(db, &key)? {
+                    Some(value) => parse_integer::<i64>(value)?,
+                    None => 0,
+                };
+                let new = current.checked_add(increment).ok_or_else(|| {
+                    CommandError::Other("increment or decrement would overflow".to_string())
+                })?;
+                *string_or_insert(db, &key)? = Bytes::from(new.to_string());
+                db.mark_dirty(1);
+                Response::Echo(RESPValue::Integer(new))
+            }
+            StringCommand::IncrByFloat { key, increment } => {
+                let current = match string(db, &key)? {
+                    Some(value) => parse_float(value)?,
+                    None => 0.0,
+                };
+                let new = current + increment;
+                if !new.is_finite() {
+                    return Err(CommandError::Other(
+                        "increment would produce NaN or Infinity".to_string(),
+                    ));
+                }
+                let new = Bytes::from(format_double(new));
+                *string_or_insert(db, &key)? = new.clone();
+                db.mark_dirty(1);
+                Response::Echo(RESPValue::BulkString(new))
+            }
+            StringCommand::Append { key, value } => {
+                let string = string_or_insert(db, &key)?;
+                let mut appended = BytesMut::with_capacity(string.len() + value.len());
+                appended.extend_from_slice(string);
+                appended.extend_from_slice(&value);
+                *string = appended.freeze();
+                let len = string.len();
+                db.mark_dirty(1);
+                integer(len)
+            }
+            StringCommand::StrLen(key) => integer(string(db, &key)?.map_or(0, Bytes::len)),
+            StringCommand::GetRange { key, start, end } => {
+                let value = string(db, &key)?.cloned().unwrap_or_default();
+                let range = substring_range(start, end, value.len());
+                Response::Echo(RESPValue::BulkString(
+                    range.map_or_else(Bytes::new, |(start, end)| value.slice(start..=end)),
+                ))
+            }
+            StringCommand::SetRange { key, offset, value } => {
+                // Writing nothing changes nothing, and doesn't create the key either.
+                if value.is_empty() {
+                    return Ok(integer(string(db, &key)?.map_or(0, Bytes::len)));
+                }
+                if offset + value.len() > MAX_STRING_LEN {
+                    return Err(CommandError::Other(
+                        "string exceeds maximum allowed size (proto-max-bulk-len)".to_string(),
+                    ));
+                }
+
+                let string = string_or_insert(db, &key)?;
+                let mut updated = BytesMut::from(string.as_ref());
+                if updated.len() < offset + value.len() {
+                    updated.resize(offset + value.len(), 0);
+                }
+                updated[offset..offset + value.len()].copy_from_slice(&value);
+                *string = updated.freeze();
+                let len = string.len();
+                db.mark_dirty(1);
+                integer(len)
+            }
+            StringCommand::GetDel(key) => match string(db, &key)?.cloned() {
+                Some(value) => {
+                    db.remove(&key);
+                    Response::Echo(RESPValue::BulkString(value))
+                }
+                None => Response::Null,
+            },
+            StringCommand::GetEx { key, expiry } => {
+                let Some(value) = string(db, &key)?.cloned() else {
+                    return Ok(Response::Null);
+                };
+                match expiry {
+                    // A time that has already passed deletes the key, once it's been read.
+                    Some(GetExpiry::At(at)) if at <= SystemTime::now() => {
+                        db.remove(&key);
+                    }
+                    Some(GetExpiry::At(at)) => {
+                        db.set_expiry(&key, Some(at));
+                    }
+                    // Only a key that had an expiry changes.
+                    Some(GetExpiry::Persist)
+                        if db
+                            .peek(&key)
+                            .is_some_and(|entry| entry.expires_at().is_some()) =>
+                    {
+                        db.set_expiry(&key, None);
+                    }
+                    _ => {}
+                }
+                Response::Echo(RESPValue::BulkString(value))
+            }
+            StringCommand::SetNx { key, value } => {
+                let set = !db.contains_key(&key);
+                if set {
+                    db.insert(key, DBEntry::new(Value::String(value), None));
+                }
+                Response::Echo(RESPValue::Integer(set as i64))
+            }
+            StringCommand::MGet(keys) => {
+                // Keys that don't hold strings read as missing rather than failing the command.
+                let values = keys
+                    .iter()
+                    .map(|key| match db.get(key).map(|entry| entry.value()) {
+                        Some(Value::String(value)) => RESPValue::BulkString(value.clone()),
+                        _ => RESPValue::Null,
+                    })
+                    .collect();
+                Response::Echo(RESPValue::Array(values))
+            }
+            StringCommand::MSet {
+                pairs,
+                only_if_none_exist,
+            } => {
+                if only_if_none_exist && pairs.iter().any(|(key, _)| db.contains_key(key)) {
+                    return Ok(Response::Echo(RESPValue::Integer(0)));
+                }
+                for (key, value) in pairs {
+                    db.insert(key, DBEntry::new(Value::String(value), None));
+                }
+                if only_if_none_exist {
+                    Response::Echo(RESPValue::Integer(1))
+                } else {
+                    Response::Ok
+                }
+            }
+            StringCommand::Lcs {
+                first,
+                second,
+                reply,
+            } => {
+                let lookup = |db: &mut Rdb, key: &Bytes| match db.get(key).map(|e| e.value()) {
+                    Some(Value::String(value)) => Ok(value.clone()),
+                    Some(_) => Err(CommandError::Other(
+                        "The specified keys must contain string values".to_string(),
+                    )),
+                    None => Ok(Bytes::new()),
+                };
+                let first = lookup(db, &first)?;
+                let second = lookup(db, &second)?;
+
+                // The table of subsequence lengths takes four bytes per pair of positions.
+                let table_size = (first.len() + 1).saturating_mul(second.len() + 1);
+                if table_size.saturating_mul(4) > MAX_STRING_LEN {
+                    return Err(CommandError::Other(
+                        "Insufficient memory, transient memory for LCS exceeds \
+                         proto-max-bulk-len"
+                            .to_string(),
+                    ));
+                }
+
+                let lcs = Lcs::new(&first, &second);
+                match reply {
+                    LcsReply::String => Response::Echo(RESPValue::BulkString(lcs.subsequence)),
+                    LcsReply::Len => integer(lcs.subsequence.len()),
+                    LcsReply::Idx {
+                        min_match_len,
+                        with_match_len,
+                    } => {
+                        let matches = lcs
+                            .matches
+                            .iter()
+                            .filter(|m| m.len >= min_match_len)
+                            .map(|m| {
+                                let range = |(start, end): (usize, usize)| {
+                                    RESPValue::Array(vec![
+                                        RESPValue::Integer(start as i64),
+                                        RESPValue::Integer(end as i64),
+                                    ])
+                                };
+                                let mut reply = vec![range(m.first), range(m.second)];
+                                if with_match_len {
+                                    reply.push(RESPValue::Integer(m.len as i64));
+                                }
+                                RESPValue::Array(reply)
+                            })
+                            .collect();
+
+                        let field =
+                            |name: &str| RESPValue::BulkString(Bytes::from(name.to_string()));
+                        Response::Echo(RESPValue::Map(vec![
+                            (field("matches"), RESPValue::Array(matches)),
+                            (
+                                field("len"),
+                                RESPValue::Integer(lcs.subsequence.len() as i64),
+                            ),
+                        ]))
+                    }
+                }
+            }
+        };
+
+        Ok(response)
+    }
+}
+
+pub fn string<'a>(db: &'a mut Rdb, key: &[u8]) -> Result<Option<&'a Bytes>, CommandError> {
+    db.get(key)
+        .map(|entry| entry.value().as_string())
+        .transpose()
+}
+
+pub fn string_or_insert<'a>(db: &'a mut Rdb, key: &Bytes) -> Result<&'a mut Bytes, CommandError> {
+    db.get_or_insert_with(key, || Value::String(Bytes::new()))
+        .value_mut()
+        .as_string_mut()
+}
+
+/// Resolves GETRANGE's inclusive `start` and `end`, either of which may count back from the end,
+/// into in-bounds indexes, or `None` for an empty result. Unlike other ranges, an end that falls
+/// before the start of the string is taken as the first byte, as Redis does.
+pub fn substring_range(start: i64, end: i64, len: usize) -> Option<(usize, usize)> {
+    if len == 0 || (start < 0 && end < 0 && start > end) {
+        return None;
+    }
+    let len = len as i64;
+    let start = if start < 0 { len + start } else { start }.max(0);
+    let end = if end < 0 { len + end } else { end }.clamp(0, len - 1);
+
+    (start <= end).then_some((start as usize, end as usize))
+}
+
+/// The longest common subsequence of two strings, along with the runs of it that are contiguous
+/// in both, from the last to the first as LCS IDX lists them.
+struct Lcs {
+    subsequence: Bytes,
+    matches: Vec<LcsMatch>,
+}
+
+struct LcsMatch {
+    /// Inclusive start and end indexes of the run in the first string.
+    first: (usize, usize),
+    second: (usize, usize),
+    len: usize,
+}
+
+impl Lcs {
+    fn new(a: &[u8], b: &[u8]) -> Self {
+        // lengths[i][j] is the length of the LCS of the first i bytes of `a` and first j of `b`.
+        let width = b.len() + 1;
+        let mut lengths = vec![0u32; (a.len() + 1) * width];
+        for i in 1..=a.len() {
+            for j in 1..=b.len() {
+                lengths[i * width + j] = if a[i - 1] == b[j - 1] {
+                    lengths[(i - 1) * width + j - 1] + 1
+                } else {
+                    lengths[(i - 1) * width + j].max(lengths[i * width + j - 1])
+                };
+            }
+        }
+
+        // Walk back from the end, collecting the subsequence and the runs it's made of.
+        let mut subsequence = vec![0; lengths[a.len() * width + b.len()] as usize];
+        let mut matches = Vec::new();
+        let mut current: Option<LcsMatch> = None;
+        let (mut i, mut j, mut k) = (a.len(), b.len(), subsequence.len());
+        while i > 0 && j > 0 {
+            let mut emit = false;
+            if a[i - 1] == b[j - 1] {
+                subsequence[k - 1] = a[i - 1];
+                match current.as_mut() {
+                    None => {
+                        current = Some(LcsMatch {
+                            first: (i - 1, i - 1),
+                            second: (j - 1, j - 1),
+                            len: 1,
+                        })
+                    }
+                    // Contiguous with the run so far, so it grows backwards.
+                    Some(run) if run.first.0 == i && run.second.0 == j => {
+                        run.first.0 -= 1;
+                        run.second.0 -= 1;
+                        run.len += 1;
+                    }
+                    Some(_) => emit = true,
+                }
+                // A run reaching the start of either string can't grow any further.
+                if current
+                    .as_ref()
+                    .is_some_and(|run| run.first.0 == 0 || run.second.0 == 0)
+                {
+                    emit = true;
+                }
+                i -= 1;
+                j -= 1;
+                k -= 1;
+            } else {
+                if lengths[(i - 1) * width + j] > lengths[i * width + j - 1] {
+                    i -= 1;
+                } else {
+                    j -= 1;
+                }
+                emit = current.is_some();
+            }
+
+            if emit {
+                matches.extend(current.take());
+            }
+        }
+
+        Lcs {
+            subsequence: Bytes::from(subsequence),
+            matches,
+        }
+    }
+}
+
+fn integer(value: usize) -> Response {
+    Response::Echo(RESPValue::Integer(value as i64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_substring_range() {
+        assert_eq!(substring_range(0, 3, 13), Some((0, 3)));
+        assert_eq!(substring_range(-3, -1, 13), Some((10, 12)));
+        assert_eq!(substring_range(0, -1, 13), Some((0, 12)));
+        assert_eq!(substring_range(10, 100, 13), Some((10, 12)));
+        assert_eq!(substring_range(0, -100, 13), Some((0, 0)));
+        assert_eq!(substring_range(-1, -5, 13), None);
+        assert_eq!(substring_range(5, 3, 13), None);
+        assert_eq!(substring_range(0, 0, 0), None);
+    }
+
+    #[test]
+    fn test_lcs() {
+        let lcs = Lcs::new(b"ohmytext", b"mynewtext");
+        assert_eq!(lcs.subsequence.as_ref(), b"mytext");
+
+        let runs: Vec<_> = lcs
+            .matches
+            .iter()
+            .map(|m| (m.first, m.second, m.len))
+            .collect();
+        assert_eq!(runs, vec![((4, 7), (5, 8), 4), ((2, 3), (0, 1), 2)]);
+
+        assert!(Lcs::new(b"", b"abc").subsequence.is_empty());
+        assert!(Lcs::new(b"abc", b"xyz").matches.is_empty());
+    }
+}
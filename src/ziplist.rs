@@ -0,0 +1,161 @@
+//! Ziplists and zipmaps, the compact serialized lists that older versions of Redis kept small
+//! collections in, and which RDB files written by them still hold. Nothing writes them any more,
+//! so they only need reading.
+//!
+//! A ziplist is a header holding its total size, the offset of its last element and the number
+//! of elements, then the elements and an end byte. Each element starts with the length of the one
+//! before it, so the list can be walked backwards, followed by an encoding byte which may carry
+//! part of the length or value, and then the rest of the value.
+//!
+//! A zipmap is a count of pairs, then each key and value with their lengths, the value followed
+//! by some unused bytes left for it to grow into, and an end byte.
+
+use crate::listpack::{integer, string, take};
+use bytes::Bytes;
+
+const HEADER_LEN: usize = 10;
+const END: u8 = 0xff;
+// Lengths of the previous element this long or longer take up four more bytes.
+const PREVLEN_BIG: u8 = 0xfe;
+
+const ENCODING_INT16: u8 = 0xc0;
+const ENCODING_INT32: u8 = 0xd0;
+const ENCODING_INT64: u8 = 0xe0;
+const ENCODING_INT24: u8 = 0xf0;
+const ENCODING_INT8: u8 = 0xfe;
+// Small integers are stored in the encoding byte, as one more than their value.
+const ENCODING_IMMEDIATE_MIN: u8 = 0xf1;
+const ENCODING_IMMEDIATE_MAX: u8 = 0xfd;
+
+// Zipmap lengths this long or longer take up four more bytes.
+const ZIPMAP_BIG_LEN: u8 = 0xfe;
+
+/// Reads every element of a ziplist, with integers written out as strings. Returns `None` if it's
+/// corrupt.
+pub fn read(zl: &[u8]) -> Option<Vec<Bytes>> {
+    let total = u32::from_le_bytes(zl.get(..4)?.try_into().ok()?) as usize;
+    if total != zl.len() || zl.last() != Some(&END) {
+        return None;
+    }
+
+    let mut elements = Vec::new();
+    let mut at = HEADER_LEN;
+    loop {
+        let prevlen = *zl.get(at)?;
+        if prevlen == END {
+            break;
+        }
+        at += if prevlen == PREVLEN_BIG { 5 } else { 1 };
+
+        let encoding = *zl.get(at)?;
+        at += 1;
+        let element = match encoding >> 6 {
+            0 => string(zl, &mut at, (encoding & 0x3f) as usize)?,
+            1 => {
+                let low = take(zl, &mut at, 1)?[0];
+                let len = u16::from_be_bytes([encoding & 0x3f, low]);
+                string(zl, &mut at, len as usize)?
+            }
+            2 => {
+                let len = u32::from_be_bytes(take(zl, &mut at, 4)?.try_into().ok()?);
+                string(zl, &mut at, len as usize)?
+            }
+            _ => match encoding {
+                ENCODING_INT16 => integer(zl, &mut at, 2)?,
+                ENCODING_INT32 => integer(zl, &mut at, 4)?,
+                ENCODING_INT64 => integer(zl, &mut at, 8)?,
+                ENCODING_INT24 => integer(zl, &mut at, 3)?,
+                ENCODING_INT8 => integer(zl, &mut at, 1)?,
+                ENCODING_IMMEDIATE_MIN..=ENCODING_IMMEDIATE_MAX => {
+                    Bytes::from(((encoding & 0x0f) - 1).to_string())
+                }
+                _ => return None,
+            },
+        };
+        elements.push(element);
+    }
+
+    (at == zl.len() - 1).then_some(elements)
+}
+
+/// Reads the pairs of a zipmap. Returns `None` if it's corrupt.
+pub fn read_zipmap(zm: &[u8]) -> Option<Vec<(Bytes, Bytes)>> {
+    let mut pairs = Vec::new();
+    let mut at = 1;
+    while let Some(key_len) = zipmap_len(zm, &mut at)? {
+        let key = take(zm, &mut at, key_len)?;
+        let value_len = zipmap_len(zm, &mut at)??;
+        let free = *zm.get(at)? as usize;
+        at += 1;
+        let value = take(zm, &mut at, value_len)?;
+        take(zm, &mut at, free)?;
+        pairs.push((Bytes::copy_from_slice(key), Bytes::copy_from_slice(value)));
+    }
+
+    (at == zm.len()).then_some(pairs)
+}
+
+// Reads a zipmap length, which is `None` at the end byte.
+fn zipmap_len(zm: &[u8], at: &mut usize) -> Option<Option<usize>> {
+    let first = *zm.get(*at)?;
+    *at += 1;
+    match first {
+        END => Some(None),
+        ZIPMAP_BIG_LEN => {
+            let len = u32::from_le_bytes(take(zm, at, 4)?.try_into().ok()?);
+            Some(Some(len as usize))
+        }
+        len => Some(Some(len as usize)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read() {
+        let body = [
+            &[0x00, 0x05][..],
+            b"hello",
+            &[0x07, 0xf4],
+            &[0x02, 0xc0, 0x00, 0x04],
+            &[0x04, 0xf0, 0xff, 0xff, 0xff],
+            &[0x05, 0xfe, 0x80],
+        ]
+        .concat();
+        let mut zl = Vec::new();
+        zl.extend_from_slice(&((HEADER_LEN + body.len() + 1) as u32).to_le_bytes());
+        zl.extend_from_slice(&0u32.to_le_bytes());
+        zl.extend_from_slice(&5u16.to_le_bytes());
+        zl.extend_from_slice(&body);
+        zl.push(END);
+
+        let elements = read(&zl).unwrap();
+        assert_eq!(elements, ["hello", "3", "1024", "-1", "-128"]);
+        assert_eq!(read(&zl[..zl.len() - 1]), None);
+    }
+
+    #[test]
+    fn test_read_zipmap() {
+        let zm = [
+            &[0x02, 0x03][..],
+            b"foo",
+            &[0x03, 0x02],
+            b"bar",
+            &[0, 0],
+            &[0x01],
+            b"a",
+            &[0x00, 0x00, 0xff],
+        ]
+        .concat();
+        assert_eq!(
+            read_zipmap(&zm).unwrap(),
+            [
+                (Bytes::from("foo"), Bytes::from("bar")),
+                (Bytes::from("a"), Bytes::new()),
+            ]
+        );
+        assert_eq!(read_zipmap(&zm[..zm.len() - 1]), None);
+    }
+}
@@ -0,0 +1,1137 @@
+//! The sorted set type: distinct members each with a score, kept ordered by score and then by
+//! member. A map gives each member's score directly, and a [`SkipList`] gives the order.
+
+use std::{collections::HashMap, ops::Range};
+
+use bytes::Bytes;
+
+use crate::{
+    error::CommandError,
+    protocol_parser::{
+        format_double, normalize_range, parse_cursor, parse_float, parse_integer, CommandArgs,
+        Protocol, RESPValue, Response,
+    },
+    rdb::{DBEntry, Rdb, Value},
+    scan::{scan_reply, ScanIndex, ScanOptions},
+    set::Set,
+    skiplist::{self, SkipList},
+};
+
+#[derive(Clone, Debug, Default)]
+pub struct SortedSet {
+    scores: HashMap<Bytes, f64>,
+    index: SkipList,
+    scan_index: ScanIndex,
+}
+
+// Two sorted sets are equal when they have the same members with the same scores.
+impl PartialEq for SortedSet {
+    fn eq(&self, other: &Self) -> bool {
+        self.scores == other.scores
+    }
+}
+
+impl FromIterator<(Bytes, f64)> for SortedSet {
+    fn from_iter<I: IntoIterator<Item = (Bytes, f64)>>(entries: I) -> Self {
+        let mut set = SortedSet::default();
+        for (member, score) in entries {
+            set.insert(member, score);
+        }
+        set
+    }
+}
+
+impl SortedSet {
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    /// Adds a member or changes its score, returning whether it was newly added.
+    pub fn insert(&mut self, member: Bytes, score: f64) -> bool {
+        match self.scores.insert(member.clone(), score) {
+            Some(previous) => {
+                if previous != score {
+                    self.index.remove(previous, &member);
+                    self.index.insert(score, member);
+                }
+                false
+            }
+            None => {
+                self.scan_index.insert(member.clone());
+                self.index.insert(score, member);
+                true
+            }
+        }
+    }
+
+    /// Removes a member, returning whether it was there.
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        let Some((member, score)) = self.scores.remove_entry(member) else {
+            return false;
+        };
+        self.index.remove(score, &member);
+        self.scan_index.remove(member);
+        true
+    }
+
+    /// The position of a member in score order, counting from zero.
+    pub fn rank(&self, member: &[u8]) -> Option<usize> {
+        let score = self.score(member)?;
+        Some(
+            self.index
+                .count_while(|s, m| s < score || (s == score && m.as_ref() < member)),
+        )
+    }
+
+    /// Every member in score order, lowest first.
+    pub fn iter(&self) -> skiplist::Iter<'_> {
+        self.index.iter_from(0)
+    }
+
+    /// The ranks of the members whose scores fall within `range`.
+    pub fn score_ranks(&self, range: &ScoreRange) -> Range<usize> {
+        let start = self.index.count_while(|score, _| range.min.below(score));
+        let end = self.index.count_while(|score, _| !range.max.above(score));
+        start..end.max(start)
+    }
+
+    /// The ranks of the members within `range`, when compared as strings. This only makes sense
+    /// when every member has the same score.
+    pub fn lex_ranks(&self, range: &LexRange) -> Range<usize> {
+        let start = self.index.count_while(|_, member| range.min.below(member));
+        let end = self.index.count_while(|_, member| !range.max.above(member));
+        start..end.max(start)
+    }
+
+    /// The members with ranks in `ranks`, in score order or, if `rev` is set, the reverse.
+    pub fn entries(&self, ranks: Range<usize>, rev: bool) -> Vec<(Bytes, f64)> {
+        if ranks.is_empty() {
+            return vec![];
+        }
+        let iter = if rev {
+            self.index.iter_back_from(ranks.end - 1)
+        } else {
+            self.index.iter_from(ranks.start)
+        };
+        iter.take(ranks.len())
+            .map(|(member, score)| (member.clone(), score))
+            .collect()
+    }
+
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<Bytes>) {
+        self.scan_index.scan(cursor, count)
+    }
+}
+
+/// One end of a score range, as given to ZRANGE BYSCORE or ZCOUNT: `1.5`, `(1.5` to exclude the
+/// score itself, or `-inf` and `+inf`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ScoreBound {
+    value: f64,
+    exclusive: bool,
+}
+
+impl ScoreBound {
+    pub fn new(value: f64, exclusive: bool) -> Self {
+        ScoreBound { value, exclusive }
+    }
+
+    pub fn parse(arg: &[u8]) -> Result<Self, CommandError> {
+        let (value, exclusive) = match arg.strip_prefix(b"(") {
+            Some(value) => (value, true),
+            None => (arg, false),
+        };
+        let value = parse_float(value)
+            .map_err(|_| CommandError::Other("min or max is not a float".to_string()))?;
+        Ok(ScoreBound { value, exclusive })
+    }
+
+    // Whether `score` is below this bound, taken as a minimum.
+    fn below(&self, score: f64) -> bool {
+        score < self.value || (self.exclusive && score == self.value)
+    }
+
+    // Whether `score` is above this bound, taken as a maximum.
+    fn above(&self, score: f64) -> bool {
+        score > self.value || (self.exclusive && score == self.value)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ScoreRange {
+    pub min: ScoreBound,
+    pub max: ScoreBound,
+}
+
+/// One end of a string range, as given to ZRANGE BYLEX: `[a` or `(a` to include or exclude `a`,
+/// or `-` and `+` for the smallest and largest possible strings.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LexBound {
+    Min,
+    Max,
+    Inclusive(Bytes),
+    Exclusive(Bytes),
+}
+
+impl LexBound {
+    pub fn parse(arg: &Bytes) -> Result<Self, CommandError> {
+        match arg.first() {
+            Some(b'-') if arg.len() == 1 => Ok(LexBound::Min),
+            Some(b'+') if arg.len() == 1 => Ok(LexBound::Max),
+            Some(b'[') => Ok(LexBound::Inclusive(arg.slice(1..))),
+            Some(b'(') => Ok(LexBound::Exclusive(arg.slice(1..))),
+            _ => Err(CommandError::Other(
+                "min or max not valid string range item".to_string(),
+            )),
+        }
+    }
+
+    fn below(&self, member: &[u8]) -> bool {
+        match self {
+            LexBound::Min => false,
+            LexBound::Max => true,
+            LexBound::Inclusive(bound) => member < bound.as_ref(),
+            LexBound::Exclusive(bound) => member <= bound.as_ref(),
+        }
+    }
+
+    fn above(&self, member: &[u8]) -> bool {
+        match self {
+            LexBound::Min => true,
+            LexBound::Max => false,
+            LexBound::Inclusive(bound) => member > bound.as_ref(),
+            LexBound::Exclusive(bound) => member >= bound.as_ref(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LexRange {
+    pub min: LexBound,
+    pub max: LexBound,
+}
+
+/// Which members ZRANGE and ZRANGESTORE select.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RangeBy {
+    Index { start: i64, stop: i64 },
+    Score(ScoreRange),
+    Lex(LexRange),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RangeQuery {
+    by: RangeBy,
+    rev: bool,
+    /// An offset and count to page through the selected members, where a negative count means
+    /// all of them.
+    limit: Option<(i64, i64)>,
+}
+
+impl RangeQuery {
+    // Parses `start stop [BYSCORE | BYLEX] [REV] [LIMIT offset count]`, along with WITHSCORES if
+    // `with_scores` is given to record it in.
+    fn parse(
+        args: &mut CommandArgs,
+        mut with_scores: Option<&mut bool>,
+    ) -> Result<Self, CommandError> {
+        let start = args.required()?;
+        let stop = args.required()?;
+        let (mut by_score, mut by_lex, mut rev, mut limit) = (false, false, false, None);
+
+        while let Some(option) = args.next() {
+            match String::from_utf8_lossy(&option)
+                .to_ascii_uppercase()
+                .as_str()
+            {
+                "BYSCORE" => by_score = true,
+                "BYLEX" => by_lex = true,
+                "REV" => rev = true,
+                "LIMIT" => {
+                    let offset = parse_integer(&args.option_value()?)?;
+                    let count = parse_integer(&args.option_value()?)?;
+                    limit = Some((offset, count));
+                }
+                "WITHSCORES" if with_scores.is_some() => {
+                    if let Some(with_scores) = with_scores.as_deref_mut() {
+                        *with_scores = true;
+                    }
+                }
+                _ => return Err(CommandError::Syntax),
+            }
+        }
+
+        if by_score && by_lex {
+            return Err(CommandError::Syntax);
+        }
+        if limit.is_some() && !by_score && !by_lex {
+            return Err(CommandError::Other(
+                "syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX"
+                    .to_string(),
+            ));
+        }
+        if by_lex && with_scores.is_some_and(|with_scores| *with_scores) {
+            return Err(CommandError::Other(
+                "syntax error, WITHSCORES not supported in combination with BYLEX".to_string(),
+            ));
+        }
+
+        // Reversed score and string ranges are given from the top down.
+        let (low, high) = if rev && (by_score || by_lex) {
+            (stop, start)
+        } else {
+            (start, stop)
+        };
+        let by = if by_score {
+            RangeBy::Score(ScoreRange {
+                min: ScoreBound::parse(&low)?,
+                max: ScoreBound::parse(&high)?,
+            })
+        } else if by_lex {
+            RangeBy::Lex(LexRange {
+                min: LexBound::parse(&low)?,
+                max: LexBound::parse(&high)?,
+            })
+        } else {
+            RangeBy::Index {
+                start: parse_integer(&low)?,
+                stop: parse_integer(&high)?,
+            }
+        };
+
+        Ok(RangeQuery { by, rev, limit })
+    }
+
+    fn run(&self, set: &SortedSet) -> Vec<(Bytes, f64)> {
+        let mut ranks = match &self.by {
+            RangeBy::Index { start, stop } => match normalize_range(*start, *stop, set.len()) {
+                // Reversed indexes count from the highest score.
+                Some((start, stop)) if self.rev => set.len() - 1 - stop..set.len() - start,
+                Some((start, stop)) => start..stop + 1,
+                None => 0..0,
+            },
+            RangeBy::Score(range) => set.score_ranks(range),
+            RangeBy::Lex(range) => set.lex_ranks(range),
+        };
+
+        if let Some((offset, count)) = self.limit {
+            if offset < 0 {
+                return vec![];
+            }
+            let offset = (offset as usize).min(ranks.len());
+            let count = if count < 0 {
+                ranks.len()
+            } else {
+                count as usize
+            };
+            let len = count.min(ranks.len() - offset);
+            ranks = if self.rev {
+                ranks.end - offset - len..ranks.end - offset
+            } else {
+                ranks.start + offset..ranks.start + offset + len
+            };
+        }
+
+        set.entries(ranks, self.rev)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Aggregate {
+    Sum,
+    Min,
+    Max,
+}
+
+impl Aggregate {
+    fn apply(self, a: f64, b: f64) -> f64 {
+        match self {
+            // Adding opposite infinities gives NaN, which Redis turns into zero.
+            Aggregate::Sum if (a + b).is_nan() => 0.0,
+            Aggregate::Sum => a + b,
+            Aggregate::Min => a.min(b),
+            Aggregate::Max => a.max(b),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ZSetOperation {
+    Union,
+    Intersection,
+    Difference,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SortedSetCommand {
+    Add {
+        key: Bytes,
+        entries: Vec<(f64, Bytes)>,
+        options: AddOptions,
+    },
+    Rem {
+        key: Bytes,
+        members: Vec<Bytes>,
+    },
+    Score {
+        key: Bytes,
+        member: Bytes,
+    },
+    MScore {
+        key: Bytes,
+        members: Vec<Bytes>,
+    },
+    IncrBy {
+        key: Bytes,
+        increment: f64,
+        member: Bytes,
+    },
+    Card(Bytes),
+    Count {
+        key: Bytes,
+        range: ScoreRange,
+    },
+    Rank {
+        key: Bytes,
+        member: Bytes,
+        rev: bool,
+        with_score: bool,
+    },
+    Range {
+        key: Bytes,
+        query: RangeQuery,
+        with_scores: bool,
+    },
+    RangeStore {
+        destination: Bytes,
+        source: Bytes,
+        query: RangeQuery,
+    },
+    Pop {
+        key: Bytes,
+        max: bool,
+        count: Option<usize>,
+    },
+    Store {
+        operation: ZSetOperation,
+        destination: Bytes,
+        keys: Vec<Bytes>,
+        weights: Vec<f64>,
+        aggregate: Aggregate,
+    },
+    Scan {
+        key: Bytes,
+        cursor: u64,
+        options: ScanOptions,
+    },
+}
+
+/// The flags ZADD takes before its score and member pairs.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct AddOptions {
+    nx: bool,
+    xx: bool,
+    gt: bool,
+    lt: bool,
+    ch: bool,
+    incr: bool,
+}
+
+impl SortedSetCommand {
+    /// Parses the arguments of the sorted set command `name`, which must be upper-case.
+    pub fn parse(name: &str, args: &mut CommandArgs) -> Result<Self, CommandError> {
+        let command = match name {
+            "ZADD" => {
+                let key = args.required()?;
+                let mut rest = args.at_least_one()?.into_iter().peekable();
+                let mut options = AddOptions::default();
+                while let Some(option) = rest.peek() {
+                    match String::from_utf8_lossy(option)
+                        .to_ascii_uppercase()
+                        .as_str()
+                    {
+                        "NX" => options.nx = true,
+                        "XX" => options.xx = true,
+                        "GT" => options.gt = true,
+                        "LT" => options.lt = true,
+                        "CH" => options.ch = true,
+                        "INCR" => options.incr = true,
+                        _ => break,
+                    }
+                    rest.next();
+                }
+
+                let rest: Vec<Bytes> = rest.collect();
+                let pairs = rest.chunks_exact(2);
+                if rest.is_empty() || !pairs.remainder().is_empty() {
+                    return Err(CommandError::Syntax);
+                }
+                if options.nx && options.xx {
+                    return Err(CommandError::Other(
+                        "XX and NX options at the same time are not compatible".to_string(),
+                    ));
+                }
+                if [options.nx, options.gt, options.lt]
+                    .iter()
+                    .filter(|&&set| set)
+                    .count()
+                    > 1
+                {
+                    return Err(CommandError::Other(
+                        "GT, LT, and/or NX options at the same time are not compatible".to_string(),
+                    ));
+                }
+                if options.incr && rest.len() > 2 {
+                    return Err(CommandError::Other(
+                        "INCR option supports a single increment-element pair".to_string(),
+                    ));
+                }
+
+                let entries = pairs
+                    .map(|pair| Ok((parse_float(&pair[0])?, pair[1].clone())))
+                    .collect::<Result<_, CommandError>>()?;
+                SortedSetCommand::Add {
+                    key,
+                    entries,
+                    options,
+                }
+            }
+            "ZREM" => SortedSetCommand::Rem {
+                key: args.required()?,
+                members: args.at_least_one()?,
+            },
+            "ZSCORE" => SortedSetCommand::Score {
+                key: args.required()?,
+                member: args.required()?,
+            },
+            "ZMSCORE" => SortedSetCommand::MScore {
+                key: args.required()?,
+                members: args.at_least_one()?,
+            },
+            "ZINCRBY" => SortedSetCommand::IncrBy {
+                key: args.required()?,
+                increment: parse_float(&args.required()?)?,
+                member: args.required()?,
+            },
+            "ZCARD" => SortedSetCommand::Card(args.required()?),
+            "ZCOUNT" => SortedSetCommand::Count {
+                key: args.required()?,
+                range: ScoreRange {
+                    min: ScoreBound::parse(&args.required()?)?,
+                    max: ScoreBound::parse(&args.required()?)?,
+                },
+            },
+            "ZRANK" | "ZREVRANK" => {
+                let key = args.required()?;
+                let member = args.required()?;
+                let with_score = match args.next() {
+                    Some(option) if option.eq_ignore_ascii_case(b"WITHSCORE") => true,
+                    Some(_) => return Err(CommandError::Syntax),
+                    None => false,
+                };
+                SortedSetCommand::Rank {
+                    key,
+                    member,
+                    rev: name == "ZREVRANK",
+                    with_score,
+                }
+            }
+            "ZRANGE" => {
+                let key = args.required()?;
+                let mut with_scores = false;
+                let query = RangeQuery::parse(args, Some(&mut with_scores))?;
+                SortedSetCommand::Range {
+                    key,
+                    query,
+                    with_scores,
+                }
+            }
+            "ZRANGESTORE" => SortedSetCommand::RangeStore {
+                destination: args.required()?,
+                source: args.required()?,
+                query: RangeQuery::parse(args, None)?,
+            },
+            "ZPOPMIN" | "ZPOPMAX" => {
+                let key = args.required()?;
+                let count = match args.next() {
+                    Some(count) => {
+                        let count: i64 = parse_integer(&count)?;
+                        Some(usize::try_from(count).map_err(|_| CommandError::NotPositive)?)
+                    }
+                    None => None,
+                };
+                SortedSetCommand::Pop {
+                    key,
+                    max: name == "ZPOPMAX",
+                    count,
+                }
+            }
+            "ZUNIONSTORE" | "ZINTERSTORE" | "ZDIFFSTORE" => {
+                let operation = match name {
+                    "ZUNIONSTORE" => ZSetOperation::Union,
+                    "ZINTERSTORE" => ZSetOperation::Intersection,
+                    _ => ZSetOperation::Difference,
+                };
+                let destination = args.required()?;
+                let numkeys: i64 = parse_integer(&args.required()?)?;
+                if numkeys <= 0 {
+                    return Err(CommandError::Other(format!(
+                        "at least 1 input key is needed for '{}' command",
+                        name.to_ascii_lowercase()
+                    )));
+                }
+                if numkeys as usize > args.len() {
+                    return Err(CommandError::Syntax);
+                }
+                let keys: Vec<Bytes> = (0..numkeys)
+                    .map(|_| args.required())
+                    .collect::<Result<_, _>>()?;
+
+                let mut weights = vec![1.0; keys.len()];
+                let mut aggregate = Aggregate::Sum;
+                while let Some(option) = args.next() {
+                    // ZDIFFSTORE takes neither option, as only the first set's scores matter.
+                    if operation == ZSetOperation::Difference {
+                        return Err(CommandError::Syntax);
+                    }
+                    match String::from_utf8_lossy(&option)
+                        .to_ascii_uppercase()
+                        .as_str()
+                    {
+                        "WEIGHTS" => {
+                            for weight in weights.iter_mut() {
+                                *weight = parse_float(&args.option_value()?).map_err(|_| {
+                                    CommandError::Other("weight value is not a float".to_string())
+                                })?;
+                            }
+                        }
+                        "AGGREGATE" => {
+                            aggregate = match String::from_utf8_lossy(&args.option_value()?)
+                                .to_ascii_uppercase()
+                                .as_str()
+                            {
+                                "SUM" => Aggregate::Sum,
+                                "MIN" => Aggregate::Min,
+                                "MAX" => Aggregate::Max,
+                                _ => return Err(CommandError::Syntax),
+                            };
+                        }
+                        _ => return Err(CommandError::Syntax),
+                    }
+                }
+
+                SortedSetCommand::Store {
+                    operation,
+                    destination,
+                    keys,
+                    weights,
+                    aggregate,
+                }
+            }
+            "ZSCAN" => {
+                let key = args.required()?;
+                let cursor = parse_cursor(&args.required()?)?;
+                let mut options = ScanOptions::default();
+                while let Some(option) = args.next() {
+                    let option = String::from_utf8_lossy(&option).to_ascii_uppercase();
+                    if !options.parse_option(&option, args)? {
+                        return Err(CommandError::Syntax);
+                    }
+                }
+                SortedSetCommand::Scan {
+                    key,
+                    cursor,
+                    options,
+                }
+            }
+            _ => unreachable!("not a sorted set command: {}", name),
+        };
+
+        Ok(command)
+    }
+
+    pub fn execute(self, db: &mut Rdb, protocol: Protocol) -> Result<Response, CommandError> {
+        let response = match self {
+            SortedSetCommand::Add {
+                key,
+                entries,
+                options,
+            } => {
+                let set = sorted_set_or_insert(db, &key)?;
+                let (mut added, mut changed) = (0, 0);
+                let mut incremented = None;
+
+                for (score, member) in entries {
+                    let new_score = match set.score(&member) {
+                        Some(_) if options.nx => continue,
+                        None if options.xx => continue,
+                        Some(current) => {
+                            let new_score = if options.incr { current + score } else { score };
+                            if new_score.is_nan() {
+                                db.remove_if_empty(&key);
+                                return Err(nan_score());
+                            }
+                            if (options.gt && new_score <= current)
+                                || (options.lt && new_score >= current)
+                            {
+                                continue;
+                            }
+                            if new_score != current {
+                                changed += 1;
+                            }
+                            new_score
+                        }
+                        None => {
+                            added += 1;
+                            score
+                        }
+                    };
+                    set.insert(member, new_score);
+                    incremented = Some(new_score);
+                }
+
+                db.mark_dirty((added + changed) as usize);
+                // Flags like NX can leave a newly created key empty.
+                db.remove_if_empty(&key);
+                if options.incr {
+                    match incremented {
+                        Some(score) => Response::Echo(RESPValue::Double(score)),
+                        None => Response::Null,
+                    }
+                } else if options.ch {
+                    Response::Echo(RESPValue::Integer(added + changed))
+                } else {
+                    Response::Echo(RESPValue::Integer(added))
+                }
+            }
+            SortedSetCommand::Rem { key, members } => {
+                let Some(set) = sorted_set_mut(db, &key)? else {
+                    return Ok(Response::Echo(RESPValue::Integer(0)));
+                };
+                let removed = members.iter().filter(|member| set.remove(member)).count();
+                db.mark_dirty(removed);
+                db.remove_if_empty(&key);
+                Response::Echo(RESPValue::Integer(removed as i64))
+            }
+            SortedSetCommand::Score { key, member } => {
+                match sorted_set(db, &key)?.and_then(|set| set.score(&member)) {
+                    Some(score) => Response::Echo(RESPValue::Double(score)),
+                    None => Response::Null,
+                }
+            }
+            SortedSetCommand::MScore { key, members } => {
+                let set = sorted_set(db, &key)?;
+                let scores = members
+                    .iter()
+                    .map(|member| match set.and_then(|set| set.score(member)) {
+                        Some(score) => RESPValue::Double(score),
+                        None => RESPValue::Null,
+                    })
+                    .collect();
+                Response::Echo(RESPValue::Array(scores))
+            }
+            SortedSetCommand::IncrBy {
+                key,
+                increment,
+                member,
+            } => {
+                let set = sorted_set_or_insert(db, &key)?;
+                let score = set.score(&member).unwrap_or(0.0) + increment;
+                if score.is_nan() {
+                    return Err(nan_score());
+                }
+                set.insert(member, score);
+                db.mark_dirty(1);
+                Response::Echo(RESPValue::Double(score))
+            }
+            SortedSetCommand::Card(key) => {
+                let len = sorted_set(db, &key)?.map_or(0, SortedSet::len);
+                Response::Echo(RESPValue::Integer(len as i64))
+            }
+            SortedSetCommand::Count { key, range } => {
+                let count = sorted_set(db, &key)?.map_or(0, |set| set.score_ranks(&range).len());
+                Response::Echo(RESPValue::Integer(count as i64))
+            }
+            SortedSetCommand::Rank {
+                key,
+                member,
+                rev,
+                with_score,
+            } => {
+                let Some(set) = sorted_set(db, &key)? else {
+                    return Ok(Response::Null);
+                };
+                let Some(rank) = set.rank(&member) else {
+                    return Ok(Response::Null);
+                };
+                let rank = if rev { set.len() - 1 - rank } else { rank };
+                let rank = RESPValue::Integer(rank as i64);
+                match set.score(&member) {
+                    Some(score) if with_score => {
+                        Response::Echo(RESPValue::Array(vec![rank, RESPValue::Double(score)]))
+                    }
+                    _ => Response::Echo(rank),
+                }
+            }
+            SortedSetCommand::Range {
+                key,
+                query,
+                with_scores,
+            } => {
+                let entries = match sorted_set(db, &key)? {
+                    Some(set) => query.run(set),
+                    None => vec![],
+                };
+                Response::Echo(RESPValue::Array(scored_entries(
+                    entries,
+                    with_scores,
+                    protocol,
+                )))
+            }
+            SortedSetCommand::RangeStore {
+                destination,
+                source,
+                query,
+            } => {
+                let entries = match sorted_set(db, &source)? {
+                    Some(set) => query.run(set),
+                    None => vec![],
+                };
+                let len = entries.len();
+                store(db, destination, entries);
+                Response::Echo(RESPValue::Integer(len as i64))
+            }
+            SortedSetCommand::Pop { key, max, count } => {
+                let Some(set) = sorted_set_mut(db, &key)? else {
+                    return Ok(Response::Echo(RESPValue::Array(vec![])));
+                };
+                let len = set.len();
+                let taken = count.unwrap_or(1).min(len);
+                let popped = if max {
+                    set.entries(len - taken..len, true)
+                } else {
+                    set.entries(0..taken, false)
+                };
+                for (member, _) in &popped {
+                    set.remove(member);
+                }
+                db.mark_dirty(popped.len());
+                db.remove_if_empty(&key);
+
+                // A single pop is always a flat member and score, as it was before COUNT existed.
+                let protocol = if count.is_some() {
+                    protocol
+                } else {
+                    Protocol::Resp2
+                };
+                Response::Echo(RESPValue::Array(scored_entries(popped, true, protocol)))
+            }
+            SortedSetCommand::Store {
+                operation,
+                destination,
+                keys,
+                weights,
+                aggregate,
+            } => {
+                let entries = combine(db, operation, &keys, &weights, aggregate)?;
+                let len = entries.len();
+                store(db, destination, entries);
+                Response::Echo(RESPValue::Integer(len as i64))
+            }
+            SortedSetCommand::Scan {
+                key,
+                cursor,
+                options,
+            } => {
+                let Some(set) = sorted_set(db, &key)? else {
+                    return Ok(scan_reply(0, vec![]));
+                };
+                let (cursor, visited) = set.scan(cursor, options.count());
+
+                let mut items = Vec::new();
+                for member in visited {
+                    let Some(score) = set.score(&member) else {
+                        continue;
+                    };
+                    if !options.matches(&member) {
+                        continue;
+                    }
+                    items.push(RESPValue::BulkString(member));
+                    items.push(RESPValue::BulkString(Bytes::from(format_double(score))));
+                }
+
+                scan_reply(cursor, items)
+            }
+        };
+
+        Ok(response)
+    }
+}
+
+pub fn sorted_set<'a>(db: &'a mut Rdb, key: &[u8]) -> Result<Option<&'a SortedSet>, CommandError> {
+    db.get(key)
+        .map(|entry| entry.value().as_sorted_set())
+        .transpose()
+}
+
+fn sorted_set_mut<'a>(
+    db: &'a mut Rdb,
+    key: &[u8],
+) -> Result<Option<&'a mut SortedSet>, CommandError> {
+    db.get_mut(key)
+        .map(|entry| entry.value_mut().as_sorted_set_mut())
+        .transpose()
+}
+
+pub fn sorted_set_or_insert<'a>(
+    db: &'a mut Rdb,
+    key: &Bytes,
+) -> Result<&'a mut SortedSet, CommandError> {
+    db.get_or_insert_with(key, || Value::SortedSet(SortedSet::default()))
+        .value_mut()
+        .as_sorted_set_mut()
+}
+
+/// Replaces whatever `destination` held with a sorted set of `entries`, or deletes it if there
+/// are none, as the STORE commands do.
+pub fn store(db: &mut Rdb, destination: Bytes, entries: Vec<(Bytes, f64)>) {
+    db.remove(&destination);
+    if !entries.is_empty() {
+        let set = entries.into_iter().collect();
+        db.insert(destination, DBEntry::new(Value::SortedSet(set), None));
+    }
+}
+
+/// Builds the reply for a list of members, with their scores if asked for. RESP3 clients get each
+/// member paired with its score, RESP2 clients a flat list of alternating members and scores.
+pub fn scored_entries(
+    entries: Vec<(Bytes, f64)>,
+    with_scores: bool,
+    protocol: Protocol,
+) -> Vec<RESPValue> {
+    entries
+        .into_iter()
+        .flat_map(|(member, score)| {
+            let member = RESPValue::BulkString(member);
+            match (with_scores, protocol) {
+                (false, _) => vec![member],
+                (true, Protocol::Resp3) => {
+                    vec![RESPValue::Array(vec![member, RESPValue::Double(score)])]
+                }
+                (true, Protocol::Resp2) => vec![member, RESPValue::Double(score)],
+            }
+        })
+        .collect()
+}
+
+fn nan_score() -> CommandError {
+    CommandError::Other("resulting score is not a number (NaN)".to_string())
+}
+
+// An input to ZUNIONSTORE and friends, which also accept plain sets, whose members all score 1.
+enum Input<'a> {
+    Sorted(&'a SortedSet),
+    Plain(&'a Set),
+}
+
+impl<'a> Input<'a> {
+    fn len(&self) -> usize {
+        match self {
+            Input::Sorted(set) => set.len(),
+            Input::Plain(set) => set.len(),
+        }
+    }
+
+    fn score(&self, member: &[u8]) -> Option<f64> {
+        match self {
+            Input::Sorted(set) => set.score(member),
+            Input::Plain(set) => set.contains(member).then_some(1.0),
+        }
+    }
+
+    fn entries(&self) -> Box<dyn Iterator<Item = (&'a Bytes, f64)> + 'a> {
+        match *self {
+            Input::Sorted(set) => Box::new(set.iter()),
+            Input::Plain(set) => Box::new(set.iter().map(|member| (member, 1.0))),
+        }
+    }
+}
+
+// Works out the result of ZUNIONSTORE, ZINTERSTORE or ZDIFFSTORE. Missing keys count as empty.
+fn combine(
+    db: &Rdb,
+    operation: ZSetOperation,
+    keys: &[Bytes],
+    weights: &[f64],
+    aggregate: Aggregate,
+) -> Result<Vec<(Bytes, f64)>, CommandError> {
+    let inputs = keys
+        .iter()
+        .map(|key| match db.peek(key).map(DBEntry::value) {
+            None => Ok(None),
+            Some(Value::SortedSet(set)) => Ok(Some(Input::Sorted(set))),
+            Some(Value::Set(set)) => Ok(Some(Input::Plain(set))),
+            Some(_) => Err(CommandError::WrongType),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Redis treats a weighted score that comes out as NaN, like infinity times zero, as zero.
+    let weigh = |score: f64, weight: f64| {
+        let weighted = score * weight;
+        if weighted.is_nan() {
+            0.0
+        } else {
+            weighted
+        }
+    };
+
+    let entries = match operation {
+        ZSetOperation::Union => {
+            let mut scores: HashMap<Bytes, f64> = HashMap::new();
+            for (input, &weight) in inputs.iter().zip(weights) {
+                for (member, score) in input.iter().flat_map(Input::entries) {
+                    let score = weigh(score, weight);
+                    scores
+                        .entry(member.clone())
+                        .and_modify(|total| *total = aggregate.apply(*total, score))
+                        .or_insert(score);
+                }
+            }
+            scores.into_iter().collect()
+        }
+        ZSetOperation::Intersection => {
+            let Some(inputs) = inputs.into_iter().collect::<Option<Vec<_>>>() else {
+                return Ok(vec![]);
+            };
+            // Walk the smallest input, looking its members up in the rest.
+            let smallest = (0..inputs.len())
+                .min_by_key(|&i| inputs[i].len())
+                .unwrap_or_default();
+
+            inputs[smallest]
+                .entries()
+                .filter_map(|(member, _)| {
+                    let mut total: Option<f64> = None;
+                    for (input, &weight) in inputs.iter().zip(weights) {
+                        let score = weigh(input.score(member)?, weight);
+                        total = Some(match total {
+                            Some(total) => aggregate.apply(total, score),
+                            None => score,
+                        });
+                    }
+                    Some((member.clone(), total?))
+                })
+                .collect()
+        }
+        ZSetOperation::Difference => match inputs.split_first() {
+            Some((Some(first), rest)) => first
+                .entries()
+                .filter(|(member, _)| {
+                    rest.iter()
+                        .flatten()
+                        .all(|input| input.score(member).is_none())
+                })
+                .map(|(member, score)| (member.clone(), score))
+                .collect(),
+            _ => vec![],
+        },
+    };
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted_set(entries: &[(&'static str, f64)]) -> SortedSet {
+        entries
+            .iter()
+            .map(|(member, score)| (Bytes::from(*member), *score))
+            .collect()
+    }
+
+    fn members(entries: Vec<(Bytes, f64)>) -> Vec<Bytes> {
+        entries.into_iter().map(|(member, _)| member).collect()
+    }
+
+    #[test]
+    fn test_insert_updates_order() {
+        let mut set = sorted_set(&[("a", 1.0), ("b", 2.0), ("c", 3.0)]);
+        assert!(!set.insert(Bytes::from("a"), 5.0));
+        assert_eq!(set.len(), 3);
+        assert_eq!(set.rank(b"a"), Some(2));
+        assert_eq!(set.rank(b"b"), Some(0));
+        assert!(set.remove(b"b"));
+        assert_eq!(set.rank(b"c"), Some(0));
+        assert_eq!(set.rank(b"b"), None);
+    }
+
+    #[test]
+    fn test_score_ranges() {
+        let set = sorted_set(&[("a", 1.0), ("b", 2.0), ("c", 2.0), ("d", 3.0)]);
+        let range = |min: &str, max: &str| ScoreRange {
+            min: ScoreBound::parse(min.as_bytes()).unwrap(),
+            max: ScoreBound::parse(max.as_bytes()).unwrap(),
+        };
+
+        assert_eq!(set.score_ranks(&range("2", "3")), 1..4);
+        assert_eq!(set.score_ranks(&range("(2", "3")), 3..4);
+        assert_eq!(set.score_ranks(&range("-inf", "(2")), 0..1);
+        assert_eq!(set.score_ranks(&range("3", "1")).len(), 0);
+        assert!(ScoreBound::parse(b"nope").is_err());
+    }
+
+    #[test]
+    fn test_lex_ranges() {
+        let set = sorted_set(&[("a", 0.0), ("b", 0.0), ("c", 0.0), ("d", 0.0)]);
+        let range = |min: &str, max: &str| LexRange {
+            min: LexBound::parse(&Bytes::from(min.to_string())).unwrap(),
+            max: LexBound::parse(&Bytes::from(max.to_string())).unwrap(),
+        };
+
+        assert_eq!(set.lex_ranks(&range("-", "+")), 0..4);
+        assert_eq!(set.lex_ranks(&range("[b", "(d")), 1..3);
+        assert_eq!(set.lex_ranks(&range("(a", "[a")).len(), 0);
+    }
+
+    #[test]
+    fn test_range_query_limit_and_rev() {
+        let set = sorted_set(&[("a", 1.0), ("b", 2.0), ("c", 3.0), ("d", 4.0)]);
+        let query = |by, rev, limit| RangeQuery { by, rev, limit };
+        let all = ScoreRange {
+            min: ScoreBound {
+                value: f64::NEG_INFINITY,
+                exclusive: false,
+            },
+            max: ScoreBound {
+                value: f64::INFINITY,
+                exclusive: false,
+            },
+        };
+
+        assert_eq!(
+            members(query(RangeBy::Index { start: 0, stop: 1 }, true, None).run(&set)),
+            vec!["d", "c"]
+        );
+        assert_eq!(
+            members(query(RangeBy::Score(all), false, Some((1, 2))).run(&set)),
+            vec!["b", "c"]
+        );
+        assert_eq!(
+            members(query(RangeBy::Score(all), true, Some((1, -1))).run(&set)),
+            vec!["c", "b", "a"]
+        );
+        assert!(query(RangeBy::Score(all), false, Some((-1, 2)))
+            .run(&set)
+            .is_empty());
+    }
+}